@@ -0,0 +1,330 @@
+use chrono::Datelike;
+use rusqlite::Connection;
+
+use crate::database;
+use crate::models::BibliographicRecord;
+
+/// Metadata keys holding the bibliographic record, except the title, which
+/// lives under the existing `title` key. They are only written through
+/// `set_record` so values are always validated.
+pub const KEY_PREFIX: &str = "bib.";
+const AUTHORS: &str = "bib.authors";
+const YEAR: &str = "bib.year";
+const VENUE: &str = "bib.venue";
+const DOI: &str = "bib.doi";
+const ARXIV_ID: &str = "bib.arxiv_id";
+const ABSTRACT: &str = "bib.abstract";
+const KEYWORDS: &str = "bib.keywords";
+const URL: &str = "bib.url";
+
+/// Whether a metadata key is part of the bibliographic record.
+pub fn is_record_key(key: &str) -> bool {
+    key == "title" || key.starts_with(KEY_PREFIX)
+}
+
+/// Read the document's bibliographic record from metadata.
+pub fn get_record(db: &Connection) -> Result<BibliographicRecord, String> {
+    let metadata = |key: &str| {
+        database::get_metadata(db, key).map_err(|e| format!("Failed to read {}: {}", key, e))
+    };
+    let list = |key: &str| -> Result<Vec<String>, String> {
+        Ok(metadata(key)?
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default())
+    };
+    Ok(BibliographicRecord {
+        title: metadata("title")?,
+        authors: list(AUTHORS)?,
+        year: metadata(YEAR)?.and_then(|s| s.parse().ok()),
+        venue: metadata(VENUE)?,
+        doi: metadata(DOI)?,
+        arxiv_id: metadata(ARXIV_ID)?,
+        abstract_text: metadata(ABSTRACT)?,
+        keywords: list(KEYWORDS)?,
+        url: metadata(URL)?,
+    })
+}
+
+/// Validate and store a bibliographic record, replacing the previous one.
+/// Returns the record as stored, after normalisation.
+pub fn set_record(
+    db: &Connection,
+    record: &BibliographicRecord,
+) -> Result<BibliographicRecord, String> {
+    let record = validate(record)?;
+    let json = |list: &Vec<String>| {
+        (!list.is_empty()).then(|| serde_json::to_string(list).unwrap_or_default())
+    };
+    let values = [
+        ("title", record.title.clone()),
+        (AUTHORS, json(&record.authors)),
+        (YEAR, record.year.map(|y| y.to_string())),
+        (VENUE, record.venue.clone()),
+        (DOI, record.doi.clone()),
+        (ARXIV_ID, record.arxiv_id.clone()),
+        (ABSTRACT, record.abstract_text.clone()),
+        (KEYWORDS, json(&record.keywords)),
+        (URL, record.url.clone()),
+    ];
+
+    let tx = db
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    for (key, value) in values {
+        match value {
+            Some(value) => database::set_metadata(&tx, key, &value),
+            None => database::delete_metadata(&tx, key).map(|_| ()),
+        }
+        .map_err(|e| format!("Failed to store {}: {}", key, e))?;
+    }
+    tx.commit()
+        .map_err(|e| format!("Failed to save bibliographic record: {}", e))?;
+    Ok(record)
+}
+
+/// Check a record and normalise it: text is trimmed (blank becomes unset),
+/// DOIs and arXiv ids lose URL and scheme prefixes, duplicate keywords go.
+pub fn validate(record: &BibliographicRecord) -> Result<BibliographicRecord, String> {
+    let text = |value: &Option<String>| {
+        value
+            .as_deref()
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    };
+
+    let max_year = chrono::Utc::now().year() + 1;
+    if let Some(year) = record.year {
+        if !(1000..=max_year).contains(&year) {
+            return Err(format!(
+                "Year must be between 1000 and {}, got {}",
+                max_year, year
+            ));
+        }
+    }
+
+    let doi = match text(&record.doi) {
+        Some(doi) => Some(normalize_doi(&doi).ok_or_else(|| format!("Invalid DOI: {}", doi))?),
+        None => None,
+    };
+    let arxiv_id = match text(&record.arxiv_id) {
+        Some(id) => {
+            Some(normalize_arxiv_id(&id).ok_or_else(|| format!("Invalid arXiv id: {}", id))?)
+        }
+        None => None,
+    };
+    let url = match text(&record.url) {
+        Some(raw) => {
+            let parsed =
+                url::Url::parse(&raw).map_err(|e| format!("Invalid URL '{}': {}", raw, e))?;
+            if !matches!(parsed.scheme(), "http" | "https") {
+                return Err(format!("URL must use http or https: {}", raw));
+            }
+            Some(parsed.to_string())
+        }
+        None => None,
+    };
+
+    let mut keywords: Vec<String> = Vec::new();
+    for keyword in record.keywords.iter().map(|k| k.trim()) {
+        if !keyword.is_empty() && !keywords.iter().any(|k| k.eq_ignore_ascii_case(keyword)) {
+            keywords.push(keyword.to_string());
+        }
+    }
+
+    Ok(BibliographicRecord {
+        title: text(&record.title),
+        authors: record
+            .authors
+            .iter()
+            .map(|a| a.trim())
+            .filter(|a| !a.is_empty())
+            .map(str::to_string)
+            .collect(),
+        year: record.year,
+        venue: text(&record.venue),
+        doi,
+        arxiv_id,
+        abstract_text: text(&record.abstract_text),
+        keywords,
+        url,
+    })
+}
+
+/// Bare DOI (`10.NNNN/suffix`) from a DOI, `doi:` reference or doi.org URL.
+pub fn normalize_doi(value: &str) -> Option<String> {
+    let value = value.trim();
+    let lower = value.to_ascii_lowercase();
+    let bare = [
+        "https://doi.org/",
+        "http://doi.org/",
+        "https://dx.doi.org/",
+        "http://dx.doi.org/",
+        "doi:",
+    ]
+    .iter()
+    .find(|prefix| lower.starts_with(*prefix))
+    .map_or(value, |prefix| value[prefix.len()..].trim_start());
+
+    let (registrant, suffix) = bare.split_once('/')?;
+    let code = registrant.strip_prefix("10.")?;
+    let valid_code = (4..=9).contains(&code.len()) && code.chars().all(|c| c.is_ascii_digit());
+    let valid_suffix = !suffix.is_empty() && !suffix.chars().any(char::is_whitespace);
+    (valid_code && valid_suffix).then(|| bare.to_string())
+}
+
+/// Bare arXiv id from an id, `arXiv:` reference or arxiv.org URL. Accepts
+/// current ids (`2101.00001v2`) and pre-2007 ones (`hep-th/9901001`).
+pub fn normalize_arxiv_id(value: &str) -> Option<String> {
+    let value = value.trim();
+    let lower = value.to_ascii_lowercase();
+    let mut bare = [
+        "https://arxiv.org/abs/",
+        "http://arxiv.org/abs/",
+        "https://arxiv.org/pdf/",
+        "http://arxiv.org/pdf/",
+        "arxiv:",
+    ]
+    .iter()
+    .find(|prefix| lower.starts_with(*prefix))
+    .map_or(value, |prefix| &value[prefix.len()..]);
+    bare = bare.strip_suffix(".pdf").unwrap_or(bare);
+
+    // Split off a version suffix such as `v2`
+    let (id, version) = match bare.rfind('v') {
+        Some(i) if i > 0 && digits_only(&bare[i + 1..]) => (&bare[..i], &bare[i..]),
+        _ => (bare, ""),
+    };
+    let digits = |s: &str, lengths: &[usize]| {
+        lengths.contains(&s.len()) && s.chars().all(|c| c.is_ascii_digit())
+    };
+    let valid = match id.split_once('/') {
+        // Old style: archive(.subject class)/YYMMNNN
+        Some((archive, number)) => {
+            let archive = archive.split('.').next().unwrap_or("");
+            !archive.is_empty()
+                && archive.chars().all(|c| c.is_ascii_lowercase() || c == '-')
+                && digits(number, &[7])
+        }
+        // New style: YYMM.NNNN(N)
+        None => match id.split_once('.') {
+            Some((yymm, number)) => digits(yymm, &[4]) && digits(number, &[4, 5]),
+            None => false,
+        },
+    };
+    valid.then(|| format!("{}{}", id, version))
+}
+
+fn digits_only(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dois_lose_their_prefixes() {
+        for value in [
+            "10.1000/ABC.123",
+            " https://doi.org/10.1000/ABC.123 ",
+            "http://dx.doi.org/10.1000/ABC.123",
+            "DOI:10.1000/ABC.123",
+            "doi: 10.1000/ABC.123",
+        ] {
+            assert_eq!(
+                normalize_doi(value).as_deref(),
+                Some("10.1000/ABC.123"),
+                "{}",
+                value
+            );
+        }
+        for value in [
+            "10.12/short",
+            "10.1000/",
+            "10.1000/with space",
+            "11.1000/abc",
+            "https://example.org/10.1000/abc",
+        ] {
+            assert_eq!(normalize_doi(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn arxiv_ids_keep_their_version() {
+        for (value, id) in [
+            ("2101.00001", "2101.00001"),
+            ("arXiv:2101.00001v2", "2101.00001v2"),
+            ("https://arxiv.org/abs/2101.00001v12", "2101.00001v12"),
+            ("http://arxiv.org/pdf/2101.00001v2.pdf", "2101.00001v2"),
+            ("0704.0001", "0704.0001"),
+            ("hep-th/9901001v1", "hep-th/9901001v1"),
+            ("arXiv:math.GT/0309136", "math.GT/0309136"),
+        ] {
+            assert_eq!(normalize_arxiv_id(value).as_deref(), Some(id), "{}", value);
+        }
+        for value in [
+            "2101.001",
+            "2101.000001",
+            "2101.00001v",
+            "2101.00001vx",
+            "v2",
+            "Hep-th/9901001",
+            "hep-th/990100",
+            "https://example.org/abs/2101.00001",
+        ] {
+            assert_eq!(normalize_arxiv_id(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn records_are_validated_and_stored() {
+        let db = Connection::open_in_memory().unwrap();
+        database::init_db(&db).unwrap();
+        let record = BibliographicRecord {
+            title: Some("  A Title ".to_string()),
+            authors: vec![" Ada Lovelace ".to_string(), " ".to_string()],
+            year: Some(1843),
+            venue: Some(" ".to_string()),
+            doi: Some("https://doi.org/10.1000/xyz".to_string()),
+            arxiv_id: Some("arXiv:2101.00001v2".to_string()),
+            abstract_text: None,
+            keywords: vec![
+                "Notes".to_string(),
+                "notes".to_string(),
+                "engines".to_string(),
+            ],
+            url: Some("https://example.org/a b".to_string()),
+        };
+        let stored = set_record(&db, &record).unwrap();
+        assert_eq!(
+            stored,
+            BibliographicRecord {
+                title: Some("A Title".to_string()),
+                authors: vec!["Ada Lovelace".to_string()],
+                year: Some(1843),
+                venue: None,
+                doi: Some("10.1000/xyz".to_string()),
+                arxiv_id: Some("2101.00001v2".to_string()),
+                abstract_text: None,
+                keywords: vec!["Notes".to_string(), "engines".to_string()],
+                url: Some("https://example.org/a%20b".to_string()),
+            }
+        );
+        assert_eq!(get_record(&db).unwrap(), stored);
+
+        let invalid = |record: BibliographicRecord| validate(&record).is_err();
+        assert!(invalid(BibliographicRecord {
+            year: Some(999),
+            ..Default::default()
+        }));
+        assert!(invalid(BibliographicRecord {
+            doi: Some("not a doi".to_string()),
+            ..Default::default()
+        }));
+        assert!(invalid(BibliographicRecord {
+            url: Some("ftp://example.org/".to_string()),
+            ..Default::default()
+        }));
+    }
+}
@@ -0,0 +1,905 @@
+use rusqlite::{params, Connection};
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::bibliography;
+use crate::database;
+use crate::library;
+use crate::models::*;
+use crate::references;
+use crate::rr_file::{self, RrSession};
+
+/// Metadata key holding the document's citation key in data.sqlite.
+pub const METADATA_KEY: &str = "citation_key";
+
+const MAX_KEY_LEN: usize = 100;
+
+/// Characters allowed in a citation key besides ASCII letters and digits.
+const KEY_PUNCTUATION: &str = "-_:./+";
+
+/// Title words skipped when picking the one that goes into a generated key.
+const STOPWORDS: &[&str] = &[
+    "a", "an", "and", "are", "at", "by", "for", "from", "in", "is", "of", "on", "the", "to",
+    "toward", "towards", "via", "with",
+];
+
+/// The citation key stored in a document's data.sqlite, if any.
+pub fn stored_key(db: &Connection) -> Result<Option<String>, String> {
+    database::get_metadata(db, METADATA_KEY)
+        .map_err(|e| format!("Failed to read citation key: {}", e))
+}
+
+/// Give an open document a citation key and store it in both the library and
+/// the session's data.sqlite. Returns the key.
+pub fn assign_key(
+    conn: &Connection,
+    entry: &LibraryEntry,
+    db: &Connection,
+) -> Result<String, String> {
+    let stored = stored_key(db)?;
+    let key = ensure_key(conn, entry, stored.as_deref())?;
+    if stored.as_deref() != Some(key.as_str()) {
+        database::set_metadata(db, METADATA_KEY, &key)
+            .map_err(|e| format!("Failed to store citation key: {}", e))?;
+    }
+    Ok(key)
+}
+
+/// Make sure a library document has a citation key. The library's key wins,
+/// then the one `stored` in the document, as long as no other document uses
+/// it; otherwise a new key is generated. Returns the key.
+pub fn ensure_key(
+    conn: &Connection,
+    entry: &LibraryEntry,
+    stored: Option<&str>,
+) -> Result<String, String> {
+    for key in [entry.citation_key.as_deref(), stored]
+        .into_iter()
+        .flatten()
+    {
+        if validate_key(key).is_ok() && !is_taken(conn, key, entry.id)? {
+            if entry.citation_key.as_deref() != Some(key) {
+                update_key(conn, entry.id, key)?;
+            }
+            return Ok(key.to_string());
+        }
+    }
+    let key = generate_key(conn, entry)?;
+    update_key(conn, entry.id, &key)?;
+    Ok(key)
+}
+
+/// Change a library document's citation key. The open session, if it is that
+/// document, gets the new key right away; other documents pick it up the next
+/// time they are opened.
+pub fn set_key(
+    conn: &Connection,
+    id: i64,
+    key: &str,
+    open: Option<&RrSession>,
+) -> Result<LibraryEntry, String> {
+    let key = validate_key(key)?;
+    let entry = library::get_entry(conn, id)
+        .map_err(|e| format!("Failed to read library entry: {}", e))?
+        .ok_or_else(|| format!("Library entry {} not found", id))?;
+    if is_taken(conn, key, id)? {
+        return Err(format!(
+            "Citation key '{}' is already used by another document",
+            key
+        ));
+    }
+    update_key(conn, id, key)?;
+    if let Some(session) = open {
+        if fs::canonicalize(&session.rr_path).ok() == fs::canonicalize(&entry.path).ok() {
+            database::set_metadata(&session.db, METADATA_KEY, key)
+                .map_err(|e| format!("Failed to store citation key: {}", e))?;
+        }
+    }
+    library::get_entry(conn, id)
+        .map_err(|e| format!("Failed to read library entry: {}", e))?
+        .ok_or_else(|| "Library entry disappeared after update".to_string())
+}
+
+/// Render library documents, and the documents in the given collections and
+/// their subcollections, as a BibTeX (`bibtex`) or CSL-JSON (`csl_json`)
+/// bibliography. Returns the text and how many documents it holds.
+pub fn export(
+    conn: &Connection,
+    document_ids: &[i64],
+    collection_ids: &[i64],
+    format: &str,
+    open: Option<&RrSession>,
+) -> Result<(String, u32), String> {
+    if format != "bibtex" && format != "csl_json" {
+        return Err(format!("Unknown bibliography format '{}'", format));
+    }
+    let mut ids = document_ids.to_vec();
+    ids.extend(
+        library::collection_document_ids(conn, collection_ids)
+            .map_err(|e| format!("Failed to read collections: {}", e))?,
+    );
+    ids.sort_unstable();
+    ids.dedup();
+
+    let open_path = open.and_then(|session| fs::canonicalize(&session.rr_path).ok());
+    let mut items = Vec::new();
+    for id in ids {
+        let entry = library::get_entry(conn, id)
+            .map_err(|e| format!("Failed to read library entry: {}", e))?
+            .ok_or_else(|| format!("Library entry {} not found", id))?;
+        let (record, stored) = match open {
+            Some(session)
+                if open_path.is_some() && open_path == fs::canonicalize(&entry.path).ok() =>
+            {
+                (
+                    bibliography::get_record(&session.db)?,
+                    stored_key(&session.db)?,
+                )
+            }
+            _ => read_record(&entry),
+        };
+        let key = ensure_key(conn, &entry, stored.as_deref())?;
+        items.push((key, record));
+    }
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let text = if format == "bibtex" {
+        items
+            .iter()
+            .map(|(key, record)| to_bibtex(key, record))
+            .collect::<Vec<_>>()
+            .join("\n")
+    } else {
+        let entries: Vec<Value> = items
+            .iter()
+            .map(|(key, record)| to_csl_json(key, record))
+            .collect();
+        serde_json::to_string_pretty(&entries)
+            .map_err(|e| format!("Failed to serialize CSL-JSON: {}", e))?
+    };
+    Ok((text, items.len() as u32))
+}
+
+/// Format a document's citation in `style`: `apa`, `mla`, `chicago`
+/// (author-date), `bibtex` or `csl_json`.
+pub fn format_citation(
+    key: &str,
+    record: &BibliographicRecord,
+    style: &str,
+) -> Result<String, String> {
+    match style {
+        "apa" => Ok(apa(record)),
+        "mla" => Ok(mla(record)),
+        "chicago" => Ok(chicago(record)),
+        "bibtex" => Ok(to_bibtex(key, record)),
+        "csl_json" => serde_json::to_string_pretty(&to_csl_json(key, record))
+            .map_err(|e| format!("Failed to serialize CSL-JSON: {}", e)),
+        _ => Err(format!("Unknown citation style '{}'", style)),
+    }
+}
+
+/// A key in the usual `family` + `year` + `first title word` form, e.g.
+/// `vaswani2017attention`, without checking whether it is taken.
+pub fn base_key(record: &BibliographicRecord) -> String {
+    let family = record
+        .authors
+        .first()
+        .map(|author| key_word(&split_name(author).1))
+        .filter(|word| !word.is_empty());
+    let word = record.title.as_deref().and_then(|title| {
+        title
+            .split_whitespace()
+            .map(key_word)
+            .find(|word| !word.is_empty() && !STOPWORDS.contains(&word.as_str()))
+    });
+
+    let mut key = family.unwrap_or_else(|| "anon".to_string());
+    if let Some(year) = record.year {
+        key.push_str(&year.to_string());
+    }
+    if let Some(word) = word {
+        key.push_str(&word);
+    }
+    key
+}
+
+fn validate_key(key: &str) -> Result<&str, String> {
+    let key = key.trim();
+    if key.is_empty() {
+        return Err("Citation key can't be empty".to_string());
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(format!(
+            "Citation key can be at most {} characters long",
+            MAX_KEY_LEN
+        ));
+    }
+    if !key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || KEY_PUNCTUATION.contains(c))
+    {
+        return Err(format!(
+            "Citation key '{}' may only contain letters, digits and {}",
+            key, KEY_PUNCTUATION
+        ));
+    }
+    Ok(key)
+}
+
+/// Whether a document other than `id` uses `key`. Keys differing only in case
+/// count as the same, as they do for BibTeX.
+fn is_taken(conn: &Connection, key: &str, id: i64) -> Result<bool, String> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM documents WHERE citation_key = ?1 COLLATE NOCASE AND id != ?2",
+        params![key, id],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+    .map_err(|e| format!("Failed to read citation keys: {}", e))
+}
+
+fn update_key(conn: &Connection, id: i64, key: &str) -> Result<(), String> {
+    conn.execute(
+        "UPDATE documents SET citation_key = ?1 WHERE id = ?2",
+        params![key, id],
+    )
+    .map_err(|e| format!("Failed to set citation key: {}", e))?;
+    Ok(())
+}
+
+/// A free key for the document: its linked reference entry's key if that
+/// isn't taken, otherwise `base_key` with a letter (then a number) appended
+/// until it is unique.
+fn generate_key(conn: &Connection, entry: &LibraryEntry) -> Result<String, String> {
+    let link =
+        references::get_link(conn, entry.id).map_err(|e| format!("Failed to read link: {}", e))?;
+    if let Some(link) = link.filter(|link| link.matched_by != "rejected") {
+        if validate_key(&link.key).is_ok() && !is_taken(conn, &link.key, entry.id)? {
+            return Ok(link.key);
+        }
+    }
+
+    let base = base_key(&entry_record(entry));
+    let suffixes = std::iter::once(String::new())
+        .chain((b'a'..=b'z').map(|c| (c as char).to_string()))
+        .chain((2..).map(|n: u32| n.to_string()));
+    for suffix in suffixes {
+        let key = format!("{}{}", base, suffix);
+        if !is_taken(conn, &key, entry.id)? {
+            return Ok(key);
+        }
+    }
+    unreachable!("numeric suffixes never run out")
+}
+
+/// The full record of a closed document and its stored key, read from the
+/// .rr file; a missing or unreadable file falls back to the library's copy.
+fn read_record(entry: &LibraryEntry) -> (BibliographicRecord, Option<String>) {
+    let result = rr_file::read_database(Path::new(&entry.path)).and_then(|snapshot| {
+        Ok((
+            bibliography::get_record(&snapshot.db)?,
+            stored_key(&snapshot.db)?,
+        ))
+    });
+    match result {
+        Ok(read) => read,
+        Err(e) => {
+            if !entry.missing {
+                log::warn!("[citation] Failed to read {}: {}", entry.path, e);
+            }
+            (entry_record(entry), None)
+        }
+    }
+}
+
+fn entry_record(entry: &LibraryEntry) -> BibliographicRecord {
+    BibliographicRecord {
+        title: entry.title.clone(),
+        authors: entry.authors.clone(),
+        year: entry.year,
+        venue: entry.venue.clone(),
+        doi: entry.doi.clone(),
+        arxiv_id: entry.arxiv_id.clone(),
+        ..Default::default()
+    }
+}
+
+/// Lowercase ASCII letters and digits of `word`, with accents stripped.
+fn key_word(word: &str) -> String {
+    word.nfd()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Split a name into given names and family name. "Family, Given" is taken
+/// as written; otherwise the last word is the family name.
+fn split_name(name: &str) -> (Option<String>, String) {
+    let name = name.trim();
+    if let Some((family, given)) = name.split_once(',') {
+        let given = given.trim();
+        return (
+            (!given.is_empty()).then(|| given.to_string()),
+            family.trim().to_string(),
+        );
+    }
+    match name.rsplit_once(char::is_whitespace) {
+        Some((given, family)) => (Some(given.trim().to_string()), family.to_string()),
+        None => (None, name.to_string()),
+    }
+}
+
+/// "Given Q. Family" as "Family, G. Q."
+fn initials_name(name: &str) -> String {
+    let (given, family) = split_name(name);
+    let Some(given) = given else {
+        return family;
+    };
+    let initials: Vec<String> = given
+        .split_whitespace()
+        .map(|part| {
+            part.split('-')
+                .filter_map(|piece| piece.chars().next())
+                .map(|c| format!("{}.", c))
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect();
+    format!("{}, {}", family, initials.join(" "))
+}
+
+/// "Given Q. Family" as "Family, Given Q."
+fn inverted_name(name: &str) -> String {
+    match split_name(name) {
+        (Some(given), family) => format!("{}, {}", family, given),
+        (None, family) => family,
+    }
+}
+
+/// "Family, Given" as "Given Family"
+fn natural_name(name: &str) -> String {
+    match split_name(name) {
+        (Some(given), family) => format!("{} {}", given, family),
+        (None, family) => family,
+    }
+}
+
+/// `text` followed by a period, unless it already ends in punctuation.
+fn sentence(text: &str) -> String {
+    let text = text.trim();
+    if text.ends_with(['.', '?', '!']) {
+        text.to_string()
+    } else {
+        format!("{}.", text)
+    }
+}
+
+/// The best link to the work: its DOI, arXiv page or URL.
+fn link(record: &BibliographicRecord) -> Option<String> {
+    if let Some(doi) = &record.doi {
+        return Some(format!("https://doi.org/{}", doi));
+    }
+    if let Some(id) = &record.arxiv_id {
+        return Some(format!("https://arxiv.org/abs/{}", id));
+    }
+    record.url.clone()
+}
+
+/// Join `parts` with ", " and `last` before the final one.
+fn list(parts: &[String], last: &str) -> String {
+    match parts {
+        [] => String::new(),
+        [only] => only.clone(),
+        [init @ .., final_part] => format!("{}{} {}", init.join(", "), last, final_part),
+    }
+}
+
+fn apa(record: &BibliographicRecord) -> String {
+    let names: Vec<String> = record.authors.iter().map(|a| initials_name(a)).collect();
+    let authors = if names.len() > 20 {
+        format!(
+            "{}, . . . {}",
+            names[..19].join(", "),
+            names[names.len() - 1]
+        )
+    } else {
+        list(&names, ", &")
+    };
+    let year = record
+        .year
+        .map_or_else(|| "n.d.".to_string(), |y| y.to_string());
+    let title = record.title.as_deref().unwrap_or("Untitled");
+
+    let mut parts = Vec::new();
+    if authors.is_empty() {
+        parts.push(sentence(title));
+        parts.push(format!("({}).", year));
+    } else {
+        parts.push(sentence(&authors));
+        parts.push(format!("({}).", year));
+        parts.push(sentence(title));
+    }
+    if let Some(venue) = &record.venue {
+        parts.push(sentence(venue));
+    }
+    if let Some(link) = link(record) {
+        parts.push(link);
+    }
+    parts.join(" ")
+}
+
+fn mla(record: &BibliographicRecord) -> String {
+    let authors = match record.authors.as_slice() {
+        [] => String::new(),
+        [only] => inverted_name(only),
+        [first, second] => format!("{}, and {}", inverted_name(first), natural_name(second)),
+        [first, ..] => format!("{}, et al", inverted_name(first)),
+    };
+    let title = record.title.as_deref().unwrap_or("Untitled");
+
+    let mut parts = Vec::new();
+    if !authors.is_empty() {
+        parts.push(sentence(&authors));
+    }
+    parts.push(format!("\u{201c}{}\u{201d}", sentence(title)));
+    let details: Vec<String> = record
+        .venue
+        .iter()
+        .cloned()
+        .chain(record.year.map(|y| y.to_string()))
+        .chain(link(record))
+        .collect();
+    if !details.is_empty() {
+        parts.push(sentence(&details.join(", ")));
+    }
+    parts.join(" ")
+}
+
+fn chicago(record: &BibliographicRecord) -> String {
+    let names: Vec<String> = record
+        .authors
+        .iter()
+        .enumerate()
+        .map(|(i, a)| {
+            if i == 0 {
+                inverted_name(a)
+            } else {
+                natural_name(a)
+            }
+        })
+        .collect();
+    let authors = if names.len() > 10 {
+        format!("{}, et al", names[..7].join(", "))
+    } else {
+        list(&names, ", and")
+    };
+    let year = record
+        .year
+        .map_or_else(|| "n.d.".to_string(), |y| y.to_string());
+    let title = record.title.as_deref().unwrap_or("Untitled");
+
+    let mut parts = Vec::new();
+    if !authors.is_empty() {
+        parts.push(sentence(&authors));
+    }
+    parts.push(sentence(&year));
+    parts.push(format!("\u{201c}{}\u{201d}", sentence(title)));
+    if let Some(venue) = &record.venue {
+        parts.push(sentence(venue));
+    }
+    if let Some(link) = link(record) {
+        parts.push(sentence(&link));
+    }
+    parts.join(" ")
+}
+
+/// Venues that name a conference or workshop are cited as proceedings papers.
+fn is_proceedings(venue: &str) -> bool {
+    let venue = venue.to_lowercase();
+    ["proceedings", "conference", "workshop", "symposium"]
+        .iter()
+        .any(|word| venue.contains(word))
+}
+
+/// One BibTeX entry: `@article` with a journal, `@inproceedings` with
+/// proceedings, `@misc` (with arXiv eprint fields where known) otherwise.
+pub fn to_bibtex(key: &str, record: &BibliographicRecord) -> String {
+    let mut fields: Vec<(&str, String)> = Vec::new();
+    if !record.authors.is_empty() {
+        let authors: Vec<String> = record.authors.iter().map(|a| inverted_name(a)).collect();
+        fields.push(("author", escape_latex(&authors.join(" and "))));
+    }
+    if let Some(title) = &record.title {
+        fields.push(("title", escape_latex(title)));
+    }
+    let entry_type = match &record.venue {
+        Some(venue) if is_proceedings(venue) => {
+            fields.push(("booktitle", escape_latex(venue)));
+            "inproceedings"
+        }
+        Some(venue) => {
+            fields.push(("journal", escape_latex(venue)));
+            "article"
+        }
+        None => "misc",
+    };
+    if let Some(year) = record.year {
+        fields.push(("year", year.to_string()));
+    }
+    if let Some(doi) = &record.doi {
+        fields.push(("doi", verbatim(doi)));
+    }
+    if let Some(id) = &record.arxiv_id {
+        fields.push(("eprint", verbatim(id)));
+        fields.push(("archiveprefix", "arXiv".to_string()));
+    }
+    if let Some(url) = &record.url {
+        fields.push(("url", verbatim(url)));
+    }
+    if let Some(text) = &record.abstract_text {
+        fields.push(("abstract", escape_latex(text)));
+    }
+    if !record.keywords.is_empty() {
+        fields.push(("keywords", escape_latex(&record.keywords.join(", "))));
+    }
+
+    let mut out = format!("@{}{{{},\n", entry_type, key);
+    for (name, value) in fields {
+        out.push_str(&format!("  {} = {{{}}},\n", name, value));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn escape_latex(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '~' => out.push_str("\\textasciitilde{}"),
+            '^' => out.push_str("\\textasciicircum{}"),
+            '{' | '}' | '&' | '%' | '$' | '#' | '_' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' | '\r' => out.push(' '),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// DOIs and URLs are written as they are, minus braces that would end the field.
+fn verbatim(text: &str) -> String {
+    text.chars().filter(|c| *c != '{' && *c != '}').collect()
+}
+
+/// One CSL-JSON item with `id` set to the citation key.
+pub fn to_csl_json(key: &str, record: &BibliographicRecord) -> Value {
+    let mut item = Map::new();
+    item.insert("id".to_string(), json!(key));
+    let item_type = match &record.venue {
+        Some(venue) if is_proceedings(venue) => "paper-conference",
+        Some(_) => "article-journal",
+        None => "article",
+    };
+    item.insert("type".to_string(), json!(item_type));
+    if let Some(title) = &record.title {
+        item.insert("title".to_string(), json!(title));
+    }
+    if !record.authors.is_empty() {
+        let authors: Vec<Value> = record
+            .authors
+            .iter()
+            .map(|author| match split_name(author) {
+                (Some(given), family) => json!({ "family": family, "given": given }),
+                (None, family) => json!({ "literal": family }),
+            })
+            .collect();
+        item.insert("author".to_string(), json!(authors));
+    }
+    if let Some(year) = record.year {
+        item.insert("issued".to_string(), json!({ "date-parts": [[year]] }));
+    }
+    if let Some(venue) = &record.venue {
+        item.insert("container-title".to_string(), json!(venue));
+    }
+    if let Some(doi) = &record.doi {
+        item.insert("DOI".to_string(), json!(doi));
+    }
+    if let Some(id) = &record.arxiv_id {
+        item.insert("number".to_string(), json!(format!("arXiv:{}", id)));
+        if record.venue.is_none() {
+            item.insert("publisher".to_string(), json!("arXiv"));
+        }
+    }
+    if let Some(url) = record.url.clone().or_else(|| {
+        record
+            .arxiv_id
+            .as_ref()
+            .map(|id| format!("https://arxiv.org/abs/{}", id))
+    }) {
+        item.insert("URL".to_string(), json!(url));
+    }
+    if let Some(text) = &record.abstract_text {
+        item.insert("abstract".to_string(), json!(text));
+    }
+    if !record.keywords.is_empty() {
+        item.insert("keyword".to_string(), json!(record.keywords.join(", ")));
+    }
+    Value::Object(item)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(authors: &[&str], title: &str, year: i32) -> BibliographicRecord {
+        BibliographicRecord {
+            title: Some(title.to_string()),
+            authors: authors.iter().map(|a| a.to_string()).collect(),
+            year: Some(year),
+            ..Default::default()
+        }
+    }
+
+    fn turing() -> BibliographicRecord {
+        BibliographicRecord {
+            venue: Some("Proceedings of the London Mathematical Society".to_string()),
+            doi: Some("10.1112/plms/s2-42.1.230".to_string()),
+            ..record(
+                &["Ada Lovelace", "Alan M. Turing"],
+                "On Computable Numbers",
+                1936,
+            )
+        }
+    }
+
+    fn add_document(conn: &Connection, dir: &Path, name: &str) -> LibraryEntry {
+        let pdf = dir.join(format!("{}.pdf", name));
+        fs::write(&pdf, format!("%PDF-1.4 {}", name)).unwrap();
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        let attention = record(
+            &["Ashish Vaswani", "Noam Shazeer"],
+            "Attention Is All You Need",
+            2017,
+        );
+        bibliography::set_record(&session.db, &attention).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        let entry = library::record_session(conn, &session, false).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        rr_file::cleanup_session(&session);
+        entry
+    }
+
+    fn reopen(conn: &Connection, entry: &LibraryEntry) -> String {
+        let session = rr_file::open_rr(Path::new(&entry.path)).unwrap();
+        let key = library::record_session(conn, &session, true)
+            .unwrap()
+            .citation_key
+            .unwrap();
+        rr_file::cleanup_session(&session);
+        key
+    }
+
+    #[test]
+    fn base_keys() {
+        assert_eq!(
+            base_key(&record(
+                &["Ashish Vaswani"],
+                "Attention Is All You Need",
+                2017
+            )),
+            "vaswani2017attention"
+        );
+        assert_eq!(
+            base_key(&record(
+                &["M\u{fc}ller-L\u{fc}denscheidt, J\u{fc}rgen"],
+                "The Role of Types",
+                2001
+            )),
+            "mullerludenscheidt2001role"
+        );
+        assert_eq!(base_key(&BibliographicRecord::default()), "anon");
+    }
+
+    #[test]
+    fn keys_are_stable_and_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = library::open(dir.path()).unwrap();
+        let first = add_document(&conn, dir.path(), "first");
+        let second = add_document(&conn, dir.path(), "second");
+        let third = add_document(&conn, dir.path(), "third");
+        let keys = [&first, &second, &third].map(|e| e.citation_key.clone().unwrap());
+        assert_eq!(
+            keys,
+            [
+                "vaswani2017attention",
+                "vaswani2017attentiona",
+                "vaswani2017attentionb"
+            ]
+        );
+
+        // Reopening keeps the key, here and in another library that has
+        // only the key stored in the document
+        assert_eq!(reopen(&conn, &second), "vaswani2017attentiona");
+        let other_dir = tempfile::tempdir().unwrap();
+        let other = library::open(other_dir.path()).unwrap();
+        assert_eq!(reopen(&other, &second), "vaswani2017attentiona");
+        assert_eq!(reopen(&other, &first), "vaswani2017attention");
+
+        // Keys differing in case collide
+        assert!(set_key(&conn, third.id, "Vaswani2017Attention", None).is_err());
+        assert!(set_key(&conn, third.id, "bad key", None).is_err());
+        let renamed = set_key(&conn, third.id, "transformer", None).unwrap();
+        assert_eq!(renamed.citation_key.as_deref(), Some("transformer"));
+        // The library's key wins over the one stored in the document
+        assert_eq!(reopen(&conn, &third), "transformer");
+
+        let (text, count) = export(&conn, &[third.id, first.id], &[], "bibtex", None).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            text,
+            "@misc{transformer,\n  \
+             author = {Vaswani, Ashish and Shazeer, Noam},\n  \
+             title = {Attention Is All You Need},\n  \
+             year = {2017},\n}\n\n\
+             @misc{vaswani2017attention,\n  \
+             author = {Vaswani, Ashish and Shazeer, Noam},\n  \
+             title = {Attention Is All You Need},\n  \
+             year = {2017},\n}\n"
+        );
+        assert!(export(&conn, &[first.id], &[], "ris", None).is_err());
+    }
+
+    #[test]
+    fn bibtex_is_escaped() {
+        let record = BibliographicRecord {
+            title: Some("Attention & {Transformers}: 100% of $x_i$ ~ ^ \\".to_string()),
+            authors: vec!["Ashish Vaswani".to_string(), "Shazeer, Noam".to_string()],
+            year: Some(2017),
+            venue: Some("Proceedings of NeurIPS".to_string()),
+            doi: Some("10.5555/{x}".to_string()),
+            arxiv_id: Some("1706.03762".to_string()),
+            abstract_text: Some("Line one\nline #2".to_string()),
+            keywords: vec!["deep learning".to_string(), "C#".to_string()],
+            url: Some("https://example.org/a_b?c=1&d=~2".to_string()),
+        };
+        assert_eq!(
+            to_bibtex("vaswani2017attention", &record),
+            r"@inproceedings{vaswani2017attention,
+  author = {Vaswani, Ashish and Shazeer, Noam},
+  title = {Attention \& \{Transformers\}: 100\% of \$x\_i\$ \textasciitilde{} \textasciicircum{} \textbackslash{}},
+  booktitle = {Proceedings of NeurIPS},
+  year = {2017},
+  doi = {10.5555/x},
+  eprint = {1706.03762},
+  archiveprefix = {arXiv},
+  url = {https://example.org/a_b?c=1&d=~2},
+  abstract = {Line one line \#2},
+  keywords = {deep learning, C\#},
+}
+"
+        );
+        let journal = BibliographicRecord {
+            venue: Some("Nature".to_string()),
+            ..record.clone()
+        };
+        assert!(to_bibtex("k", &journal).contains("  journal = {Nature},\n"));
+    }
+
+    #[test]
+    fn csl_json_items() {
+        assert_eq!(
+            to_csl_json("turing1936on", &turing()),
+            json!({
+                "id": "turing1936on",
+                "type": "paper-conference",
+                "title": "On Computable Numbers",
+                "author": [
+                    { "family": "Lovelace", "given": "Ada" },
+                    { "family": "Turing", "given": "Alan M." },
+                ],
+                "issued": { "date-parts": [[1936]] },
+                "container-title": "Proceedings of the London Mathematical Society",
+                "DOI": "10.1112/plms/s2-42.1.230",
+            })
+        );
+        let preprint = BibliographicRecord {
+            arxiv_id: Some("2101.00001v2".to_string()),
+            keywords: vec!["logic".to_string(), "ethics".to_string()],
+            ..record(&["Plato"], "Republic", 2021)
+        };
+        assert_eq!(
+            to_csl_json("plato2021republic", &preprint),
+            json!({
+                "id": "plato2021republic",
+                "type": "article",
+                "title": "Republic",
+                "author": [{ "literal": "Plato" }],
+                "issued": { "date-parts": [[2021]] },
+                "number": "arXiv:2101.00001v2",
+                "publisher": "arXiv",
+                "URL": "https://arxiv.org/abs/2101.00001v2",
+                "keyword": "logic, ethics",
+            })
+        );
+    }
+
+    #[test]
+    fn citation_styles() {
+        let record = turing();
+        assert_eq!(
+            format_citation("k", &record, "apa").unwrap(),
+            "Lovelace, A., & Turing, A. M. (1936). On Computable Numbers. Proceedings of \
+             the London Mathematical Society. https://doi.org/10.1112/plms/s2-42.1.230"
+        );
+        assert_eq!(
+            format_citation("k", &record, "mla").unwrap(),
+            "Lovelace, Ada, and Alan M. Turing. \u{201c}On Computable Numbers.\u{201d} \
+             Proceedings of the London Mathematical Society, 1936, \
+             https://doi.org/10.1112/plms/s2-42.1.230."
+        );
+        assert_eq!(
+            format_citation("k", &record, "chicago").unwrap(),
+            "Lovelace, Ada, and Alan M. Turing. 1936. \u{201c}On Computable Numbers.\u{201d} \
+             Proceedings of the London Mathematical Society. \
+             https://doi.org/10.1112/plms/s2-42.1.230."
+        );
+        assert_eq!(
+            format_citation("k", &BibliographicRecord::default(), "apa").unwrap(),
+            "Untitled. (n.d.)."
+        );
+        assert!(format_citation("k", &record, "harvard").is_err());
+    }
+
+    #[test]
+    fn long_author_lists_are_truncated() {
+        let authors = |count: usize| -> Vec<String> {
+            (1..=count).map(|i| format!("Ann Lee{}", i)).collect()
+        };
+        let apa_names = |range: std::ops::RangeInclusive<usize>| {
+            range
+                .map(|i| format!("Lee{}, A.", i))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let chicago_names = |range: std::ops::RangeInclusive<usize>| {
+            range
+                .map(|i| format!("Ann Lee{}", i))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let with = |count: usize| BibliographicRecord {
+            authors: authors(count),
+            ..record(&[], "T", 2020)
+        };
+
+        // APA lists up to 20 authors, then the first 19 and the last
+        assert_eq!(
+            apa(&with(20)),
+            format!("{}, & Lee20, A. (2020). T.", apa_names(1..=19))
+        );
+        assert_eq!(
+            apa(&with(21)),
+            format!("{}, . . . Lee21, A. (2020). T.", apa_names(1..=19))
+        );
+        // Chicago lists up to 10 authors, then the first 7
+        assert_eq!(
+            chicago(&with(10)),
+            format!(
+                "Lee1, Ann, {}, and Ann Lee10. 2020. \u{201c}T.\u{201d}",
+                chicago_names(2..=9)
+            )
+        );
+        assert_eq!(
+            chicago(&with(11)),
+            format!(
+                "Lee1, Ann, {}, et al. 2020. \u{201c}T.\u{201d}",
+                chicago_names(2..=7)
+            )
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use std::path::Path;
+
+use url::Url;
+
+use crate::deep_link::{self, OpenRequest};
+
+/// Printed for `--help` and after argument errors
+pub const USAGE: &str = "\
+Usage: vellum [OPTIONS] [FILE | LINK]
+
+Open FILE (an .rr file, or a PDF to import) or a vellum:// LINK.
+
+Options:
+  -p, --page <N>  Go to page N
+  -h, --help      Print this help
+  -V, --version   Print the version";
+
+/// What the command line asks for
+#[derive(Debug, PartialEq)]
+pub enum CliAction {
+    /// Start the app, opening a document if one was given
+    Open(Option<OpenRequest>),
+    Help,
+    Version,
+}
+
+/// Parse the arguments after the program name. Relative paths are resolved
+/// against `cwd`, which for arguments forwarded by a second instance is that
+/// instance's working directory.
+pub fn parse<I: IntoIterator<Item = String>>(args: I, cwd: &Path) -> Result<CliAction, String> {
+    let mut page = None;
+    let mut target: Option<OpenRequest> = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(CliAction::Help),
+            "-V" | "--version" => return Ok(CliAction::Version),
+            "-p" | "--page" => {
+                let value = args.next().ok_or("--page needs a page number")?;
+                page = Some(parse_page(&value)?);
+            }
+            _ if arg.starts_with("--page=") => {
+                page = Some(parse_page(&arg["--page=".len()..])?);
+            }
+            // Process serial number older macOS adds when launched from Finder
+            _ if arg.starts_with("-psn_") => {}
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            _ => {
+                if target.is_some() {
+                    return Err("Only one file or link can be opened at a time".to_string());
+                }
+                target = Some(open_target(&arg, cwd)?);
+            }
+        }
+    }
+    if let Some(page) = page {
+        match target.as_mut() {
+            Some(target) => target.set_page(page),
+            None => return Err("--page needs a file or link to open".to_string()),
+        }
+    }
+    Ok(CliAction::Open(target))
+}
+
+/// Whether the arguments are just a `vellum://` link, which is how Windows
+/// and Linux hand the app a clicked link
+pub fn is_link(args: &[String]) -> bool {
+    matches!(args, [arg] if Url::parse(arg).is_ok_and(|url| url.scheme() == deep_link::SCHEME))
+}
+
+fn parse_page(value: &str) -> Result<u32, String> {
+    value
+        .parse::<u32>()
+        .ok()
+        .filter(|&page| page > 0)
+        .ok_or_else(|| format!("Invalid page number: {}", value))
+}
+
+/// A `vellum://` link, a `file://` URL or a path. Windows paths such as
+/// `C:\paper.rr` parse as URLs too, hence the scheme check.
+fn open_target(arg: &str, cwd: &Path) -> Result<OpenRequest, String> {
+    match Url::parse(arg) {
+        Ok(url) if url.scheme() == deep_link::SCHEME || url.scheme() == "file" => {
+            deep_link::from_url(&url)
+        }
+        _ => deep_link::file_target(&cwd.join(arg)).map(OpenRequest::File),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINK: &str =
+        "vellum://open?doc=9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn open(action: CliAction) -> OpenRequest {
+        match action {
+            CliAction::Open(Some(request)) => request,
+            other => panic!("expected a document to open, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn options_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let rr = dir.path().join("paper.rr");
+        std::fs::write(&rr, b"x").unwrap();
+        let parse = |a: &[&str]| parse(args(a), dir.path());
+
+        assert_eq!(parse(&[]).unwrap(), CliAction::Open(None));
+        assert_eq!(parse(&["--help"]).unwrap(), CliAction::Help);
+        assert_eq!(parse(&["paper.rr", "-h"]).unwrap(), CliAction::Help);
+        assert_eq!(parse(&["-V"]).unwrap(), CliAction::Version);
+
+        let mut expected = deep_link::file_target(&rr).unwrap();
+        expected.page_number = Some(4);
+        let expected = OpenRequest::File(expected);
+        assert_eq!(open(parse(&["paper.rr", "--page", "4"]).unwrap()), expected);
+        assert_eq!(
+            open(parse(&["-p", "4", rr.to_str().unwrap(), "-psn_0_123"]).unwrap()),
+            expected
+        );
+        assert_eq!(open(parse(&["--page=4", "paper.rr"]).unwrap()), expected);
+
+        let file_url = Url::from_file_path(&rr).unwrap().to_string();
+        assert_eq!(
+            open(parse(&[&file_url]).unwrap()),
+            OpenRequest::File(deep_link::file_target(&rr).unwrap())
+        );
+    }
+
+    #[test]
+    fn links() {
+        let dir = tempfile::tempdir().unwrap();
+        let OpenRequest::Link(link) =
+            open(parse(args(&[LINK, "--page", "3"]), dir.path()).unwrap())
+        else {
+            panic!("expected a link");
+        };
+        assert_eq!(link.page_number, Some(3));
+        assert!(parse(args(&["vellum://open?path=/x.rr"]), dir.path()).is_err());
+
+        assert!(is_link(&args(&[LINK])));
+        assert!(!is_link(&args(&[LINK, "--page", "3"])));
+        assert!(!is_link(&args(&["paper.rr"])));
+        assert!(!is_link(&args(&[])));
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("paper.rr"), b"x").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"x").unwrap();
+        let parse = |a: &[&str]| parse(args(a), dir.path());
+
+        assert!(parse(&["missing.rr"]).is_err());
+        assert!(parse(&["notes.txt"]).is_err());
+        assert!(parse(&["paper.rr", "paper.rr"]).is_err());
+        assert!(parse(&["--page", "0", "paper.rr"]).is_err());
+        assert!(parse(&["--page=x", "paper.rr"]).is_err());
+        assert!(parse(&["--page", "3"]).is_err());
+        assert!(parse(&["--page"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+    }
+}
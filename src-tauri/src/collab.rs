@@ -0,0 +1,947 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use automerge::ChangeHash;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tungstenite::{Message, WebSocket};
+
+use crate::crdt;
+use crate::models::Presence;
+
+/// How long a connection waits for a message before checking its outbox.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(2);
+/// Outbox key of a guest's connection to the host.
+const HOST_PEER: &str = "host";
+/// Crockford base32, used for join codes
+const CODE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+/// Bytes of the join code after the address
+const SECRET_LEN: usize = 16;
+/// Wrong join codes accepted from one address before it is locked out
+const MAX_FAILED_JOINS: u32 = 5;
+const JOIN_LOCKOUT: Duration = Duration::from_secs(300);
+
+/// The document being edited together. The app implements this over the open
+/// session; each participant applies the others' Automerge changes to it.
+pub trait SharedDocument: Send + Sync + 'static {
+    /// Encoded changes a peer at `heads` is missing, with our current heads.
+    fn changes_since(&self, heads: &[ChangeHash]) -> Result<crdt::Changes, String>;
+    /// Apply changes from a peer. Returns the heads they added.
+    fn apply_changes(&self, data: &[u8]) -> Result<Vec<ChangeHash>, String>;
+    /// Called from session threads when presence or the connection changes.
+    fn notify(&self, event: CollabEvent);
+}
+
+#[derive(Debug, Clone)]
+pub enum CollabEvent {
+    Presence(Vec<Presence>),
+    Connected,
+    /// Lost the host; the guest keeps reconnecting unless it was rejected
+    Disconnected(String),
+    Rejected(String),
+}
+
+/// Messages exchanged over the WebSocket, as JSON text frames.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WireMessage {
+    /// First message from a guest, with the heads of its copy
+    Hello {
+        secret: String,
+        peer_id: String,
+        name: String,
+        pdf_sha256: String,
+        heads: Vec<String>,
+    },
+    /// Host's reply to an accepted Hello, with the heads of its copy
+    Welcome {
+        heads: Vec<String>,
+    },
+    Rejected {
+        reason: String,
+    },
+    /// Base64-encoded Automerge changes to the annotation document
+    Changes {
+        changes: String,
+    },
+    /// A participant moved to another page
+    Page {
+        page: Option<u32>,
+    },
+    /// Everyone in the session, sent by the host whenever it changes
+    Presence {
+        peers: Vec<Presence>,
+    },
+}
+
+/// Host address and a 128-bit secret, shared as a code like
+/// `R2M02-5XVZD-FHS6H-YFCPM-S00PX-6KZ7C-2X5H0-G`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinCode {
+    pub addr: SocketAddrV4,
+    pub secret: [u8; SECRET_LEN],
+}
+
+impl JoinCode {
+    pub fn encode(&self) -> String {
+        let mut bytes = Vec::with_capacity(6 + SECRET_LEN);
+        bytes.extend(self.addr.ip().octets());
+        bytes.extend(self.addr.port().to_be_bytes());
+        bytes.extend(self.secret);
+
+        let mut chars = String::new();
+        let (mut buffer, mut bits) = (0u32, 0);
+        for byte in bytes {
+            buffer = (buffer << 8) | byte as u32;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                chars.push(CODE_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+            }
+        }
+        if bits > 0 {
+            chars.push(CODE_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+        }
+
+        chars
+            .as_bytes()
+            .chunks(5)
+            .map(|c| String::from_utf8_lossy(c).to_string())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    /// Parse a join code, ignoring case and separators and accepting the
+    /// usual Crockford substitutions (O for 0, I/L for 1).
+    pub fn parse(code: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid join code: {}", code);
+        let mut bytes = Vec::new();
+        let (mut buffer, mut bits) = (0u32, 0);
+        for c in code.chars().filter(|c| !matches!(c, '-' | ' ')) {
+            let c = match c.to_ascii_uppercase() {
+                'O' => '0',
+                'I' | 'L' => '1',
+                c => c,
+            };
+            let value = CODE_ALPHABET
+                .iter()
+                .position(|&a| a as char == c)
+                .ok_or_else(invalid)?;
+            buffer = (buffer << 5) | value as u32;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((buffer >> bits) as u8);
+            }
+        }
+        if bytes.len() != 6 + SECRET_LEN {
+            return Err(invalid());
+        }
+
+        let mut secret = [0; SECRET_LEN];
+        secret.copy_from_slice(&bytes[6..]);
+        Ok(Self {
+            addr: SocketAddrV4::new(
+                Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]),
+                u16::from_be_bytes([bytes[4], bytes[5]]),
+            ),
+            secret,
+        })
+    }
+}
+
+/// State shared by a session's threads.
+struct Shared {
+    doc: Arc<dyn SharedDocument>,
+    me: Mutex<Presence>,
+    pdf_sha256: String,
+    /// Set on the host: the secret guests must present
+    secret: Option<String>,
+    /// Heads every connected participant is known to have
+    known: Mutex<Vec<ChangeHash>>,
+    /// Queues of serialized messages, by peer id (or HOST_PEER on a guest)
+    outboxes: Mutex<HashMap<String, Sender<String>>>,
+    /// Other participants; on the host this is authoritative
+    peers: Mutex<Vec<Presence>>,
+    /// Host: wrong join codes by address, with the time of the last one
+    failed_joins: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn is_host(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Host: whether `ip` sent too many wrong join codes recently.
+    fn locked_out(&self, ip: IpAddr) -> bool {
+        let Ok(mut failed) = self.failed_joins.lock() else {
+            return true;
+        };
+        match failed.get(&ip) {
+            Some((_, last)) if last.elapsed() >= JOIN_LOCKOUT => {
+                failed.remove(&ip);
+                false
+            }
+            Some((count, _)) => *count >= MAX_FAILED_JOINS,
+            None => false,
+        }
+    }
+
+    fn join_failed(&self, ip: IpAddr) {
+        if let Ok(mut failed) = self.failed_joins.lock() {
+            let entry = failed.entry(ip).or_insert((0, Instant::now()));
+            *entry = (entry.0 + 1, Instant::now());
+        }
+    }
+
+    fn add_known(&self, heads: &[ChangeHash]) {
+        if let Ok(mut known) = self.known.lock() {
+            for head in heads {
+                if !known.contains(head) {
+                    known.push(*head);
+                }
+            }
+        }
+    }
+
+    /// Send to every connection except `except`.
+    fn broadcast(&self, message: &WireMessage, except: Option<&str>) {
+        let Ok(text) = serde_json::to_string(message) else {
+            return;
+        };
+        if let Ok(outboxes) = self.outboxes.lock() {
+            for (peer, outbox) in outboxes.iter() {
+                if Some(peer.as_str()) != except {
+                    let _ = outbox.send(text.clone());
+                }
+            }
+        }
+    }
+
+    fn presence(&self) -> Vec<Presence> {
+        let mut all = Vec::new();
+        if self.is_host() {
+            all.extend(self.me.lock().ok().map(|me| me.clone()));
+        }
+        all.extend(self.peers.lock().map(|p| p.clone()).unwrap_or_default());
+        all
+    }
+
+    /// Host: tell everyone, including ourselves, who is where.
+    fn presence_changed(&self) {
+        let peers = self.presence();
+        self.broadcast(
+            &WireMessage::Presence {
+                peers: peers.clone(),
+            },
+            None,
+        );
+        self.doc.notify(CollabEvent::Presence(peers));
+    }
+
+    /// Apply changes a peer sent.
+    fn receive_changes(&self, changes: &str) -> Result<(), String> {
+        let data = BASE64_STANDARD
+            .decode(changes)
+            .map_err(|e| format!("Invalid changes from peer: {}", e))?;
+        let heads = self.doc.apply_changes(&data)?;
+        self.add_known(&heads);
+        Ok(())
+    }
+
+    /// Send local changes that connected participants don't have yet.
+    fn publish(&self) -> Result<(), String> {
+        let known = self.known.lock().map_err(|e| e.to_string())?.clone();
+        let (data, heads) = self.doc.changes_since(&known)?;
+        if !data.is_empty() {
+            self.broadcast(
+                &WireMessage::Changes {
+                    changes: BASE64_STANDARD.encode(&data),
+                },
+                None,
+            );
+        }
+        self.add_known(&heads);
+        Ok(())
+    }
+}
+
+/// A live collaboration session, either hosted here or joined by code.
+///
+/// Annotation edits travel as Automerge changes, so they merge the same way as
+/// synced copies do. A guest that loses the host keeps reconnecting; on each
+/// (re)connect both sides exchange heads and send whatever the other is missing.
+pub struct CollabSession {
+    shared: Arc<Shared>,
+    join_code: Option<JoinCode>,
+    /// Wakes the publisher thread after a local edit
+    local_changes: Sender<()>,
+}
+
+impl CollabSession {
+    /// Start hosting on the local network. Listens on all interfaces; the join
+    /// code carries this machine's LAN address.
+    pub fn host(
+        doc: Arc<dyn SharedDocument>,
+        pdf_sha256: &str,
+        name: &str,
+    ) -> Result<Self, String> {
+        let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .map_err(|e| format!("Failed to start collaboration server: {}", e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("Failed to start collaboration server: {}", e))?;
+        let port = listener
+            .local_addr()
+            .map_err(|e| format!("Failed to start collaboration server: {}", e))?
+            .port();
+
+        let mut secret = [0; SECRET_LEN];
+        getrandom::fill(&mut secret).map_err(|e| format!("Failed to generate join code: {}", e))?;
+        let code = JoinCode {
+            addr: SocketAddrV4::new(lan_address(), port),
+            secret,
+        };
+        let shared = Self::shared(doc, pdf_sha256, name, true, Some(hex::encode(code.secret)))?;
+
+        let server = shared.clone();
+        thread::spawn(move || accept_loop(server, listener));
+        let local_changes = spawn_publisher(shared.clone());
+        Ok(Self {
+            shared,
+            join_code: Some(code),
+            local_changes,
+        })
+    }
+
+    /// Join a session hosted elsewhere.
+    pub fn join(
+        code: &str,
+        doc: Arc<dyn SharedDocument>,
+        pdf_sha256: &str,
+        name: &str,
+    ) -> Result<Self, String> {
+        let code = JoinCode::parse(code)?;
+        let shared = Self::shared(doc, pdf_sha256, name, false, None)?;
+
+        let guest = shared.clone();
+        let secret = hex::encode(code.secret);
+        let addr = SocketAddr::V4(code.addr);
+        thread::spawn(move || guest_loop(guest, addr, secret));
+        let local_changes = spawn_publisher(shared.clone());
+        Ok(Self {
+            shared,
+            join_code: None,
+            local_changes,
+        })
+    }
+
+    fn shared(
+        doc: Arc<dyn SharedDocument>,
+        pdf_sha256: &str,
+        name: &str,
+        is_host: bool,
+        secret: Option<String>,
+    ) -> Result<Arc<Shared>, String> {
+        let (_, heads) = doc.changes_since(&[])?;
+        Ok(Arc::new(Shared {
+            doc,
+            me: Mutex::new(Presence {
+                peer_id: uuid::Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                page: None,
+                is_host,
+            }),
+            pdf_sha256: pdf_sha256.to_string(),
+            secret,
+            known: Mutex::new(heads),
+            outboxes: Mutex::new(HashMap::new()),
+            peers: Mutex::new(Vec::new()),
+            failed_joins: Mutex::new(HashMap::new()),
+            stop: AtomicBool::new(false),
+        }))
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.shared.is_host()
+    }
+
+    pub fn join_code(&self) -> Option<String> {
+        self.join_code.as_ref().map(JoinCode::encode)
+    }
+
+    /// Everyone in the session, including us when hosting.
+    pub fn presence(&self) -> Vec<Presence> {
+        self.shared.presence()
+    }
+
+    /// Send local annotation edits to the other participants.
+    pub fn local_change(&self) {
+        let _ = self.local_changes.send(());
+    }
+
+    /// Share the page we're on.
+    pub fn set_page(&self, page: Option<u32>) {
+        if let Ok(mut me) = self.shared.me.lock() {
+            me.page = page;
+        }
+        if self.shared.is_host() {
+            self.shared.presence_changed();
+        } else {
+            self.shared.broadcast(&WireMessage::Page { page }, None);
+        }
+    }
+
+    /// Disconnect everyone and stop the session's threads.
+    pub fn stop(&self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for CollabSession {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// This machine's address on the local network, found by asking the OS which
+/// interface it would route through (no packets are sent). Falls back to
+/// loopback when there's no network.
+fn lan_address() -> Ipv4Addr {
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9))?;
+            socket.local_addr()
+        })
+        .ok()
+        .and_then(|addr| match addr.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+            _ => None,
+        })
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
+
+fn spawn_publisher(shared: Arc<Shared>) -> Sender<()> {
+    let (tx, rx) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        while !shared.stopped() {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(()) => {
+                    // Edits often come in bursts; send them together
+                    while rx.try_recv().is_ok() {}
+                    if let Err(e) = shared.publish() {
+                        log::warn!("[collab] Failed to publish changes: {}", e);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+    tx
+}
+
+fn accept_loop(shared: Arc<Shared>, listener: TcpListener) {
+    while !shared.stopped() {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve_guest(&shared, stream, addr.ip()) {
+                        log::info!("[collab] Guest {} disconnected: {}", addr, e);
+                    }
+                });
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                log::warn!("[collab] Failed to accept connection: {}", e);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Host side of one guest's connection.
+fn serve_guest(shared: &Arc<Shared>, stream: TcpStream, ip: IpAddr) -> Result<(), String> {
+    stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_read_timeout(Some(CONNECT_TIMEOUT)))
+        .map_err(|e| e.to_string())?;
+    let mut ws = tungstenite::accept(stream).map_err(|e| format!("Handshake failed: {}", e))?;
+
+    let WireMessage::Hello {
+        secret,
+        peer_id,
+        name,
+        pdf_sha256,
+        heads,
+    } = read_message(&mut ws)?
+    else {
+        return Err("Expected hello".to_string());
+    };
+    let expected = shared.secret.as_deref().unwrap_or_default();
+    let rejection = if shared.locked_out(ip) {
+        Some("Too many wrong join codes, try again later")
+    } else if !bool::from(secret.as_bytes().ct_eq(expected.as_bytes())) {
+        shared.join_failed(ip);
+        Some("Wrong join code")
+    } else if !pdf_sha256.eq_ignore_ascii_case(&shared.pdf_sha256) {
+        Some("The host has a different PDF open")
+    } else {
+        None
+    };
+    if let Some(reason) = rejection {
+        send_message(
+            &mut ws,
+            &WireMessage::Rejected {
+                reason: reason.to_string(),
+            },
+        )?;
+        let _ = ws.close(None);
+        return Err(reason.to_string());
+    }
+    if let Ok(mut failed) = shared.failed_joins.lock() {
+        failed.remove(&ip);
+    }
+
+    // Reconcile: tell the guest what we have and send what it's missing
+    let (missing, our_heads) = shared.doc.changes_since(&parse_heads(&heads))?;
+    send_message(
+        &mut ws,
+        &WireMessage::Welcome {
+            heads: our_heads.iter().map(|h| h.to_string()).collect(),
+        },
+    )?;
+    if !missing.is_empty() {
+        send_message(
+            &mut ws,
+            &WireMessage::Changes {
+                changes: BASE64_STANDARD.encode(&missing),
+            },
+        )?;
+    }
+
+    let (outbox, inbox) = mpsc::channel();
+    shared
+        .outboxes
+        .lock()
+        .map_err(|e| e.to_string())?
+        .insert(peer_id.clone(), outbox);
+    if let Ok(mut peers) = shared.peers.lock() {
+        peers.retain(|p| p.peer_id != peer_id);
+        peers.push(Presence {
+            peer_id: peer_id.clone(),
+            name,
+            page: None,
+            is_host: false,
+        });
+    }
+    shared.presence_changed();
+
+    ws.get_mut()
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(|e| e.to_string())?;
+    let result = pump(shared, &mut ws, &inbox, |message| match message {
+        WireMessage::Changes { changes } => {
+            shared.receive_changes(&changes)?;
+            shared.broadcast(&WireMessage::Changes { changes }, Some(&peer_id));
+            Ok(())
+        }
+        WireMessage::Page { page } => {
+            if let Ok(mut peers) = shared.peers.lock() {
+                if let Some(peer) = peers.iter_mut().find(|p| p.peer_id == peer_id) {
+                    peer.page = page;
+                }
+            }
+            shared.presence_changed();
+            Ok(())
+        }
+        _ => Ok(()),
+    });
+
+    if let Ok(mut outboxes) = shared.outboxes.lock() {
+        outboxes.remove(&peer_id);
+    }
+    if let Ok(mut peers) = shared.peers.lock() {
+        peers.retain(|p| p.peer_id != peer_id);
+    }
+    shared.presence_changed();
+    result
+}
+
+/// Guest side: stay connected to the host until stopped or rejected.
+fn guest_loop(shared: Arc<Shared>, addr: SocketAddr, secret: String) {
+    while !shared.stopped() {
+        match connect_to_host(&shared, addr, &secret) {
+            Ok(()) => break,
+            Err(GuestError::Rejected(reason)) => {
+                shared.doc.notify(CollabEvent::Rejected(reason));
+                break;
+            }
+            Err(GuestError::Disconnected(reason)) => {
+                if let Ok(mut peers) = shared.peers.lock() {
+                    peers.clear();
+                }
+                shared.doc.notify(CollabEvent::Disconnected(reason));
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+enum GuestError {
+    Rejected(String),
+    Disconnected(String),
+}
+
+impl From<String> for GuestError {
+    fn from(e: String) -> Self {
+        GuestError::Disconnected(e)
+    }
+}
+
+fn connect_to_host(shared: &Arc<Shared>, addr: SocketAddr, secret: &str) -> Result<(), GuestError> {
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)
+        .map_err(|e| format!("Failed to reach host: {}", e))?;
+    stream
+        .set_read_timeout(Some(CONNECT_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let (mut ws, _) = tungstenite::client(format!("ws://{}/", addr), stream)
+        .map_err(|e| format!("Handshake failed: {}", e))?;
+
+    let (_, our_heads) = shared.doc.changes_since(&[])?;
+    let me = shared.me.lock().map_err(|e| e.to_string())?.clone();
+    send_message(
+        &mut ws,
+        &WireMessage::Hello {
+            secret: secret.to_string(),
+            peer_id: me.peer_id.clone(),
+            name: me.name.clone(),
+            pdf_sha256: shared.pdf_sha256.clone(),
+            heads: our_heads.iter().map(|h| h.to_string()).collect(),
+        },
+    )?;
+
+    let host_heads = match read_message(&mut ws)? {
+        WireMessage::Welcome { heads } => parse_heads(&heads),
+        WireMessage::Rejected { reason } => return Err(GuestError::Rejected(reason)),
+        _ => return Err("Unexpected reply from host".to_string().into()),
+    };
+    // Send edits made while we were apart
+    let (missing, _) = shared.doc.changes_since(&host_heads)?;
+    if !missing.is_empty() {
+        send_message(
+            &mut ws,
+            &WireMessage::Changes {
+                changes: BASE64_STANDARD.encode(&missing),
+            },
+        )?;
+    }
+    shared.add_known(&our_heads);
+    shared.add_known(&host_heads);
+    send_message(&mut ws, &WireMessage::Page { page: me.page })?;
+
+    let (outbox, inbox) = mpsc::channel();
+    shared
+        .outboxes
+        .lock()
+        .map_err(|e| e.to_string())?
+        .insert(HOST_PEER.to_string(), outbox);
+    shared.doc.notify(CollabEvent::Connected);
+
+    ws.get_mut()
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .map_err(|e| e.to_string())?;
+    let result = pump(shared, &mut ws, &inbox, |message| match message {
+        WireMessage::Changes { changes } => shared.receive_changes(&changes),
+        WireMessage::Presence { peers } => {
+            let my_id = shared.me.lock().map_err(|e| e.to_string())?.peer_id.clone();
+            let others: Vec<Presence> = peers.into_iter().filter(|p| p.peer_id != my_id).collect();
+            if let Ok(mut stored) = shared.peers.lock() {
+                *stored = others.clone();
+            }
+            shared.doc.notify(CollabEvent::Presence(others));
+            Ok(())
+        }
+        _ => Ok(()),
+    });
+
+    if let Ok(mut outboxes) = shared.outboxes.lock() {
+        outboxes.remove(HOST_PEER);
+    }
+    result.map_err(GuestError::Disconnected)
+}
+
+/// Move messages between the socket and the outbox until the connection drops
+/// (Err) or the session is stopped (Ok).
+fn pump(
+    shared: &Shared,
+    ws: &mut WebSocket<TcpStream>,
+    outbox: &Receiver<String>,
+    mut handle: impl FnMut(WireMessage) -> Result<(), String>,
+) -> Result<(), String> {
+    loop {
+        if shared.stopped() {
+            let _ = ws.close(None);
+            let _ = ws.flush();
+            return Ok(());
+        }
+        while let Ok(text) = outbox.try_recv() {
+            ws.send(Message::text(text))
+                .map_err(|e| format!("Connection lost: {}", e))?;
+        }
+        match ws.read() {
+            Ok(Message::Text(text)) => match serde_json::from_str(text.as_str()) {
+                Ok(message) => {
+                    if let Err(e) = handle(message) {
+                        log::warn!("[collab] Failed to handle message: {}", e);
+                    }
+                }
+                Err(e) => log::warn!("[collab] Ignoring bad message: {}", e),
+            },
+            Ok(Message::Close(_)) => return Err("Connection closed".to_string()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(format!("Connection lost: {}", e)),
+        }
+    }
+}
+
+fn send_message(ws: &mut WebSocket<TcpStream>, message: &WireMessage) -> Result<(), String> {
+    let text = serde_json::to_string(message)
+        .map_err(|e| format!("Failed to serialize message: {}", e))?;
+    ws.send(Message::text(text))
+        .map_err(|e| format!("Connection lost: {}", e))
+}
+
+/// Blocking read of the next protocol message (used during the handshake).
+fn read_message(ws: &mut WebSocket<TcpStream>) -> Result<WireMessage, String> {
+    loop {
+        match ws.read() {
+            Ok(Message::Text(text)) => {
+                return serde_json::from_str(text.as_str())
+                    .map_err(|e| format!("Invalid message: {}", e))
+            }
+            Ok(Message::Close(_)) => return Err("Connection closed".to_string()),
+            Ok(_) => {}
+            Err(e) => return Err(format!("Connection lost: {}", e)),
+        }
+    }
+}
+
+fn parse_heads(heads: &[String]) -> Vec<ChangeHash> {
+    heads.iter().filter_map(|h| h.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+    use crate::models::{AnnotationType, CreateAnnotationInput};
+    use rusqlite::Connection;
+
+    /// A document over an in-memory database that records the events it gets.
+    struct TestDoc {
+        conn: Mutex<Connection>,
+        events: Mutex<Vec<CollabEvent>>,
+    }
+
+    impl TestDoc {
+        fn new() -> Arc<Self> {
+            let conn = Connection::open_in_memory().unwrap();
+            database::init_db(&conn).unwrap();
+            Arc::new(Self {
+                conn: Mutex::new(conn),
+                events: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn annotate(&self, content: &str) -> String {
+            let conn = self.conn.lock().unwrap();
+            let annotation = database::create_annotation(
+                &conn,
+                &CreateAnnotationInput {
+                    annotation_type: AnnotationType::Highlight,
+                    page_number: 2,
+                    color: None,
+                    content: Some(content.to_string()),
+                    position_data: None,
+                },
+            )
+            .unwrap();
+            crdt::record(&conn, &annotation.id).unwrap();
+            annotation.id
+        }
+
+        fn has(&self, id: &str) -> bool {
+            database::get_annotation(&self.conn.lock().unwrap(), id)
+                .unwrap()
+                .is_some()
+        }
+
+        fn rejection(&self) -> Option<String> {
+            self.events.lock().unwrap().iter().find_map(|e| match e {
+                CollabEvent::Rejected(reason) => Some(reason.clone()),
+                _ => None,
+            })
+        }
+    }
+
+    impl SharedDocument for TestDoc {
+        fn changes_since(&self, heads: &[ChangeHash]) -> Result<crdt::Changes, String> {
+            crdt::changes_since(&self.conn.lock().unwrap(), heads)
+        }
+
+        fn apply_changes(&self, data: &[u8]) -> Result<Vec<ChangeHash>, String> {
+            crdt::apply_remote(&self.conn.lock().unwrap(), data).map(|(heads, _)| heads)
+        }
+
+        fn notify(&self, event: CollabEvent) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        false
+    }
+
+    /// The host's join code, pointed at loopback.
+    fn local_code(host: &CollabSession, secret: Option<[u8; SECRET_LEN]>) -> String {
+        let code = host.join_code.clone().unwrap();
+        JoinCode {
+            addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, code.addr.port()),
+            secret: secret.unwrap_or(code.secret),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn join_code_round_trips() {
+        let code = JoinCode {
+            addr: "192.168.1.23:48123".parse().unwrap(),
+            secret: [7; SECRET_LEN],
+        };
+        let text = code.encode();
+        assert_eq!(text.len(), 36 + 7);
+        assert_eq!(JoinCode::parse(&text).unwrap(), code);
+        assert_eq!(JoinCode::parse(&text.to_lowercase()).unwrap(), code);
+        assert_eq!(JoinCode::parse(&text.replace('-', " ")).unwrap(), code);
+        assert_eq!(
+            JoinCode::parse(&text.replace('0', "o").replace('1', "l")).unwrap(),
+            code
+        );
+    }
+
+    #[test]
+    fn join_code_rejects_malformed_input() {
+        let text = JoinCode {
+            addr: "10.0.0.2:9000".parse().unwrap(),
+            secret: [1; SECRET_LEN],
+        }
+        .encode();
+        assert!(JoinCode::parse("").is_err());
+        assert!(JoinCode::parse(&text[..text.len() - 6]).is_err());
+        assert!(JoinCode::parse(&format!("{}-00000", text)).is_err());
+        assert!(JoinCode::parse(&text.replacen(char::is_alphanumeric, "U", 1)).is_err());
+        // Codes from before the secret was lengthened
+        assert!(JoinCode::parse("8A0M1-0G2Q8-W3H5R").is_err());
+    }
+
+    #[test]
+    fn guests_join_edit_and_converge() {
+        let host_doc = TestDoc::new();
+        let before = host_doc.annotate("before the session");
+        let host = CollabSession::host(host_doc.clone(), "abc", "Host").unwrap();
+        let code = local_code(&host, None);
+
+        let guest_doc = TestDoc::new();
+        let guest = CollabSession::join(&code, guest_doc.clone(), "abc", "Guest").unwrap();
+        assert!(wait_until(|| guest_doc.has(&before)));
+
+        let from_guest = guest_doc.annotate("from the guest");
+        guest.local_change();
+        guest.set_page(Some(7));
+        assert!(wait_until(|| host_doc.has(&from_guest)));
+        assert!(wait_until(|| host
+            .presence()
+            .iter()
+            .any(|p| p.name == "Guest" && p.page == Some(7))));
+
+        let from_host = host_doc.annotate("from the host");
+        host.local_change();
+        assert!(wait_until(|| guest_doc.has(&from_host)));
+
+        // Edits made apart are exchanged when the guest rejoins
+        drop(guest);
+        let offline_guest = guest_doc.annotate("guest offline");
+        let offline_host = host_doc.annotate("host offline");
+        host.local_change();
+        let _guest = CollabSession::join(&code, guest_doc.clone(), "abc", "Guest").unwrap();
+        assert!(wait_until(|| host_doc.has(&offline_guest)));
+        assert!(wait_until(|| guest_doc.has(&offline_host)));
+    }
+
+    #[test]
+    fn wrong_secret_is_rejected_and_repeats_lock_out() {
+        let host_doc = TestDoc::new();
+        let host = CollabSession::host(host_doc, "abc", "Host").unwrap();
+        let mut wrong = host.join_code.clone().unwrap().secret;
+        wrong[0] ^= 1;
+
+        for _ in 0..MAX_FAILED_JOINS {
+            let guest_doc = TestDoc::new();
+            let _guest = CollabSession::join(
+                &local_code(&host, Some(wrong)),
+                guest_doc.clone(),
+                "abc",
+                "G",
+            )
+            .unwrap();
+            assert!(wait_until(|| guest_doc.rejection().is_some()));
+            assert_eq!(guest_doc.rejection().unwrap(), "Wrong join code");
+        }
+        assert!(host.presence().iter().all(|p| p.is_host));
+
+        // Now even the right code is refused from this address
+        let guest_doc = TestDoc::new();
+        let _guest =
+            CollabSession::join(&local_code(&host, None), guest_doc.clone(), "abc", "G").unwrap();
+        assert!(wait_until(|| guest_doc.rejection().is_some()));
+        assert!(guest_doc.rejection().unwrap().starts_with("Too many"));
+    }
+
+    #[test]
+    fn guest_with_another_pdf_is_rejected() {
+        let host = CollabSession::host(TestDoc::new(), "abc", "Host").unwrap();
+        let guest_doc = TestDoc::new();
+        let _guest =
+            CollabSession::join(&local_code(&host, None), guest_doc.clone(), "def", "G").unwrap();
+        assert!(wait_until(|| guest_doc.rejection().is_some()));
+        assert_eq!(
+            guest_doc.rejection().unwrap(),
+            "The host has a different PDF open"
+        );
+    }
+}
@@ -1,21 +1,60 @@
-use std::path::PathBuf;
-use std::sync::Mutex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tauri::ipc::Response;
-use tauri::State;
+use tauri::{AppHandle, Emitter, Manager, State};
 
+use crate::bibliography;
+use crate::citation;
+use crate::cli::{self, CliAction};
+use crate::collab::{self, CollabEvent, CollabSession};
+use crate::crdt;
+use crate::credentials;
 use crate::database;
+use crate::deep_link::{self, DocumentLink, OpenRequest};
+use crate::fingerprint;
+use crate::importer::{self, LibraryConfig, WatchedFolder};
+use crate::library;
+use crate::merge;
 use crate::models::*;
+use crate::outline;
+use crate::page_labels;
+use crate::pdf_metadata;
+use crate::pdf_protocol;
+use crate::reference_list;
+use crate::references;
 use crate::rr_file::{self, RrSession};
+use crate::search;
+use crate::sidecar;
+use crate::sync::{self, SyncConfig};
+use crate::thumbnails;
+use crate::web_annotation;
+use crate::webdav::{self, WebDavClient, WebDavSettings};
+use crate::xfdf;
 
 /// Application state holding the current session
 pub struct AppState {
     pub session: Mutex<Option<RrSession>>,
+    /// Watches the sync folder while folder sync is enabled
+    pub sync_watcher: Mutex<Option<notify::RecommendedWatcher>>,
+    /// Watches the library's watched folders for new PDFs
+    pub library_watcher: Mutex<Option<notify::RecommendedWatcher>>,
+    /// Live collaboration session on the open document, if any
+    pub collab: Mutex<Option<CollabSession>>,
+    /// Deep link received but not yet picked up by the frontend
+    pub pending_link: Mutex<Option<DeepLinkTarget>>,
 }
 
-/// Open a .rr file or import a PDF
+/// Open a .rr file or import a PDF. Reading the PDF can take a while, so it
+/// runs on a blocking task.
 #[tauri::command]
-pub fn open_file(path: String, state: State<AppState>) -> Result<DocumentInfo, String> {
-    let path = PathBuf::from(&path);
+pub async fn open_file(path: String, app: AppHandle) -> Result<DocumentInfo, String> {
+    tauri::async_runtime::spawn_blocking(move || open_path(&app, &PathBuf::from(path)))
+        .await
+        .map_err(|e| format!("Open task failed: {}", e))?
+}
+
+fn open_path(app: &AppHandle, path: &Path) -> Result<DocumentInfo, String> {
     let ext = path
         .extension()
         .and_then(|e| e.to_str())
@@ -23,12 +62,19 @@ pub fn open_file(path: String, state: State<AppState>) -> Result<DocumentInfo, S
         .to_lowercase();
 
     let session = match ext.as_str() {
-        "rr" => rr_file::open_rr(&path)?,
-        "pdf" => rr_file::import_pdf(&path, None)?,
+        "rr" => rr_file::open_rr(path)?,
+        "pdf" => rr_file::import_pdf(path, None)?,
         _ => return Err(format!("Unsupported file type: .{}", ext)),
     };
+    // Pick up edits made by versions without CRDT support
+    crdt::capture(&session.db)?;
+    // Documents imported before outlines, page labels or thumbnails were
+    // read get theirs now
+    rr_file::extract_missing(&session)?;
+    index_session(app, &session, true);
 
     let pdf_path = session.pdf_path().to_string_lossy().to_string();
+    let pdf_sha256 = rr_file::document_hash(&session)?;
     let title = database::get_metadata(&session.db, "title")
         .map_err(|e| format!("Failed to read title: {}", e))?;
     let page_count_str = database::get_metadata(&session.db, "page_count")
@@ -38,12 +84,16 @@ pub fn open_file(path: String, state: State<AppState>) -> Result<DocumentInfo, S
 
     let info = DocumentInfo {
         pdf_path,
+        pdf_sha256,
         rr_path: session.rr_path.to_string_lossy().to_string(),
         title,
         page_count: page_count_str.and_then(|s| s.parse().ok()),
         last_page: last_page_str.and_then(|s| s.parse().ok()),
+        duplicates: find_duplicates(app, &session),
     };
 
+    let state = app.state::<AppState>();
+    end_collab_session(&state)?;
     let mut state_session = state.session.lock().map_err(|e| e.to_string())?;
     // Clean up previous session if any
     if let Some(prev) = state_session.take() {
@@ -54,21 +104,37 @@ pub fn open_file(path: String, state: State<AppState>) -> Result<DocumentInfo, S
     Ok(info)
 }
 
-/// Save the current session back to the .rr file
+/// Save the current session back to the .rr file.
+/// When folder sync is enabled, the document is synced afterwards.
 #[tauri::command]
-pub fn save_file(state: State<AppState>) -> Result<(), String> {
+pub fn save_file(app: AppHandle, state: State<AppState>) -> Result<(), String> {
     let session = state.session.lock().map_err(|e| e.to_string())?;
     let session = session.as_ref().ok_or("No file is open")?;
-    rr_file::save_rr(session)
+    rr_file::save_rr(session)?;
+    index_session(&app, session, false);
+
+    let config = load_sync_config(&app)?;
+    if let Some(folder) = &config.folder {
+        // A sync failure shouldn't fail the save itself
+        match sync::sync_document(session, folder, &config.device_id) {
+            Ok(report) => {
+                let _ = app.emit("sync-completed", report);
+            }
+            Err(e) => log::warn!("[sync] Sync after save failed: {}", e),
+        }
+    }
+    Ok(())
 }
 
 /// Close the current session
 #[tauri::command]
-pub fn close_file(state: State<AppState>) -> Result<(), String> {
+pub fn close_file(app: AppHandle, state: State<AppState>) -> Result<(), String> {
+    end_collab_session(&state)?;
     let mut session = state.session.lock().map_err(|e| e.to_string())?;
     if let Some(prev) = session.take() {
         // Save before closing
         rr_file::save_rr(&prev)?;
+        index_session(&app, &prev, false);
         rr_file::cleanup_session(&prev);
     }
     Ok(())
@@ -82,8 +148,10 @@ pub fn get_annotations(
 ) -> Result<Vec<Annotation>, String> {
     let session = state.session.lock().map_err(|e| e.to_string())?;
     let session = session.as_ref().ok_or("No file is open")?;
-    database::get_annotations(&session.db, page_number)
-        .map_err(|e| format!("Failed to get annotations: {}", e))
+    let mut annotations = database::get_annotations(&session.db, page_number)
+        .map_err(|e| format!("Failed to get annotations: {}", e))?;
+    page_labels::label_annotations(&session.db, &mut annotations)?;
+    Ok(annotations)
 }
 
 /// Create a new annotation
@@ -94,8 +162,15 @@ pub fn create_annotation(
 ) -> Result<Annotation, String> {
     let session = state.session.lock().map_err(|e| e.to_string())?;
     let session = session.as_ref().ok_or("No file is open")?;
-    database::create_annotation(&session.db, &input)
-        .map_err(|e| format!("Failed to create annotation: {}", e))
+    let annotation = database::create_annotation(&session.db, &input)
+        .map_err(|e| format!("Failed to create annotation: {}", e))?;
+    crdt::record(&session.db, &annotation.id)?;
+    notify_collab(&state);
+    let page_label = page_labels::label(&session.db, annotation.page_number)?;
+    Ok(Annotation {
+        page_label,
+        ..annotation
+    })
 }
 
 /// Update an existing annotation
@@ -106,8 +181,13 @@ pub fn update_annotation(
 ) -> Result<bool, String> {
     let session = state.session.lock().map_err(|e| e.to_string())?;
     let session = session.as_ref().ok_or("No file is open")?;
-    database::update_annotation(&session.db, &input)
-        .map_err(|e| format!("Failed to update annotation: {}", e))
+    let updated = database::update_annotation(&session.db, &input)
+        .map_err(|e| format!("Failed to update annotation: {}", e))?;
+    if updated {
+        crdt::record(&session.db, &input.id)?;
+        notify_collab(&state);
+    }
+    Ok(updated)
 }
 
 /// Delete an annotation
@@ -115,25 +195,372 @@ pub fn update_annotation(
 pub fn delete_annotation(id: String, state: State<AppState>) -> Result<bool, String> {
     let session = state.session.lock().map_err(|e| e.to_string())?;
     let session = session.as_ref().ok_or("No file is open")?;
-    database::delete_annotation(&session.db, &id)
-        .map_err(|e| format!("Failed to delete annotation: {}", e))
+    let deleted = database::delete_annotation(&session.db, &id)
+        .map_err(|e| format!("Failed to delete annotation: {}", e))?;
+    if deleted {
+        crdt::record(&session.db, &id)?;
+        notify_collab(&state);
+    }
+    Ok(deleted)
 }
 
-/// Set document metadata (e.g., page_count, last_page, title)
+/// Set document metadata (e.g., page_count, last_page, title). Keys derived
+/// from the PDF, other than the page count the viewer reports, and keys kept
+/// by other commands are refused.
 #[tauri::command]
 pub fn set_document_metadata(
     key: String,
     value: String,
     state: State<AppState>,
 ) -> Result<(), String> {
+    if key.starts_with(bibliography::KEY_PREFIX) {
+        return Err(format!(
+            "{} is part of the bibliographic record; use set_bibliography",
+            key
+        ));
+    }
+    if key == citation::METADATA_KEY {
+        return Err(format!("{} can only be changed with set_citation_key", key));
+    }
+    if database::is_derived_metadata(&key) && key != "page_count" {
+        return Err(format!("{} is derived from the PDF and can't be set", key));
+    }
     let session = state.session.lock().map_err(|e| e.to_string())?;
     let session = session.as_ref().ok_or("No file is open")?;
     database::set_metadata(&session.db, &key, &value)
         .map_err(|e| format!("Failed to set metadata: {}", e))
 }
 
+/// Get the bibliographic record of the current document
+#[tauri::command]
+pub fn get_bibliography(state: State<AppState>) -> Result<BibliographicRecord, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    bibliography::get_record(&session.db)
+}
+
+/// Validate and replace the bibliographic record of the current document.
+/// Returns the record as stored, with DOIs, arXiv ids and URLs normalised.
+/// Saving the record confirms it, so extraction no longer changes it.
+#[tauri::command]
+pub fn set_bibliography(
+    record: BibliographicRecord,
+    state: State<AppState>,
+) -> Result<BibliographicRecord, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let record = bibliography::set_record(&session.db, &record)?;
+    pdf_metadata::confirm(&session.db)?;
+    Ok(record)
+}
+
+/// Get the metadata extracted from the PDF of the current document, with
+/// the source and confidence of each value
+#[tauri::command]
+pub fn get_extracted_metadata(state: State<AppState>) -> Result<Option<ExtractedMetadata>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    pdf_metadata::get_extracted(&session.db)
+}
+
+/// Extract metadata from the PDF of the current document again, filling
+/// empty fields of the bibliographic record unless the user has confirmed it
+#[tauri::command]
+pub async fn extract_metadata(app: AppHandle) -> Result<ExtractedMetadata, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let session = state.session.lock().map_err(|e| e.to_string())?;
+        let session = session.as_ref().ok_or("No file is open")?;
+        let stem = session.rr_path.file_stem().and_then(|s| s.to_str());
+        let pdf = pdf_metadata::parse(&session.pdf_path());
+        pdf_metadata::extract_into(&session.db, pdf.as_ref(), stem)
+    })
+    .await
+    .map_err(|e| format!("Metadata extraction task failed: {}", e))?
+}
+
+/// Store page text extracted by the renderer, keyed by page number.
+/// It is added to the library search index when the document is saved.
+/// Returns library documents the text shows to be the same paper.
+#[tauri::command]
+pub fn set_page_texts(
+    pages: HashMap<u32, String>,
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<Vec<DuplicateMatch>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let mut pages: Vec<(u32, String)> = pages.into_iter().collect();
+    pages.sort_by_key(|(page, _)| *page);
+    database::set_page_texts(&session.db, &pages)
+        .map_err(|e| format!("Failed to store page text: {}", e))?;
+    fingerprint::update_text_fingerprint(&session.db)?;
+    reference_list::update(&session.db)?;
+    outline::update_from_text(&session.db)?;
+    Ok(find_duplicates(&app, session))
+}
+
+/// The current document's table of contents: the PDF's own outline, or
+/// headings detected in the page text when it has none
+#[tauri::command]
+pub fn get_outline(state: State<AppState>) -> Result<Outline, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    outline::get(&session.db)
+}
+
+/// Labels of the current document's pages from its /PageLabels, e.g. "xii"
+/// for the front matter of a book; empty when the PDF doesn't label its pages
+#[tauri::command]
+pub fn get_page_labels(state: State<AppState>) -> Result<Vec<PageLabel>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    page_labels::get(&session.db)
+}
+
+/// The physical page number a page label refers to, or None if no page
+/// has that label
+#[tauri::command]
+pub fn page_label_to_number(label: String, state: State<AppState>) -> Result<Option<u32>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    page_labels::page_number(&session.db, &label)
+}
+
+/// The label of a physical page, or None if the PDF doesn't label its pages
+#[tauri::command]
+pub fn page_number_to_label(
+    page_number: u32,
+    state: State<AppState>,
+) -> Result<Option<String>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    page_labels::label(&session.db, page_number)
+}
+
+/// The current document's reference list, parsed from its page text, with
+/// each entry matched against the library so the app can open the cited
+/// document
+#[tauri::command]
+pub fn get_cited_references(
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<Vec<CitedReference>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    reference_list::update(&session.db)?;
+    let conn = open_library(&app)?;
+    reference_list::get(Some(&conn), &session.db)
+}
+
+/// Mark an entry of the current document's reference list to read, or clear
+/// the mark; a cited library document gets the "to read" status too
+#[tauri::command]
+pub fn set_cited_reference_to_read(
+    position: u32,
+    to_read: bool,
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<CitedReference, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let conn = open_library(&app)?;
+    reference_list::set_to_read(&conn, &session.db, position, to_read)
+}
+
+/// Handle URLs the OS asked the app to open: `vellum://` links, and on
+/// macOS `file://` URLs of associated documents. Only the last valid one is
+/// opened.
+pub fn handle_deep_links(app: &AppHandle, urls: Vec<url::Url>) {
+    let request = urls
+        .iter()
+        .rev()
+        .find_map(|url| match deep_link::from_url(url) {
+            Ok(request) => Some(request),
+            Err(e) => {
+                log::warn!("[deep-link] {}", e);
+                None
+            }
+        });
+    if let Some(request) = request {
+        open_request(app, request);
+    }
+}
+
+/// Handle the arguments of a second launch, which exits after handing them
+/// to this instance.
+pub fn handle_forwarded_args(app: &AppHandle, args: Vec<String>, cwd: String) {
+    // The single instance plugin has passed a lone link on to the deep link
+    // plugin, which delivers it to `handle_deep_links`
+    if cfg!(any(windows, target_os = "linux")) && cli::is_link(args.get(1..).unwrap_or_default()) {
+        return;
+    }
+    match cli::parse(args.into_iter().skip(1), Path::new(&cwd)) {
+        Ok(CliAction::Open(Some(request))) => open_request(app, request),
+        Ok(_) => focus_main_window(app),
+        Err(e) => {
+            log::warn!("[cli] Ignoring forwarded arguments: {}", e);
+            focus_main_window(app);
+        }
+    }
+}
+
+/// Open a file, or the library document a link points to. Links to
+/// documents that aren't in the library are refused; a link to the open
+/// document just moves to its target.
+pub fn open_request(app: &AppHandle, request: OpenRequest) {
+    let target = match request {
+        OpenRequest::File(target) => Ok(target),
+        OpenRequest::Link(link) => match open_document_target(app, &link) {
+            Some(target) => Ok(target),
+            None => open_library(app).and_then(|conn| deep_link::resolve(&conn, &link)),
+        },
+    };
+    match target {
+        Ok(target) => open_target(app, target),
+        Err(e) => {
+            log::warn!("[deep-link] {}", e);
+            focus_main_window(app);
+        }
+    }
+}
+
+fn open_document_target(app: &AppHandle, link: &DocumentLink) -> Option<DeepLinkTarget> {
+    let state = app.state::<AppState>();
+    let session = state.session.lock().ok()?;
+    let session = session.as_ref()?;
+    if rr_file::document_hash(session).ok()? != link.pdf_sha256 {
+        return None;
+    }
+    Some(DeepLinkTarget {
+        path: session.rr_path.to_string_lossy().to_string(),
+        page_number: link.page_number,
+        annotation_id: link.annotation_id.clone(),
+    })
+}
+
+/// Keep a document to open for the frontend, which picks it up with
+/// `take_deep_link` on start and when told by a `deep-link` event.
+fn open_target(app: &AppHandle, target: DeepLinkTarget) {
+    let state = app.state::<AppState>();
+    match state.pending_link.lock() {
+        Ok(mut pending) => *pending = Some(target),
+        Err(e) => {
+            log::warn!("[deep-link] Failed to store link: {}", e);
+            return;
+        }
+    }
+    focus_main_window(app);
+    let _ = app.emit("deep-link", ());
+}
+
+fn focus_main_window(app: &AppHandle) {
+    if let Some(window) = app.get_webview_window("main") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+/// The document the app was last asked to open, by a link or the command
+/// line, if the frontend hasn't handled it yet
+#[tauri::command]
+pub fn take_deep_link(state: State<AppState>) -> Result<Option<DeepLinkTarget>, String> {
+    let mut pending = state.pending_link.lock().map_err(|e| e.to_string())?;
+    Ok(pending.take())
+}
+
+/// A `vellum://open` link to the current document, optionally at a page or
+/// annotation, for pasting elsewhere
+#[tauri::command]
+pub fn get_deep_link(
+    page_number: Option<u32>,
+    annotation_id: Option<String>,
+    state: State<AppState>,
+) -> Result<String, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    Ok(deep_link::build(&DocumentLink {
+        pdf_sha256: rr_file::document_hash(session)?,
+        page_number,
+        annotation_id,
+    }))
+}
+
+/// Answer a request on the `rr://` scheme with the open document's PDF, or
+/// the byte range of it that the viewer asked for
+pub fn pdf_protocol_response(
+    app: &AppHandle,
+    request: &tauri::http::Request<Vec<u8>>,
+) -> tauri::http::Response<Vec<u8>> {
+    let state = app.state::<AppState>();
+    // Only the hash is looked up under the lock; the file is read after
+    let pdf = match state.session.lock() {
+        Ok(session) => session.as_ref().and_then(|session| {
+            let hash = rr_file::document_hash(session).ok()?;
+            Some((session.pdf_path(), hash))
+        }),
+        Err(_) => None,
+    };
+    let range = request
+        .headers()
+        .get(tauri::http::header::RANGE)
+        .and_then(|value| value.to_str().ok());
+    let response = pdf_protocol::respond(
+        pdf.as_ref()
+            .map(|(path, hash)| (path.as_path(), hash.as_str())),
+        request.method().as_str(),
+        request.uri().path(),
+        range,
+    );
+
+    let mut builder = tauri::http::Response::builder().status(response.status);
+    for (name, value) in response.headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(response.body).unwrap_or_else(|e| {
+        let mut error = tauri::http::Response::new(e.to_string().into_bytes());
+        *error.status_mut() = tauri::http::StatusCode::INTERNAL_SERVER_ERROR;
+        error
+    })
+}
+
+/// Thumbnail image of a page of the current document, as raw bytes. Empty
+/// when the page has none yet, so the frontend renders and uploads one.
+#[tauri::command]
+pub fn get_thumbnail(page_number: u32, state: State<AppState>) -> Result<Response, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let bytes = thumbnails::get(session, page_number)?.unwrap_or_default();
+    Ok(Response::new(bytes))
+}
+
+/// Store a thumbnail of a page of the current document, rendered by the
+/// frontend. The PNG, JPEG or WebP image is sent as the raw request body,
+/// with the page number in the `page-number` header.
+#[tauri::command]
+pub fn set_thumbnail(
+    request: tauri::ipc::Request<'_>,
+    state: State<AppState>,
+) -> Result<(), String> {
+    let page_number = request
+        .headers()
+        .get("page-number")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u32>().ok())
+        .ok_or("Missing or invalid page-number header")?;
+    let tauri::ipc::InvokeBody::Raw(bytes) = request.body() else {
+        return Err("The thumbnail must be sent as raw bytes".to_string());
+    };
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    thumbnails::put(session, page_number, bytes)
+}
+
 /// Read the PDF bytes for the current session.
 /// Returns raw bytes via IPC Response (efficient binary transfer).
+/// The viewer loads the PDF in ranges over the `rr://` scheme instead and
+/// only falls back to this when that fails.
 #[tauri::command]
 pub fn read_pdf_bytes(state: State<AppState>) -> Result<Response, String> {
     let session = state.session.lock().map_err(|e| e.to_string())?;
@@ -144,14 +571,1147 @@ pub fn read_pdf_bytes(state: State<AppState>) -> Result<Response, String> {
     Ok(Response::new(bytes))
 }
 
+/// Export all annotations of the current document to an XFDF file
+#[tauri::command]
+pub fn export_xfdf(path: String, state: State<AppState>) -> Result<(), String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let annotations = database::get_annotations(&session.db, None)
+        .map_err(|e| format!("Failed to get annotations: {}", e))?;
+    let pdf_name = session
+        .rr_path
+        .with_extension("pdf")
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string());
+    let xml = xfdf::to_xfdf(&annotations, pdf_name.as_deref());
+    std::fs::write(&path, xml).map_err(|e| format!("Failed to write XFDF: {}", e))
+}
+
+/// Import annotations from an XFDF file into the current document.
+/// `page_sizes` lists each page's dimensions so coordinates can be converted.
+/// Annotations whose name matches an existing id replace it.
+#[tauri::command]
+pub fn import_xfdf(
+    path: String,
+    page_sizes: Vec<PageSize>,
+    state: State<AppState>,
+) -> Result<Vec<Annotation>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let xml = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read XFDF: {}", e))?;
+    let mut annotations = xfdf::from_xfdf(&xml, &page_sizes)?;
+    for annotation in &annotations {
+        database::upsert_annotation(&session.db, annotation)
+            .map_err(|e| format!("Failed to import annotation: {}", e))?;
+    }
+    crdt::capture(&session.db)?;
+    notify_collab(&state);
+    page_labels::label_annotations(&session.db, &mut annotations)?;
+    Ok(annotations)
+}
+
+/// Export all annotations of the current document as a W3C Web Annotation
+/// collection (JSON-LD)
+#[tauri::command]
+pub fn export_web_annotations(path: String, state: State<AppState>) -> Result<(), String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let annotations = database::get_annotations(&session.db, None)
+        .map_err(|e| format!("Failed to get annotations: {}", e))?;
+    let title = database::get_metadata(&session.db, "title")
+        .map_err(|e| format!("Failed to read title: {}", e))?;
+    let source = web_annotation::source_urn(&rr_file::document_hash(session)?);
+    let collection = web_annotation::to_collection(&annotations, &source, title);
+    let json = serde_json::to_string_pretty(&collection)
+        .map_err(|e| format!("Failed to serialize annotations: {}", e))?;
+    std::fs::write(&path, json).map_err(|e| format!("Failed to write annotations: {}", e))
+}
+
+/// Import a collection of W3C Web Annotations into the current document.
+/// `page_sizes` lists each page's dimensions at zoom=1.0. Annotations of
+/// other documents are skipped, and fail the import if there are only such,
+/// unless `force` is true.
+#[tauri::command]
+pub fn import_web_annotations(
+    path: String,
+    page_sizes: Vec<PageSize>,
+    force: Option<bool>,
+    state: State<AppState>,
+) -> Result<Vec<Annotation>, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let json =
+        std::fs::read_to_string(&path).map_err(|e| format!("Failed to read annotations: {}", e))?;
+    let sources = if force.unwrap_or(false) {
+        None
+    } else {
+        let pdf = pdf_metadata::parse(&session.pdf_path());
+        let mut sources =
+            web_annotation::document_sources(&rr_file::document_hash(session)?, pdf.as_ref());
+        // Exports from before targets named the PDF by its hash
+        if let Ok(url) = url::Url::from_file_path(&session.rr_path) {
+            sources.push(url.to_string());
+        }
+        Some(sources)
+    };
+    let items = web_annotation::parse_web_annotations(&json)?;
+    let mut annotations = Vec::new();
+    for web in &items {
+        if let Some(annotation) =
+            web_annotation::from_web_annotation(web, sources.as_deref(), &page_sizes)?
+        {
+            annotations.push(annotation);
+        }
+    }
+    if annotations.len() < items.len() {
+        if annotations.is_empty() {
+            return Err("None of the annotations are for this document".to_string());
+        }
+        log::warn!(
+            "[web-annotation] Skipped {} annotations of other documents or without a page",
+            items.len() - annotations.len()
+        );
+    }
+    for annotation in &annotations {
+        database::upsert_annotation(&session.db, annotation)
+            .map_err(|e| format!("Failed to import annotation: {}", e))?;
+    }
+    crdt::capture(&session.db)?;
+    notify_collab(&state);
+    page_labels::label_annotations(&session.db, &mut annotations)?;
+    Ok(annotations)
+}
+
+/// Export annotations and metadata, without the PDF, to a sidecar bundle
+#[tauri::command]
+pub fn export_sidecar(path: String, state: State<AppState>) -> Result<(), String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    sidecar::export_sidecar(session, &PathBuf::from(path))
+}
+
+/// Attach a sidecar bundle to the current document.
+/// Fails on a PDF hash mismatch unless `force` is true.
+#[tauri::command]
+pub fn import_sidecar(
+    path: String,
+    force: Option<bool>,
+    state: State<AppState>,
+) -> Result<SidecarImportResult, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let result = sidecar::import_sidecar(session, &PathBuf::from(path), force.unwrap_or(false))?;
+    crdt::capture(&session.db)?;
+    notify_collab(&state);
+    Ok(result)
+}
+
+/// Merge annotations from another .rr file of the same PDF into the current document
+#[tauri::command]
+pub fn merge_from(
+    path: String,
+    strategy: Option<MergeStrategy>,
+    state: State<AppState>,
+) -> Result<MergeSummary, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let summary = merge::merge_from(
+        session,
+        &PathBuf::from(path),
+        strategy.unwrap_or(MergeStrategy::NewerWins),
+    )?;
+    notify_collab(&state);
+    Ok(summary)
+}
+
+/// Three-way merge of diverged .rr files (e.g. a sync "conflicted copy")
+/// into `output`. Does not touch the open session.
+#[tauri::command]
+pub fn merge_three_way(
+    base: String,
+    ours: String,
+    theirs: String,
+    output: String,
+    strategy: Option<MergeStrategy>,
+) -> Result<ThreeWayMergeSummary, String> {
+    merge::merge_three_way(
+        &PathBuf::from(base),
+        &PathBuf::from(ours),
+        &PathBuf::from(theirs),
+        &PathBuf::from(output),
+        strategy.unwrap_or(MergeStrategy::NewerWins),
+    )
+}
+
+fn load_sync_config(app: &AppHandle) -> Result<SyncConfig, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to resolve config dir: {}", e))?;
+    SyncConfig::load(&config_dir)
+}
+
+/// Tie the actors local annotation edits are recorded under to this
+/// installation's device id.
+pub fn init_device_id(app: &AppHandle) -> Result<(), String> {
+    let config = load_sync_config(app)?;
+    crdt::set_device_id(&config.device_id);
+    Ok(())
+}
+
+/// (Re)start the sync folder watcher from the saved config. When a peer changes
+/// the journal of the open document or of another library document, that
+/// document is synced and `sync-completed` is emitted.
+pub fn restart_sync_watcher(app: &AppHandle) -> Result<(), String> {
+    let config = load_sync_config(app)?;
+    let state = app.state::<AppState>();
+    let mut watcher = state.sync_watcher.lock().map_err(|e| e.to_string())?;
+    *watcher = None;
+
+    let Some(folder) = config.folder.clone() else {
+        return Ok(());
+    };
+    std::fs::create_dir_all(&folder).map_err(|e| format!("Failed to create sync folder: {}", e))?;
+
+    let handle = app.clone();
+    let device_id = config.device_id.clone();
+    *watcher = Some(sync::watch(&folder, &config.device_id, move |hash| {
+        let result = match sync_open_document(&handle, &hash, &folder, &device_id) {
+            Some(result) => result,
+            None => {
+                let entry = open_library(&handle).and_then(|conn| {
+                    library::find_by_hash(&conn, &hash)
+                        .map_err(|e| format!("Failed to search library: {}", e))
+                });
+                match entry {
+                    Ok(Some(entry)) => sync_library_document(
+                        &handle,
+                        std::path::Path::new(&entry.path),
+                        &folder,
+                        &device_id,
+                    ),
+                    Ok(None) => return,
+                    Err(e) => Err(e),
+                }
+            }
+        };
+        match result {
+            Ok(report) => {
+                let _ = handle.emit("sync-completed", report);
+            }
+            Err(e) => log::warn!("[sync] Background sync failed: {}", e),
+        }
+    })?);
+    Ok(())
+}
+
+/// Sync the open document if its PDF hash is `hash`.
+fn sync_open_document(
+    app: &AppHandle,
+    hash: &str,
+    folder: &std::path::Path,
+    device_id: &str,
+) -> Option<Result<SyncReport, String>> {
+    let state = app.state::<AppState>();
+    let session = state.session.lock().ok()?;
+    let session = session.as_ref()?;
+    if rr_file::document_hash(session).ok().as_deref() != Some(hash) {
+        return None;
+    }
+    let result = sync::sync_document(session, folder, device_id);
+    notify_collab(&state);
+    Some(result)
+}
+
+/// Sync a library document: the open session when it is that file, otherwise
+/// the file itself, which is saved and re-indexed if anything changed.
+fn sync_library_document(
+    app: &AppHandle,
+    path: &std::path::Path,
+    folder: &std::path::Path,
+    device_id: &str,
+) -> Result<SyncReport, String> {
+    let state = app.state::<AppState>();
+    {
+        let session = state.session.lock().map_err(|e| e.to_string())?;
+        if let Some(session) = session.as_ref().filter(|s| s.rr_path == path) {
+            let result = sync::sync_document(session, folder, device_id);
+            notify_collab(&state);
+            return result;
+        }
+    }
+
+    let session = rr_file::open_rr(path)?;
+    let result = sync::sync_document(&session, folder, device_id).and_then(|report| {
+        if report.pushed + report.applied + report.skipped > 0 {
+            rr_file::save_rr(&session)?;
+            index_session(app, &session, false);
+        }
+        Ok(report)
+    });
+    rr_file::cleanup_session(&session);
+    result
+}
+
+/// Get the folder sync settings
+#[tauri::command]
+pub fn get_sync_config(app: AppHandle) -> Result<SyncConfig, String> {
+    load_sync_config(&app)
+}
+
+/// Enable folder sync to `folder`, or disable it with `None`
+#[tauri::command]
+pub fn set_sync_folder(folder: Option<String>, app: AppHandle) -> Result<SyncConfig, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to resolve config dir: {}", e))?;
+    let mut config = SyncConfig::load(&config_dir)?;
+    config.folder = folder.map(PathBuf::from);
+    config.save(&config_dir)?;
+    restart_sync_watcher(&app)?;
+    Ok(config)
+}
+
+/// Sync the current document with the sync folder now
+#[tauri::command]
+pub fn sync_now(app: AppHandle, state: State<AppState>) -> Result<SyncReport, String> {
+    let config = load_sync_config(&app)?;
+    let folder = config.folder.ok_or("Folder sync is not enabled")?;
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let report = sync::sync_document(session, &folder, &config.device_id)?;
+    notify_collab(&state);
+    Ok(report)
+}
+
+/// Sync every library document with the sync folder. A document that fails
+/// is logged and skipped. Runs off the main thread since it opens every file.
+#[tauri::command]
+pub async fn sync_library(app: AppHandle) -> Result<Vec<SyncReport>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let config = load_sync_config(&app)?;
+        let folder = config.folder.ok_or("Folder sync is not enabled")?;
+        let entries = library::list_entries(&open_library(&app)?, &LibraryQuery::default())
+            .map_err(|e| format!("Failed to list library: {}", e))?;
+
+        let mut reports = Vec::new();
+        for entry in entries {
+            let path = PathBuf::from(&entry.path);
+            if !path.exists() {
+                continue;
+            }
+            match sync_library_document(&app, &path, &folder, &config.device_id) {
+                Ok(report) => reports.push(report),
+                Err(e) => log::warn!("[sync] Failed to sync {}: {}", entry.path, e),
+            }
+        }
+        Ok(reports)
+    })
+    .await
+    .map_err(|e| format!("Library sync task failed: {}", e))?
+}
+
+/// Documents published to the sync folder by any device
+#[tauri::command]
+pub fn list_synced_documents(app: AppHandle) -> Result<Vec<SyncedDocument>, String> {
+    let config = load_sync_config(&app)?;
+    let folder = config.folder.ok_or("Folder sync is not enabled")?;
+    Ok(sync::list_remote_documents(&folder)?
+        .into_iter()
+        .map(|(pdf_sha256, path)| SyncedDocument {
+            pdf_sha256,
+            path: path.to_string_lossy().to_string(),
+        })
+        .collect())
+}
+
+/// Configure the WebDAV sync account. The password goes to the OS credential
+/// store; pass `None` to keep the stored one.
+#[tauri::command]
+pub fn set_webdav_account(
+    url: String,
+    username: String,
+    password: Option<String>,
+    chunked_upload_url: Option<String>,
+    app: AppHandle,
+) -> Result<SyncConfig, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to resolve config dir: {}", e))?;
+    let mut config = SyncConfig::load(&config_dir)?;
+    let settings = WebDavSettings {
+        url,
+        username,
+        chunked_upload_url: chunked_upload_url.filter(|u| !u.is_empty()),
+    };
+    // Validates the URLs before anything is stored
+    WebDavClient::new(
+        &settings.url,
+        &settings.username,
+        "",
+        settings.chunked_upload_url.as_deref(),
+    )?;
+    if let Some(password) = password {
+        credentials::set_secret(&settings.credential_account(), &password)?;
+    }
+    if let Some(previous) = &config.webdav {
+        if previous.credential_account() != settings.credential_account() {
+            credentials::delete_secret(&previous.credential_account())?;
+        }
+    }
+    config.webdav = Some(settings);
+    config.save(&config_dir)?;
+    Ok(config)
+}
+
+/// Remove the WebDAV account and its stored password
+#[tauri::command]
+pub fn clear_webdav_account(app: AppHandle) -> Result<SyncConfig, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to resolve config dir: {}", e))?;
+    let mut config = SyncConfig::load(&config_dir)?;
+    if let Some(settings) = config.webdav.take() {
+        credentials::delete_secret(&settings.credential_account())?;
+    }
+    config.save(&config_dir)?;
+    Ok(config)
+}
+
+fn webdav_client(app: &AppHandle) -> Result<(SyncConfig, WebDavClient), String> {
+    let config = load_sync_config(app)?;
+    let settings = config
+        .webdav
+        .as_ref()
+        .ok_or("WebDAV sync is not configured")?;
+    let client = WebDavClient::from_settings(settings)?;
+    Ok((config, client))
+}
+
+/// Sync the current document with the WebDAV server.
+/// Runs off the main thread since it does network I/O, and only holds the
+/// session while merging, not while talking to the server.
+#[tauri::command]
+pub async fn webdav_sync_now(app: AppHandle) -> Result<SyncReport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (config, client) = webdav_client(&app)?;
+        let mirror_root = app
+            .path()
+            .app_cache_dir()
+            .map_err(|e| format!("Failed to resolve cache dir: {}", e))?
+            .join("webdav");
+        let state = app.state::<AppState>();
+        let pdf_hash = {
+            let session = state.session.lock().map_err(|e| e.to_string())?;
+            rr_file::document_hash(session.as_ref().ok_or("No file is open")?)?
+        };
+        let report =
+            webdav::sync_document(&client, &mirror_root, &pdf_hash, &config.device_id, || {
+                let session = state.session.lock().map_err(|e| e.to_string())?;
+                let session = session.as_ref().ok_or("No file is open")?;
+                if rr_file::document_hash(session)? != pdf_hash {
+                    return Err("A different document was opened during sync".to_string());
+                }
+                sync::sync_document(session, &mirror_root, &config.device_id)
+            })?;
+        notify_collab(&state);
+        Ok(report)
+    })
+    .await
+    .map_err(|e| format!("WebDAV sync task failed: {}", e))?
+}
+
+/// PDF hashes of the documents published on the WebDAV server
+#[tauri::command]
+pub async fn webdav_list_documents(app: AppHandle) -> Result<Vec<String>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (_, client) = webdav_client(&app)?;
+        webdav::list_remote_documents(&client)
+    })
+    .await
+    .map_err(|e| format!("WebDAV task failed: {}", e))?
+}
+
+/// Download a document published on the WebDAV server to `dest` (a .rr path)
+#[tauri::command]
+pub async fn webdav_download_document(
+    pdf_sha256: String,
+    dest: String,
+    app: AppHandle,
+) -> Result<(), String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let (_, client) = webdav_client(&app)?;
+        webdav::download_document(&client, &pdf_sha256, &PathBuf::from(dest))
+    })
+    .await
+    .map_err(|e| format!("WebDAV task failed: {}", e))?
+}
+
+/// The open document, as seen by a live collaboration session
+struct OpenDocument {
+    app: AppHandle,
+}
+
+impl collab::SharedDocument for OpenDocument {
+    fn changes_since(&self, heads: &[automerge::ChangeHash]) -> Result<crdt::Changes, String> {
+        let state = self.app.state::<AppState>();
+        let session = state.session.lock().map_err(|e| e.to_string())?;
+        let session = session.as_ref().ok_or("No file is open")?;
+        crdt::changes_since(&session.db, heads)
+    }
+
+    fn apply_changes(&self, data: &[u8]) -> Result<Vec<automerge::ChangeHash>, String> {
+        let state = self.app.state::<AppState>();
+        let session = state.session.lock().map_err(|e| e.to_string())?;
+        let session = session.as_ref().ok_or("No file is open")?;
+        let (heads, (added, updated, deleted)) = crdt::apply_remote(&session.db, data)?;
+        if !(added.is_empty() && updated.is_empty() && deleted.is_empty()) {
+            let _ = self.app.emit(
+                "collab-annotations-changed",
+                AnnotationChangeSet {
+                    added,
+                    updated,
+                    deleted,
+                },
+            );
+        }
+        Ok(heads)
+    }
+
+    fn notify(&self, event: CollabEvent) {
+        let _ = match event {
+            CollabEvent::Presence(peers) => self.app.emit("collab-presence", peers),
+            CollabEvent::Connected => self.app.emit(
+                "collab-connection",
+                CollabConnection {
+                    connected: true,
+                    message: None,
+                },
+            ),
+            CollabEvent::Disconnected(message) | CollabEvent::Rejected(message) => self.app.emit(
+                "collab-connection",
+                CollabConnection {
+                    connected: false,
+                    message: Some(message),
+                },
+            ),
+        };
+    }
+}
+
+/// Send local annotation edits to collaborators, if a session is running.
+fn notify_collab(state: &AppState) {
+    if let Ok(collab) = state.collab.lock() {
+        if let Some(collab) = collab.as_ref() {
+            collab.local_change();
+        }
+    }
+}
+
+fn end_collab_session(state: &AppState) -> Result<(), String> {
+    if let Some(collab) = state.collab.lock().map_err(|e| e.to_string())?.take() {
+        collab.stop();
+    }
+    Ok(())
+}
+
+fn collab_info(collab: &CollabSession) -> CollabInfo {
+    CollabInfo {
+        is_host: collab.is_host(),
+        join_code: collab.join_code(),
+        peers: collab.presence(),
+    }
+}
+
+/// Host a live collaboration session for the open document on the local
+/// network. Others join with the returned code while this app stays open.
+#[tauri::command]
+pub fn start_collab_session(
+    name: String,
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<CollabInfo, String> {
+    start_collab(&app, &state, |doc, hash| {
+        CollabSession::host(doc, hash, &name)
+    })
+}
+
+/// Join a collaboration session by its code. The same PDF must be open here;
+/// annotations made on either side while apart are exchanged on (re)connect.
+#[tauri::command]
+pub fn join_collab_session(
+    code: String,
+    name: String,
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<CollabInfo, String> {
+    start_collab(&app, &state, |doc, hash| {
+        CollabSession::join(&code, doc, hash, &name)
+    })
+}
+
+fn start_collab(
+    app: &AppHandle,
+    state: &AppState,
+    start: impl FnOnce(Arc<dyn collab::SharedDocument>, &str) -> Result<CollabSession, String>,
+) -> Result<CollabInfo, String> {
+    end_collab_session(state)?;
+    let pdf_sha256 = {
+        let session = state.session.lock().map_err(|e| e.to_string())?;
+        let session = session.as_ref().ok_or("No file is open")?;
+        rr_file::document_hash(session)?
+    };
+    let collab = start(Arc::new(OpenDocument { app: app.clone() }), &pdf_sha256)?;
+    let info = collab_info(&collab);
+    *state.collab.lock().map_err(|e| e.to_string())? = Some(collab);
+    Ok(info)
+}
+
+/// Leave (or, as host, end) the collaboration session
+#[tauri::command]
+pub fn leave_collab_session(state: State<AppState>) -> Result<(), String> {
+    end_collab_session(&state)
+}
+
+/// Share the page being viewed with collaborators
+#[tauri::command]
+pub fn set_collab_page(page: Option<u32>, state: State<AppState>) -> Result<(), String> {
+    if let Some(collab) = state.collab.lock().map_err(|e| e.to_string())?.as_ref() {
+        collab.set_page(page);
+    }
+    Ok(())
+}
+
+/// The current collaboration session, if any
+#[tauri::command]
+pub fn get_collab_session(state: State<AppState>) -> Result<Option<CollabInfo>, String> {
+    let collab = state.collab.lock().map_err(|e| e.to_string())?;
+    Ok(collab.as_ref().map(collab_info))
+}
+
+fn open_library(app: &AppHandle) -> Result<rusqlite::Connection, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to resolve data dir: {}", e))?;
+    library::open(&data_dir)
+}
+
+/// Add or refresh the session's library entry. A library failure shouldn't
+/// stop the document from opening or saving, so errors are only logged.
+fn index_session(app: &AppHandle, session: &RrSession, opened: bool) {
+    if let Err(e) =
+        open_library(app).and_then(|conn| library::record_session(&conn, session, opened))
+    {
+        log::warn!(
+            "[library] Failed to index {}: {}",
+            session.rr_path.display(),
+            e
+        );
+    }
+}
+
+/// Library documents that duplicate the session's PDF. Like indexing, a
+/// library failure is only logged.
+fn find_duplicates(app: &AppHandle, session: &RrSession) -> Vec<DuplicateMatch> {
+    let result = open_library(app).and_then(|conn| {
+        let pdf_sha256 = rr_file::document_hash(session)?;
+        let text_fingerprint = database::get_metadata(&session.db, fingerprint::METADATA_KEY)
+            .map_err(|e| format!("Failed to read text fingerprint: {}", e))?;
+        library::find_duplicates(
+            &conn,
+            &session.rr_path,
+            &pdf_sha256,
+            text_fingerprint.as_deref(),
+        )
+        .map_err(|e| format!("Failed to search library: {}", e))
+    });
+    result.unwrap_or_else(|e| {
+        log::warn!("[library] Duplicate check failed: {}", e);
+        Vec::new()
+    })
+}
+
+/// Merge the open document's annotations into a library copy of the same
+/// paper, e.g. after importing it twice. The copy is saved; open it afterwards
+/// to continue there.
+#[tauri::command]
+pub async fn merge_into_library_document(
+    id: i64,
+    strategy: Option<MergeStrategy>,
+    app: AppHandle,
+) -> Result<MergeSummary, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        // Save the open document, then merge from the saved file without
+        // holding on to the session
+        let (rr_path, pdf_sha256) = {
+            let state = app.state::<AppState>();
+            let session = state.session.lock().map_err(|e| e.to_string())?;
+            let session = session.as_ref().ok_or("No file is open")?;
+            rr_file::save_rr(session)?;
+            (session.rr_path.clone(), rr_file::document_hash(session)?)
+        };
+
+        let conn = open_library(&app)?;
+        let entry = library::get_entry(&conn, id)
+            .map_err(|e| format!("Failed to read library entry: {}", e))?
+            .ok_or("Library entry not found")?;
+        if entry.missing {
+            return Err(format!("{} can no longer be found", entry.path));
+        }
+
+        let target = rr_file::open_rr(&PathBuf::from(&entry.path))?;
+        let strategy = strategy.unwrap_or(MergeStrategy::NewerWins);
+        let merged = crdt::capture(&target.db).and_then(|_| {
+            let summary = if entry.pdf_sha256.as_deref() == Some(pdf_sha256.as_str()) {
+                merge::merge_from(&target, &rr_path, strategy)?
+            } else {
+                merge::merge_from_duplicate(&target, &rr_path, strategy)?
+            };
+            rr_file::save_rr(&target)?;
+            library::record_session(&conn, &target, false)?;
+            Ok(summary)
+        });
+        rr_file::cleanup_session(&target);
+        merged
+    })
+    .await
+    .map_err(|e| format!("Library merge task failed: {}", e))?
+}
+
+/// List the documents in the library
+#[tauri::command]
+pub fn list_library(
+    query: Option<LibraryQuery>,
+    app: AppHandle,
+) -> Result<Vec<LibraryEntry>, String> {
+    let conn = open_library(&app)?;
+    library::list_entries(&conn, &query.unwrap_or_default())
+        .map_err(|e| format!("Failed to list library: {}", e))
+}
+
+/// Point a library entry at the new location of its .rr file
+#[tauri::command]
+pub fn relocate_library_entry(
+    id: i64,
+    path: String,
+    app: AppHandle,
+) -> Result<LibraryEntry, String> {
+    let conn = open_library(&app)?;
+    library::relocate_entry(&conn, id, &PathBuf::from(path))
+}
+
+/// Remove a document from the library without deleting its file
+#[tauri::command]
+pub fn remove_library_entry(id: i64, app: AppHandle) -> Result<bool, String> {
+    let conn = open_library(&app)?;
+    library::remove_entry(&conn, id).map_err(|e| format!("Failed to remove library entry: {}", e))
+}
+
+fn load_library_config(app: &AppHandle) -> Result<LibraryConfig, String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to resolve config dir: {}", e))?;
+    LibraryConfig::load(&config_dir)
+}
+
+fn save_library_config(app: &AppHandle, config: &LibraryConfig) -> Result<(), String> {
+    let config_dir = app
+        .path()
+        .app_config_dir()
+        .map_err(|e| format!("Failed to resolve config dir: {}", e))?;
+    config.save(&config_dir)
+}
+
+/// Folder imported documents are stored in
+fn library_folder(app: &AppHandle, config: &LibraryConfig) -> Result<PathBuf, String> {
+    match &config.folder {
+        Some(folder) => Ok(folder.clone()),
+        None => Ok(app
+            .path()
+            .app_data_dir()
+            .map_err(|e| format!("Failed to resolve data dir: {}", e))?
+            .join("Library")),
+    }
+}
+
+/// (Re)start watching the configured folders. Each new PDF is imported into
+/// the library folder and the outcome is emitted as `library-import`.
+pub fn restart_library_watcher(app: &AppHandle) -> Result<(), String> {
+    let config = load_library_config(app)?;
+    let state = app.state::<AppState>();
+    let mut watcher = state.library_watcher.lock().map_err(|e| e.to_string())?;
+    *watcher = None;
+
+    if config.watched_folders.is_empty() {
+        return Ok(());
+    }
+    let destination = library_folder(app, &config)?;
+    let handle = app.clone();
+    *watcher = Some(importer::watch(
+        config.watched_folders.clone(),
+        move |folder, pdf| {
+            let result = open_library(&handle).map(|conn| {
+                importer::import_into_library(&conn, pdf, &destination, folder.remove_original)
+            });
+            match result {
+                Ok(import) => {
+                    let _ = handle.emit("library-import", import);
+                }
+                Err(e) => log::warn!("[library] Failed to import {}: {}", pdf.display(), e),
+            }
+        },
+    )?);
+    Ok(())
+}
+
+/// Set or clear a library document's reading status
+#[tauri::command]
+pub fn set_reading_status(
+    id: i64,
+    status: Option<ReadingStatus>,
+    app: AppHandle,
+) -> Result<bool, String> {
+    let conn = open_library(&app)?;
+    library::set_status(&conn, id, status)
+        .map_err(|e| format!("Failed to set reading status: {}", e))
+}
+
+/// Set or clear a library document's priority (1 = most urgent, 5 = least)
+#[tauri::command]
+pub fn set_reading_priority(id: i64, priority: Option<u8>, app: AppHandle) -> Result<bool, String> {
+    let conn = open_library(&app)?;
+    library::set_priority(&conn, id, priority)
+}
+
+/// Set or clear a library document's due date (YYYY-MM-DD)
+#[tauri::command]
+pub fn set_due_date(id: i64, due_date: Option<String>, app: AppHandle) -> Result<bool, String> {
+    let conn = open_library(&app)?;
+    library::set_due_date(&conn, id, due_date.as_deref())
+}
+
+/// List all collections; nesting is given by `parent_id`
+#[tauri::command]
+pub fn list_collections(app: AppHandle) -> Result<Vec<Collection>, String> {
+    let conn = open_library(&app)?;
+    library::list_collections(&conn).map_err(|e| format!("Failed to list collections: {}", e))
+}
+
+/// Create a collection, optionally inside another one
+#[tauri::command]
+pub fn create_collection(
+    name: String,
+    parent_id: Option<i64>,
+    app: AppHandle,
+) -> Result<Collection, String> {
+    let conn = open_library(&app)?;
+    library::create_collection(&conn, &name, parent_id)
+}
+
+#[tauri::command]
+pub fn rename_collection(id: i64, name: String, app: AppHandle) -> Result<Collection, String> {
+    let conn = open_library(&app)?;
+    library::rename_collection(&conn, id, &name)
+}
+
+/// Move a collection under another one, or to the top level with `None`
+#[tauri::command]
+pub fn move_collection(
+    id: i64,
+    parent_id: Option<i64>,
+    app: AppHandle,
+) -> Result<Collection, String> {
+    let conn = open_library(&app)?;
+    library::move_collection(&conn, id, parent_id)
+}
+
+/// Delete a collection and its subcollections, keeping their documents
+#[tauri::command]
+pub fn delete_collection(id: i64, app: AppHandle) -> Result<bool, String> {
+    let conn = open_library(&app)?;
+    library::delete_collection(&conn, id).map_err(|e| format!("Failed to delete collection: {}", e))
+}
+
+#[tauri::command]
+pub fn add_to_collection(
+    collection_id: i64,
+    document_ids: Vec<i64>,
+    app: AppHandle,
+) -> Result<(), String> {
+    let conn = open_library(&app)?;
+    library::add_to_collection(&conn, collection_id, &document_ids)
+}
+
+#[tauri::command]
+pub fn remove_from_collection(
+    collection_id: i64,
+    document_ids: Vec<i64>,
+    app: AppHandle,
+) -> Result<(), String> {
+    let conn = open_library(&app)?;
+    library::remove_from_collection(&conn, collection_id, &document_ids)
+        .map_err(|e| format!("Failed to remove from collection: {}", e))
+}
+
+/// Search annotations and page text across every document in the library
+#[tauri::command]
+pub async fn search_library(query: String, app: AppHandle) -> Result<Vec<SearchHit>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let conn = open_library(&app)?;
+        search::search(&conn, &query)
+    })
+    .await
+    .map_err(|e| format!("Search task failed: {}", e))?
+}
+
+/// Get the library folder and watched folder settings
+#[tauri::command]
+pub fn get_library_config(app: AppHandle) -> Result<LibraryConfig, String> {
+    load_library_config(&app)
+}
+
+/// Store imported documents in `folder`, or the default location with `None`
+#[tauri::command]
+pub async fn set_library_folder(
+    folder: Option<String>,
+    app: AppHandle,
+) -> Result<LibraryConfig, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut config = load_library_config(&app)?;
+        config.folder = folder.map(PathBuf::from);
+        save_library_config(&app, &config)?;
+        restart_library_watcher(&app)?;
+        Ok(config)
+    })
+    .await
+    .map_err(|e| format!("Library config task failed: {}", e))?
+}
+
+/// Replace the watched folders and restart watching (watching a large folder
+/// tree takes a while, hence the blocking task)
+#[tauri::command]
+pub async fn set_watched_folders(
+    folders: Vec<WatchedFolder>,
+    app: AppHandle,
+) -> Result<LibraryConfig, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        for folder in &folders {
+            folder.validate()?;
+        }
+        let mut config = load_library_config(&app)?;
+        config.watched_folders = folders;
+        save_library_config(&app, &config)?;
+        restart_library_watcher(&app)?;
+        Ok(config)
+    })
+    .await
+    .map_err(|e| format!("Library config task failed: {}", e))?
+}
+
+/// Import a PDF into the library folder, skipping it if already in the library
+#[tauri::command]
+pub async fn import_to_library(
+    path: String,
+    remove_original: bool,
+    app: AppHandle,
+) -> Result<LibraryImport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let config = load_library_config(&app)?;
+        let destination = library_folder(&app, &config)?;
+        let conn = open_library(&app)?;
+        Ok(importer::import_into_library(
+            &conn,
+            &PathBuf::from(path),
+            &destination,
+            remove_original,
+        ))
+    })
+    .await
+    .map_err(|e| format!("Library import task failed: {}", e))?
+}
+
+/// Import a BibTeX or CSL-JSON file as a local reference database, link
+/// library documents to its entries by DOI, arXiv id or title, and fill in
+/// their bibliographic records. Importing the same file again updates it.
+#[tauri::command]
+pub async fn import_reference_file(
+    path: String,
+    app: AppHandle,
+) -> Result<ReferenceImport, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let conn = open_library(&app)?;
+        let state = app.state::<AppState>();
+        let session = state.session.lock().map_err(|e| e.to_string())?;
+        references::import_file(&conn, &PathBuf::from(path), session.as_ref())
+    })
+    .await
+    .map_err(|e| format!("Reference import task failed: {}", e))?
+}
+
+/// Re-import reference files that changed on disk and update the documents
+/// linked to their entries
+#[tauri::command]
+pub async fn refresh_reference_files(app: AppHandle) -> Result<Vec<ReferenceImport>, String> {
+    tauri::async_runtime::spawn_blocking(move || refresh_references(&app))
+        .await
+        .map_err(|e| format!("Reference refresh task failed: {}", e))?
+}
+
+/// Check the reference files for changes in the background, emitting
+/// `references-updated` with the files that were re-imported.
+pub fn refresh_references_in_background(app: &AppHandle) {
+    let app = app.clone();
+    std::thread::spawn(move || match refresh_references(&app) {
+        Ok(imports) if !imports.is_empty() => {
+            let _ = app.emit("references-updated", imports);
+        }
+        Ok(_) => {}
+        Err(e) => log::warn!("[references] Refresh failed: {}", e),
+    });
+}
+
+fn refresh_references(app: &AppHandle) -> Result<Vec<ReferenceImport>, String> {
+    let conn = open_library(app)?;
+    let state = app.state::<AppState>();
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    references::refresh(&conn, session.as_ref())
+}
+
+/// List the imported reference files
+#[tauri::command]
+pub fn list_reference_files(app: AppHandle) -> Result<Vec<ReferenceFile>, String> {
+    let conn = open_library(&app)?;
+    references::list_files(&conn).map_err(|e| format!("Failed to list reference files: {}", e))
+}
+
+/// Forget a reference file; linked documents keep their bibliographic records
+#[tauri::command]
+pub fn remove_reference_file(id: i64, app: AppHandle) -> Result<bool, String> {
+    let conn = open_library(&app)?;
+    references::remove_file(&conn, id)
+        .map_err(|e| format!("Failed to remove reference file: {}", e))
+}
+
+/// Search reference entries by title or citation key
+#[tauri::command]
+pub fn search_references(query: String, app: AppHandle) -> Result<Vec<ReferenceEntry>, String> {
+    let conn = open_library(&app)?;
+    references::search_entries(&conn, &query)
+}
+
+/// Get the reference entry a library document is linked to
+#[tauri::command]
+pub fn get_reference_link(
+    document_id: i64,
+    app: AppHandle,
+) -> Result<Option<ReferenceLink>, String> {
+    let conn = open_library(&app)?;
+    references::get_link(&conn, document_id).map_err(|e| format!("Failed to read link: {}", e))
+}
+
+/// Link a library document to a reference entry chosen by the user and
+/// fill in its bibliographic record from it
+#[tauri::command]
+pub fn link_reference(
+    document_id: i64,
+    file_id: i64,
+    key: String,
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<ReferenceLink, String> {
+    let conn = open_library(&app)?;
+    let link = references::link_document(&conn, document_id, file_id, &key)?;
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    references::propagate(&conn, session.as_ref())?;
+    Ok(link)
+}
+
+/// Unlink a library document from its reference entry; it won't be
+/// matched to an entry again until linked by hand
+#[tauri::command]
+pub fn unlink_reference(document_id: i64, app: AppHandle) -> Result<bool, String> {
+    let conn = open_library(&app)?;
+    references::unlink_document(&conn, document_id)
+        .map_err(|e| format!("Failed to unlink document: {}", e))
+}
+
+/// Export library documents, and the documents in the given collections and
+/// their subcollections, to a BibTeX (`bibtex`) or CSL-JSON (`csl_json`) file.
+/// Returns how many documents were written.
+#[tauri::command]
+pub async fn export_bibliography(
+    path: String,
+    document_ids: Vec<i64>,
+    collection_ids: Vec<i64>,
+    format: String,
+    app: AppHandle,
+) -> Result<u32, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let conn = open_library(&app)?;
+        let state = app.state::<AppState>();
+        let session = state.session.lock().map_err(|e| e.to_string())?;
+        let (text, count) = citation::export(
+            &conn,
+            &document_ids,
+            &collection_ids,
+            &format,
+            session.as_ref(),
+        )?;
+        std::fs::write(&path, text).map_err(|e| format!("Failed to write bibliography: {}", e))?;
+        Ok(count)
+    })
+    .await
+    .map_err(|e| format!("Bibliography export task failed: {}", e))?
+}
+
+/// Change a library document's citation key; it must be unique in the library
+#[tauri::command]
+pub fn set_citation_key(
+    id: i64,
+    key: String,
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<LibraryEntry, String> {
+    let conn = open_library(&app)?;
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    citation::set_key(&conn, id, &key, session.as_ref())
+}
+
+/// Format the current document's citation for copying, in `apa`, `mla`,
+/// `chicago`, `bibtex` or `csl_json` style
+#[tauri::command]
+pub fn format_citation(style: String, state: State<AppState>) -> Result<String, String> {
+    let session = state.session.lock().map_err(|e| e.to_string())?;
+    let session = session.as_ref().ok_or("No file is open")?;
+    let record = bibliography::get_record(&session.db)?;
+    let key = citation::stored_key(&session.db)?.unwrap_or_else(|| citation::base_key(&record));
+    citation::format_citation(&key, &record, &style)
+}
+
+/// A document snapshot in the sync folder
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncedDocument {
+    pub pdf_sha256: String,
+    pub path: String,
+}
+
 /// Response for open_file
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DocumentInfo {
     pub pdf_path: String,
+    /// Names the PDF in `rr://` URLs, see `pdf_protocol`
+    pub pdf_sha256: String,
     pub rr_path: String,
     pub title: Option<String>,
     pub page_count: Option<u32>,
     pub last_page: Option<u32>,
+    /// Other library documents holding the same paper
+    pub duplicates: Vec<DuplicateMatch>,
 }
 
 use serde::{Deserialize, Serialize};
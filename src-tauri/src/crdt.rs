@@ -0,0 +1,739 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use automerge::transaction::{CommitOptions, Transactable};
+use automerge::{
+    ActorId, AutoCommit, ChangeHash, ObjId, ObjType, PatchAction, Prop, ReadDoc, ScalarValue, Value,
+};
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
+
+use crate::database;
+use crate::models::*;
+
+/// Name of the annotations document in the crdt_state table.
+const ANNOTATIONS_DOC: &str = "annotations";
+
+/// This installation's sync device id, set at startup. Until then a random
+/// one stands in, which only keeps actors stable for the life of the process.
+static DEVICE_ID: OnceLock<String> = OnceLock::new();
+
+/// Ids of the annotations (added, updated, deleted) by a materialise.
+pub type Materialised = (Vec<String>, Vec<String>, Vec<String>);
+
+/// Encoded changes and the document heads they lead to.
+pub type Changes = (Vec<u8>, Vec<ChangeHash>);
+
+/// What a merge changed in the table, and the ids of annotations both copies
+/// changed (or deleted) since they diverged.
+pub type Merged = (Materialised, Vec<String>);
+
+/// Annotations held in an Automerge document.
+///
+/// Each annotation is a map at the document root keyed by its id. Note text is
+/// a text object so concurrent edits to the same note interleave instead of one
+/// overwriting the other; every other field is a register. The `annotations`
+/// table is a materialised view of this document: local edits are written to
+/// the table and recorded here, while merges go through the document and are
+/// then materialised back into the table.
+pub struct AnnotationDoc {
+    doc: AutoCommit,
+}
+
+impl AnnotationDoc {
+    /// Load the saved document, or an empty one if the file predates it.
+    /// Edits are made under the actor claimed for the session, if any.
+    pub fn load(conn: &Connection) -> Result<Self, String> {
+        let mut doc = match database::get_crdt_document(conn, ANNOTATIONS_DOC)
+            .map_err(|e| format!("Failed to read annotation history: {}", e))?
+        {
+            Some(data) => AutoCommit::load(&data)
+                .map_err(|e| format!("Failed to load annotation history: {}", e))?,
+            None => AutoCommit::new(),
+        };
+        if let Some(actor) = database::get_session_actor(conn)
+            .map_err(|e| format!("Failed to read annotation actor: {}", e))?
+        {
+            doc.set_actor(ActorId::from(actor));
+        }
+        Ok(Self { doc })
+    }
+
+    /// Load the saved document if there is one, without creating it.
+    pub fn load_existing(conn: &Connection) -> Result<Option<Self>, String> {
+        match database::get_crdt_document(conn, ANNOTATIONS_DOC)
+            .map_err(|e| format!("Failed to read annotation history: {}", e))?
+        {
+            Some(_) => Self::load(conn).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(&mut self, conn: &Connection) -> Result<(), String> {
+        database::set_crdt_document(conn, ANNOTATIONS_DOC, &self.doc.save())
+            .map_err(|e| format!("Failed to save annotation history: {}", e))
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.doc.get_heads().is_empty()
+    }
+
+    /// All annotations in the document, sorted like `get_annotations`.
+    pub fn annotations(&self) -> Vec<Annotation> {
+        let mut annotations: Vec<Annotation> = self
+            .doc
+            .keys(automerge::ROOT)
+            .filter_map(|id| self.annotation(&id))
+            .collect();
+        annotations.sort_by(|a, b| {
+            a.page_number
+                .cmp(&b.page_number)
+                .then_with(|| a.created_at.cmp(&b.created_at))
+        });
+        annotations
+    }
+
+    pub fn annotation(&self, id: &str) -> Option<Annotation> {
+        let obj = self.object(id)?;
+        let text = |field: &str| self.scalar_str(&obj, field);
+
+        Some(Annotation {
+            id: id.to_string(),
+            annotation_type: text("type")?.parse().ok()?,
+            page_number: self
+                .doc
+                .get(&obj, "page_number")
+                .ok()
+                .flatten()
+                .and_then(|(v, _)| v.to_i64())? as u32,
+            page_label: None,
+            color: text("color"),
+            content: match self.doc.get(&obj, "content").ok().flatten() {
+                Some((Value::Object(ObjType::Text), content)) => self.doc.text(&content).ok(),
+                _ => None,
+            },
+            position_data: text("position_data").and_then(|s| serde_json::from_str(&s).ok()),
+            created_at: text("created_at")?,
+            updated_at: text("updated_at")?,
+        })
+    }
+
+    /// Record a local edit to an annotation. Only fields that differ from the
+    /// document are written, so an unchanged field never overrides a concurrent
+    /// edit from another copy, and note text is changed by splicing.
+    pub fn put(&mut self, annotation: &Annotation) -> Result<(), String> {
+        self.put_with(annotation, true)
+    }
+
+    /// Like `put`, but note text that differs is replaced as a whole. Used for
+    /// changes that didn't originate here (imports, older versions, row-level
+    /// sync), which several copies may record independently: splicing the same
+    /// edit twice would duplicate the inserted text.
+    fn put_with(&mut self, annotation: &Annotation, splice: bool) -> Result<(), String> {
+        let am = |e: automerge::AutomergeError| format!("Failed to record annotation: {}", e);
+        let obj = match self.object(&annotation.id) {
+            Some(obj) => obj,
+            None => self
+                .doc
+                .put_object(automerge::ROOT, annotation.id.as_str(), ObjType::Map)
+                .map_err(am)?,
+        };
+
+        let position_data = annotation
+            .position_data
+            .as_ref()
+            .map(|pd| serde_json::to_string(pd).unwrap_or_default());
+        let registers: [(&str, ScalarValue); 6] = [
+            ("type", annotation.annotation_type.as_str().into()),
+            ("page_number", (annotation.page_number as i64).into()),
+            ("color", optional(annotation.color.as_deref())),
+            ("position_data", optional(position_data.as_deref())),
+            ("created_at", annotation.created_at.as_str().into()),
+            ("updated_at", annotation.updated_at.as_str().into()),
+        ];
+        for (field, value) in registers {
+            let current = self.doc.get(&obj, field).map_err(am)?;
+            if !matches!(&current, Some((Value::Scalar(v), _)) if **v == value) {
+                self.doc.put(&obj, field, value).map_err(am)?;
+            }
+        }
+
+        let current = self.doc.get(&obj, "content").map_err(am)?;
+        let current_text = match &current {
+            Some((Value::Object(ObjType::Text), text)) => {
+                Some((self.doc.text(text).map_err(am)?, text.clone()))
+            }
+            _ => None,
+        };
+        match (&annotation.content, current_text) {
+            (Some(content), Some((existing, _))) if existing == *content => {}
+            (Some(content), Some((_, text))) if splice => {
+                self.doc.update_text(&text, content).map_err(am)?;
+            }
+            (Some(content), _) => {
+                let text = self
+                    .doc
+                    .put_object(&obj, "content", ObjType::Text)
+                    .map_err(am)?;
+                self.doc.splice_text(&text, 0, 0, content).map_err(am)?;
+            }
+            (None, _) if matches!(&current, Some((Value::Scalar(v), _)) if v.is_null()) => {}
+            (None, _) => self
+                .doc
+                .put(&obj, "content", ScalarValue::Null)
+                .map_err(am)?,
+        }
+        Ok(())
+    }
+
+    /// Remove an annotation. Returns true if it was present.
+    pub fn remove(&mut self, id: &str) -> Result<bool, String> {
+        if self.object(id).is_none() {
+            return Ok(false);
+        }
+        self.doc
+            .delete(automerge::ROOT, id)
+            .map_err(|e| format!("Failed to record deletion: {}", e))?;
+        Ok(true)
+    }
+
+    /// Merge all changes from another copy of the document.
+    pub fn merge(&mut self, other: &mut AnnotationDoc) -> Result<(), String> {
+        self.doc
+            .merge(&mut other.doc)
+            .map_err(|e| format!("Failed to merge annotation history: {}", e))?;
+        Ok(())
+    }
+
+    /// Apply changes produced by `changes_since` on another copy.
+    /// Returns the change hashes that became heads as a result.
+    pub fn apply_changes(&mut self, data: &[u8]) -> Result<Vec<ChangeHash>, String> {
+        let before: HashSet<ChangeHash> = self.doc.get_heads().into_iter().collect();
+        self.doc
+            .load_incremental(data)
+            .map_err(|e| format!("Failed to apply annotation changes: {}", e))?;
+        Ok(self
+            .doc
+            .get_heads()
+            .into_iter()
+            .filter(|h| !before.contains(h))
+            .collect())
+    }
+
+    /// Encoded changes that aren't ancestors of `heads`.
+    pub fn changes_since(&mut self, heads: &[ChangeHash]) -> Vec<u8> {
+        self.doc.save_after(heads)
+    }
+
+    pub fn heads(&mut self) -> Vec<ChangeHash> {
+        self.doc.get_heads()
+    }
+
+    /// Heads of the history this document shares with `other`, i.e. the state
+    /// both copies last had in common. Empty for unrelated documents.
+    fn common_heads(&mut self, other: &mut AnnotationDoc) -> Vec<ChangeHash> {
+        let theirs: HashSet<ChangeHash> = other
+            .doc
+            .get_changes(&[])
+            .iter()
+            .map(|c| c.hash())
+            .collect();
+        let common: Vec<(ChangeHash, Vec<ChangeHash>)> = self
+            .doc
+            .get_changes(&[])
+            .into_iter()
+            .filter(|c| theirs.contains(&c.hash()))
+            .map(|c| (c.hash(), c.deps().to_vec()))
+            .collect();
+        let ancestors: HashSet<&ChangeHash> = common.iter().flat_map(|(_, deps)| deps).collect();
+        common
+            .iter()
+            .map(|(hash, _)| *hash)
+            .filter(|hash| !ancestors.contains(hash))
+            .collect()
+    }
+
+    /// Ids of the annotations that differ between the states at `before` and
+    /// `after`. Both must be part of this document's history.
+    fn changed_between(&mut self, before: &[ChangeHash], after: &[ChangeHash]) -> HashSet<String> {
+        self.doc
+            .diff(before, after)
+            .into_iter()
+            .filter_map(|patch| match patch.path.first() {
+                // A field inside an annotation's map
+                Some((_, Prop::Map(id))) => Some(id.clone()),
+                Some(_) => None,
+                // An annotation added, replaced or removed at the root
+                None => match patch.action {
+                    PatchAction::PutMap { key, .. } | PatchAction::DeleteMap { key } => Some(key),
+                    PatchAction::Conflict {
+                        prop: Prop::Map(key),
+                    } => Some(key),
+                    _ => None,
+                },
+            })
+            .collect()
+    }
+
+    /// Bring the document in line with the annotations table. Returns true if
+    /// anything was recorded.
+    ///
+    /// An empty document is seeded with a fixed actor and timestamp derived from
+    /// the table contents, so copies of a file made before it had a document
+    /// produce identical seed changes and merge without conflicts.
+    pub fn capture(&mut self, conn: &Connection) -> Result<bool, String> {
+        let mut rows = database::get_annotations(conn, None)
+            .map_err(|e| format!("Failed to get annotations: {}", e))?;
+        rows.sort_by(|a, b| a.id.cmp(&b.id));
+        let seeding = self.is_empty();
+        if seeding && rows.is_empty() {
+            return Ok(false);
+        }
+
+        let before = self.heads();
+        let local_actor = self.doc.get_actor().clone();
+        if seeding {
+            let seed = serde_json::to_vec(&rows).unwrap_or_default();
+            self.doc
+                .set_actor(ActorId::from(&Sha256::digest(&seed)[..16]));
+        }
+
+        let table_ids: HashSet<&str> = rows.iter().map(|a| a.id.as_str()).collect();
+        for annotation in &rows {
+            if self.annotation(&annotation.id).as_ref() != Some(annotation) {
+                self.put_with(annotation, false)?;
+            }
+        }
+        let stale: Vec<String> = self
+            .doc
+            .keys(automerge::ROOT)
+            .filter(|id| !table_ids.contains(id.as_str()))
+            .collect();
+        for id in stale {
+            self.remove(&id)?;
+        }
+
+        if seeding {
+            self.doc.commit_with(CommitOptions::default().with_time(0));
+            self.doc.set_actor(local_actor);
+        }
+        Ok(self.heads() != before)
+    }
+
+    fn object(&self, id: &str) -> Option<ObjId> {
+        match self.doc.get(automerge::ROOT, id).ok().flatten() {
+            Some((Value::Object(ObjType::Map), obj)) => Some(obj),
+            _ => None,
+        }
+    }
+
+    fn scalar_str(&self, obj: &ObjId, field: &str) -> Option<String> {
+        self.doc
+            .get(obj, field)
+            .ok()
+            .flatten()
+            .and_then(|(v, _)| v.to_str().map(str::to_string))
+    }
+}
+
+fn optional(value: Option<&str>) -> ScalarValue {
+    value.map_or(ScalarValue::Null, ScalarValue::from)
+}
+
+/// Record the table's current state of one annotation after a local edit.
+/// Only the new change is appended to the stored document; `compact` folds
+/// such changes in when the file is saved.
+pub fn record(conn: &Connection, id: &str) -> Result<(), String> {
+    let mut doc = AnnotationDoc::load(conn)?;
+    if doc.is_empty() {
+        // First edit since the file gained a document: seed it from the table
+        doc.capture(conn)?;
+        return doc.save(conn);
+    }
+
+    let before = doc.heads();
+    match database::get_annotation(conn, id)
+        .map_err(|e| format!("Failed to get annotation: {}", e))?
+    {
+        Some(annotation) => doc.put(&annotation)?,
+        None => {
+            doc.remove(id)?;
+        }
+    }
+    let changes = doc.changes_since(&before);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    database::append_crdt_changes(conn, ANNOTATIONS_DOC, &changes)
+        .map_err(|e| format!("Failed to save annotation history: {}", e))
+}
+
+/// Rewrite the stored document in its compact form if changes were appended
+/// to it since it was last saved whole.
+pub fn compact(conn: &Connection) -> Result<(), String> {
+    let Some(stored) = database::get_crdt_document(conn, ANNOTATIONS_DOC)
+        .map_err(|e| format!("Failed to read annotation history: {}", e))?
+    else {
+        return Ok(());
+    };
+    let mut doc = AnnotationDoc::load(conn)?;
+    if doc.doc.save() != stored {
+        doc.save(conn)?;
+    }
+    Ok(())
+}
+
+/// Set this installation's device id, which `claim_actor` ties actors to.
+pub fn set_device_id(device_id: &str) {
+    let _ = DEVICE_ID.set(device_id.to_string());
+}
+
+/// Choose the actor local edits through `conn` are recorded under: one per
+/// device and location of the .rr file, stored in the file so it is reused
+/// across sessions. A copy of the file opened from another path or on another
+/// device gets its own, since two writers sharing an actor would produce
+/// clashing histories.
+pub fn claim_actor(conn: &Connection, rr_path: &Path) -> Result<(), String> {
+    let device_id = DEVICE_ID.get_or_init(|| uuid::Uuid::new_v4().to_string());
+    let location = std::fs::canonicalize(rr_path).unwrap_or_else(|_| rr_path.to_path_buf());
+    let mut hasher = Sha256::new();
+    hasher.update(device_id.as_bytes());
+    hasher.update([0]);
+    hasher.update(location.to_string_lossy().as_bytes());
+    let owner = hex::encode(hasher.finalize());
+
+    let actor = match database::get_crdt_actor(conn, &owner)
+        .map_err(|e| format!("Failed to read annotation actor: {}", e))?
+    {
+        Some(actor) => actor,
+        None => {
+            let actor = ActorId::random().to_bytes().to_vec();
+            database::set_crdt_actor(conn, &owner, &actor)
+                .map_err(|e| format!("Failed to store annotation actor: {}", e))?;
+            actor
+        }
+    };
+    database::set_session_actor(conn, &actor)
+        .map_err(|e| format!("Failed to set annotation actor: {}", e))
+}
+
+/// Record every difference between the table and the document, e.g. after a
+/// bulk import or when the file was last edited by a version without CRDT
+/// support.
+pub fn capture(conn: &Connection) -> Result<(), String> {
+    let mut doc = AnnotationDoc::load(conn)?;
+    if doc.capture(conn)? {
+        doc.save(conn)?;
+    }
+    Ok(())
+}
+
+/// Rewrite the annotations table to match the document. Deleted annotations
+/// get tombstones so row-level merges with older files still see them.
+pub fn materialise(conn: &Connection, doc: &AnnotationDoc) -> Result<Materialised, String> {
+    let mut table: HashMap<String, Annotation> = database::get_annotations(conn, None)
+        .map_err(|e| format!("Failed to get annotations: {}", e))?
+        .into_iter()
+        .map(|a| (a.id.clone(), a))
+        .collect();
+
+    let (mut added, mut updated) = (Vec::new(), Vec::new());
+    for annotation in doc.annotations() {
+        match table.remove(&annotation.id) {
+            Some(row) if row == annotation => continue,
+            Some(_) => updated.push(annotation.id.clone()),
+            None => added.push(annotation.id.clone()),
+        }
+        database::upsert_annotation(conn, &annotation)
+            .and_then(|_| database::clear_tombstone(conn, "annotations", &annotation.id))
+            .map_err(|e| format!("Failed to update annotation: {}", e))?;
+    }
+
+    let mut deleted: Vec<String> = table.into_keys().collect();
+    deleted.sort();
+    for id in &deleted {
+        database::delete_annotation(conn, id)
+            .map_err(|e| format!("Failed to delete annotation: {}", e))?;
+    }
+    Ok((added, updated, deleted))
+}
+
+/// Encoded changes a peer at `heads` is missing, with our current heads.
+pub fn changes_since(conn: &Connection, heads: &[ChangeHash]) -> Result<Changes, String> {
+    let mut doc = AnnotationDoc::load(conn)?;
+    Ok((doc.changes_since(heads), doc.heads()))
+}
+
+/// Apply changes received from a live peer and materialise them.
+/// Returns the heads the changes added and what changed in the table.
+pub fn apply_remote(
+    conn: &Connection,
+    data: &[u8],
+) -> Result<(Vec<ChangeHash>, Materialised), String> {
+    let mut doc = AnnotationDoc::load(conn)?;
+    let heads = doc.apply_changes(data)?;
+    doc.save(conn)?;
+    Ok((heads, materialise(conn, &doc)?))
+}
+
+/// Merge another copy's annotation document into the session database and
+/// materialise the result. Returns None, changing nothing, when the other copy
+/// has no document; callers then fall back to a row-level merge.
+///
+/// Besides what changed in the table, returns the annotations edited on both
+/// sides since the copies diverged. Their merged state combines the two edits
+/// field by field, which callers may want to resolve differently.
+pub fn merge_from_db(conn: &Connection, other: &Connection) -> Result<Option<Merged>, String> {
+    let Some(mut theirs) = AnnotationDoc::load_existing(other)? else {
+        return Ok(None);
+    };
+    // Rows edited in their table by a version without CRDT support
+    theirs.capture(other)?;
+
+    let mut ours = AnnotationDoc::load(conn)?;
+    ours.capture(conn)?;
+    let base = ours.common_heads(&mut theirs);
+    let (our_heads, their_heads) = (ours.heads(), theirs.heads());
+    ours.merge(&mut theirs)?;
+
+    let ours_changed = ours.changed_between(&base, &our_heads);
+    let theirs_changed = ours.changed_between(&base, &their_heads);
+    let mut concurrent: Vec<String> = ours_changed
+        .intersection(&theirs_changed)
+        .cloned()
+        .collect();
+    concurrent.sort();
+
+    ours.save(conn)?;
+    Ok(Some((materialise(conn, &ours)?, concurrent)))
+}
+
+fn encode_heads(heads: &[ChangeHash]) -> String {
+    heads
+        .iter()
+        .map(|h| h.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn decode_heads(text: &str) -> Vec<ChangeHash> {
+    text.split(',').filter_map(|h| h.parse().ok()).collect()
+}
+
+fn synced_heads(conn: &Connection) -> Result<Vec<ChangeHash>, String> {
+    database::get_crdt_synced_heads(conn, ANNOTATIONS_DOC)
+        .map(|text| decode_heads(&text))
+        .map_err(|e| format!("Failed to read sync state: {}", e))
+}
+
+fn set_synced_heads(conn: &Connection, heads: &[ChangeHash]) -> Result<(), String> {
+    database::set_crdt_synced_heads(conn, ANNOTATIONS_DOC, &encode_heads(heads))
+        .map_err(|e| format!("Failed to update sync state: {}", e))
+}
+
+/// Changes not yet published to peers, with the heads they bring peers up to.
+/// Pass the heads to `mark_published` once the changes are written.
+pub fn unpublished_changes(conn: &Connection) -> Result<Option<Changes>, String> {
+    let mut doc = AnnotationDoc::load(conn)?;
+    let synced = synced_heads(conn)?;
+    let heads = doc.heads();
+    if heads.iter().all(|h| synced.contains(h)) {
+        return Ok(None);
+    }
+    Ok(Some((doc.changes_since(&synced), heads)))
+}
+
+pub fn mark_published(conn: &Connection, heads: &[ChangeHash]) -> Result<(), String> {
+    set_synced_heads(conn, heads)
+}
+
+/// Record changes received from a peer as already known to peers, so they
+/// aren't published again.
+pub fn mark_received(conn: &Connection, heads: &[ChangeHash]) -> Result<(), String> {
+    let mut synced = synced_heads(conn)?;
+    for head in heads {
+        if !synced.contains(head) {
+            synced.push(*head);
+        }
+    }
+    set_synced_heads(conn, &synced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{AnnotationType, CreateAnnotationInput, UpdateAnnotationInput};
+    use crate::rr_file::{self, RrSession};
+
+    fn note(session: &RrSession) -> String {
+        let annotation = database::create_annotation(
+            &session.db,
+            &CreateAnnotationInput {
+                annotation_type: AnnotationType::Note,
+                page_number: 1,
+                color: None,
+                content: Some("first".to_string()),
+                position_data: None,
+            },
+        )
+        .unwrap();
+        capture(&session.db).unwrap();
+        annotation.id
+    }
+
+    fn edit(session: &RrSession, id: &str, content: &str) {
+        let input = UpdateAnnotationInput {
+            id: id.to_string(),
+            color: None,
+            content: Some(content.to_string()),
+            position_data: None,
+        };
+        assert!(database::update_annotation(&session.db, &input).unwrap());
+        record(&session.db, id).unwrap();
+    }
+
+    fn recolor(session: &RrSession, id: &str, color: &str) {
+        let input = UpdateAnnotationInput {
+            id: id.to_string(),
+            color: Some(color.to_string()),
+            content: None,
+            position_data: None,
+        };
+        assert!(database::update_annotation(&session.db, &input).unwrap());
+        record(&session.db, id).unwrap();
+    }
+
+    fn delete(session: &RrSession, id: &str) {
+        assert!(database::delete_annotation(&session.db, id).unwrap());
+        record(&session.db, id).unwrap();
+    }
+
+    /// Actors of the changes after the seed change, oldest first.
+    fn edit_actors(session: &RrSession) -> Vec<ActorId> {
+        let mut doc = AnnotationDoc::load(&session.db).unwrap();
+        let actors: Vec<ActorId> = doc
+            .doc
+            .get_changes(&[])
+            .into_iter()
+            .map(|c| c.actor_id().clone())
+            .collect();
+        let seed = actors[0].clone();
+        actors.into_iter().filter(|actor| actor != &seed).collect()
+    }
+
+    #[test]
+    fn edits_keep_one_actor_per_file_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let a_path = dir.path().join("a.rr");
+        let a = rr_file::import_pdf(&pdf, Some(&a_path)).unwrap();
+        let id = note(&a);
+        edit(&a, &id, "second");
+        edit(&a, &id, "third");
+        rr_file::save_rr(&a).unwrap();
+        drop(a);
+
+        let a = rr_file::open_rr(&a_path).unwrap();
+        edit(&a, &id, "fourth");
+        let actors = edit_actors(&a);
+        assert_eq!(actors.len(), 3);
+        assert!(actors.iter().all(|actor| actor == &actors[0]));
+        rr_file::save_rr(&a).unwrap();
+
+        let b_path = dir.path().join("b.rr");
+        std::fs::copy(&a_path, &b_path).unwrap();
+        let b = rr_file::open_rr(&b_path).unwrap();
+        edit(&b, &id, "fifth");
+        let b_actors = edit_actors(&b);
+        assert_eq!(b_actors.len(), 4);
+        assert_ne!(b_actors[3], actors[0]);
+    }
+
+    #[test]
+    fn concurrent_edits_converge_whichever_side_merges_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let a_path = dir.path().join("a.rr");
+        let a = rr_file::import_pdf(&pdf, Some(&a_path)).unwrap();
+        let both = note(&a);
+        let deleted = note(&a);
+        let untouched = note(&a);
+        rr_file::save_rr(&a).unwrap();
+        let b_path = dir.path().join("b.rr");
+        std::fs::copy(&a_path, &b_path).unwrap();
+        let b = rr_file::open_rr(&b_path).unwrap();
+
+        // Both edit the same annotation, one its text and one its colour,
+        // then both its text; one deletes what the other edits
+        edit(&a, &both, "ours");
+        recolor(&b, &both, "#ff0000");
+        edit(&b, &both, "theirs");
+        delete(&a, &deleted);
+        edit(&b, &deleted, "edited");
+        rr_file::save_rr(&a).unwrap();
+        rr_file::save_rr(&b).unwrap();
+
+        // Merge each into the other, from copies of the diverged files
+        let a_copy = rr_file::open_rr_readonly(&a_path).unwrap();
+        let b_copy = rr_file::open_rr_readonly(&b_path).unwrap();
+        let (_, a_concurrent) = merge_from_db(&a.db, &b_copy.db).unwrap().unwrap();
+        let (_, b_concurrent) = merge_from_db(&b.db, &a_copy.db).unwrap().unwrap();
+        assert_eq!(a_concurrent, b_concurrent);
+        let mut expected = vec![both.clone(), deleted.clone()];
+        expected.sort();
+        assert_eq!(a_concurrent, expected);
+
+        let rows = |session: &RrSession| database::get_annotations(&session.db, None).unwrap();
+        assert_eq!(rows(&a), rows(&b));
+        assert!(rows(&a).iter().any(|row| row.id == untouched));
+        let merged = rows(&a).into_iter().find(|row| row.id == both).unwrap();
+        // Different fields are both kept; the two text edits are combined the
+        // same way on both sides, for callers to resolve
+        assert_eq!(merged.color.as_deref(), Some("#ff0000"));
+        assert_ne!(merged.content.as_deref(), Some("first"));
+
+        // Merging again changes nothing
+        let (changes, concurrent) = merge_from_db(&a.db, &b_copy.db).unwrap().unwrap();
+        assert_eq!(changes, Default::default());
+        assert!(concurrent.is_empty());
+        assert_eq!(rows(&a), rows(&b));
+    }
+
+    #[test]
+    fn appended_edits_load_and_are_compacted_on_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let session = rr_file::import_pdf(&pdf, Some(&dir.path().join("a.rr"))).unwrap();
+        let id = note(&session);
+        let compacted = database::get_crdt_document(&session.db, ANNOTATIONS_DOC)
+            .unwrap()
+            .unwrap();
+        edit(&session, &id, "second");
+        edit(&session, &id, "third");
+
+        let stored = database::get_crdt_document(&session.db, ANNOTATIONS_DOC)
+            .unwrap()
+            .unwrap();
+        assert!(stored.starts_with(&compacted));
+        let doc = AnnotationDoc::load(&session.db).unwrap();
+        assert_eq!(
+            doc.annotation(&id).unwrap().content.as_deref(),
+            Some("third")
+        );
+
+        rr_file::save_rr(&session).unwrap();
+        let mut doc = AnnotationDoc::load(&session.db).unwrap();
+        let stored = database::get_crdt_document(&session.db, ANNOTATIONS_DOC)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored, doc.doc.save());
+        assert_eq!(
+            doc.annotation(&id).unwrap().content.as_deref(),
+            Some("third")
+        );
+    }
+}
@@ -0,0 +1,31 @@
+/// Service name for secrets (e.g. sync passwords) kept in the OS credential
+/// store rather than in the frontend's localStorage or our config files.
+const SERVICE: &str = "com.vellum.app";
+
+/// Store a secret for `account`, replacing any previous one.
+pub fn set_secret(account: &str, secret: &str) -> Result<(), String> {
+    keyring::Entry::new(SERVICE, account)
+        .and_then(|entry| entry.set_password(secret))
+        .map_err(|e| format!("Failed to store credentials: {}", e))
+}
+
+/// Get the secret for `account`, if one is stored.
+pub fn get_secret(account: &str) -> Result<Option<String>, String> {
+    let entry = keyring::Entry::new(SERVICE, account)
+        .map_err(|e| format!("Failed to access credentials: {}", e))?;
+    match entry.get_password() {
+        Ok(secret) => Ok(Some(secret)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(format!("Failed to read credentials: {}", e)),
+    }
+}
+
+/// Remove the secret for `account`. Missing entries are not an error.
+pub fn delete_secret(account: &str) -> Result<(), String> {
+    let entry = keyring::Entry::new(SERVICE, account)
+        .map_err(|e| format!("Failed to access credentials: {}", e))?;
+    match entry.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(e) => Err(format!("Failed to delete credentials: {}", e)),
+    }
+}
@@ -25,6 +25,100 @@ pub fn init_db(conn: &Connection) -> rusqlite::Result<()> {
             ON annotations(page_number);
         CREATE INDEX IF NOT EXISTS idx_annotations_type
             ON annotations(type);
+
+        CREATE TABLE IF NOT EXISTS conversations (
+            id TEXT PRIMARY KEY,
+            role TEXT NOT NULL CHECK(role IN ('user', 'assistant')),
+            content TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+
+        -- Records deleted rows so merges can tell a deletion from a missing row
+        CREATE TABLE IF NOT EXISTS tombstones (
+            table_name TEXT NOT NULL,
+            id TEXT NOT NULL,
+            deleted_at TEXT NOT NULL,
+            PRIMARY KEY (table_name, id)
+        );
+
+        -- Local change feed for sync; filled by the triggers below
+        CREATE TABLE IF NOT EXISTS change_log (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            table_name TEXT NOT NULL,
+            row_id TEXT NOT NULL,
+            op TEXT NOT NULL CHECK(op IN ('upsert', 'delete')),
+            changed_at TEXT NOT NULL
+        );
+
+        CREATE TRIGGER IF NOT EXISTS annotations_log_insert AFTER INSERT ON annotations
+        BEGIN
+            INSERT INTO change_log (table_name, row_id, op, changed_at)
+            VALUES ('annotations', NEW.id, 'upsert', NEW.updated_at);
+        END;
+        CREATE TRIGGER IF NOT EXISTS annotations_log_update AFTER UPDATE ON annotations
+        BEGIN
+            INSERT INTO change_log (table_name, row_id, op, changed_at)
+            VALUES ('annotations', NEW.id, 'upsert', NEW.updated_at);
+        END;
+        CREATE TRIGGER IF NOT EXISTS annotations_log_delete AFTER DELETE ON annotations
+        BEGIN
+            INSERT INTO change_log (table_name, row_id, op, changed_at)
+            VALUES ('annotations', OLD.id, 'delete', strftime('%Y-%m-%dT%H:%M:%fZ', 'now'));
+        END;
+
+        -- Sync progress per journal: for peers, the number of journal lines
+        -- applied; for the local journal ('local'), the last change_log seq written
+        CREATE TABLE IF NOT EXISTS sync_state (
+            peer TEXT PRIMARY KEY,
+            position INTEGER NOT NULL
+        );
+
+        -- Saved CRDT documents; `synced_heads` are the change hashes already
+        -- published to the sync journal (hex, comma-separated)
+        CREATE TABLE IF NOT EXISTS crdt_state (
+            name TEXT PRIMARY KEY,
+            data BLOB NOT NULL,
+            synced_heads TEXT NOT NULL DEFAULT ''
+        );
+
+        -- Actor local CRDT edits are recorded under, per device and file
+        -- location (hashed together as owner)
+        CREATE TABLE IF NOT EXISTS crdt_actors (
+            owner TEXT PRIMARY KEY,
+            actor BLOB NOT NULL
+        );
+
+        -- Text of each page as extracted by the renderer, used for search
+        CREATE TABLE IF NOT EXISTS page_text (
+            page_number INTEGER PRIMARY KEY,
+            text TEXT NOT NULL
+        );
+
+        -- Table of contents, from the PDF's /Outlines or detected headings
+        CREATE TABLE IF NOT EXISTS outline (
+            position INTEGER PRIMARY KEY,
+            level INTEGER NOT NULL,
+            title TEXT NOT NULL,
+            page_number INTEGER,
+            top REAL
+        );
+
+        -- Label of every page from the PDF's /PageLabels; empty without them
+        CREATE TABLE IF NOT EXISTS page_labels (
+            page_number INTEGER PRIMARY KEY,
+            label TEXT NOT NULL
+        );
+
+        -- The document's own reference list, parsed from page_text;
+        -- `record` is a BibliographicRecord as JSON
+        CREATE TABLE IF NOT EXISTS cited_references (
+            position INTEGER PRIMARY KEY,
+            label TEXT,
+            page_number INTEGER NOT NULL,
+            text TEXT NOT NULL,
+            record TEXT NOT NULL,
+            to_read INTEGER NOT NULL DEFAULT 0
+        );
         ",
     )?;
     Ok(())
@@ -41,6 +135,29 @@ pub fn get_metadata(conn: &Connection, key: &str) -> rusqlite::Result<Option<Str
     }
 }
 
+/// Get all metadata key-value pairs.
+pub fn get_all_metadata(conn: &Connection) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT key, value FROM metadata ORDER BY key")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Whether a metadata key describes document.pdf itself (its hash, page count
+/// or what was extracted from it) rather than the reader's own data. These
+/// only hold for that exact PDF.
+pub fn is_derived_metadata(key: &str) -> bool {
+    [
+        "pdf_sha256",
+        "page_count",
+        crate::fingerprint::METADATA_KEY,
+        crate::outline::METADATA_KEY,
+        crate::page_labels::METADATA_KEY,
+        crate::reference_list::METADATA_KEY,
+        crate::thumbnails::METADATA_KEY,
+    ]
+    .contains(&key)
+}
+
 /// Set a metadata key-value pair (upsert).
 pub fn set_metadata(conn: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
     conn.execute(
@@ -50,6 +167,19 @@ pub fn set_metadata(conn: &Connection, key: &str, value: &str) -> rusqlite::Resu
     Ok(())
 }
 
+/// Get a single annotation by id.
+pub fn get_annotation(conn: &Connection, id: &str) -> rusqlite::Result<Option<Annotation>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, type, page_number, color, content, position_data, created_at, updated_at
+         FROM annotations WHERE id = ?1",
+    )?;
+    let mut rows = stmt.query(params![id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(annotation_from_row(row)?)),
+        None => Ok(None),
+    }
+}
+
 /// Get all annotations, optionally filtered by page number.
 pub fn get_annotations(
     conn: &Connection,
@@ -80,26 +210,33 @@ pub fn get_annotations(
 
     let mut rows = rows;
     while let Some(row) = rows.next()? {
-        let type_str: String = row.get(1)?;
-        let position_data_str: Option<String> = row.get(5)?;
-
-        let annotation = Annotation {
-            id: row.get(0)?,
-            annotation_type: AnnotationType::from_str(&type_str)
-                .map_err(|e| rusqlite::Error::InvalidParameterName(e))?,
-            page_number: row.get(2)?,
-            color: row.get(3)?,
-            content: row.get(4)?,
-            position_data: position_data_str.and_then(|s| serde_json::from_str(&s).ok()),
-            created_at: row.get(6)?,
-            updated_at: row.get(7)?,
-        };
-        annotations.push(annotation);
+        annotations.push(annotation_from_row(row)?);
     }
 
     Ok(annotations)
 }
 
+/// Map a row of `SELECT id, type, page_number, color, content, position_data,
+/// created_at, updated_at` to an Annotation.
+fn annotation_from_row(row: &rusqlite::Row) -> rusqlite::Result<Annotation> {
+    let type_str: String = row.get(1)?;
+    let position_data_str: Option<String> = row.get(5)?;
+
+    Ok(Annotation {
+        id: row.get(0)?,
+        annotation_type: type_str
+            .parse::<AnnotationType>()
+            .map_err(rusqlite::Error::InvalidParameterName)?,
+        page_number: row.get(2)?,
+        page_label: None,
+        color: row.get(3)?,
+        content: row.get(4)?,
+        position_data: position_data_str.and_then(|s| serde_json::from_str(&s).ok()),
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
 /// Create a new annotation. Returns the created annotation.
 pub fn create_annotation(
     conn: &Connection,
@@ -131,6 +268,7 @@ pub fn create_annotation(
         id,
         annotation_type: input.annotation_type.clone(),
         page_number: input.page_number,
+        page_label: None,
         color: input.color.clone(),
         content: input.content.clone(),
         position_data: input.position_data.clone(),
@@ -139,6 +277,31 @@ pub fn create_annotation(
     })
 }
 
+/// Insert an annotation with its existing id and timestamps, replacing any
+/// annotation with the same id. Used when importing from other tools.
+pub fn upsert_annotation(conn: &Connection, annotation: &Annotation) -> rusqlite::Result<()> {
+    let position_data_json = annotation
+        .position_data
+        .as_ref()
+        .map(|pd| serde_json::to_string(pd).unwrap_or_default());
+
+    conn.execute(
+        "INSERT OR REPLACE INTO annotations (id, type, page_number, color, content, position_data, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            annotation.id,
+            annotation.annotation_type.as_str(),
+            annotation.page_number,
+            annotation.color,
+            annotation.content,
+            position_data_json,
+            annotation.created_at,
+            annotation.updated_at,
+        ],
+    )?;
+    Ok(())
+}
+
 /// Update an existing annotation's color, content, and/or position_data.
 pub fn update_annotation(
     conn: &Connection,
@@ -172,5 +335,323 @@ pub fn update_annotation(
 /// Delete an annotation by id. Returns true if it existed.
 pub fn delete_annotation(conn: &Connection, id: &str) -> rusqlite::Result<bool> {
     let rows_affected = conn.execute("DELETE FROM annotations WHERE id = ?1", params![id])?;
+    if rows_affected > 0 {
+        let now = chrono::Utc::now().to_rfc3339();
+        record_tombstone(conn, "annotations", id, &now)?;
+    }
     Ok(rows_affected > 0)
 }
+
+/// Number of annotations of each type present in the document.
+pub fn count_annotations_by_type(
+    conn: &Connection,
+) -> rusqlite::Result<Vec<(AnnotationType, u32)>> {
+    let mut stmt = conn.prepare("SELECT type, COUNT(*) FROM annotations GROUP BY type")?;
+    let rows = stmt.query_map([], |row| {
+        let type_str: String = row.get(0)?;
+        let annotation_type = type_str
+            .parse::<AnnotationType>()
+            .map_err(rusqlite::Error::InvalidParameterName)?;
+        Ok((annotation_type, row.get(1)?))
+    })?;
+    rows.collect()
+}
+
+/// Whether a table exists (older .rr files may predate some tables).
+pub fn table_exists(conn: &Connection, table: &str) -> rusqlite::Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        params![table],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Record that a row was deleted from `table`.
+pub fn record_tombstone(
+    conn: &Connection,
+    table: &str,
+    id: &str,
+    deleted_at: &str,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO tombstones (table_name, id, deleted_at) VALUES (?1, ?2, ?3)",
+        params![table, id, deleted_at],
+    )?;
+    Ok(())
+}
+
+/// Forget a tombstone, e.g. when a deleted row is restored by a merge.
+pub fn clear_tombstone(conn: &Connection, table: &str, id: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM tombstones WHERE table_name = ?1 AND id = ?2",
+        params![table, id],
+    )?;
+    Ok(())
+}
+
+/// Get the tombstones for a table as id -> deleted_at.
+pub fn get_tombstones(
+    conn: &Connection,
+    table: &str,
+) -> rusqlite::Result<std::collections::HashMap<String, String>> {
+    if !table_exists(conn, "tombstones")? {
+        return Ok(Default::default());
+    }
+    let mut stmt = conn.prepare("SELECT id, deleted_at FROM tombstones WHERE table_name = ?1")?;
+    let rows = stmt.query_map(params![table], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Get all conversation messages in chronological order.
+pub fn get_conversation_messages(conn: &Connection) -> rusqlite::Result<Vec<ConversationMessage>> {
+    if !table_exists(conn, "conversations")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare(
+        "SELECT id, role, content, created_at FROM conversations ORDER BY created_at ASC",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(ConversationMessage {
+            id: row.get(0)?,
+            role: row.get(1)?,
+            content: row.get(2)?,
+            created_at: row.get(3)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Insert a conversation message, replacing any message with the same id.
+pub fn upsert_conversation_message(
+    conn: &Connection,
+    message: &ConversationMessage,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO conversations (id, role, content, created_at)
+         VALUES (?1, ?2, ?3, ?4)",
+        params![
+            message.id,
+            message.role,
+            message.content,
+            message.created_at
+        ],
+    )?;
+    Ok(())
+}
+
+/// Delete a conversation message by id, leaving a tombstone. Returns true if it existed.
+pub fn delete_conversation_message(conn: &Connection, id: &str) -> rusqlite::Result<bool> {
+    let rows_affected = conn.execute("DELETE FROM conversations WHERE id = ?1", params![id])?;
+    if rows_affected > 0 {
+        let now = chrono::Utc::now().to_rfc3339();
+        record_tombstone(conn, "conversations", id, &now)?;
+    }
+    Ok(rows_affected > 0)
+}
+
+/// Delete a metadata key. Returns true if it existed.
+pub fn delete_metadata(conn: &Connection, key: &str) -> rusqlite::Result<bool> {
+    let rows_affected = conn.execute("DELETE FROM metadata WHERE key = ?1", params![key])?;
+    Ok(rows_affected > 0)
+}
+
+/// An entry of the local change feed
+#[derive(Debug, Clone)]
+pub struct ChangeLogEntry {
+    pub seq: i64,
+    pub table_name: String,
+    pub row_id: String,
+    pub op: String,
+    pub changed_at: String,
+}
+
+/// Get change_log entries after `seq`, oldest first.
+pub fn get_changes_since(conn: &Connection, seq: i64) -> rusqlite::Result<Vec<ChangeLogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT seq, table_name, row_id, op, changed_at FROM change_log
+         WHERE seq > ?1 ORDER BY seq ASC",
+    )?;
+    let rows = stmt.query_map(params![seq], |row| {
+        Ok(ChangeLogEntry {
+            seq: row.get(0)?,
+            table_name: row.get(1)?,
+            row_id: row.get(2)?,
+            op: row.get(3)?,
+            changed_at: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Highest change_log seq, or 0 when empty.
+pub fn latest_change_seq(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row("SELECT COALESCE(MAX(seq), 0) FROM change_log", [], |row| {
+        row.get(0)
+    })
+}
+
+/// Drop change_log entries after `seq` (used to hide changes applied from peers).
+pub fn discard_changes_after(conn: &Connection, seq: i64) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM change_log WHERE seq > ?1", params![seq])?;
+    Ok(())
+}
+
+/// Drop change_log entries up to and including `seq` once they're synced.
+pub fn prune_changes_through(conn: &Connection, seq: i64) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM change_log WHERE seq <= ?1", params![seq])?;
+    Ok(())
+}
+
+/// Keep only the latest change_log entry for each row. Journal entries are
+/// built from a row's current state, so earlier entries for it are redundant;
+/// this keeps the log bounded by the number of rows when nothing syncs it.
+pub fn compact_change_log(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.execute(
+        "DELETE FROM change_log WHERE seq NOT IN
+         (SELECT MAX(seq) FROM change_log GROUP BY table_name, row_id)",
+        [],
+    )
+}
+
+/// Get a sync position, or 0 if the peer hasn't been seen.
+pub fn get_sync_position(conn: &Connection, peer: &str) -> rusqlite::Result<i64> {
+    let mut stmt = conn.prepare("SELECT position FROM sync_state WHERE peer = ?1")?;
+    let mut rows = stmt.query(params![peer])?;
+    match rows.next()? {
+        Some(row) => row.get(0),
+        None => Ok(0),
+    }
+}
+
+/// Set a sync position (upsert).
+pub fn set_sync_position(conn: &Connection, peer: &str, position: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO sync_state (peer, position) VALUES (?1, ?2)",
+        params![peer, position],
+    )?;
+    Ok(())
+}
+
+/// Get a saved CRDT document, or None if it hasn't been created yet.
+pub fn get_crdt_document(conn: &Connection, name: &str) -> rusqlite::Result<Option<Vec<u8>>> {
+    if !table_exists(conn, "crdt_state")? {
+        return Ok(None);
+    }
+    let mut stmt = conn.prepare("SELECT data FROM crdt_state WHERE name = ?1")?;
+    let mut rows = stmt.query(params![name])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+/// Save a CRDT document, keeping its synced heads.
+pub fn set_crdt_document(conn: &Connection, name: &str, data: &[u8]) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO crdt_state (name, data) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET data = excluded.data",
+        params![name, data],
+    )?;
+    Ok(())
+}
+
+/// Append encoded changes to a saved CRDT document; loading reads the saved
+/// document followed by them.
+pub fn append_crdt_changes(conn: &Connection, name: &str, changes: &[u8]) -> rusqlite::Result<()> {
+    let mut data = get_crdt_document(conn, name)?.unwrap_or_default();
+    data.extend_from_slice(changes);
+    set_crdt_document(conn, name, &data)
+}
+
+/// Get the CRDT actor recorded for `owner`.
+pub fn get_crdt_actor(conn: &Connection, owner: &str) -> rusqlite::Result<Option<Vec<u8>>> {
+    let mut stmt = conn.prepare("SELECT actor FROM crdt_actors WHERE owner = ?1")?;
+    let mut rows = stmt.query(params![owner])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+/// Record `actor` as the CRDT actor of `owner`, replacing any earlier one.
+pub fn set_crdt_actor(conn: &Connection, owner: &str, actor: &[u8]) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO crdt_actors (owner, actor) VALUES (?1, ?2)",
+        params![owner, actor],
+    )?;
+    Ok(())
+}
+
+/// Record `actor` as the one this connection's edits are made under. It lives
+/// in a temporary table, so it is never saved with the file.
+pub fn set_session_actor(conn: &Connection, actor: &[u8]) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS session_actor (actor BLOB NOT NULL);
+         DELETE FROM temp.session_actor;",
+    )?;
+    conn.execute(
+        "INSERT INTO temp.session_actor (actor) VALUES (?1)",
+        params![actor],
+    )?;
+    Ok(())
+}
+
+/// The actor set with `set_session_actor`, if any.
+pub fn get_session_actor(conn: &Connection) -> rusqlite::Result<Option<Vec<u8>>> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_temp_master WHERE type = 'table' AND name = 'session_actor'",
+        [],
+        |row| row.get(0),
+    )?;
+    if count == 0 {
+        return Ok(None);
+    }
+    let mut stmt = conn.prepare("SELECT actor FROM temp.session_actor")?;
+    let mut rows = stmt.query([])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
+/// Get the synced heads recorded for a CRDT document.
+pub fn get_crdt_synced_heads(conn: &Connection, name: &str) -> rusqlite::Result<String> {
+    let mut stmt = conn.prepare("SELECT synced_heads FROM crdt_state WHERE name = ?1")?;
+    let mut rows = stmt.query(params![name])?;
+    match rows.next()? {
+        Some(row) => row.get(0),
+        None => Ok(String::new()),
+    }
+}
+
+/// Record the synced heads for a saved CRDT document.
+pub fn set_crdt_synced_heads(conn: &Connection, name: &str, heads: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE crdt_state SET synced_heads = ?2 WHERE name = ?1",
+        params![name, heads],
+    )?;
+    Ok(())
+}
+
+/// Get the stored text of every page, in page order.
+pub fn get_page_texts(conn: &Connection) -> rusqlite::Result<Vec<(u32, String)>> {
+    if !table_exists(conn, "page_text")? {
+        return Ok(Vec::new());
+    }
+    let mut stmt = conn.prepare("SELECT page_number, text FROM page_text ORDER BY page_number")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Store page texts (upsert), e.g. after the renderer extracted them.
+pub fn set_page_texts(conn: &Connection, pages: &[(u32, String)]) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    for (page_number, text) in pages {
+        tx.execute(
+            "INSERT OR REPLACE INTO page_text (page_number, text) VALUES (?1, ?2)",
+            params![page_number, text],
+        )?;
+    }
+    tx.commit()
+}
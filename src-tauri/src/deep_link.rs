@@ -0,0 +1,229 @@
+use std::path::Path;
+
+use rusqlite::Connection;
+use url::Url;
+
+use crate::library;
+use crate::models::DeepLinkTarget;
+
+/// URL scheme of links to library documents, e.g.
+/// `vellum://open?doc=<pdf_sha256>&page=12&annotation=<id>`
+pub const SCHEME: &str = "vellum";
+
+/// A `vellum://open` link. It names the document by the SHA-256 of its PDF,
+/// so it can only open documents in the library, wherever their files are.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentLink {
+    pub pdf_sha256: String,
+    pub page_number: Option<u32>,
+    pub annotation_id: Option<String>,
+}
+
+/// Something the app was asked to open: a file given on the command line or
+/// by the file manager, or a link to a library document.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OpenRequest {
+    File(DeepLinkTarget),
+    Link(DocumentLink),
+}
+
+impl OpenRequest {
+    pub fn set_page(&mut self, page: u32) {
+        match self {
+            OpenRequest::File(target) => target.page_number = Some(page),
+            OpenRequest::Link(link) => link.page_number = Some(page),
+        }
+    }
+}
+
+/// Parse a deep link. `doc` is required; `page` and `annotation` are
+/// optional, and an annotation's own page takes precedence over `page`.
+pub fn parse(link: &str) -> Result<DocumentLink, String> {
+    let url = Url::parse(link.trim()).map_err(|e| format!("Invalid link {}: {}", link, e))?;
+    if url.scheme() != SCHEME {
+        return Err(format!("Not a {}:// link: {}", SCHEME, link));
+    }
+    // `vellum://open?..` has "open" as host; `vellum:open?..` as path
+    let action = url
+        .host_str()
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| url.path().trim_matches('/'));
+    if action != "open" {
+        return Err(format!("Unknown link action: {}", action));
+    }
+
+    let mut target = DocumentLink {
+        pdf_sha256: String::new(),
+        page_number: None,
+        annotation_id: None,
+    };
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "doc" => target.pdf_sha256 = value.to_ascii_lowercase(),
+            "page" => {
+                target.page_number = Some(
+                    value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|&page| page > 0)
+                        .ok_or_else(|| format!("Invalid page in link: {}", value))?,
+                )
+            }
+            "annotation" if !value.is_empty() => target.annotation_id = Some(value.into_owned()),
+            // Unknown parameters are left for newer versions
+            _ => {}
+        }
+    }
+    if target.pdf_sha256.is_empty() {
+        return Err("Link has no document".to_string());
+    }
+    if target.pdf_sha256.len() != 64 || !target.pdf_sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Invalid document in link: {}", target.pdf_sha256));
+    }
+    Ok(target)
+}
+
+/// A request for a URL the OS asked the app to open: a `vellum://` link, or
+/// a `file://` URL of a document (macOS file associations, Linux `%u`).
+pub fn from_url(url: &Url) -> Result<OpenRequest, String> {
+    if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|_| format!("Invalid file URL: {}", url))?;
+        return file_target(&path).map(OpenRequest::File);
+    }
+    parse(url.as_str()).map(OpenRequest::Link)
+}
+
+/// A target opening an .rr file, or a PDF to import, at its saved position.
+pub fn file_target(path: &Path) -> Result<DeepLinkTarget, String> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    if ext != "rr" && ext != "pdf" {
+        return Err(format!("Unsupported file type: {}", path.display()));
+    }
+    if !path.is_file() {
+        return Err(format!("No such file: {}", path.display()));
+    }
+    Ok(DeepLinkTarget {
+        path: path.to_string_lossy().to_string(),
+        page_number: None,
+        annotation_id: None,
+    })
+}
+
+/// The library document a link points to.
+pub fn resolve(conn: &Connection, link: &DocumentLink) -> Result<DeepLinkTarget, String> {
+    let entry = library::find_by_hash(conn, &link.pdf_sha256)
+        .map_err(|e| format!("Failed to search library: {}", e))?
+        .ok_or("The linked document isn't in the library")?;
+    Ok(DeepLinkTarget {
+        path: entry.path,
+        page_number: link.page_number,
+        annotation_id: link.annotation_id.clone(),
+    })
+}
+
+/// The deep link to a document, optionally at a page or annotation.
+pub fn build(link: &DocumentLink) -> String {
+    let mut url = Url::parse(&format!("{}://open", SCHEME)).expect("scheme URL is valid");
+    {
+        let mut query = url.query_pairs_mut();
+        query.append_pair("doc", &link.pdf_sha256);
+        if let Some(page) = link.page_number {
+            query.append_pair("page", &page.to_string());
+        }
+        if let Some(id) = &link.annotation_id {
+            query.append_pair("annotation", id);
+        }
+    }
+    url.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr_file;
+
+    const HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn links_round_trip() {
+        let link = DocumentLink {
+            pdf_sha256: HASH.to_string(),
+            page_number: Some(12),
+            annotation_id: Some("a b&c".to_string()),
+        };
+        let built = build(&link);
+        assert!(built.starts_with("vellum://open?doc="), "{}", built);
+        assert_eq!(parse(&built).unwrap(), link);
+
+        let plain = parse(&format!("vellum://open?doc={}", HASH.to_uppercase())).unwrap();
+        assert_eq!(plain.pdf_sha256, HASH);
+        assert_eq!((plain.page_number, plain.annotation_id), (None, None));
+        let opaque = parse(&format!("vellum:open?doc={}&page=3&annotation=", HASH)).unwrap();
+        assert_eq!((opaque.page_number, opaque.annotation_id), (Some(3), None));
+    }
+
+    #[test]
+    fn invalid_links_are_rejected() {
+        let doc = format!("doc={}", HASH);
+        assert!(parse(&format!("http://open?{}", doc)).is_err());
+        assert!(parse(&format!("vellum://delete?{}", doc)).is_err());
+        assert!(parse(&format!("vellum://open?{}&page=0", doc)).is_err());
+        assert!(parse(&format!("vellum://open?{}&page=x", doc)).is_err());
+        assert!(parse("vellum://open").is_err());
+        assert!(parse("vellum://open?doc=abc").is_err());
+        assert!(parse(&format!("vellum://open?doc={}", "g".repeat(64))).is_err());
+        // Links can't name files
+        assert!(parse("vellum://open?path=%2Fhome%2Fme%2Fpaper.rr").is_err());
+        assert!(from_url(&Url::parse("vellum://open?path=/etc/passwd").unwrap()).is_err());
+    }
+
+    #[test]
+    fn file_urls_open_supported_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let rr = dir.path().join("paper.rr");
+        let txt = dir.path().join("notes.txt");
+        std::fs::write(&rr, b"x").unwrap();
+        std::fs::write(&txt, b"x").unwrap();
+
+        let request = from_url(&Url::from_file_path(&rr).unwrap()).unwrap();
+        assert_eq!(request, OpenRequest::File(file_target(&rr).unwrap()));
+        assert!(from_url(&Url::from_file_path(&txt).unwrap()).is_err());
+        assert!(file_target(&dir.path().join("missing.pdf")).is_err());
+    }
+
+    #[test]
+    fn links_resolve_to_library_documents_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = library::open(dir.path()).unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        let hash = rr_file::document_hash(&session).unwrap();
+
+        let link = DocumentLink {
+            pdf_sha256: hash.clone(),
+            page_number: Some(2),
+            annotation_id: None,
+        };
+        assert!(resolve(&conn, &link).is_err());
+
+        let entry = library::record_session(&conn, &session, true).unwrap();
+        let target = resolve(&conn, &link).unwrap();
+        assert_eq!(target.path, entry.path);
+        assert_eq!(target.page_number, Some(2));
+
+        let other = DocumentLink {
+            pdf_sha256: HASH.to_string(),
+            ..link
+        };
+        assert!(resolve(&conn, &other).is_err());
+        rr_file::cleanup_session(&session);
+    }
+}
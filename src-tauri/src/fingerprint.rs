@@ -0,0 +1,230 @@
+use lopdf::Document;
+use rusqlite::Connection;
+
+use crate::database;
+use crate::pdf_metadata;
+
+/// Metadata key the fingerprint is stored under.
+pub const METADATA_KEY: &str = "text_fingerprint";
+/// Fingerprints this many bits apart or fewer are treated as the same text.
+pub const MAX_DISTANCE: u32 = 3;
+/// Documents with fewer words (e.g. scans without a text layer) get no
+/// fingerprint, since a handful of words can't identify a document.
+const MIN_WORDS: usize = 50;
+/// Words per shingle hashed into the fingerprint.
+const SHINGLE_WORDS: usize = 3;
+
+/// Simhash of the normalised page text, as 16 hex digits.
+///
+/// Unlike the PDF's SHA-256, it barely changes when a re-downloaded copy
+/// differs only by a watermark, a download stamp or other small edits.
+pub fn text_fingerprint(pages: &[(u32, String)]) -> Option<String> {
+    let text = pages
+        .iter()
+        .map(|(_, text)| text.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    if words.len() < MIN_WORDS {
+        return None;
+    }
+
+    let mut weights = [0i64; 64];
+    for shingle in words.windows(SHINGLE_WORDS) {
+        let hash = fnv1a(&shingle.join(" "));
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash & (1 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    let fingerprint = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0u64, |acc, (bit, _)| acc | (1 << bit));
+    Some(format!("{:016x}", fingerprint))
+}
+
+/// Number of differing bits between two fingerprints, or None if either is malformed.
+pub fn distance(a: &str, b: &str) -> Option<u32> {
+    let a = u64::from_str_radix(a, 16).ok()?;
+    let b = u64::from_str_radix(b, 16).ok()?;
+    Some((a ^ b).count_ones())
+}
+
+/// Recompute the fingerprint from the stored page text and save it in metadata.
+pub fn update_text_fingerprint(db: &Connection) -> Result<Option<String>, String> {
+    let pages =
+        database::get_page_texts(db).map_err(|e| format!("Failed to get page text: {}", e))?;
+    let fingerprint = text_fingerprint(&pages);
+    match &fingerprint {
+        Some(value) => database::set_metadata(db, METADATA_KEY, value),
+        None => database::delete_metadata(db, METADATA_KEY).map(|_| ()),
+    }
+    .map_err(|e| format!("Failed to store text fingerprint: {}", e))?;
+    Ok(fingerprint)
+}
+
+/// Store the text layer of a newly imported PDF as its page text and
+/// fingerprint it, so documents imported without being opened can be matched
+/// too. The viewer replaces the text with its own when the document is opened.
+pub fn extract_into(db: &Connection, pdf: Option<&Document>) -> Result<Option<String>, String> {
+    let Some(doc) = pdf else {
+        return Ok(None);
+    };
+    let pages: Vec<(u32, String)> = doc
+        .get_pages()
+        .into_keys()
+        .map(|page| {
+            let text = doc
+                .extract_text_chunks_with_limit(&[page], pdf_metadata::MAX_STREAM_BYTES)
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>()
+                .join(" ");
+            (page, text)
+        })
+        .collect();
+    database::set_page_texts(db, &pages)
+        .map_err(|e| format!("Failed to store page text: {}", e))?;
+    update_text_fingerprint(db)
+}
+
+/// 64-bit FNV-1a; stable across platforms and releases, unlike `DefaultHasher`.
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr_file;
+    use lopdf::content::{Content, Operation};
+    use lopdf::{dictionary, Object, Stream};
+    use std::path::Path;
+
+    fn words(count: usize, seed: usize) -> String {
+        (0..count)
+            .map(|i| format!("w{}x{}", (i * seed) % 1000, i % 13))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// A PDF whose pages show `lines`, one page per entry.
+    fn write_pdf(path: &Path, pages: &[&[String]]) {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica",
+        });
+        let resources_id =
+            doc.add_object(dictionary! { "Font" => dictionary! { "F1" => font_id } });
+        let kids: Vec<Object> = pages
+            .iter()
+            .map(|lines| {
+                let mut operations = Vec::new();
+                for (i, line) in lines.iter().enumerate() {
+                    operations.extend([
+                        Operation::new("BT", vec![]),
+                        Operation::new("Tf", vec!["F1".into(), 10.into()]),
+                        Operation::new("Td", vec![72.into(), (720 - 14 * i as i64).into()]),
+                        Operation::new("Tj", vec![Object::string_literal(line.as_str())]),
+                        Operation::new("ET", vec![]),
+                    ]);
+                }
+                let content = Content { operations }.encode().unwrap();
+                let content_id = doc.add_object(Stream::new(dictionary! {}, content));
+                doc.add_object(dictionary! {
+                    "Type" => "Page", "Parent" => pages_id, "Contents" => content_id,
+                })
+                .into()
+            })
+            .collect();
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages", "Kids" => kids, "Count" => count,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+        doc.save(path).unwrap();
+    }
+
+    #[test]
+    fn small_edits_stay_within_max_distance() {
+        let body = words(800, 7919);
+        let v1 =
+            text_fingerprint(&[(1, format!("arXiv:2101.00001v1 1 Jan 2021 {}", body))]).unwrap();
+        let v2 = text_fingerprint(&[(
+            1,
+            format!("Downloaded 9 Mar 2021 arXiv:2101.00001v2 {} Page 1", body),
+        )])
+        .unwrap();
+        let other = text_fingerprint(&[(1, words(800, 31))]).unwrap();
+
+        assert_eq!(distance(&v1, &v1), Some(0));
+        assert!(distance(&v1, &v2).unwrap() <= MAX_DISTANCE);
+        assert!(distance(&v1, &other).unwrap() > MAX_DISTANCE);
+    }
+
+    #[test]
+    fn page_breaks_and_case_do_not_matter() {
+        let body = words(200, 7919);
+        let (first, second) = body.split_at(body.len() / 2);
+        let whole = text_fingerprint(&[(1, body.clone())]).unwrap();
+        let split =
+            text_fingerprint(&[(1, first.to_uppercase()), (2, second.to_string())]).unwrap();
+        assert!(distance(&whole, &split).unwrap() <= MAX_DISTANCE);
+    }
+
+    #[test]
+    fn short_or_malformed_input_is_not_compared() {
+        assert_eq!(
+            text_fingerprint(&[(1, "too short to identify".to_string())]),
+            None
+        );
+        assert_eq!(text_fingerprint(&[]), None);
+        assert_eq!(distance("ffff", "not hex"), None);
+        assert_eq!(distance("", "ffff"), None);
+        assert_eq!(distance("0", "ffffffffffffffff"), Some(64));
+    }
+
+    #[test]
+    fn imported_pdfs_are_fingerprinted_from_their_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        let lines: Vec<String> = (0..12).map(|i| words(8, 7919 + i)).collect();
+        write_pdf(&pdf, &[&lines[..6], &lines[6..]]);
+
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        let pages = database::get_page_texts(&session.db).unwrap();
+        assert_eq!(pages.len(), 2);
+        let stored = database::get_metadata(&session.db, METADATA_KEY).unwrap();
+        assert!(stored.is_some());
+        assert_eq!(stored, text_fingerprint(&pages));
+        rr_file::cleanup_session(&session);
+
+        // Not a PDF lopdf can read: imported without a fingerprint
+        let fake = dir.path().join("fake.pdf");
+        std::fs::write(&fake, b"%PDF-1.4 fake").unwrap();
+        let session = rr_file::import_pdf(&fake, None).unwrap();
+        assert_eq!(
+            database::get_metadata(&session.db, METADATA_KEY).unwrap(),
+            None
+        );
+        rr_file::cleanup_session(&session);
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+use crate::library;
+use crate::models::*;
+use crate::rr_file;
+
+/// A PDF must go this long without changes before it's imported, so
+/// downloads that are still being written are left alone.
+const QUIET_PERIOD: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Library settings, stored as library.json in the app config directory.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LibraryConfig {
+    /// Where imported documents are stored; defaults to a folder in the app data directory
+    #[serde(default)]
+    pub folder: Option<PathBuf>,
+    /// Folders whose new PDFs are imported automatically
+    #[serde(default)]
+    pub watched_folders: Vec<WatchedFolder>,
+}
+
+/// A folder watched for new PDFs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WatchedFolder {
+    pub path: PathBuf,
+    /// File name patterns such as `arxiv-*.pdf`; every PDF matches when empty
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// Delete the PDF once it has been imported instead of leaving it in place
+    #[serde(default)]
+    pub remove_original: bool,
+}
+
+impl LibraryConfig {
+    /// Load the config from `config_dir`, or the defaults if there is none yet.
+    pub fn load(config_dir: &Path) -> Result<Self, String> {
+        let path = config_dir.join("library.json");
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let json = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read library config: {}", e))?;
+        serde_json::from_str(&json).map_err(|e| format!("Invalid library config: {}", e))
+    }
+
+    pub fn save(&self, config_dir: &Path) -> Result<(), String> {
+        fs::create_dir_all(config_dir)
+            .map_err(|e| format!("Failed to create config dir: {}", e))?;
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize library config: {}", e))?;
+        fs::write(config_dir.join("library.json"), json)
+            .map_err(|e| format!("Failed to write library config: {}", e))
+    }
+}
+
+impl WatchedFolder {
+    /// Check the folder exists and its patterns are valid globs.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.path.is_dir() {
+            return Err(format!("{} is not a folder", self.path.display()));
+        }
+        for pattern in &self.patterns {
+            glob::Pattern::new(pattern)
+                .map_err(|e| format!("Invalid pattern '{}': {}", pattern, e))?;
+        }
+        Ok(())
+    }
+
+    /// Whether `path` is a PDF this folder's rules import.
+    pub fn matches(&self, path: &Path) -> bool {
+        let is_pdf = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("pdf"));
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return false;
+        };
+        // Skip hidden files, such as temporary copies written before a rename
+        if !is_pdf || name.starts_with('.') {
+            return false;
+        }
+        if self.patterns.is_empty() {
+            return true;
+        }
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..Default::default()
+        };
+        self.patterns
+            .iter()
+            .any(|pattern| glob::Pattern::new(pattern).is_ok_and(|p| p.matches_with(name, options)))
+    }
+}
+
+/// Import a PDF into `library_folder` as a new .rr file and index it.
+/// A PDF already in the library (by SHA-256) is reported as a duplicate
+/// and left untouched.
+pub fn import_into_library(
+    conn: &rusqlite::Connection,
+    pdf_path: &Path,
+    library_folder: &Path,
+    remove_original: bool,
+) -> LibraryImport {
+    let source = pdf_path.to_string_lossy().to_string();
+    match try_import(conn, pdf_path, library_folder, remove_original) {
+        Ok((status, entry)) => LibraryImport {
+            source,
+            status: status.to_string(),
+            entry: Some(entry),
+            error: None,
+        },
+        Err(e) => LibraryImport {
+            source,
+            status: "failed".to_string(),
+            entry: None,
+            error: Some(e),
+        },
+    }
+}
+
+fn try_import(
+    conn: &rusqlite::Connection,
+    pdf_path: &Path,
+    library_folder: &Path,
+    remove_original: bool,
+) -> Result<(&'static str, LibraryEntry), String> {
+    let hash = rr_file::sha256_file(pdf_path)?;
+    if let Some(existing) = library::find_by_hash(conn, &hash)
+        .map_err(|e| format!("Failed to search library: {}", e))?
+    {
+        return Ok(("duplicate", existing));
+    }
+
+    fs::create_dir_all(library_folder)
+        .map_err(|e| format!("Failed to create library folder: {}", e))?;
+    let stem = pdf_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("document");
+    let rr_path = unused_path(library_folder, stem);
+
+    let session = rr_file::import_pdf(pdf_path, Some(&rr_path))?;
+    // Indexing caches the hash in metadata, so pack again afterwards
+    let indexed = library::record_session(conn, &session, false).and_then(|entry| {
+        rr_file::save_rr(&session)?;
+        Ok(entry)
+    });
+    rr_file::cleanup_session(&session);
+    let entry = indexed?;
+
+    if remove_original {
+        fs::remove_file(pdf_path)
+            .map_err(|e| format!("Imported, but failed to remove the original: {}", e))?;
+    }
+    Ok(("imported", entry))
+}
+
+/// `folder/stem.rr`, or `folder/stem (n).rr` if that is taken.
+fn unused_path(folder: &Path, stem: &str) -> PathBuf {
+    let mut path = folder.join(format!("{}.rr", stem));
+    let mut n = 2;
+    while path.exists() {
+        path = folder.join(format!("{} ({}).rr", stem, n));
+        n += 1;
+    }
+    path
+}
+
+/// Watch `folders` and call `on_pdf` for each matching PDF once it has
+/// stopped changing. Watching stops when the returned watcher is dropped.
+pub fn watch(
+    folders: Vec<WatchedFolder>,
+    on_pdf: impl Fn(&WatchedFolder, &Path) + Send + 'static,
+) -> Result<notify::RecommendedWatcher, String> {
+    let (tx, rx) = mpsc::channel::<PathBuf>();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                log::warn!("[library] Watch error: {}", e);
+                return;
+            }
+        };
+        if !(event.kind.is_create() || event.kind.is_modify()) {
+            return;
+        }
+        for path in event.paths {
+            let _ = tx.send(path);
+        }
+    })
+    .map_err(|e| format!("Failed to create folder watcher: {}", e))?;
+
+    // Compare canonical paths, since some platforms report events that way
+    let folders: Vec<WatchedFolder> = folders
+        .into_iter()
+        .map(|mut folder| {
+            if let Ok(path) = fs::canonicalize(&folder.path) {
+                folder.path = path;
+            }
+            folder
+        })
+        .collect();
+    for folder in &folders {
+        watcher
+            .watch(&folder.path, RecursiveMode::Recursive)
+            .map_err(|e| format!("Failed to watch {}: {}", folder.path.display(), e))?;
+    }
+
+    // Debounce on a worker thread; it exits once the watcher (and with it
+    // the sender) is dropped
+    thread::spawn(move || {
+        let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+        loop {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(path) => {
+                    pending.insert(path, Instant::now());
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+            let settled: Vec<PathBuf> = pending
+                .iter()
+                .filter(|(_, changed)| changed.elapsed() >= QUIET_PERIOD)
+                .map(|(path, _)| path.clone())
+                .collect();
+            for path in settled {
+                pending.remove(&path);
+                let Ok(path) = fs::canonicalize(&path) else {
+                    continue;
+                };
+                if !path.is_file() {
+                    continue;
+                }
+                if let Some(folder) = folders
+                    .iter()
+                    .find(|f| path.starts_with(&f.path) && f.matches(&path))
+                {
+                    on_pdf(folder, &path);
+                }
+            }
+        }
+    });
+
+    Ok(watcher)
+}
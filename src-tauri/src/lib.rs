@@ -1,25 +1,79 @@
+mod bibliography;
+mod citation;
+pub mod cli;
+mod collab;
 mod commands;
+mod crdt;
+mod credentials;
 mod database;
+mod deep_link;
+mod fingerprint;
+mod importer;
+mod library;
+mod merge;
 mod models;
+mod outline;
+mod page_labels;
+mod pdf_metadata;
+mod pdf_protocol;
+mod reference_list;
+mod references;
 mod rr_file;
+mod search;
+mod sidecar;
+mod sync;
+mod thumbnails;
+mod web_annotation;
+mod webdav;
+mod xfdf;
 
 use commands::AppState;
+use deep_link::OpenRequest;
 use std::sync::Mutex;
+use tauri_plugin_deep_link::DeepLinkExt;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    let builder = tauri::Builder::default()
+    run_with(None);
+}
+
+/// Run the app, opening `launch` (from the command line) once it's up.
+pub fn run_with(launch: Option<OpenRequest>) {
+    let builder = tauri::Builder::default();
+
+    // Registered first, so a second launch exits before setting anything up
+    #[cfg(desktop)]
+    let builder = builder.plugin(tauri_plugin_single_instance::init(
+        commands::handle_forwarded_args,
+    ));
+
+    let builder = builder
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
+        .plugin(tauri_plugin_deep_link::init())
+        .register_asynchronous_uri_scheme_protocol(
+            pdf_protocol::SCHEME,
+            |ctx, request, responder| {
+                let app = ctx.app_handle().clone();
+                // Read the file off the main thread
+                tauri::async_runtime::spawn_blocking(move || {
+                    responder.respond(commands::pdf_protocol_response(&app, &request));
+                });
+            },
+        )
         .manage(AppState {
             session: Mutex::new(None),
+            sync_watcher: Mutex::new(None),
+            library_watcher: Mutex::new(None),
+            collab: Mutex::new(None),
+            pending_link: Mutex::new(None),
         });
 
     #[cfg(desktop)]
     let builder = builder.plugin(tauri_plugin_process::init());
 
     builder
-        .setup(|app| {
+        .setup(move |app| {
             if cfg!(debug_assertions) {
                 app.handle().plugin(
                     tauri_plugin_log::Builder::default()
@@ -32,6 +86,38 @@ pub fn run() {
             app.handle()
                 .plugin(tauri_plugin_updater::Builder::new().build())?;
 
+            if let Err(e) = commands::init_device_id(app.handle()) {
+                log::warn!("[crdt] Failed to load device id: {}", e);
+            }
+            if let Err(e) = commands::restart_sync_watcher(app.handle()) {
+                log::warn!("[sync] Failed to start folder watcher: {}", e);
+            }
+            if let Err(e) = commands::restart_library_watcher(app.handle()) {
+                log::warn!("[library] Failed to start folder watcher: {}", e);
+            }
+            commands::refresh_references_in_background(app.handle());
+
+            // vellum:// links and, on macOS, associated files: the one the app
+            // was started with, and later ones delivered to this instance
+            #[cfg(any(windows, target_os = "linux"))]
+            if let Err(e) = app.deep_link().register_all() {
+                log::warn!("[deep-link] Failed to register URL scheme: {}", e);
+            }
+            let handle = app.handle().clone();
+            app.deep_link().on_open_url(move |event| {
+                commands::handle_deep_links(&handle, event.urls());
+            });
+            // On Windows and Linux a link arrives as the only argument, which
+            // the command line parser has handled already
+            match launch {
+                Some(request) => commands::open_request(app.handle(), request),
+                None => match app.deep_link().get_current() {
+                    Ok(Some(urls)) => commands::handle_deep_links(app.handle(), urls),
+                    Ok(None) => {}
+                    Err(e) => log::warn!("[deep-link] Failed to read startup link: {}", e),
+                },
+            }
+
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
@@ -39,11 +125,79 @@ pub fn run() {
             commands::save_file,
             commands::close_file,
             commands::read_pdf_bytes,
+            commands::take_deep_link,
+            commands::get_deep_link,
+            commands::get_thumbnail,
+            commands::set_thumbnail,
             commands::get_annotations,
             commands::create_annotation,
             commands::update_annotation,
             commands::delete_annotation,
             commands::set_document_metadata,
+            commands::get_bibliography,
+            commands::set_bibliography,
+            commands::get_extracted_metadata,
+            commands::extract_metadata,
+            commands::set_page_texts,
+            commands::get_cited_references,
+            commands::set_cited_reference_to_read,
+            commands::get_outline,
+            commands::get_page_labels,
+            commands::page_label_to_number,
+            commands::page_number_to_label,
+            commands::export_xfdf,
+            commands::import_xfdf,
+            commands::export_web_annotations,
+            commands::import_web_annotations,
+            commands::export_sidecar,
+            commands::import_sidecar,
+            commands::merge_from,
+            commands::merge_three_way,
+            commands::get_sync_config,
+            commands::set_sync_folder,
+            commands::sync_now,
+            commands::sync_library,
+            commands::list_synced_documents,
+            commands::set_webdav_account,
+            commands::clear_webdav_account,
+            commands::webdav_sync_now,
+            commands::webdav_list_documents,
+            commands::webdav_download_document,
+            commands::start_collab_session,
+            commands::join_collab_session,
+            commands::leave_collab_session,
+            commands::set_collab_page,
+            commands::get_collab_session,
+            commands::list_library,
+            commands::relocate_library_entry,
+            commands::remove_library_entry,
+            commands::merge_into_library_document,
+            commands::set_reading_status,
+            commands::set_reading_priority,
+            commands::set_due_date,
+            commands::list_collections,
+            commands::create_collection,
+            commands::rename_collection,
+            commands::move_collection,
+            commands::delete_collection,
+            commands::add_to_collection,
+            commands::remove_from_collection,
+            commands::search_library,
+            commands::get_library_config,
+            commands::set_library_folder,
+            commands::set_watched_folders,
+            commands::import_to_library,
+            commands::import_reference_file,
+            commands::refresh_reference_files,
+            commands::list_reference_files,
+            commands::remove_reference_file,
+            commands::search_references,
+            commands::get_reference_link,
+            commands::link_reference,
+            commands::unlink_reference,
+            commands::export_bibliography,
+            commands::set_citation_key,
+            commands::format_citation,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
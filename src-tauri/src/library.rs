@@ -0,0 +1,714 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::bibliography;
+use crate::citation;
+use crate::database;
+use crate::fingerprint;
+use crate::models::*;
+use crate::references;
+use crate::rr_file::{self, RrSession};
+use crate::search;
+
+/// File name of the library database inside the app data directory.
+pub const LIBRARY_FILE: &str = "library.sqlite";
+
+/// Columns selected by every entry query, in `row_to_entry` order.
+const ENTRY_COLUMNS: &str = "id, path, title, pdf_sha256, page_count, last_page,
+    highlight_count, note_count, bookmark_count, added_at, last_opened_at,
+    status, priority, due_date, text_fingerprint,
+    authors, year, venue, doi, arxiv_id, citation_key,
+    (SELECT GROUP_CONCAT(collection_id) FROM collection_documents
+     WHERE document_id = documents.id) AS collection_ids";
+
+/// Per-document details read from a container's data.sqlite
+struct DocumentStats {
+    title: Option<String>,
+    page_count: Option<u32>,
+    last_page: Option<u32>,
+    highlight_count: u32,
+    note_count: u32,
+    bookmark_count: u32,
+    text_fingerprint: Option<String>,
+    bibliography: BibliographicRecord,
+}
+
+/// Open (and create if needed) the library database in `data_dir`.
+pub fn open(data_dir: &Path) -> Result<Connection, String> {
+    fs::create_dir_all(data_dir).map_err(|e| format!("Failed to create data dir: {}", e))?;
+    let conn = Connection::open(data_dir.join(LIBRARY_FILE))
+        .map_err(|e| format!("Failed to open library: {}", e))?;
+    init_library(&conn).map_err(|e| format!("Failed to init library: {}", e))?;
+    Ok(conn)
+}
+
+/// Initialize the library tables.
+pub fn init_library(conn: &Connection) -> rusqlite::Result<()> {
+    search::init_search(conn)?;
+    references::init_references(conn)?;
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS documents (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL UNIQUE,
+            title TEXT,
+            pdf_sha256 TEXT,
+            page_count INTEGER,
+            last_page INTEGER,
+            highlight_count INTEGER NOT NULL DEFAULT 0,
+            note_count INTEGER NOT NULL DEFAULT 0,
+            bookmark_count INTEGER NOT NULL DEFAULT 0,
+            added_at TEXT NOT NULL,
+            last_opened_at TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_documents_sha256
+            ON documents(pdf_sha256);
+
+        CREATE TABLE IF NOT EXISTS collections (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            parent_id INTEGER REFERENCES collections(id),
+            created_at TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS collection_documents (
+            collection_id INTEGER NOT NULL,
+            document_id INTEGER NOT NULL,
+            added_at TEXT NOT NULL,
+            PRIMARY KEY (collection_id, document_id)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_collection_documents_document
+            ON collection_documents(document_id);
+        ",
+    )?;
+
+    // Reading list columns, added after the first library release
+    add_column_if_missing(conn, "documents", "status", "TEXT")?;
+    add_column_if_missing(conn, "documents", "priority", "INTEGER")?;
+    add_column_if_missing(conn, "documents", "due_date", "TEXT")?;
+    add_column_if_missing(conn, "documents", "text_fingerprint", "TEXT")?;
+    // Bibliographic record; authors are a JSON array
+    add_column_if_missing(conn, "documents", "authors", "TEXT")?;
+    add_column_if_missing(conn, "documents", "year", "INTEGER")?;
+    add_column_if_missing(conn, "documents", "venue", "TEXT")?;
+    add_column_if_missing(conn, "documents", "doi", "TEXT")?;
+    add_column_if_missing(conn, "documents", "arxiv_id", "TEXT")?;
+    add_column_if_missing(conn, "documents", "citation_key", "TEXT")?;
+    conn.execute_batch(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_documents_citation_key
+            ON documents(citation_key COLLATE NOCASE);",
+    )?;
+    Ok(())
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let exists: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        params![table, column],
+        |row| row.get(0),
+    )?;
+    if exists == 0 {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))?;
+    }
+    Ok(())
+}
+
+/// Add or refresh the library entry for an open session and its search index.
+/// `opened` marks the document as opened now; saves and closes leave it as is.
+/// A changed reference entry the document is linked to is written into the
+/// session's bibliographic record first, and the document is given a
+/// citation key if it has none.
+pub fn record_session(
+    conn: &Connection,
+    session: &RrSession,
+    opened: bool,
+) -> Result<LibraryEntry, String> {
+    let mut entry = upsert_session(conn, session, opened)?;
+    if references::sync_document(conn, &entry, &session.db)? {
+        entry = upsert_session(conn, session, false)?;
+    }
+    entry.citation_key = Some(citation::assign_key(conn, &entry, &session.db)?);
+    search::index_document(conn, entry.id, &session.db)?;
+    Ok(entry)
+}
+
+fn upsert_session(
+    conn: &Connection,
+    session: &RrSession,
+    opened: bool,
+) -> Result<LibraryEntry, String> {
+    let stats = read_stats(&session.db)?;
+    let pdf_sha256 = rr_file::document_hash(session)?;
+    let path = library_path(&session.rr_path);
+    let now = chrono::Utc::now().to_rfc3339();
+
+    conn.execute(
+        "INSERT INTO documents (path, title, pdf_sha256, page_count, last_page,
+             highlight_count, note_count, bookmark_count, added_at, last_opened_at,
+             text_fingerprint, authors, year, venue, doi, arxiv_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+         ON CONFLICT(path) DO UPDATE SET
+             title = excluded.title,
+             pdf_sha256 = excluded.pdf_sha256,
+             page_count = excluded.page_count,
+             last_page = excluded.last_page,
+             highlight_count = excluded.highlight_count,
+             note_count = excluded.note_count,
+             bookmark_count = excluded.bookmark_count,
+             text_fingerprint = excluded.text_fingerprint,
+             authors = excluded.authors,
+             year = excluded.year,
+             venue = excluded.venue,
+             doi = excluded.doi,
+             arxiv_id = excluded.arxiv_id,
+             last_opened_at = COALESCE(excluded.last_opened_at, documents.last_opened_at)",
+        params![
+            path,
+            stats.title,
+            pdf_sha256,
+            stats.page_count,
+            stats.last_page,
+            stats.highlight_count,
+            stats.note_count,
+            stats.bookmark_count,
+            now,
+            if opened { Some(&now) } else { None },
+            stats.text_fingerprint,
+            authors_json(&stats.bibliography.authors),
+            stats.bibliography.year,
+            stats.bibliography.venue,
+            stats.bibliography.doi,
+            stats.bibliography.arxiv_id,
+        ],
+    )
+    .map_err(|e| format!("Failed to update library: {}", e))?;
+
+    get_entry_by_path(conn, &path)
+        .map_err(|e| format!("Failed to read library entry: {}", e))?
+        .ok_or_else(|| "Library entry disappeared after update".to_string())
+}
+
+/// Get a library entry by id.
+pub fn get_entry(conn: &Connection, id: i64) -> rusqlite::Result<Option<LibraryEntry>> {
+    conn.query_row(
+        &format!("SELECT {} FROM documents WHERE id = ?1", ENTRY_COLUMNS),
+        params![id],
+        row_to_entry,
+    )
+    .optional()
+}
+
+fn get_entry_by_path(conn: &Connection, path: &str) -> rusqlite::Result<Option<LibraryEntry>> {
+    conn.query_row(
+        &format!("SELECT {} FROM documents WHERE path = ?1", ENTRY_COLUMNS),
+        params![path],
+        row_to_entry,
+    )
+    .optional()
+}
+
+/// Find an entry whose file still exists and holds the PDF with this hash.
+pub fn find_by_hash(conn: &Connection, pdf_sha256: &str) -> rusqlite::Result<Option<LibraryEntry>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM documents WHERE pdf_sha256 = ?1 ORDER BY id ASC",
+        ENTRY_COLUMNS
+    ))?;
+    let entries = stmt
+        .query_map(params![pdf_sha256], row_to_entry)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(entries.into_iter().find(|entry| !entry.missing))
+}
+
+/// Other library documents with the same PDF, either byte-identical or with
+/// near-identical text (e.g. a re-downloaded arXiv version).
+pub fn find_duplicates(
+    conn: &Connection,
+    rr_path: &Path,
+    pdf_sha256: &str,
+    text_fingerprint: Option<&str>,
+) -> rusqlite::Result<Vec<DuplicateMatch>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM documents
+         WHERE path != ?1 AND (pdf_sha256 = ?2 OR (?3 IS NOT NULL AND text_fingerprint IS NOT NULL))
+         ORDER BY id ASC",
+        ENTRY_COLUMNS
+    ))?;
+    let entries = stmt
+        .query_map(
+            params![library_path(rr_path), pdf_sha256, text_fingerprint],
+            row_to_entry,
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !entry.missing)
+        .filter_map(|entry| {
+            let kind = if entry.pdf_sha256.as_deref() == Some(pdf_sha256) {
+                "identical"
+            } else {
+                let distance =
+                    fingerprint::distance(text_fingerprint?, entry.text_fingerprint.as_deref()?)?;
+                if distance > fingerprint::MAX_DISTANCE {
+                    return None;
+                }
+                "similar"
+            };
+            Some(DuplicateMatch {
+                kind: kind.to_string(),
+                entry,
+            })
+        })
+        .collect())
+}
+
+/// List library entries, filtered and sorted as requested.
+/// Without a sort, the most recently opened documents come first. Filtering
+/// by collection includes documents in its subcollections.
+pub fn list_entries(
+    conn: &Connection,
+    query: &LibraryQuery,
+) -> rusqlite::Result<Vec<LibraryEntry>> {
+    let (order, descending) = match query.sort {
+        Some(LibrarySort::Title) => ("COALESCE(title, path) COLLATE NOCASE", query.descending),
+        Some(LibrarySort::LastOpened) => ("last_opened_at", query.descending),
+        Some(LibrarySort::Added) => ("added_at", query.descending),
+        Some(LibrarySort::Annotations) => (
+            "highlight_count + note_count + bookmark_count",
+            query.descending,
+        ),
+        // Unset priorities and due dates sort after set ones
+        Some(LibrarySort::Priority) => ("COALESCE(priority, 6)", query.descending),
+        Some(LibrarySort::DueDate) => ("COALESCE(due_date, '9999-12-31')", query.descending),
+        Some(LibrarySort::Year) => ("year", query.descending),
+        None => ("COALESCE(last_opened_at, added_at)", true),
+    };
+    let direction = if descending { "DESC" } else { "ASC" };
+
+    let pattern = query
+        .text
+        .as_deref()
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("%{}%", escape_like(t)));
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM documents
+         WHERE (?1 IS NULL OR title LIKE ?1 ESCAPE '\\' OR path LIKE ?1 ESCAPE '\\'
+                OR authors LIKE ?1 ESCAPE '\\')
+           AND (?2 IS NULL OR status = ?2)
+           AND (?3 IS NULL OR id IN (
+               WITH RECURSIVE tree(id) AS (
+                   SELECT ?3
+                   UNION SELECT collections.id FROM collections
+                   JOIN tree ON collections.parent_id = tree.id
+               )
+               SELECT document_id FROM collection_documents
+               WHERE collection_id IN tree
+           ))
+         ORDER BY {} {}, id {}",
+        ENTRY_COLUMNS, order, direction, direction
+    ))?;
+    let entries = stmt
+        .query_map(
+            params![
+                pattern,
+                query.status.map(|s| s.as_str()),
+                query.collection_id
+            ],
+            row_to_entry,
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| !query.missing_only || entry.missing)
+        .collect())
+}
+
+/// Point an entry at the new location of a moved .rr file.
+/// The file must contain the same PDF as the one originally indexed.
+pub fn relocate_entry(conn: &Connection, id: i64, new_path: &Path) -> Result<LibraryEntry, String> {
+    let entry = get_entry(conn, id)
+        .map_err(|e| format!("Failed to read library entry: {}", e))?
+        .ok_or("Library entry not found")?;
+
+    let snapshot = rr_file::open_rr_readonly(new_path)?;
+    if let Some(expected) = &entry.pdf_sha256 {
+        if snapshot.pdf_sha256.as_ref() != Some(expected) {
+            return Err(format!(
+                "{} contains a different document",
+                new_path.display()
+            ));
+        }
+    }
+
+    let path = library_path(new_path);
+    if let Some(existing) =
+        get_entry_by_path(conn, &path).map_err(|e| format!("Failed to read library: {}", e))?
+    {
+        if existing.id != id {
+            return Err(format!("{} is already in the library", new_path.display()));
+        }
+    }
+
+    let stats = read_stats(&snapshot.db)?;
+    conn.execute(
+        "UPDATE documents SET path = ?1, title = ?2, pdf_sha256 = ?3, page_count = ?4,
+             last_page = ?5, highlight_count = ?6, note_count = ?7, bookmark_count = ?8,
+             text_fingerprint = ?9, authors = ?10, year = ?11, venue = ?12, doi = ?13,
+             arxiv_id = ?14
+         WHERE id = ?15",
+        params![
+            path,
+            stats.title,
+            snapshot.pdf_sha256,
+            stats.page_count,
+            stats.last_page,
+            stats.highlight_count,
+            stats.note_count,
+            stats.bookmark_count,
+            stats.text_fingerprint,
+            authors_json(&stats.bibliography.authors),
+            stats.bibliography.year,
+            stats.bibliography.venue,
+            stats.bibliography.doi,
+            stats.bibliography.arxiv_id,
+            id,
+        ],
+    )
+    .map_err(|e| format!("Failed to relocate library entry: {}", e))?;
+    search::index_document(conn, id, &snapshot.db)?;
+
+    get_entry(conn, id)
+        .map_err(|e| format!("Failed to read library entry: {}", e))?
+        .ok_or_else(|| "Library entry not found".to_string())
+}
+
+/// Remove an entry from the library. The .rr file itself is left alone.
+pub fn remove_entry(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    search::remove_document(conn, id)?;
+    references::remove_document(conn, id)?;
+    conn.execute(
+        "DELETE FROM collection_documents WHERE document_id = ?1",
+        params![id],
+    )?;
+    let affected = conn.execute("DELETE FROM documents WHERE id = ?1", params![id])?;
+    Ok(affected > 0)
+}
+
+/// Set or clear an entry's reading status.
+pub fn set_status(
+    conn: &Connection,
+    id: i64,
+    status: Option<ReadingStatus>,
+) -> rusqlite::Result<bool> {
+    let affected = conn.execute(
+        "UPDATE documents SET status = ?1 WHERE id = ?2",
+        params![status.map(|s| s.as_str()), id],
+    )?;
+    Ok(affected > 0)
+}
+
+/// Set or clear an entry's priority, from 1 (most urgent) to 5.
+pub fn set_priority(conn: &Connection, id: i64, priority: Option<u8>) -> Result<bool, String> {
+    if let Some(p) = priority {
+        if !(1..=5).contains(&p) {
+            return Err(format!("Priority must be between 1 and 5, got {}", p));
+        }
+    }
+    let affected = conn
+        .execute(
+            "UPDATE documents SET priority = ?1 WHERE id = ?2",
+            params![priority, id],
+        )
+        .map_err(|e| format!("Failed to set priority: {}", e))?;
+    Ok(affected > 0)
+}
+
+/// Set or clear an entry's due date, given as YYYY-MM-DD.
+pub fn set_due_date(conn: &Connection, id: i64, due_date: Option<&str>) -> Result<bool, String> {
+    if let Some(date) = due_date {
+        chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map_err(|e| format!("Invalid due date '{}': {}", date, e))?;
+    }
+    let affected = conn
+        .execute(
+            "UPDATE documents SET due_date = ?1 WHERE id = ?2",
+            params![due_date, id],
+        )
+        .map_err(|e| format!("Failed to set due date: {}", e))?;
+    Ok(affected > 0)
+}
+
+/// All collections, flat; `parent_id` gives the nesting.
+pub fn list_collections(conn: &Connection) -> rusqlite::Result<Vec<Collection>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, parent_id, created_at,
+             (SELECT COUNT(*) FROM collection_documents WHERE collection_id = collections.id)
+         FROM collections ORDER BY name COLLATE NOCASE, id",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok(Collection {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            parent_id: row.get(2)?,
+            created_at: row.get(3)?,
+            document_count: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn get_collection(conn: &Connection, id: i64) -> Result<Collection, String> {
+    list_collections(conn)
+        .map_err(|e| format!("Failed to read collections: {}", e))?
+        .into_iter()
+        .find(|c| c.id == id)
+        .ok_or_else(|| format!("Collection {} not found", id))
+}
+
+/// Create a collection, nested under `parent_id` if given.
+pub fn create_collection(
+    conn: &Connection,
+    name: &str,
+    parent_id: Option<i64>,
+) -> Result<Collection, String> {
+    let name = collection_name(name)?;
+    if let Some(parent) = parent_id {
+        get_collection(conn, parent)?;
+    }
+    conn.execute(
+        "INSERT INTO collections (name, parent_id, created_at) VALUES (?1, ?2, ?3)",
+        params![name, parent_id, chrono::Utc::now().to_rfc3339()],
+    )
+    .map_err(|e| format!("Failed to create collection: {}", e))?;
+    get_collection(conn, conn.last_insert_rowid())
+}
+
+pub fn rename_collection(conn: &Connection, id: i64, name: &str) -> Result<Collection, String> {
+    let name = collection_name(name)?;
+    conn.execute(
+        "UPDATE collections SET name = ?1 WHERE id = ?2",
+        params![name, id],
+    )
+    .map_err(|e| format!("Failed to rename collection: {}", e))?;
+    get_collection(conn, id)
+}
+
+/// Move a collection under a new parent, or to the top level with `None`.
+pub fn move_collection(
+    conn: &Connection,
+    id: i64,
+    parent_id: Option<i64>,
+) -> Result<Collection, String> {
+    get_collection(conn, id)?;
+    if let Some(parent) = parent_id {
+        get_collection(conn, parent)?;
+        if parent == id
+            || descendant_ids(conn, id)
+                .map_err(|e| format!("Failed to read collections: {}", e))?
+                .contains(&parent)
+        {
+            return Err("A collection can't be moved inside itself".to_string());
+        }
+    }
+    conn.execute(
+        "UPDATE collections SET parent_id = ?1 WHERE id = ?2",
+        params![parent_id, id],
+    )
+    .map_err(|e| format!("Failed to move collection: {}", e))?;
+    get_collection(conn, id)
+}
+
+/// Delete a collection and its subcollections. Documents stay in the library.
+pub fn delete_collection(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    let mut ids = descendant_ids(conn, id)?;
+    ids.push(id);
+    let tx = conn.unchecked_transaction()?;
+    let mut deleted = 0;
+    for collection_id in ids {
+        tx.execute(
+            "DELETE FROM collection_documents WHERE collection_id = ?1",
+            params![collection_id],
+        )?;
+        deleted += tx.execute(
+            "DELETE FROM collections WHERE id = ?1",
+            params![collection_id],
+        )?;
+    }
+    tx.commit()?;
+    Ok(deleted > 0)
+}
+
+/// Ids of every collection nested (at any depth) under `id`.
+fn descendant_ids(conn: &Connection, id: i64) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt = conn.prepare(
+        "WITH RECURSIVE tree(id) AS (
+             SELECT id FROM collections WHERE parent_id = ?1
+             UNION SELECT collections.id FROM collections
+             JOIN tree ON collections.parent_id = tree.id
+         )
+         SELECT id FROM tree",
+    )?;
+    let rows = stmt.query_map(params![id], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Ids of the documents in the given collections or any collection nested
+/// under them.
+pub fn collection_document_ids(
+    conn: &Connection,
+    collection_ids: &[i64],
+) -> rusqlite::Result<Vec<i64>> {
+    let mut ids = Vec::new();
+    let mut stmt =
+        conn.prepare("SELECT document_id FROM collection_documents WHERE collection_id = ?1")?;
+    for id in collection_ids {
+        for collection_id in std::iter::once(*id).chain(descendant_ids(conn, *id)?) {
+            let rows = stmt.query_map(params![collection_id], |row| row.get(0))?;
+            for row in rows {
+                ids.push(row?);
+            }
+        }
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+/// Add documents to a collection; ones already in it are left as they are.
+pub fn add_to_collection(
+    conn: &Connection,
+    collection_id: i64,
+    document_ids: &[i64],
+) -> Result<(), String> {
+    get_collection(conn, collection_id)?;
+    let now = chrono::Utc::now().to_rfc3339();
+    for document_id in document_ids {
+        get_entry(conn, *document_id)
+            .map_err(|e| format!("Failed to read library entry: {}", e))?
+            .ok_or_else(|| format!("Library entry {} not found", document_id))?;
+        conn.execute(
+            "INSERT OR IGNORE INTO collection_documents (collection_id, document_id, added_at)
+             VALUES (?1, ?2, ?3)",
+            params![collection_id, document_id, now],
+        )
+        .map_err(|e| format!("Failed to add to collection: {}", e))?;
+    }
+    Ok(())
+}
+
+pub fn remove_from_collection(
+    conn: &Connection,
+    collection_id: i64,
+    document_ids: &[i64],
+) -> rusqlite::Result<()> {
+    for document_id in document_ids {
+        conn.execute(
+            "DELETE FROM collection_documents WHERE collection_id = ?1 AND document_id = ?2",
+            params![collection_id, document_id],
+        )?;
+    }
+    Ok(())
+}
+
+fn collection_name(name: &str) -> Result<&str, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Collection name can't be empty".to_string());
+    }
+    Ok(name)
+}
+
+fn row_to_entry(row: &rusqlite::Row) -> rusqlite::Result<LibraryEntry> {
+    let path: String = row.get(1)?;
+    let missing = !Path::new(&path).exists();
+    let status: Option<String> = row.get(11)?;
+    let authors: Option<String> = row.get(15)?;
+    let collection_ids: Option<String> = row.get(21)?;
+    Ok(LibraryEntry {
+        id: row.get(0)?,
+        path,
+        title: row.get(2)?,
+        pdf_sha256: row.get(3)?,
+        page_count: row.get(4)?,
+        last_page: row.get(5)?,
+        highlight_count: row.get(6)?,
+        note_count: row.get(7)?,
+        bookmark_count: row.get(8)?,
+        added_at: row.get(9)?,
+        last_opened_at: row.get(10)?,
+        status: status.and_then(|s| s.parse().ok()),
+        priority: row.get(12)?,
+        due_date: row.get(13)?,
+        text_fingerprint: row.get(14)?,
+        authors: authors
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default(),
+        year: row.get(16)?,
+        venue: row.get(17)?,
+        doi: row.get(18)?,
+        arxiv_id: row.get(19)?,
+        citation_key: row.get(20)?,
+        collection_ids: collection_ids
+            .map(|ids| ids.split(',').filter_map(|id| id.parse().ok()).collect())
+            .unwrap_or_default(),
+        missing,
+    })
+}
+
+fn read_stats(db: &Connection) -> Result<DocumentStats, String> {
+    let metadata = |key: &str| {
+        database::get_metadata(db, key).map_err(|e| format!("Failed to read {}: {}", key, e))
+    };
+    let mut stats = DocumentStats {
+        title: metadata("title")?,
+        page_count: metadata("page_count")?.and_then(|s| s.parse().ok()),
+        last_page: metadata("last_page")?.and_then(|s| s.parse().ok()),
+        highlight_count: 0,
+        note_count: 0,
+        bookmark_count: 0,
+        text_fingerprint: metadata(fingerprint::METADATA_KEY)?,
+        bibliography: bibliography::get_record(db)?,
+    };
+    for (annotation_type, count) in database::count_annotations_by_type(db)
+        .map_err(|e| format!("Failed to count annotations: {}", e))?
+    {
+        match annotation_type {
+            AnnotationType::Highlight => stats.highlight_count = count,
+            AnnotationType::Note => stats.note_count = count,
+            AnnotationType::Bookmark => stats.bookmark_count = count,
+        }
+    }
+    Ok(stats)
+}
+
+/// Absolute form of `path`, so the same file opened two ways maps to one entry.
+fn library_path(path: &Path) -> String {
+    fs::canonicalize(path)
+        .unwrap_or_else(|_| PathBuf::from(path))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn authors_json(authors: &[String]) -> Option<String> {
+    (!authors.is_empty()).then(|| serde_json::to_string(authors).unwrap_or_default())
+}
+
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
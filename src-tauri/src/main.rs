@@ -1,6 +1,42 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use app_lib::cli::{self, CliAction};
+
 fn main() {
-  app_lib::run();
+  let cwd = std::env::current_dir().unwrap_or_default();
+  match cli::parse(std::env::args().skip(1), &cwd) {
+    Ok(CliAction::Open(target)) => app_lib::run_with(target),
+    Ok(CliAction::Help) => {
+      attach_console();
+      println!("{}", cli::USAGE);
+    }
+    Ok(CliAction::Version) => {
+      attach_console();
+      println!("vellum {}", env!("CARGO_PKG_VERSION"));
+    }
+    Err(e) => {
+      attach_console();
+      eprintln!("vellum: {}\n\n{}", e, cli::USAGE);
+      std::process::exit(2);
+    }
+  }
+}
+
+/// Release builds on Windows have no console of their own, so print to the
+/// one of the terminal the app was started from, if any
+#[cfg(windows)]
+fn attach_console() {
+  const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+  #[link(name = "kernel32")]
+  extern "system" {
+    fn AttachConsole(process_id: u32) -> i32;
+  }
+  // Fails harmlessly when there is no parent console or one is attached already
+  unsafe {
+    AttachConsole(ATTACH_PARENT_PROCESS);
+  }
 }
+
+#[cfg(not(windows))]
+fn attach_console() {}
@@ -0,0 +1,983 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::crdt;
+use crate::database;
+use crate::models::*;
+use crate::rr_file::{self, RrSession};
+use crate::sidecar::is_newer;
+
+/// Merge annotations from another .rr of the same document into the session.
+///
+/// The other container is opened read-only and must hold a byte-identical PDF.
+/// When the other copy carries annotation history the CRDT documents are
+/// merged, so edits made on one side only are taken as they are; annotations
+/// both sides changed since they diverged are resolved according to
+/// `strategy`, as is an edit on one side of an annotation deleted on the other.
+/// Otherwise annotations are matched by id: new ones are added, identical ones
+/// are skipped, and ones that differ are resolved according to `strategy`.
+pub fn merge_from(
+    session: &RrSession,
+    other_path: &Path,
+    strategy: MergeStrategy,
+) -> Result<MergeSummary, String> {
+    let other = rr_file::open_rr_readonly(other_path)?;
+    let our_hash = rr_file::sha256_file(&session.pdf_path())?;
+    match &other.pdf_sha256 {
+        Some(hash) if hash.eq_ignore_ascii_case(&our_hash) => {}
+        Some(_) => return Err("Cannot merge: the other file contains a different PDF".to_string()),
+        None => return Err("Cannot merge: the other file has no document.pdf".to_string()),
+    }
+    merge_snapshot(session, &other, strategy)
+}
+
+/// Merge annotations from a copy of the same paper whose PDF bytes differ,
+/// such as a re-downloaded version. Annotation positions are taken as they
+/// are, so this is only meant for documents with near-identical text.
+pub fn merge_from_duplicate(
+    session: &RrSession,
+    other_path: &Path,
+    strategy: MergeStrategy,
+) -> Result<MergeSummary, String> {
+    let other = rr_file::open_rr_readonly(other_path)?;
+    merge_snapshot(session, &other, strategy)
+}
+
+fn merge_snapshot(
+    session: &RrSession,
+    other: &rr_file::RrSnapshot,
+    strategy: MergeStrategy,
+) -> Result<MergeSummary, String> {
+    let tx = session
+        .db
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    let ours = annotations_by_id(&tx)?;
+    let summary = match crdt::merge_from_db(&tx, &other.db)? {
+        Some((_, concurrent)) => {
+            let theirs = annotations_by_id(&other.db)?;
+            let summary =
+                resolve_concurrent(&tx, &ours, &theirs, None, &other.db, &concurrent, strategy)
+                    .map_err(|e| format!("Failed to merge annotations: {}", e))?;
+            crdt::capture(&tx)?;
+            summary
+        }
+        None => {
+            let theirs = database::get_annotations(&other.db, None)
+                .map_err(|e| format!("Failed to read annotations to merge: {}", e))?;
+            let summary = merge_annotations(&tx, &ours, theirs, strategy)
+                .map_err(|e| format!("Failed to merge annotations: {}", e))?;
+            crdt::capture(&tx)?;
+            summary
+        }
+    };
+    tx.commit()
+        .map_err(|e| format!("Failed to commit merge: {}", e))?;
+
+    Ok(summary)
+}
+
+fn annotations_by_id(conn: &rusqlite::Connection) -> Result<HashMap<String, Annotation>, String> {
+    Ok(database::get_annotations(conn, None)
+        .map_err(|e| format!("Failed to get annotations: {}", e))?
+        .into_iter()
+        .map(|a| (a.id.clone(), a))
+        .collect())
+}
+
+fn merge_annotations(
+    conn: &rusqlite::Connection,
+    ours: &HashMap<String, Annotation>,
+    theirs: Vec<Annotation>,
+    strategy: MergeStrategy,
+) -> rusqlite::Result<MergeSummary> {
+    let mut summary = MergeSummary::default();
+
+    for their in theirs {
+        let Some(our) = ours.get(&their.id) else {
+            database::upsert_annotation(conn, &their)?;
+            summary.added.push(their.id);
+            continue;
+        };
+
+        if same_content(our, &their) {
+            summary.unchanged += 1;
+            continue;
+        }
+
+        match strategy {
+            MergeStrategy::NewerWins if is_newer(&their.updated_at, &our.updated_at) => {
+                database::upsert_annotation(conn, &their)?;
+                summary.updated.push(their.id);
+            }
+            MergeStrategy::NewerWins => summary.conflicts.push(MergeConflict {
+                id: their.id,
+                resolution: "kept_ours".to_string(),
+                copy_id: None,
+            }),
+            MergeStrategy::KeepBoth => {
+                let copy = Annotation {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    ..their.clone()
+                };
+                database::upsert_annotation(conn, &copy)?;
+                summary.conflicts.push(MergeConflict {
+                    id: their.id,
+                    resolution: "kept_both".to_string(),
+                    copy_id: Some(copy.id),
+                });
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Apply `strategy` to the annotations both copies changed after a CRDT merge,
+/// then summarise the merge against `ours`, the table before it.
+///
+/// Where both sides edited an annotation, NewerWins keeps the whole version
+/// with the later `updated_at` and KeepBoth keeps ours plus theirs under a new
+/// id. Where one side edited what the other deleted, KeepBoth keeps the edit
+/// and NewerWins keeps it unless the deletion came later. A side that still
+/// matches `base`, the last common version, didn't change the annotation, so
+/// the other side is taken without a conflict.
+fn resolve_concurrent(
+    conn: &rusqlite::Connection,
+    ours: &HashMap<String, Annotation>,
+    theirs: &HashMap<String, Annotation>,
+    base: Option<&HashMap<String, Annotation>>,
+    their_db: &rusqlite::Connection,
+    concurrent: &[String],
+    strategy: MergeStrategy,
+) -> rusqlite::Result<MergeSummary> {
+    let our_tombstones = database::get_tombstones(conn, "annotations")?;
+    let their_tombstones = database::get_tombstones(their_db, "annotations")?;
+    let put = |annotation: &Annotation| {
+        database::upsert_annotation(conn, annotation)
+            .and_then(|_| database::clear_tombstone(conn, "annotations", &annotation.id))
+    };
+
+    let mut conflicts = Vec::new();
+    for id in concurrent {
+        let conflict = |resolution: &str, copy_id: Option<String>| MergeConflict {
+            id: id.clone(),
+            resolution: resolution.to_string(),
+            copy_id,
+        };
+        let unchanged = |annotation: &Annotation| {
+            base.and_then(|base| base.get(id))
+                .is_some_and(|b| same_content(b, annotation))
+        };
+        match (ours.get(id), theirs.get(id)) {
+            (Some(our), Some(their)) if same_content(our, their) => {}
+            (Some(our), Some(their)) if unchanged(our) => put(their)?,
+            (Some(our), Some(their)) if unchanged(their) => put(our)?,
+            // Deleted on one side, left alone on the other
+            (Some(kept), None) | (None, Some(kept)) if unchanged(kept) => {
+                database::delete_annotation(conn, id)?;
+            }
+            (Some(our), Some(their)) => match strategy {
+                MergeStrategy::NewerWins if is_newer(&their.updated_at, &our.updated_at) => {
+                    put(their)?;
+                }
+                MergeStrategy::NewerWins => {
+                    put(our)?;
+                    conflicts.push(conflict("kept_ours", None));
+                }
+                MergeStrategy::KeepBoth => {
+                    let copy = Annotation {
+                        id: uuid::Uuid::new_v4().to_string(),
+                        ..their.clone()
+                    };
+                    put(our)?;
+                    put(&copy)?;
+                    conflicts.push(conflict("kept_both", Some(copy.id)));
+                }
+            },
+            (Some(edited), None) | (None, Some(edited)) => {
+                let tombstones = if ours.contains_key(id) {
+                    &their_tombstones
+                } else {
+                    &our_tombstones
+                };
+                let keep = match (strategy, tombstones.get(id)) {
+                    (MergeStrategy::NewerWins, Some(deleted_at)) => {
+                        is_newer(&edited.updated_at, deleted_at)
+                    }
+                    _ => true,
+                };
+                if keep {
+                    put(edited)?;
+                    conflicts.push(conflict("kept_edit", None));
+                } else {
+                    database::delete_annotation(conn, id)?;
+                    conflicts.push(conflict("deleted", None));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let copies: Vec<String> = conflicts.iter().filter_map(|c| c.copy_id.clone()).collect();
+    let conflicted: Vec<String> = conflicts.iter().map(|c| c.id.clone()).collect();
+    let mut summary = MergeSummary {
+        conflicts,
+        ..MergeSummary::default()
+    };
+    let merged = database::get_annotations(conn, None)?;
+    for annotation in &merged {
+        match ours.get(&annotation.id) {
+            _ if copies.contains(&annotation.id) => {}
+            // Kept on a conflict, which is reported as such
+            Some(our) if our == annotation && conflicted.contains(&annotation.id) => {}
+            Some(our) if our == annotation => summary.unchanged += 1,
+            Some(_) => summary.updated.push(annotation.id.clone()),
+            None => summary.added.push(annotation.id.clone()),
+        }
+    }
+    let mut deleted: Vec<String> = ours
+        .keys()
+        .filter(|id| !merged.iter().any(|a| &a.id == *id))
+        .cloned()
+        .collect();
+    deleted.sort();
+    summary.deleted = deleted;
+    Ok(summary)
+}
+
+/// Whether two versions of an annotation carry the same user-visible data.
+fn same_content(a: &Annotation, b: &Annotation) -> bool {
+    a.annotation_type == b.annotation_type
+        && a.page_number == b.page_number
+        && a.color == b.color
+        && a.content == b.content
+        && a.position_data == b.position_data
+}
+
+/// What to do with one row of the merged copy (which starts out as ours).
+enum Action<T> {
+    Keep,
+    Put(T),
+    Delete,
+}
+
+/// Three-way decision for a single row, identified by id across all three copies.
+///
+/// A row missing on one side counts as deleted there if the base had it or that
+/// side left a tombstone. Changes made on only one side win; when both sides
+/// changed a row differently (or one edited what the other deleted) the
+/// conflict is resolved by modification time, keeping ours on a tie or when
+/// rows carry no timestamp. Returns the action and whether it was a conflict.
+fn decide<T: PartialEq + Clone>(
+    base: Option<&T>,
+    ours: Option<&T>,
+    theirs: Option<&T>,
+    our_tombstone: Option<&String>,
+    their_tombstone: Option<&String>,
+    modified: fn(&T) -> Option<&str>,
+) -> (Action<T>, bool) {
+    let changed_since = |row: &T, deleted_at: Option<&String>| match (modified(row), deleted_at) {
+        (Some(m), Some(d)) => is_newer(m, d),
+        _ => false,
+    };
+
+    match (ours, theirs) {
+        (Some(o), Some(t)) => {
+            if o == t || base == Some(t) {
+                (Action::Keep, false)
+            } else if base == Some(o) {
+                (Action::Put(t.clone()), false)
+            } else {
+                let theirs_newer = match (modified(t), modified(o)) {
+                    (Some(t_at), Some(o_at)) => is_newer(t_at, o_at),
+                    _ => false,
+                };
+                if theirs_newer {
+                    (Action::Put(t.clone()), true)
+                } else {
+                    (Action::Keep, true)
+                }
+            }
+        }
+        (Some(o), None) => {
+            if base.is_none() && their_tombstone.is_none() {
+                // Added on our side only
+                (Action::Keep, false)
+            } else if base == Some(o) {
+                (Action::Delete, false)
+            } else if base.is_some() || changed_since(o, their_tombstone) {
+                // We edited what they deleted
+                (Action::Keep, true)
+            } else {
+                (Action::Delete, false)
+            }
+        }
+        (None, Some(t)) => {
+            if base.is_none() && our_tombstone.is_none() {
+                (Action::Put(t.clone()), false)
+            } else if base == Some(t) {
+                (Action::Keep, false)
+            } else if base.is_some() || changed_since(t, our_tombstone) {
+                // They edited what we deleted
+                (Action::Put(t.clone()), true)
+            } else {
+                (Action::Keep, false)
+            }
+        }
+        (None, None) => (Action::Keep, false),
+    }
+}
+
+/// Merge one table given each copy's rows and tombstones, applying the result
+/// through `put` / `delete` on the merged copy.
+#[allow(clippy::too_many_arguments)]
+fn merge_table<T: PartialEq + Clone>(
+    base: &HashMap<String, T>,
+    ours: &HashMap<String, T>,
+    theirs: &HashMap<String, T>,
+    our_tombstones: &HashMap<String, String>,
+    their_tombstones: &HashMap<String, String>,
+    modified: fn(&T) -> Option<&str>,
+    mut put: impl FnMut(&str, &T) -> rusqlite::Result<()>,
+    mut delete: impl FnMut(&str) -> rusqlite::Result<()>,
+) -> rusqlite::Result<TableMergeSummary> {
+    let mut ids: Vec<&String> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+    ids.sort();
+    ids.dedup();
+
+    let mut summary = TableMergeSummary::default();
+    for id in ids {
+        let (action, conflict) = decide(
+            base.get(id),
+            ours.get(id),
+            theirs.get(id),
+            our_tombstones.get(id),
+            their_tombstones.get(id),
+            modified,
+        );
+        match action {
+            Action::Keep => {}
+            Action::Put(row) => {
+                put(id, &row)?;
+                if ours.contains_key(id) {
+                    summary.updated += 1;
+                } else {
+                    summary.added += 1;
+                }
+            }
+            Action::Delete => {
+                delete(id)?;
+                summary.deleted += 1;
+            }
+        }
+        if conflict {
+            summary.conflicts.push(id.clone());
+        }
+    }
+    Ok(summary)
+}
+
+/// Three-way merge of .rr files, e.g. a sync tool's "conflicted copy".
+///
+/// `base` is the last common version, `ours` and `theirs` the diverged copies;
+/// all three must contain the same PDF. Metadata and conversations are merged
+/// row by row; annotations too, unless theirs carries annotation history, in
+/// which case the CRDT documents are merged instead. Either way, annotations
+/// both sides changed are resolved according to `strategy`, as in
+/// `merge_from`. The result, with ours' PDF, is written to `output` (which may
+/// be `ours` itself). Tombstones from both sides are carried over.
+pub fn merge_three_way(
+    base_path: &Path,
+    ours_path: &Path,
+    theirs_path: &Path,
+    output_path: &Path,
+    strategy: MergeStrategy,
+) -> Result<ThreeWayMergeSummary, String> {
+    let base = rr_file::open_rr_readonly(base_path)?;
+    let theirs = rr_file::open_rr_readonly(theirs_path)?;
+    let mut ours = rr_file::open_rr(ours_path)?;
+    ours.rr_path = output_path.to_path_buf();
+
+    let result = merge_into(&ours, &base, &theirs, strategy).and_then(|summary| {
+        rr_file::save_rr(&ours)?;
+        Ok(summary)
+    });
+    rr_file::cleanup_session(&ours);
+    result
+}
+
+fn merge_into(
+    ours: &RrSession,
+    base: &rr_file::RrSnapshot,
+    theirs: &rr_file::RrSnapshot,
+    strategy: MergeStrategy,
+) -> Result<ThreeWayMergeSummary, String> {
+    let our_hash = rr_file::sha256_file(&ours.pdf_path())?;
+    for (name, snapshot) in [("base", base), ("their", theirs)] {
+        if !snapshot
+            .pdf_sha256
+            .as_deref()
+            .is_some_and(|h| h.eq_ignore_ascii_case(&our_hash))
+        {
+            return Err(format!(
+                "Cannot merge: the {} copy contains a different PDF",
+                name
+            ));
+        }
+    }
+
+    let tx = ours
+        .db
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    let ours_before = annotations_by_id(&tx)?;
+    let (mut summary, concurrent) = match crdt::merge_from_db(&tx, &theirs.db)? {
+        Some((_, concurrent)) => (
+            merge_tables(&tx, &base.db, &theirs.db, false)
+                .map_err(|e| format!("Failed to merge: {}", e))?,
+            concurrent,
+        ),
+        None => {
+            // The row-level merge settles conflicts by time; apply the
+            // strategy to them as to concurrent CRDT edits
+            let summary = merge_tables(&tx, &base.db, &theirs.db, true)
+                .map_err(|e| format!("Failed to merge: {}", e))?;
+            let conflicts = summary.annotations.conflicts.clone();
+            (summary, conflicts)
+        }
+    };
+    let annotations = resolve_concurrent(
+        &tx,
+        &ours_before,
+        &annotations_by_id(&theirs.db)?,
+        Some(&annotations_by_id(&base.db)?),
+        &theirs.db,
+        &concurrent,
+        strategy,
+    )
+    .map_err(|e| format!("Failed to merge annotations: {}", e))?;
+    crdt::capture(&tx)?;
+    summary.annotations = TableMergeSummary {
+        // Copies kept alongside conflicting annotations count as added
+        added: annotations.added.len()
+            + annotations
+                .conflicts
+                .iter()
+                .filter(|c| c.copy_id.is_some())
+                .count(),
+        updated: annotations.updated.len(),
+        deleted: annotations.deleted.len(),
+        conflicts: annotations.conflicts.into_iter().map(|c| c.id).collect(),
+    };
+    tx.commit()
+        .map_err(|e| format!("Failed to commit merge: {}", e))?;
+    Ok(summary)
+}
+
+/// Row-level merge of each table into `merged`. Annotations are skipped unless
+/// `include_annotations` is set (they've been merged through their CRDT documents).
+fn merge_tables(
+    merged: &rusqlite::Connection,
+    base: &rusqlite::Connection,
+    theirs: &rusqlite::Connection,
+    include_annotations: bool,
+) -> rusqlite::Result<ThreeWayMergeSummary> {
+    fn by_id<T>(rows: Vec<T>, id: fn(&T) -> &String) -> HashMap<String, T> {
+        rows.into_iter().map(|r| (id(&r).clone(), r)).collect()
+    }
+    fn annotation_rows(
+        conn: &rusqlite::Connection,
+    ) -> rusqlite::Result<HashMap<String, Annotation>> {
+        Ok(by_id(database::get_annotations(conn, None)?, |a| &a.id))
+    }
+    fn conversations(
+        conn: &rusqlite::Connection,
+    ) -> rusqlite::Result<HashMap<String, ConversationMessage>> {
+        Ok(by_id(database::get_conversation_messages(conn)?, |m| &m.id))
+    }
+    fn metadata(conn: &rusqlite::Connection) -> rusqlite::Result<HashMap<String, String>> {
+        Ok(database::get_all_metadata(conn)?.into_iter().collect())
+    }
+
+    let our_annotation_tombstones = database::get_tombstones(merged, "annotations")?;
+    let their_annotation_tombstones = database::get_tombstones(theirs, "annotations")?;
+    let annotation_summary = if include_annotations {
+        merge_table(
+            &annotation_rows(base)?,
+            &annotation_rows(merged)?,
+            &annotation_rows(theirs)?,
+            &our_annotation_tombstones,
+            &their_annotation_tombstones,
+            |a| Some(a.updated_at.as_str()),
+            |_, a| {
+                database::upsert_annotation(merged, a)?;
+                database::clear_tombstone(merged, "annotations", &a.id)
+            },
+            |id| database::delete_annotation(merged, id).map(|_| ()),
+        )?
+    } else {
+        TableMergeSummary::default()
+    };
+
+    let our_conversation_tombstones = database::get_tombstones(merged, "conversations")?;
+    let their_conversation_tombstones = database::get_tombstones(theirs, "conversations")?;
+    let conversation_summary = merge_table(
+        &conversations(base)?,
+        &conversations(merged)?,
+        &conversations(theirs)?,
+        &our_conversation_tombstones,
+        &their_conversation_tombstones,
+        |m| Some(m.created_at.as_str()),
+        |_, m| {
+            database::upsert_conversation_message(merged, m)?;
+            database::clear_tombstone(merged, "conversations", &m.id)
+        },
+        |id| database::delete_conversation_message(merged, id).map(|_| ()),
+    )?;
+
+    // Metadata values carry no timestamps or tombstones, so only the base tells
+    // us about deletions and ours wins genuine conflicts.
+    let no_tombstones = HashMap::new();
+    let metadata_summary = merge_table(
+        &metadata(base)?,
+        &metadata(merged)?,
+        &metadata(theirs)?,
+        &no_tombstones,
+        &no_tombstones,
+        |_| None,
+        |key, value| database::set_metadata(merged, key, value),
+        |key| database::delete_metadata(merged, key).map(|_| ()),
+    )?;
+
+    // Keep their deletions on record so later merges see them too
+    for (table, tombstones) in [
+        ("annotations", &their_annotation_tombstones),
+        ("conversations", &their_conversation_tombstones),
+    ] {
+        for (id, deleted_at) in tombstones {
+            let still_present: i64 = merged.query_row(
+                &format!("SELECT COUNT(*) FROM {} WHERE id = ?1", table),
+                [id],
+                |row| row.get(0),
+            )?;
+            if still_present == 0 {
+                database::record_tombstone(merged, table, id, deleted_at)?;
+            }
+        }
+    }
+
+    Ok(ThreeWayMergeSummary {
+        annotations: annotation_summary,
+        metadata: metadata_summary,
+        conversations: conversation_summary,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    /// Two saved copies of the same document holding one note, opened as
+    /// sessions. With `history` both first gain their annotation documents.
+    fn two_copies(history: bool) -> (TempDir, RrSession, RrSession, Annotation) {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let a = rr_file::import_pdf(&pdf, Some(&dir.path().join("a.rr"))).unwrap();
+        let note = database::create_annotation(
+            &a.db,
+            &CreateAnnotationInput {
+                annotation_type: AnnotationType::Note,
+                page_number: 1,
+                color: None,
+                content: Some("base".to_string()),
+                position_data: None,
+            },
+        )
+        .unwrap();
+        rr_file::save_rr(&a).unwrap();
+        std::fs::copy(dir.path().join("a.rr"), dir.path().join("b.rr")).unwrap();
+        let b = rr_file::open_rr(&dir.path().join("b.rr")).unwrap();
+        if history {
+            crdt::capture(&a.db).unwrap();
+            crdt::capture(&b.db).unwrap();
+        }
+        (dir, a, b, note)
+    }
+
+    fn edit(session: &RrSession, id: &str, content: &str) {
+        let input = UpdateAnnotationInput {
+            id: id.to_string(),
+            color: None,
+            content: Some(content.to_string()),
+            position_data: None,
+        };
+        assert!(database::update_annotation(&session.db, &input).unwrap());
+        record(session, id);
+    }
+
+    fn delete(session: &RrSession, id: &str) {
+        database::delete_annotation(&session.db, id).unwrap();
+        record(session, id);
+    }
+
+    /// Record an edit the way the app does, unless the copy is meant to
+    /// predate annotation history
+    fn record(session: &RrSession, id: &str) {
+        if crdt::AnnotationDoc::load_existing(&session.db)
+            .unwrap()
+            .is_some()
+        {
+            crdt::record(&session.db, id).unwrap();
+        }
+    }
+
+    /// Save `from` and merge it into `into`
+    fn merge(into: &RrSession, from: &RrSession, strategy: MergeStrategy) -> MergeSummary {
+        rr_file::save_rr(from).unwrap();
+        merge_from(into, &from.rr_path, strategy).unwrap()
+    }
+
+    fn content(session: &RrSession, id: &str) -> Option<String> {
+        database::get_annotation(&session.db, id)
+            .unwrap()
+            .and_then(|a| a.content)
+    }
+
+    fn resolutions(summary: &MergeSummary) -> Vec<&str> {
+        summary
+            .conflicts
+            .iter()
+            .map(|c| c.resolution.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn edits_on_one_side_are_taken_with_either_strategy() {
+        for strategy in [MergeStrategy::NewerWins, MergeStrategy::KeepBoth] {
+            let (_dir, a, b, note) = two_copies(true);
+            edit(&b, &note.id, "edited");
+            let summary = merge(&a, &b, strategy);
+            assert_eq!(summary.updated, vec![note.id.clone()]);
+            assert!(summary.conflicts.is_empty());
+            assert_eq!(content(&a, &note.id).as_deref(), Some("edited"));
+        }
+    }
+
+    #[test]
+    fn new_annotations_are_added() {
+        let (_dir, a, b, _) = two_copies(true);
+        let added = database::create_annotation(
+            &b.db,
+            &CreateAnnotationInput {
+                annotation_type: AnnotationType::Bookmark,
+                page_number: 3,
+                color: None,
+                content: None,
+                position_data: None,
+            },
+        )
+        .unwrap();
+        record(&b, &added.id);
+        let summary = merge(&a, &b, MergeStrategy::NewerWins);
+        assert_eq!(summary.added, vec![added.id.clone()]);
+        assert_eq!(summary.unchanged, 1);
+        assert!(database::get_annotation(&a.db, &added.id)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn both_edited_newer_wins() {
+        for history in [false, true] {
+            // Theirs is newer
+            let (_dir, a, b, note) = two_copies(history);
+            edit(&a, &note.id, "ours");
+            edit(&b, &note.id, "theirs");
+            let summary = merge(&a, &b, MergeStrategy::NewerWins);
+            assert_eq!(summary.updated, vec![note.id.clone()]);
+            assert_eq!(content(&a, &note.id).as_deref(), Some("theirs"));
+
+            // Ours is newer
+            let (_dir, a, b, note) = two_copies(history);
+            edit(&b, &note.id, "theirs");
+            edit(&a, &note.id, "ours");
+            let summary = merge(&a, &b, MergeStrategy::NewerWins);
+            assert_eq!(resolutions(&summary), ["kept_ours"]);
+            assert_eq!(content(&a, &note.id).as_deref(), Some("ours"));
+        }
+    }
+
+    #[test]
+    fn both_edited_keep_both() {
+        for history in [false, true] {
+            let (_dir, a, b, note) = two_copies(history);
+            edit(&a, &note.id, "ours");
+            edit(&b, &note.id, "theirs");
+            let summary = merge(&a, &b, MergeStrategy::KeepBoth);
+            assert_eq!(resolutions(&summary), ["kept_both"]);
+            assert!(summary.added.is_empty());
+            let copy_id = summary.conflicts[0].copy_id.clone().unwrap();
+            assert_eq!(content(&a, &note.id).as_deref(), Some("ours"));
+            assert_eq!(content(&a, &copy_id).as_deref(), Some("theirs"));
+        }
+    }
+
+    #[test]
+    fn merged_history_keeps_the_resolution() {
+        let (_dir, a, b, note) = two_copies(true);
+        edit(&b, &note.id, "theirs");
+        edit(&a, &note.id, "ours");
+        merge(&a, &b, MergeStrategy::NewerWins);
+        // The resolution was recorded, so the document agrees with the table
+        let doc = crdt::AnnotationDoc::load(&a.db).unwrap();
+        assert_eq!(
+            doc.annotation(&note.id).and_then(|a| a.content).as_deref(),
+            Some("ours")
+        );
+    }
+
+    #[test]
+    fn edit_against_delete() {
+        // We edit, they delete later: the deletion is newer
+        let (_dir, a, b, note) = two_copies(true);
+        edit(&a, &note.id, "ours");
+        delete(&b, &note.id);
+        let summary = merge(&a, &b, MergeStrategy::NewerWins);
+        assert_eq!(resolutions(&summary), ["deleted"]);
+        assert_eq!(summary.deleted, vec![note.id.clone()]);
+        assert!(database::get_annotation(&a.db, &note.id).unwrap().is_none());
+
+        // Keeping both keeps the edit
+        let (_dir, a, b, note) = two_copies(true);
+        edit(&a, &note.id, "ours");
+        delete(&b, &note.id);
+        let summary = merge(&a, &b, MergeStrategy::KeepBoth);
+        assert_eq!(resolutions(&summary), ["kept_edit"]);
+        assert!(summary.deleted.is_empty());
+        assert_eq!(content(&a, &note.id).as_deref(), Some("ours"));
+
+        // They edit after we deleted: the edit is newer
+        let (_dir, a, b, note) = two_copies(true);
+        delete(&a, &note.id);
+        edit(&b, &note.id, "theirs");
+        let summary = merge(&a, &b, MergeStrategy::NewerWins);
+        assert_eq!(resolutions(&summary), ["kept_edit"]);
+        assert_eq!(summary.added, vec![note.id.clone()]);
+        assert_eq!(content(&a, &note.id).as_deref(), Some("theirs"));
+    }
+
+    #[test]
+    fn unedited_deletions_are_taken() {
+        let (_dir, a, b, note) = two_copies(true);
+        delete(&b, &note.id);
+        let summary = merge(&a, &b, MergeStrategy::KeepBoth);
+        assert!(summary.conflicts.is_empty());
+        assert_eq!(summary.deleted, vec![note.id.clone()]);
+    }
+
+    /// Like `two_copies`, plus the version both diverged from saved as base.rr
+    fn three_copies(history: bool) -> (TempDir, PathBuf, RrSession, RrSession, Annotation) {
+        let (dir, a, b, note) = two_copies(history);
+        let base = dir.path().join("base.rr");
+        std::fs::copy(dir.path().join("a.rr"), &base).unwrap();
+        (dir, base, a, b, note)
+    }
+
+    /// Save both copies, merge them against `base` into merged.rr and open it
+    fn merge3(
+        base: &Path,
+        ours: &RrSession,
+        theirs: &RrSession,
+        strategy: MergeStrategy,
+    ) -> (ThreeWayMergeSummary, RrSession) {
+        rr_file::save_rr(ours).unwrap();
+        rr_file::save_rr(theirs).unwrap();
+        let output = ours.rr_path.with_file_name("merged.rr");
+        let summary =
+            merge_three_way(base, &ours.rr_path, &theirs.rr_path, &output, strategy).unwrap();
+        (summary, rr_file::open_rr(&output).unwrap())
+    }
+
+    fn count(session: &RrSession) -> usize {
+        database::get_annotations(&session.db, None).unwrap().len()
+    }
+
+    #[test]
+    fn three_way_both_edited() {
+        for history in [false, true] {
+            // Theirs is newer
+            let (_dir, base, a, b, note) = three_copies(history);
+            edit(&a, &note.id, "ours");
+            edit(&b, &note.id, "theirs");
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::NewerWins);
+            assert_eq!(summary.annotations.updated, 1);
+            assert!(summary.annotations.conflicts.is_empty());
+            assert_eq!(content(&merged, &note.id).as_deref(), Some("theirs"));
+
+            // Ours is newer
+            let (_dir, base, a, b, note) = three_copies(history);
+            edit(&b, &note.id, "theirs");
+            edit(&a, &note.id, "ours");
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::NewerWins);
+            assert_eq!(summary.annotations.conflicts, vec![note.id.clone()]);
+            assert_eq!(content(&merged, &note.id).as_deref(), Some("ours"));
+
+            // Keeping both adds theirs as a copy
+            let (_dir, base, a, b, note) = three_copies(history);
+            edit(&a, &note.id, "ours");
+            edit(&b, &note.id, "theirs");
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::KeepBoth);
+            assert_eq!(summary.annotations.conflicts, vec![note.id.clone()]);
+            assert_eq!(summary.annotations.added, 1);
+            assert_eq!(content(&merged, &note.id).as_deref(), Some("ours"));
+            let contents: Vec<Option<String>> = database::get_annotations(&merged.db, None)
+                .unwrap()
+                .into_iter()
+                .map(|a| a.content)
+                .collect();
+            assert!(contents.contains(&Some("theirs".to_string())));
+            assert_eq!(contents.len(), 2);
+        }
+    }
+
+    #[test]
+    fn three_way_edit_reverted_to_base_is_not_a_conflict() {
+        for history in [false, true] {
+            let (_dir, base, a, b, note) = three_copies(history);
+            edit(&a, &note.id, "draft");
+            edit(&b, &note.id, "theirs");
+            edit(&a, &note.id, "base");
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::KeepBoth);
+            assert!(summary.annotations.conflicts.is_empty());
+            assert_eq!(content(&merged, &note.id).as_deref(), Some("theirs"));
+            assert_eq!(count(&merged), 1);
+        }
+    }
+
+    #[test]
+    fn three_way_edit_against_delete() {
+        for history in [false, true] {
+            // We edit, they delete later: the tombstone is newer
+            let (_dir, base, a, b, note) = three_copies(history);
+            edit(&a, &note.id, "ours");
+            delete(&b, &note.id);
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::NewerWins);
+            assert_eq!(summary.annotations.conflicts, vec![note.id.clone()]);
+            assert_eq!(summary.annotations.deleted, 1);
+            assert_eq!(count(&merged), 0);
+            let tombstones = database::get_tombstones(&merged.db, "annotations").unwrap();
+            assert!(tombstones.contains_key(&note.id));
+
+            // Keeping both keeps the edit
+            let (_dir, base, a, b, note) = three_copies(history);
+            edit(&a, &note.id, "ours");
+            delete(&b, &note.id);
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::KeepBoth);
+            assert_eq!(summary.annotations.conflicts, vec![note.id.clone()]);
+            assert_eq!(summary.annotations.deleted, 0);
+            assert_eq!(content(&merged, &note.id).as_deref(), Some("ours"));
+
+            // They edit after we deleted: the edit is newer
+            let (_dir, base, a, b, note) = three_copies(history);
+            delete(&a, &note.id);
+            edit(&b, &note.id, "theirs");
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::NewerWins);
+            assert_eq!(summary.annotations.conflicts, vec![note.id.clone()]);
+            assert_eq!(summary.annotations.added, 1);
+            assert_eq!(content(&merged, &note.id).as_deref(), Some("theirs"));
+
+            // A deletion against an untouched annotation is simply taken
+            let (_dir, base, a, b, note) = three_copies(history);
+            delete(&b, &note.id);
+            let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::KeepBoth);
+            assert!(summary.annotations.conflicts.is_empty());
+            assert_eq!(summary.annotations.deleted, 1);
+            assert_eq!(count(&merged), 0);
+        }
+    }
+
+    #[test]
+    fn three_way_base_missing_a_row() {
+        // The base predates the note, so edits to it can't be told apart
+        // from both sides adding it
+        for strategy in [MergeStrategy::NewerWins, MergeStrategy::KeepBoth] {
+            let (dir, _, a, b, note) = three_copies(false);
+            let base = dir.path().join("empty.rr");
+            let empty = rr_file::import_pdf(&dir.path().join("paper.pdf"), Some(&base)).unwrap();
+            rr_file::save_rr(&empty).unwrap();
+            edit(&b, &note.id, "theirs");
+            let added = database::create_annotation(
+                &a.db,
+                &CreateAnnotationInput {
+                    annotation_type: AnnotationType::Bookmark,
+                    page_number: 2,
+                    color: None,
+                    content: None,
+                    position_data: None,
+                },
+            )
+            .unwrap();
+
+            let (summary, merged) = merge3(&base, &a, &b, strategy);
+            assert!(database::get_annotation(&merged.db, &added.id)
+                .unwrap()
+                .is_some());
+            match strategy {
+                // Theirs is newer, so it's taken as with `merge_from`
+                MergeStrategy::NewerWins => {
+                    assert!(summary.annotations.conflicts.is_empty());
+                    assert_eq!(summary.annotations.updated, 1);
+                    assert_eq!(content(&merged, &note.id).as_deref(), Some("theirs"));
+                    assert_eq!(count(&merged), 2);
+                }
+                MergeStrategy::KeepBoth => {
+                    assert_eq!(summary.annotations.conflicts, vec![note.id.clone()]);
+                    assert_eq!(content(&merged, &note.id).as_deref(), Some("base"));
+                    assert_eq!(count(&merged), 3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn three_way_merges_metadata_and_keeps_one_sided_changes() {
+        let (_dir, base, a, b, note) = three_copies(false);
+        database::set_metadata(&a.db, "title", "Ours").unwrap();
+        database::set_metadata(&b.db, "doi", "10.1000/x").unwrap();
+        edit(&b, &note.id, "theirs");
+        let (summary, merged) = merge3(&base, &a, &b, MergeStrategy::KeepBoth);
+        assert!(summary.annotations.conflicts.is_empty());
+        assert_eq!(summary.annotations.updated, 1);
+        assert_eq!(content(&merged, &note.id).as_deref(), Some("theirs"));
+        assert_eq!(
+            database::get_metadata(&merged.db, "title")
+                .unwrap()
+                .as_deref(),
+            Some("Ours")
+        );
+        assert_eq!(
+            database::get_metadata(&merged.db, "doi")
+                .unwrap()
+                .as_deref(),
+            Some("10.1000/x")
+        );
+    }
+
+    #[test]
+    fn different_pdf_is_rejected() {
+        let (dir, a, _b, _) = two_copies(false);
+        let other_pdf = dir.path().join("other.pdf");
+        std::fs::write(&other_pdf, b"%PDF-1.4 other").unwrap();
+        let other = rr_file::import_pdf(&other_pdf, Some(&dir.path().join("c.rr"))).unwrap();
+        rr_file::save_rr(&other).unwrap();
+        assert!(merge_from(&a, &other.rr_path, MergeStrategy::NewerWins).is_err());
+    }
+}
@@ -1,12 +1,17 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// Represents a highlight/note/bookmark annotation
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Annotation {
     pub id: String,
     #[serde(rename = "type")]
     pub annotation_type: AnnotationType,
     pub page_number: u32,
+    /// The page's label from the PDF, e.g. "xii"; None if the PDF has no
+    /// page labels. Filled in by the commands returning annotations
+    #[serde(default)]
+    pub page_label: Option<String>,
     pub color: Option<String>,
     pub content: Option<String>,
     pub position_data: Option<PositionData>,
@@ -14,7 +19,7 @@ pub struct Annotation {
     pub updated_at: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum AnnotationType {
     Highlight,
@@ -30,8 +35,12 @@ impl AnnotationType {
             AnnotationType::Bookmark => "bookmark",
         }
     }
+}
+
+impl std::str::FromStr for AnnotationType {
+    type Err = String;
 
-    pub fn from_str(s: &str) -> Result<Self, String> {
+    fn from_str(s: &str) -> Result<Self, String> {
         match s {
             "highlight" => Ok(AnnotationType::Highlight),
             "note" => Ok(AnnotationType::Note),
@@ -43,7 +52,7 @@ impl AnnotationType {
 
 /// Position data for an annotation on a PDF page.
 /// Coordinates are normalized to zoom=1.0.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PositionData {
     pub rects: Vec<Rect>,
     pub page_width: f64,
@@ -53,7 +62,7 @@ pub struct PositionData {
     pub end_offset: Option<u32>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Rect {
     pub x: f64,
     pub y: f64,
@@ -61,6 +70,13 @@ pub struct Rect {
     pub height: f64,
 }
 
+/// Dimensions of a PDF page at zoom=1.0, as reported by the renderer
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PageSize {
+    pub width: f64,
+    pub height: f64,
+}
+
 /// Input for creating a new annotation from the frontend
 #[derive(Debug, Deserialize)]
 pub struct CreateAnnotationInput {
@@ -81,6 +97,16 @@ pub struct UpdateAnnotationInput {
     pub position_data: Option<PositionData>,
 }
 
+/// A message in the AI conversation stored with the document
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConversationMessage {
+    pub id: String,
+    /// "user" or "assistant"
+    pub role: String,
+    pub content: String,
+    pub created_at: String,
+}
+
 /// Metadata about the document inside a .rr file
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DocumentMetadata {
@@ -89,6 +115,67 @@ pub struct DocumentMetadata {
     pub last_page: Option<u32>,
 }
 
+/// Bibliographic details of the document, edited with `set_bibliography`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BibliographicRecord {
+    pub title: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    pub year: Option<i32>,
+    /// Journal, conference or publisher
+    pub venue: Option<String>,
+    /// Bare DOI, e.g. `10.1000/xyz123`
+    pub doi: Option<String>,
+    /// Bare arXiv id, e.g. `2101.00001v2`
+    pub arxiv_id: Option<String>,
+    #[serde(rename = "abstract")]
+    pub abstract_text: Option<String>,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    pub url: Option<String>,
+}
+
+/// Where an extracted metadata value was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataSource {
+    /// The PDF's document information dictionary
+    Info,
+    /// The PDF's XMP metadata stream
+    Xmp,
+    /// Identifiers found in the text of the first pages
+    Text,
+    /// Font sizes and line order on the first page
+    Layout,
+    /// The name of the imported file
+    Filename,
+}
+
+/// A value for one field of the bibliographic record, found in the PDF
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataCandidate {
+    /// Field of `BibliographicRecord`, e.g. "title", "authors" or "doi"
+    pub field: String,
+    /// A string, a list of strings (authors, keywords) or a number (year)
+    pub value: serde_json::Value,
+    pub source: MetadataSource,
+    /// From 0 to 1; the most confident candidate fills an empty field
+    pub confidence: f32,
+    /// Whether the bibliographic record currently holds this value
+    #[serde(default)]
+    pub applied: bool,
+}
+
+/// Metadata extracted from the PDF, kept so the user can confirm or correct it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtractedMetadata {
+    pub candidates: Vec<MetadataCandidate>,
+    pub extracted_at: String,
+    /// Set once the user has saved the bibliographic record; extraction
+    /// no longer changes it after that
+    pub confirmed: bool,
+}
+
 /// Manifest stored in the .rr ZIP container
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RrManifest {
@@ -106,3 +193,388 @@ impl Default for RrManifest {
         }
     }
 }
+
+/// Annotation-only bundle for sharing notes without the PDF.
+/// The PDF is identified by the SHA-256 of `document.pdf`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SidecarBundle {
+    pub format: String,
+    pub version: String,
+    pub pdf_sha256: String,
+    pub created_at: String,
+    pub metadata: BTreeMap<String, String>,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Result of attaching a sidecar bundle to the open document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SidecarImportResult {
+    pub hash_matches: bool,
+    pub annotations_imported: usize,
+    pub metadata_imported: usize,
+}
+
+/// How to resolve an annotation that was edited in both copies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// The copy with the later `updated_at` replaces the other
+    NewerWins,
+    /// Keep ours and insert theirs alongside it under a new id
+    KeepBoth,
+}
+
+/// An annotation that differed between the two copies
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MergeConflict {
+    pub id: String,
+    /// "kept_ours" or "kept_both"; for an edit against a deletion, "kept_edit"
+    /// or "deleted"
+    pub resolution: String,
+    /// Id of the inserted copy when both were kept
+    pub copy_id: Option<String>,
+}
+
+/// Summary returned by merge_from
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MergeSummary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    /// Annotations deleted in the other copy (CRDT merges only)
+    #[serde(default)]
+    pub deleted: Vec<String>,
+    pub conflicts: Vec<MergeConflict>,
+    pub unchanged: usize,
+}
+
+/// Per-table outcome of a three-way merge
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TableMergeSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub deleted: usize,
+    /// Ids (or metadata keys) changed differently on both sides
+    pub conflicts: Vec<String>,
+}
+
+/// Summary returned by a three-way merge of .rr files
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThreeWayMergeSummary {
+    pub annotations: TableMergeSummary,
+    pub metadata: TableMergeSummary,
+    pub conversations: TableMergeSummary,
+}
+
+/// Outcome of syncing a document with the sync folder
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub pdf_sha256: String,
+    /// Local changes appended to our journal
+    pub pushed: usize,
+    /// Peer changes applied locally
+    pub applied: usize,
+    /// Peer changes ignored because the local state was newer
+    pub skipped: usize,
+    /// Whether the published snapshot was rewritten
+    pub snapshot_updated: bool,
+}
+
+/// A participant in a live collaboration session
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub peer_id: String,
+    pub name: String,
+    /// Page the participant is viewing, if known
+    pub page: Option<u32>,
+    pub is_host: bool,
+}
+
+/// State of the live collaboration session, returned to the frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollabInfo {
+    pub is_host: bool,
+    /// Code other participants enter to join (host only)
+    pub join_code: Option<String>,
+    pub peers: Vec<Presence>,
+}
+
+/// Annotations changed by a collaborator, emitted as `collab-annotations-changed`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationChangeSet {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub deleted: Vec<String>,
+}
+
+/// Connection state of a joined session, emitted as `collab-connection`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollabConnection {
+    pub connected: bool,
+    pub message: Option<String>,
+}
+
+/// A .rr file indexed in the app-level library
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    pub id: i64,
+    pub path: String,
+    pub title: Option<String>,
+    pub pdf_sha256: Option<String>,
+    pub page_count: Option<u32>,
+    pub last_page: Option<u32>,
+    pub highlight_count: u32,
+    pub note_count: u32,
+    pub bookmark_count: u32,
+    pub added_at: String,
+    pub last_opened_at: Option<String>,
+    pub status: Option<ReadingStatus>,
+    /// 1 (most urgent) to 5
+    pub priority: Option<u8>,
+    /// YYYY-MM-DD
+    pub due_date: Option<String>,
+    pub collection_ids: Vec<i64>,
+    /// Simhash of the page text, used to spot re-downloaded copies
+    pub text_fingerprint: Option<String>,
+    pub authors: Vec<String>,
+    pub year: Option<i32>,
+    pub venue: Option<String>,
+    pub doi: Option<String>,
+    pub arxiv_id: Option<String>,
+    /// Unique key used when citing the document, e.g. `vaswani2017attention`
+    pub citation_key: Option<String>,
+    /// The .rr file no longer exists at `path`
+    pub missing: bool,
+}
+
+/// Another library document holding the same paper
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateMatch {
+    /// "identical" for the same PDF bytes, "similar" for near-identical text
+    pub kind: String,
+    pub entry: LibraryEntry,
+}
+
+/// Where a library document is on the reading list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadingStatus {
+    ToRead,
+    Reading,
+    Done,
+}
+
+impl ReadingStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReadingStatus::ToRead => "to_read",
+            ReadingStatus::Reading => "reading",
+            ReadingStatus::Done => "done",
+        }
+    }
+}
+
+impl std::str::FromStr for ReadingStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "to_read" => Ok(ReadingStatus::ToRead),
+            "reading" => Ok(ReadingStatus::Reading),
+            "done" => Ok(ReadingStatus::Done),
+            _ => Err(format!("Unknown reading status: {}", s)),
+        }
+    }
+}
+
+/// A named group of library documents; collections nest through `parent_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub parent_id: Option<i64>,
+    pub created_at: String,
+    /// Documents directly in this collection
+    pub document_count: u32,
+}
+
+/// Sort order for listing the library
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LibrarySort {
+    Title,
+    LastOpened,
+    Added,
+    /// Total number of annotations
+    Annotations,
+    Priority,
+    DueDate,
+    /// Publication year
+    Year,
+}
+
+/// Filter and sort options for listing the library
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LibraryQuery {
+    /// Defaults to most recently opened first
+    #[serde(default)]
+    pub sort: Option<LibrarySort>,
+    #[serde(default)]
+    pub descending: bool,
+    /// Case-insensitive text matched against title and path
+    #[serde(default)]
+    pub text: Option<String>,
+    /// Only entries whose file can no longer be found
+    #[serde(default)]
+    pub missing_only: bool,
+    #[serde(default)]
+    pub status: Option<ReadingStatus>,
+    /// Only entries in this collection or its subcollections
+    #[serde(default)]
+    pub collection_id: Option<i64>,
+}
+
+/// Outcome of importing a PDF into the library, emitted as `library-import`
+/// for watched folders
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryImport {
+    /// The PDF that was imported
+    pub source: String,
+    /// "imported", "duplicate" or "failed"
+    pub status: String,
+    /// The new entry, or the existing one for a duplicate
+    pub entry: Option<LibraryEntry>,
+    pub error: Option<String>,
+}
+
+/// A match from a library-wide search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub document_id: i64,
+    pub path: String,
+    pub title: Option<String>,
+    pub page_number: Option<u32>,
+    /// The page's label from the PDF, if it has page labels
+    pub page_label: Option<String>,
+    /// Set when the match is in an annotation's content or selected text
+    pub annotation_id: Option<String>,
+    /// "page" for page text, "metadata" for the bibliographic record,
+    /// otherwise the annotation type
+    pub kind: String,
+    /// Matching text, with matched terms wrapped in `**`
+    pub snippet: String,
+}
+
+/// A BibTeX or CSL-JSON file imported as a local reference database
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceFile {
+    pub id: i64,
+    pub path: String,
+    /// "bibtex" or "csl_json"
+    pub format: String,
+    pub entry_count: u32,
+    pub imported_at: String,
+}
+
+/// One entry of a reference file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceEntry {
+    pub file_id: i64,
+    /// BibTeX citation key or CSL-JSON id
+    pub key: String,
+    pub record: BibliographicRecord,
+}
+
+/// The reference entry a library document takes its bibliographic record from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceLink {
+    pub document_id: i64,
+    pub file_id: i64,
+    pub key: String,
+    /// "doi", "arxiv_id", "title" or "manual"; "rejected" when the user
+    /// unlinked the document, which stops it from being matched again
+    pub matched_by: String,
+    /// Title similarity from 0 to 1; 1 for identifier and manual matches
+    pub score: f64,
+}
+
+/// Outcome of importing or refreshing a reference file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReferenceImport {
+    pub file: ReferenceFile,
+    /// Entries left out because they have no title or invalid fields
+    pub skipped: u32,
+    /// Library documents newly linked to an entry of the file
+    pub linked: u32,
+    /// Library documents whose bibliographic record was updated
+    pub updated: u32,
+}
+
+/// Where a document's outline came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutlineSource {
+    /// The PDF's /Outlines tree
+    Pdf,
+    /// Headings detected in the page text
+    Headings,
+}
+
+/// An entry of a document's table of contents
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutlineItem {
+    /// 1 for top-level entries
+    pub level: u32,
+    pub title: String,
+    /// None if the entry's target couldn't be resolved
+    pub page_number: Option<u32>,
+    /// Top of the target in PDF units from the bottom of the page, if given
+    pub top: Option<f32>,
+}
+
+/// A document's table of contents, in reading order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outline {
+    /// None if the document has no outline
+    pub source: Option<OutlineSource>,
+    pub items: Vec<OutlineItem>,
+}
+
+/// The label a PDF gives a physical page, from its /PageLabels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageLabel {
+    /// 1-based physical page number
+    pub page_number: u32,
+    /// Label as displayed by readers, e.g. "xii" or "A-3"
+    pub label: String,
+}
+
+/// A document to open, from a resolved `vellum://open` link, the command line
+/// or the file manager, optionally at a page or annotation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeepLinkTarget {
+    /// Path of the .rr file (or a PDF to import)
+    pub path: String,
+    pub page_number: Option<u32>,
+    pub annotation_id: Option<String>,
+}
+
+/// An entry of the document's own reference list, parsed from its page text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CitedReference {
+    /// 1-based position in the reference list
+    pub position: u32,
+    /// Label as printed, e.g. `12` or `Vas17`; None for author-year lists
+    pub label: Option<String>,
+    /// Page the entry starts on
+    pub page_number: u32,
+    /// The entry as printed
+    pub text: String,
+    /// Fields recognised in the entry; any of them may be missing
+    pub record: BibliographicRecord,
+    /// Marked to read later
+    pub to_read: bool,
+    /// The library document the entry cites, if the library has it
+    pub document: Option<LibraryEntry>,
+    /// "doi", "arxiv_id" or "title"
+    pub matched_by: Option<String>,
+}
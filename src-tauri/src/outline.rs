@@ -0,0 +1,675 @@
+use std::collections::{HashMap, HashSet};
+
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use rusqlite::{params, Connection};
+
+use crate::database;
+use crate::models::*;
+
+/// Metadata key recording where the stored outline came from: "pdf",
+/// "headings", or "none" when neither gave any entries.
+pub const METADATA_KEY: &str = "outline.source";
+
+/// Outlines nested deeper than this are cut off.
+const MAX_DEPTH: u32 = 32;
+/// Upper bound on outline items read from a PDF.
+const MAX_ITEMS: usize = 10_000;
+/// Longest detected heading, in words.
+const MAX_HEADING_WORDS: usize = 8;
+/// Unnumbered headings recognised in page text.
+const SECTION_NAMES: &[&str] = &[
+    "Abstract",
+    "Introduction",
+    "Background",
+    "Related Work",
+    "Methods",
+    "Methodology",
+    "Experiments",
+    "Results",
+    "Discussion",
+    "Conclusion",
+    "Conclusions",
+    "Limitations",
+    "Acknowledgments",
+    "Acknowledgements",
+    "References",
+    "Bibliography",
+    "Appendix",
+];
+/// Words that start the paragraph after a numbered heading, not the heading.
+const SENTENCE_STARTS: &[&str] = &[
+    "We", "In", "This", "These", "Our", "It", "Here", "To", "As", "Given", "Let", "Figure",
+    "Table", "Fig.",
+];
+
+/// The stored outline of a document.
+pub fn get(db: &Connection) -> Result<Outline, String> {
+    let source = match database::get_metadata(db, METADATA_KEY)
+        .map_err(|e| format!("Failed to read {}: {}", METADATA_KEY, e))?
+        .as_deref()
+    {
+        Some("pdf") => Some(OutlineSource::Pdf),
+        Some("headings") => Some(OutlineSource::Headings),
+        _ => None,
+    };
+    let mut stmt = db
+        .prepare("SELECT level, title, page_number, top FROM outline ORDER BY position")
+        .map_err(|e| format!("Failed to read outline: {}", e))?;
+    let items = stmt
+        .query_map([], |row| {
+            Ok(OutlineItem {
+                level: row.get(0)?,
+                title: row.get(1)?,
+                page_number: row.get(2)?,
+                top: row.get(3)?,
+            })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|e| format!("Failed to read outline: {}", e))?;
+    Ok(Outline { source, items })
+}
+
+/// Whether an outline has been looked for in the document's PDF yet.
+pub fn is_extracted(db: &Connection) -> Result<bool, String> {
+    database::get_metadata(db, METADATA_KEY)
+        .map(|source| source.is_some())
+        .map_err(|e| format!("Failed to read {}: {}", METADATA_KEY, e))
+}
+
+/// Read the PDF's /Outlines tree (None if the PDF couldn't be parsed) and
+/// store it. Without one, the outline is left to `update_from_text` once the
+/// page text is known.
+pub fn extract_into(db: &Connection, pdf: Option<&Document>) -> Result<(), String> {
+    let items = pdf.map(pdf_outline).unwrap_or_default();
+    let source = if items.is_empty() { "none" } else { "pdf" };
+    store(db, &items, source)
+}
+
+/// Detect headings in the stored page text and store them as the outline,
+/// unless the PDF has an outline of its own.
+pub fn update_from_text(db: &Connection) -> Result<(), String> {
+    let source = database::get_metadata(db, METADATA_KEY)
+        .map_err(|e| format!("Failed to read {}: {}", METADATA_KEY, e))?;
+    if source.as_deref() == Some("pdf") {
+        return Ok(());
+    }
+    let pages =
+        database::get_page_texts(db).map_err(|e| format!("Failed to get page text: {}", e))?;
+    let items = detect_headings(&pages);
+    let source = if items.is_empty() { "none" } else { "headings" };
+    store(db, &items, source)
+}
+
+fn store(db: &Connection, items: &[OutlineItem], source: &str) -> Result<(), String> {
+    let tx = db
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    tx.execute("DELETE FROM outline", [])
+        .map_err(|e| format!("Failed to clear outline: {}", e))?;
+    for (i, item) in items.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO outline (position, level, title, page_number, top)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                i as u32 + 1,
+                item.level,
+                item.title,
+                item.page_number,
+                item.top
+            ],
+        )
+        .map_err(|e| format!("Failed to store outline: {}", e))?;
+    }
+    database::set_metadata(&tx, METADATA_KEY, source)
+        .map_err(|e| format!("Failed to set {}: {}", METADATA_KEY, e))?;
+    tx.commit()
+        .map_err(|e| format!("Failed to commit outline: {}", e))
+}
+
+/// The outline items of a PDF in reading order. Items whose target can't be
+/// resolved are kept without a page; malformed parts of the tree are skipped.
+pub fn pdf_outline(doc: &Document) -> Vec<OutlineItem> {
+    let Some(root) = doc
+        .catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"Outlines").ok())
+        .and_then(|o| resolve_dict(doc, o))
+    else {
+        return Vec::new();
+    };
+    let targets = Targets {
+        doc,
+        pages: doc
+            .get_pages()
+            .into_iter()
+            .map(|(number, id)| (id, number))
+            .collect(),
+    };
+
+    let mut items = Vec::new();
+    let mut visited = HashSet::new();
+    // (node, level) pairs still to visit; a node's children come before its siblings
+    let mut stack: Vec<(&Object, u32)> = root.get(b"First").map(|o| (o, 1)).into_iter().collect();
+    while let Some((node, level)) = stack.pop() {
+        if items.len() >= MAX_ITEMS {
+            break;
+        }
+        if let Object::Reference(id) = node {
+            if !visited.insert(*id) {
+                continue;
+            }
+        }
+        let Some(dict) = resolve_dict(doc, node) else {
+            continue;
+        };
+        if let Ok(next) = dict.get(b"Next") {
+            stack.push((next, level));
+        }
+        if level < MAX_DEPTH {
+            if let Ok(first) = dict.get(b"First") {
+                stack.push((first, level + 1));
+            }
+        }
+
+        let title = dict
+            .get(b"Title")
+            .ok()
+            .and_then(|o| doc.dereference(o).ok())
+            .and_then(|(_, o)| lopdf::decode_text_string(o).ok())
+            .map(|t| t.split_whitespace().collect::<Vec<_>>().join(" "))
+            .unwrap_or_default();
+        if title.is_empty() {
+            continue;
+        }
+        let destination = match dict.get(b"Dest") {
+            Ok(dest) => Some(dest),
+            Err(_) => dict
+                .get(b"A")
+                .ok()
+                .and_then(|a| resolve_dict(doc, a))
+                .filter(|action| {
+                    action.get(b"S").and_then(Object::as_name).ok() == Some(b"GoTo".as_slice())
+                })
+                .and_then(|action| action.get(b"D").ok()),
+        };
+        let (page_number, top) = destination
+            .and_then(|dest| targets.resolve(dest, 0))
+            .map_or((None, None), |(page, top)| (Some(page), top));
+        items.push(OutlineItem {
+            level,
+            title,
+            page_number,
+            top,
+        });
+    }
+    items
+}
+
+/// Resolves outline destinations to page numbers
+struct Targets<'a> {
+    doc: &'a Document,
+    pages: HashMap<ObjectId, u32>,
+}
+
+impl Targets<'_> {
+    /// Page number and top coordinate of an explicit destination array, a
+    /// named destination or a dictionary wrapping one in /D.
+    fn resolve(&self, dest: &Object, depth: u32) -> Option<(u32, Option<f32>)> {
+        if depth > 4 {
+            return None;
+        }
+        let (_, dest) = self.doc.dereference(dest).ok()?;
+        match dest {
+            Object::Array(array) => {
+                let page = match array.first()? {
+                    Object::Reference(id) => *self.pages.get(id)?,
+                    // Some producers give a 0-based page index instead
+                    Object::Integer(index) => u32::try_from(*index).ok()? + 1,
+                    _ => return None,
+                };
+                let kind = array.get(1).and_then(|o| o.as_name().ok());
+                let top = match kind {
+                    Some(b"XYZ") => array.get(3),
+                    Some(b"FitH" | b"FitBH") => array.get(2),
+                    Some(b"FitR") => array.get(5),
+                    _ => None,
+                }
+                .and_then(|o| o.as_float().ok());
+                Some((page, top))
+            }
+            Object::Dictionary(dict) => self.resolve(dict.get(b"D").ok()?, depth + 1),
+            Object::Name(name) => self.resolve(self.named_in_dests(name)?, depth + 1),
+            Object::String(name, _) => self.resolve(self.named_in_tree(name)?, depth + 1),
+            _ => None,
+        }
+    }
+
+    /// A destination from the catalog's /Dests dictionary (PDF 1.1 style).
+    fn named_in_dests(&self, name: &[u8]) -> Option<&Object> {
+        let catalog = self.doc.catalog().ok()?;
+        resolve_dict(self.doc, catalog.get(b"Dests").ok()?)?
+            .get(name)
+            .ok()
+    }
+
+    /// A destination from the /Names /Dests name tree.
+    fn named_in_tree(&self, name: &[u8]) -> Option<&Object> {
+        let catalog = self.doc.catalog().ok()?;
+        let names = resolve_dict(self.doc, catalog.get(b"Names").ok()?)?;
+        let mut nodes = vec![resolve_dict(self.doc, names.get(b"Dests").ok()?)?];
+        let mut visited = 0;
+        while let Some(node) = nodes.pop() {
+            visited += 1;
+            if visited > MAX_ITEMS {
+                return None;
+            }
+            if let Ok(pairs) = node.get(b"Names").and_then(Object::as_array) {
+                for pair in pairs.chunks(2) {
+                    if let [Object::String(key, _), value] = pair {
+                        if key.as_slice() == name {
+                            return Some(value);
+                        }
+                    }
+                }
+            }
+            if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+                nodes.extend(kids.iter().filter_map(|kid| resolve_dict(self.doc, kid)));
+            }
+        }
+        None
+    }
+}
+
+fn resolve_dict<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Dictionary> {
+    doc.dereference(object)
+        .ok()
+        .and_then(|(_, o)| o.as_dict().ok())
+}
+
+/// Headings found in page text, for PDFs without an outline: numbered
+/// headings ("2", "2.1 Title", "IV. TITLE") that follow on from the ones
+/// before them, and common unnumbered section names such as "Introduction".
+/// A heading must start a sentence, and each section name is taken once.
+pub fn detect_headings(pages: &[(u32, String)]) -> Vec<OutlineItem> {
+    let mut items = Vec::new();
+    let mut numbering: Vec<u32> = Vec::new();
+    let mut roman = 0;
+    let mut names_seen = HashSet::new();
+
+    for (page_number, text) in pages {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        let mut i = 0;
+        while i < words.len() {
+            let starts_sentence = i == 0 || {
+                let previous = words[i - 1];
+                previous.ends_with(['.', ':', '?', '!', ')'])
+                    || previous.chars().all(|c| c.is_ascii_digit())
+            };
+            if !starts_sentence {
+                i += 1;
+                continue;
+            }
+
+            let word = words[i];
+            let found = if let Some(number) = section_number(word, &numbering) {
+                heading_title(&words[i + 1..], false).map(|(title, len)| {
+                    names_seen.insert(title.to_lowercase());
+                    let level = number.len() as u32;
+                    numbering = number;
+                    (
+                        level,
+                        format!("{} {}", numbering_label(&numbering), title),
+                        len + 1,
+                    )
+                })
+            } else if roman_value(word) == Some(roman + 1) {
+                heading_title(&words[i + 1..], true).map(|(title, len)| {
+                    names_seen.insert(title.to_lowercase());
+                    roman += 1;
+                    (
+                        1,
+                        format!("{} {}", word.trim_end_matches('.'), title),
+                        len + 1,
+                    )
+                })
+            } else {
+                section_name(&words[i..])
+                    .filter(|(name, _)| !names_seen.contains(&name.to_lowercase()))
+                    .map(|(name, len)| {
+                        names_seen.insert(name.to_lowercase());
+                        (1, name, len)
+                    })
+            };
+
+            match found {
+                Some((level, title, len)) => {
+                    items.push(OutlineItem {
+                        level,
+                        title,
+                        page_number: Some(*page_number),
+                        top: None,
+                    });
+                    i += len;
+                }
+                None => i += 1,
+            }
+        }
+    }
+    items
+}
+
+/// The section number `word` gives ("3", "3.", "3.2"), if it follows on
+/// from `current`: the next number at the same or a higher level, or the
+/// first subsection of the current one.
+fn section_number(word: &str, current: &[u32]) -> Option<Vec<u32>> {
+    let word = word.trim_end_matches('.');
+    if word.is_empty() {
+        return None;
+    }
+    let number: Vec<u32> = word
+        .split('.')
+        .map(|part| part.parse().ok().filter(|n| (1..=99).contains(n)))
+        .collect::<Option<_>>()?;
+    let depth = number.len();
+    let follows = if depth > current.len() + 1 {
+        false
+    } else if depth == current.len() + 1 {
+        number[..depth - 1] == current[..] && number[depth - 1] == 1
+    } else {
+        number[..depth - 1] == current[..depth - 1] && number[depth - 1] == current[depth - 1] + 1
+    };
+    follows.then_some(number)
+}
+
+fn numbering_label(number: &[u32]) -> String {
+    number
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// The heading after a section number and how many words it takes. The
+/// heading is a run of capitalised words (and short lowercase ones in
+/// between), all upper case if `upper`; since the page text has no line
+/// breaks, it is taken to end at the last point in the run where a
+/// capitalised word could start the next sentence.
+fn heading_title(words: &[&str], upper: bool) -> Option<(String, usize)> {
+    let mut run = 0;
+    for (i, word) in words.iter().take(MAX_HEADING_WORDS).enumerate() {
+        let first = word.chars().next()?;
+        let fits = if upper {
+            word.chars().any(char::is_alphabetic)
+                && !word.chars().any(char::is_lowercase)
+                && !(i > 0 && word.len() == 1 && first == 'A')
+        } else if i == 0 {
+            first.is_uppercase()
+        } else {
+            (first.is_uppercase() && !SENTENCE_STARTS.contains(word))
+                || (word.len() <= 4 && first.is_lowercase())
+        };
+        if !fits {
+            break;
+        }
+        run = i + 1;
+    }
+    let len = (1..=run).rev().find(|&len| {
+        let last_capitalised = !words[len - 1].starts_with(char::is_lowercase);
+        let next_capitalised = match words.get(len).and_then(|w| w.chars().next()) {
+            Some(c) => c.is_uppercase(),
+            None => true,
+        };
+        last_capitalised && next_capitalised
+    })?;
+    let title = words[..len].join(" ");
+    let title = title.trim_end_matches(['.', ':']);
+    title
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .nth(2)
+        .map(|_| (title.to_string(), len))
+}
+
+/// A common section name at the start of `words`, in title or upper case,
+/// followed by a capitalised word.
+fn section_name(words: &[&str]) -> Option<(String, usize)> {
+    SECTION_NAMES.iter().find_map(|name| {
+        let len = name.split(' ').count();
+        let candidate = words.get(..len)?.join(" ");
+        let candidate = candidate.trim_end_matches(['.', ':']);
+        let matches = candidate == *name || candidate == name.to_uppercase();
+        let followed = match words.get(len).and_then(|w| w.chars().next()) {
+            Some(c) => !c.is_lowercase(),
+            None => true,
+        };
+        (matches && followed).then(|| (name.to_string(), len))
+    })
+}
+
+/// Value of an upper-case roman numeral followed by a period, from I to XX.
+fn roman_value(word: &str) -> Option<u32> {
+    const NUMERALS: [&str; 20] = [
+        "I", "II", "III", "IV", "V", "VI", "VII", "VIII", "IX", "X", "XI", "XII", "XIII", "XIV",
+        "XV", "XVI", "XVII", "XVIII", "XIX", "XX",
+    ];
+    let numeral = word.strip_suffix('.')?;
+    NUMERALS
+        .iter()
+        .position(|n| *n == numeral)
+        .map(|i| i as u32 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr_file;
+    use lopdf::{dictionary, Stream};
+    use std::path::Path;
+
+    fn item(level: u32, title: &str, page_number: Option<u32>, top: Option<f32>) -> OutlineItem {
+        OutlineItem {
+            level,
+            title: title.to_string(),
+            page_number,
+            top,
+        }
+    }
+
+    fn headings(pages: &[&str]) -> Vec<(u32, String, u32)> {
+        let pages: Vec<(u32, String)> = pages
+            .iter()
+            .enumerate()
+            .map(|(i, text)| (i as u32 + 1, text.to_string()))
+            .collect();
+        detect_headings(&pages)
+            .into_iter()
+            .map(|item| (item.level, item.title, item.page_number.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn headings_in_page_text() {
+        let found = headings(&[
+            "Abstract We study things. 1 Introduction We introduce. 1.1 Motivation and Goals \
+             We care. 2 Related Work In prior work, see 7 Samples and section 2 of it.",
+            "We use data. 2.1 Graph Methods These help. 3 Experiments Results are good. \
+             Conclusion Nothing more. Introduction Again. II. NOT ROMAN Here. 2 Related \
+             Work Again. References [1] A. Author.",
+        ]);
+        let expected = [
+            (1, "Abstract", 1),
+            (1, "1 Introduction", 1),
+            (2, "1.1 Motivation and Goals", 1),
+            (1, "2 Related Work", 1),
+            (2, "2.1 Graph Methods", 2),
+            (1, "3 Experiments", 2),
+            (1, "Conclusion", 2),
+            (1, "References", 2),
+        ]
+        .map(|(level, title, page)| (level, title.to_string(), page));
+        assert_eq!(found, expected);
+
+        assert_eq!(
+            headings(&["I. INTRODUCTION We begin. II. RELATED WORK Prior art. IV. SKIPPED Yes."]),
+            [
+                (1, "I INTRODUCTION".to_string(), 1),
+                (1, "II RELATED WORK".to_string(), 1)
+            ]
+        );
+        assert!(headings(&["Nothing here looks like a heading at all."]).is_empty());
+    }
+
+    #[test]
+    fn headings_are_stored_without_a_pdf_outline() {
+        let db = Connection::open_in_memory().unwrap();
+        database::init_db(&db).unwrap();
+        extract_into(&db, None).unwrap();
+        assert!(is_extracted(&db).unwrap());
+        let outline = get(&db).unwrap();
+        assert_eq!((outline.source, outline.items.len()), (None, 0));
+
+        let pages = [(3, "Introduction We begin. Conclusion We end.".to_string())];
+        database::set_page_texts(&db, &pages).unwrap();
+        update_from_text(&db).unwrap();
+        let outline = get(&db).unwrap();
+        assert_eq!(outline.source, Some(OutlineSource::Headings));
+        assert_eq!(
+            outline.items,
+            [
+                item(1, "Introduction", Some(3), None),
+                item(1, "Conclusion", Some(3), None)
+            ]
+        );
+    }
+
+    /// A three-page PDF with an outline that reaches its pages through an
+    /// explicit destination, a GoTo action, a named destination in /Dests
+    /// and one in the /Names tree, plus a dangling target and a cycle.
+    fn write_pdf(path: &Path) {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let page_ids: Vec<ObjectId> = (0..3)
+            .map(|_| {
+                let content_id = doc.add_object(Stream::new(dictionary! {}, Vec::new()));
+                doc.add_object(dictionary! {
+                    "Type" => "Page", "Parent" => pages_id, "Contents" => content_id,
+                })
+            })
+            .collect();
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Kids" => page_ids.iter().map(|&id| id.into()).collect::<Vec<Object>>(),
+                "Count" => 3,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+
+        let outlines_id = doc.new_object_id();
+        let [chapter1, section, chapter2, dangling, appendix] =
+            [(); 5].map(|_| doc.new_object_id());
+        let nodes = [
+            (
+                chapter1,
+                dictionary! {
+                    "Title" => Object::string_literal("Chapter 1"), "Parent" => outlines_id,
+                    "Dest" => vec![
+                        page_ids[0].into(), "XYZ".into(), 0.into(), 700.into(), 0.into(),
+                    ],
+                    "First" => section, "Next" => chapter2,
+                },
+            ),
+            (
+                section,
+                dictionary! {
+                    "Title" => Object::string_literal("Section 1.1"), "Parent" => chapter1,
+                    "A" => dictionary! {
+                        "S" => "GoTo",
+                        "D" => vec![page_ids[1].into(), "FitH".into(), 500.into()],
+                    },
+                },
+            ),
+            (
+                chapter2,
+                dictionary! {
+                    "Title" => Object::string_literal("  Chapter\n 2 "), "Parent" => outlines_id,
+                    "Dest" => "Second", "First" => dangling, "Next" => appendix,
+                },
+            ),
+            (
+                dangling,
+                dictionary! {
+                    "Title" => Object::string_literal("Dangling"), "Parent" => chapter2,
+                    "Dest" => vec![Object::Reference((999, 0)), "Fit".into()],
+                },
+            ),
+            (
+                appendix,
+                dictionary! {
+                    "Title" => Object::string_literal("Appendix"), "Parent" => outlines_id,
+                    "Dest" => Object::string_literal("app"),
+                    // Back to the first item: read once only
+                    "Next" => chapter1,
+                },
+            ),
+        ];
+        for (id, node) in nodes {
+            doc.objects.insert(id, Object::Dictionary(node));
+        }
+        doc.objects.insert(
+            outlines_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Outlines", "First" => chapter1, "Last" => appendix,
+            }),
+        );
+
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+            "Outlines" => outlines_id,
+            "Dests" => dictionary! {
+                "Second" => vec![page_ids[2].into(), "Fit".into()],
+            },
+            "Names" => dictionary! {
+                "Dests" => dictionary! {
+                    "Names" => vec![
+                        Object::string_literal("app"),
+                        // A 0-based page index
+                        vec![2.into(), "XYZ".into(), Object::Null, 300.into(), Object::Null].into(),
+                    ],
+                },
+            },
+        });
+        doc.trailer.set("Root", catalog_id);
+        doc.save(path).unwrap();
+    }
+
+    #[test]
+    fn pdf_outline_is_stored_in_the_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("book.pdf");
+        write_pdf(&pdf);
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        rr_file::cleanup_session(&session);
+
+        let session = rr_file::open_rr(&dir.path().join("book.rr")).unwrap();
+        let expected = [
+            item(1, "Chapter 1", Some(1), Some(700.0)),
+            item(2, "Section 1.1", Some(2), Some(500.0)),
+            item(1, "Chapter 2", Some(3), None),
+            item(2, "Dangling", None, None),
+            item(1, "Appendix", Some(3), Some(300.0)),
+        ];
+        let outline = get(&session.db).unwrap();
+        assert_eq!(outline.source, Some(OutlineSource::Pdf));
+        assert_eq!(outline.items, expected);
+
+        // Headings in the text don't replace it
+        database::set_page_texts(&session.db, &[(1, "Introduction Text.".to_string())]).unwrap();
+        update_from_text(&session.db).unwrap();
+        assert_eq!(get(&session.db).unwrap().items, expected);
+        rr_file::cleanup_session(&session);
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::{HashMap, HashSet};
+
+use lopdf::{Dictionary, Document, Object};
+use rusqlite::{params, Connection};
+
+use crate::database;
+use crate::models::*;
+
+/// Metadata key set once /PageLabels has been looked for: "pdf" when the PDF
+/// has page labels, "none" when it doesn't.
+pub const METADATA_KEY: &str = "page_labels.source";
+
+/// Upper bound on nodes visited in the /PageLabels number tree.
+const MAX_NODES: usize = 10_000;
+/// Numbers above this are written in decimal whatever the numbering style,
+/// so a bogus /St can't produce enormous roman numerals or letter runs.
+const MAX_STYLED_VALUE: i64 = 10_000;
+
+/// All stored page labels in page order; empty when the PDF has none.
+pub fn get(db: &Connection) -> Result<Vec<PageLabel>, String> {
+    // Read-only snapshots of files from older versions don't have the table
+    if !database::table_exists(db, "page_labels")
+        .map_err(|e| format!("Failed to read page labels: {}", e))?
+    {
+        return Ok(Vec::new());
+    }
+    let mut stmt = db
+        .prepare("SELECT page_number, label FROM page_labels ORDER BY page_number")
+        .map_err(|e| format!("Failed to read page labels: {}", e))?;
+    let labels = stmt
+        .query_map([], |row| {
+            Ok(PageLabel {
+                page_number: row.get(0)?,
+                label: row.get(1)?,
+            })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|e| format!("Failed to read page labels: {}", e))?;
+    Ok(labels)
+}
+
+/// Whether the document's PDF has been checked for page labels yet.
+pub fn is_extracted(db: &Connection) -> Result<bool, String> {
+    database::get_metadata(db, METADATA_KEY)
+        .map(|source| source.is_some())
+        .map_err(|e| format!("Failed to read {}: {}", METADATA_KEY, e))
+}
+
+/// Read the PDF's /PageLabels (None if the PDF couldn't be parsed) and
+/// store the label of every page.
+pub fn extract_into(db: &Connection, pdf: Option<&Document>) -> Result<(), String> {
+    let labels = pdf.map(pdf_page_labels).unwrap_or_default();
+
+    let tx = db
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    tx.execute("DELETE FROM page_labels", [])
+        .map_err(|e| format!("Failed to clear page labels: {}", e))?;
+    for (i, label) in labels.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO page_labels (page_number, label) VALUES (?1, ?2)",
+            params![i as u32 + 1, label],
+        )
+        .map_err(|e| format!("Failed to store page labels: {}", e))?;
+    }
+    let source = if labels.is_empty() { "none" } else { "pdf" };
+    database::set_metadata(&tx, METADATA_KEY, source)
+        .map_err(|e| format!("Failed to set {}: {}", METADATA_KEY, e))?;
+    tx.commit()
+        .map_err(|e| format!("Failed to commit page labels: {}", e))
+}
+
+/// The label of a physical page, if the PDF labels its pages.
+pub fn label(db: &Connection, page_number: u32) -> Result<Option<String>, String> {
+    db.query_row(
+        "SELECT label FROM page_labels WHERE page_number = ?1",
+        params![page_number],
+        |row| row.get(0),
+    )
+    .map(Some)
+    .or_else(|e| match e {
+        rusqlite::Error::QueryReturnedNoRows => Ok(None),
+        e => Err(e),
+    })
+    .map_err(|e| format!("Failed to read page label: {}", e))
+}
+
+/// The physical page number for a label such as "xii" or "A-3". An exact
+/// match wins over one that differs only in case, and the first page wins
+/// when several share a label. Without page labels, a plain number is taken
+/// as the page number itself.
+pub fn page_number(db: &Connection, label: &str) -> Result<Option<u32>, String> {
+    let label = label.trim();
+    let labels = get(db)?;
+    if labels.is_empty() {
+        return Ok(label.parse::<u32>().ok().filter(|&page| page > 0));
+    }
+    let found = labels
+        .iter()
+        .find(|l| l.label == label)
+        .or_else(|| labels.iter().find(|l| l.label.eq_ignore_ascii_case(label)));
+    Ok(found.map(|l| l.page_number))
+}
+
+/// Fill in `page_label` on annotations read from the document.
+pub fn label_annotations(db: &Connection, annotations: &mut [Annotation]) -> Result<(), String> {
+    if annotations.is_empty() {
+        return Ok(());
+    }
+    let labels: HashMap<u32, String> = get(db)?
+        .into_iter()
+        .map(|l| (l.page_number, l.label))
+        .collect();
+    for annotation in annotations {
+        annotation.page_label = labels.get(&annotation.page_number).cloned();
+    }
+    Ok(())
+}
+
+/// The label of every page of a PDF, in page order, or an empty list when
+/// the PDF has no /PageLabels. Pages before the first labelled range keep
+/// their page number as label.
+pub fn pdf_page_labels(doc: &Document) -> Vec<String> {
+    let Some(root) = doc
+        .catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"PageLabels").ok())
+        .and_then(|o| resolve_dict(doc, o))
+    else {
+        return Vec::new();
+    };
+
+    // (first page index, label dictionary) for each range in the number tree
+    let mut ranges: Vec<(i64, &Dictionary)> = Vec::new();
+    let mut visited = HashSet::new();
+    let mut nodes = vec![root];
+    while let Some(node) = nodes.pop() {
+        if visited.len() >= MAX_NODES {
+            break;
+        }
+        if !visited.insert(node as *const Dictionary) {
+            continue;
+        }
+        if let Ok(nums) = node.get(b"Nums").and_then(Object::as_array) {
+            for pair in nums.chunks(2) {
+                if let [Object::Integer(start), value] = pair {
+                    if let Some(dict) = resolve_dict(doc, value) {
+                        ranges.push((*start, dict));
+                    }
+                }
+            }
+        }
+        if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+            nodes.extend(kids.iter().filter_map(|kid| resolve_dict(doc, kid)));
+        }
+    }
+    if ranges.is_empty() {
+        return Vec::new();
+    }
+    ranges.sort_by_key(|(start, _)| *start);
+
+    let page_count = doc.get_pages().len();
+    let mut labels = Vec::with_capacity(page_count);
+    for index in 0..page_count as i64 {
+        let range = ranges.iter().rev().find(|(start, _)| *start <= index);
+        let label = match range {
+            Some((start, dict)) => range_label(doc, dict, index - start),
+            None => (index + 1).to_string(),
+        };
+        labels.push(label);
+    }
+    labels
+}
+
+/// The label of the page `offset` pages into the range described by `dict`.
+fn range_label(doc: &Document, dict: &Dictionary, offset: i64) -> String {
+    let prefix = dict
+        .get(b"P")
+        .ok()
+        .and_then(|o| doc.dereference(o).ok())
+        .and_then(|(_, o)| lopdf::decode_text_string(o).ok())
+        .unwrap_or_default();
+    let start = dict
+        .get(b"St")
+        .and_then(Object::as_i64)
+        .ok()
+        .filter(|&st| st >= 1)
+        .unwrap_or(1);
+    let value = start.saturating_add(offset);
+    let number = match dict.get(b"S").and_then(Object::as_name) {
+        Ok(_) if value > MAX_STYLED_VALUE => value.to_string(),
+        Ok(b"D") => value.to_string(),
+        Ok(b"R") => roman(value as u32).to_uppercase(),
+        Ok(b"r") => roman(value as u32),
+        Ok(b"A") => letters(value as u32).to_uppercase(),
+        Ok(b"a") => letters(value as u32),
+        // Without a numbering style the label is just the prefix
+        _ => String::new(),
+    };
+    prefix + &number
+}
+
+/// Lowercase roman numeral for `value` (at least 1).
+fn roman(mut value: u32) -> String {
+    const NUMERALS: &[(u32, &str)] = &[
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut numeral = String::new();
+    for &(step, digits) in NUMERALS {
+        while value >= step {
+            numeral.push_str(digits);
+            value -= step;
+        }
+    }
+    numeral
+}
+
+/// Lowercase letter label for `value` (at least 1): a..z, then aa..zz, and so on.
+fn letters(value: u32) -> String {
+    let letter = (b'a' + ((value - 1) % 26) as u8) as char;
+    letter.to_string().repeat(((value - 1) / 26 + 1) as usize)
+}
+
+fn resolve_dict<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Dictionary> {
+    doc.dereference(object)
+        .ok()
+        .and_then(|(_, o)| o.as_dict().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr_file;
+    use lopdf::dictionary;
+
+    /// A PDF with `count` blank pages and the given /PageLabels tree.
+    fn document(count: usize, page_labels: Option<Dictionary>) -> Document {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let kids: Vec<Object> = (0..count)
+            .map(|_| {
+                doc.add_object(dictionary! { "Type" => "Page", "Parent" => pages_id })
+                    .into()
+            })
+            .collect();
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages", "Kids" => kids, "Count" => count as i64,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let mut catalog = dictionary! { "Type" => "Catalog", "Pages" => pages_id };
+        if let Some(page_labels) = page_labels {
+            let labels_id = doc.add_object(page_labels);
+            catalog.set("PageLabels", labels_id);
+        }
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", catalog_id);
+        doc
+    }
+
+    #[test]
+    fn roman_numerals() {
+        let cases = [
+            (1, "i"),
+            (4, "iv"),
+            (9, "ix"),
+            (14, "xiv"),
+            (40, "xl"),
+            (90, "xc"),
+            (400, "cd"),
+            (1994, "mcmxciv"),
+            (3999, "mmmcmxcix"),
+        ];
+        for (value, numeral) in cases {
+            assert_eq!(roman(value), numeral, "{}", value);
+        }
+    }
+
+    #[test]
+    fn letter_labels_repeat_after_z() {
+        assert_eq!(letters(1), "a");
+        assert_eq!(letters(26), "z");
+        assert_eq!(letters(27), "aa");
+        assert_eq!(letters(52), "zz");
+        assert_eq!(letters(53), "aaa");
+    }
+
+    #[test]
+    fn ranges_from_a_nested_number_tree() {
+        let mut doc = document(9, None);
+        let appendix = doc.add_object(dictionary! {
+            "Nums" => vec![
+                5.into(),
+                dictionary! { "S" => "A", "P" => Object::string_literal("App-"), "St" => 26 }.into(),
+                7.into(),
+                dictionary! { "P" => Object::string_literal("Index") }.into(),
+            ],
+        });
+        let front = dictionary! {
+            "Nums" => vec![
+                0.into(), dictionary! { "S" => "r" }.into(),
+                3.into(), dictionary! { "S" => "D" }.into(),
+            ],
+        };
+        let tree = doc.add_object(dictionary! { "Kids" => vec![front.into(), appendix.into()] });
+        doc.catalog_mut().unwrap().set("PageLabels", tree);
+
+        assert_eq!(
+            pdf_page_labels(&doc),
+            ["i", "ii", "iii", "1", "2", "App-Z", "App-AA", "Index", "Index"]
+        );
+    }
+
+    #[test]
+    fn pages_before_the_first_range_and_huge_starts() {
+        let tree = dictionary! {
+            "Nums" => vec![
+                2.into(), dictionary! { "S" => "R", "St" => 10_000 }.into(),
+            ],
+        };
+        let labels = pdf_page_labels(&document(4, Some(tree)));
+        assert_eq!(labels, ["1", "2", &"M".repeat(10), "10001"]);
+        assert!(pdf_page_labels(&document(3, None)).is_empty());
+    }
+
+    #[test]
+    fn labels_are_looked_up_both_ways() {
+        let dir = tempfile::tempdir().unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        let tree = dictionary! {
+            "Nums" => vec![
+                0.into(), dictionary! { "S" => "r" }.into(),
+                2.into(), dictionary! { "S" => "D" }.into(),
+            ],
+        };
+        document(5, Some(tree)).save(&pdf).unwrap();
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+
+        assert_eq!(page_number(&session.db, "2").unwrap(), Some(4));
+        assert_eq!(page_number(&session.db, " II ").unwrap(), Some(2));
+        assert_eq!(page_number(&session.db, "4").unwrap(), None);
+        assert_eq!(label(&session.db, 1).unwrap().as_deref(), Some("i"));
+        assert_eq!(label(&session.db, 9).unwrap(), None);
+
+        // Files from before page labels were read get them on opening
+        database::delete_metadata(&session.db, METADATA_KEY).unwrap();
+        session.db.execute("DELETE FROM page_labels", []).unwrap();
+        rr_file::extract_missing(&session).unwrap();
+        assert_eq!(label(&session.db, 4).unwrap().as_deref(), Some("2"));
+        rr_file::cleanup_session(&session);
+
+        // Without labels a number is the page itself
+        let plain = dir.path().join("plain.pdf");
+        document(2, None).save(&plain).unwrap();
+        let session = rr_file::import_pdf(&plain, None).unwrap();
+        assert_eq!(page_number(&session.db, "2").unwrap(), Some(2));
+        assert_eq!(page_number(&session.db, "0").unwrap(), None);
+        rr_file::cleanup_session(&session);
+    }
+}
@@ -0,0 +1,1151 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use lopdf::content::Content;
+use lopdf::{Document, Encoding, LoadOptions, Object, ObjectId};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+
+use crate::bibliography;
+use crate::database;
+use crate::models::*;
+
+/// Metadata key holding the extracted candidates as JSON. It shares the
+/// bibliography prefix so `set_document_metadata` can't overwrite it.
+const METADATA_KEY: &str = "bib.extracted";
+/// Pages searched for a DOI or arXiv id.
+const TEXT_PAGES: u32 = 2;
+/// Lines at the top of the first page where the title is looked for.
+const HEAD_LINES: usize = 15;
+/// Upper bound on any decompressed stream, so a hostile PDF can't exhaust memory.
+pub const MAX_STREAM_BYTES: usize = 64 * 1024 * 1024;
+
+/// Fields of `BibliographicRecord` filled from candidates, in the JSON
+/// names it serialises with.
+const FIELDS: [&str; 8] = [
+    "title", "authors", "year", "venue", "doi", "arxiv_id", "abstract", "keywords",
+];
+
+type Matrix = [f32; 6];
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// Extract metadata from a parsed PDF (None if it couldn't be parsed), store
+/// the candidates, and fill the fields of the bibliographic record that are
+/// still empty with the most confident ones. `fallback_title`, usually the
+/// file name, is a low-confidence title candidate and is replaced if it is
+/// the current title. A file named after its arXiv id, such as
+/// "2310.01234v2", gives that id as well. Nothing in the record changes
+/// once the user has confirmed it.
+pub fn extract_into(
+    db: &Connection,
+    pdf: Option<&Document>,
+    fallback_title: Option<&str>,
+) -> Result<ExtractedMetadata, String> {
+    let mut candidates = pdf.map(extract).unwrap_or_default();
+    if let Some(title) = fallback_title.filter(|t| !t.trim().is_empty()) {
+        candidates.push(candidate(
+            "title",
+            json!(title),
+            MetadataSource::Filename,
+            0.1,
+        ));
+        if let Some(id) = bibliography::normalize_arxiv_id(title) {
+            candidates.push(candidate(
+                "arxiv_id",
+                json!(id),
+                MetadataSource::Filename,
+                0.4,
+            ));
+            if let Some(year) = arxiv_year(&id) {
+                candidates.push(candidate(
+                    "year",
+                    json!(year),
+                    MetadataSource::Filename,
+                    0.3,
+                ));
+            }
+        }
+    }
+
+    let confirmed = get_extracted(db)?.is_some_and(|previous| previous.confirmed);
+    let mut record = bibliography::get_record(db)?;
+    if !confirmed {
+        let mut fields = record_fields(&record)?;
+        for field in FIELDS {
+            let Some(best) = best_candidate(&candidates, field) else {
+                continue;
+            };
+            let empty = match &fields[field] {
+                Value::Null => true,
+                Value::Array(items) => items.is_empty(),
+                _ => false,
+            };
+            let stale_title = field == "title" && record.title.as_deref() == fallback_title;
+            if empty || stale_title {
+                fields[field] = best.value.clone();
+            }
+        }
+        let filled: BibliographicRecord = serde_json::from_value(fields)
+            .map_err(|e| format!("Failed to build bibliographic record: {}", e))?;
+        record = bibliography::set_record(db, &filled)?;
+    }
+
+    let mut extracted = ExtractedMetadata {
+        candidates,
+        extracted_at: chrono::Utc::now().to_rfc3339(),
+        confirmed,
+    };
+    let json = serde_json::to_string(&extracted)
+        .map_err(|e| format!("Failed to serialize extracted metadata: {}", e))?;
+    database::set_metadata(db, METADATA_KEY, &json)
+        .map_err(|e| format!("Failed to store extracted metadata: {}", e))?;
+    mark_applied(&mut extracted, &record)?;
+    Ok(extracted)
+}
+
+/// The metadata extracted on import, with `applied` set on the candidates
+/// the bibliographic record currently holds. None for documents imported
+/// before extraction existed.
+pub fn get_extracted(db: &Connection) -> Result<Option<ExtractedMetadata>, String> {
+    let Some(json) = database::get_metadata(db, METADATA_KEY)
+        .map_err(|e| format!("Failed to read extracted metadata: {}", e))?
+    else {
+        return Ok(None);
+    };
+    let mut extracted: ExtractedMetadata =
+        serde_json::from_str(&json).map_err(|e| format!("Invalid extracted metadata: {}", e))?;
+    mark_applied(&mut extracted, &bibliography::get_record(db)?)?;
+    Ok(Some(extracted))
+}
+
+/// Record that the user has reviewed the bibliographic record, so later
+/// extraction leaves it alone.
+pub fn confirm(db: &Connection) -> Result<(), String> {
+    let Some(mut extracted) = get_extracted(db)? else {
+        return Ok(());
+    };
+    extracted.confirmed = true;
+    let json = serde_json::to_string(&extracted)
+        .map_err(|e| format!("Failed to serialize extracted metadata: {}", e))?;
+    database::set_metadata(db, METADATA_KEY, &json)
+        .map_err(|e| format!("Failed to store extracted metadata: {}", e))
+}
+
+/// Candidates from every source in the PDF.
+pub fn extract(doc: &Document) -> Vec<MetadataCandidate> {
+    let mut candidates = Vec::new();
+    xmp_candidates(doc, &mut candidates);
+    info_candidates(doc, &mut candidates);
+    text_candidates(doc, &mut candidates);
+    layout_candidates(doc, &mut candidates);
+    candidates
+}
+
+/// Parse a PDF once for everything read from it on import. A PDF that can't
+/// be parsed yields None rather than failing, since the viewer may still
+/// display it.
+pub fn parse(pdf_path: &Path) -> Option<Document> {
+    load(pdf_path)
+        .inspect_err(|e| log::warn!("[metadata] Failed to parse {}: {}", pdf_path.display(), e))
+        .ok()
+}
+
+/// Parse a PDF, refusing streams that decompress to more than
+/// `MAX_STREAM_BYTES`.
+pub fn load(pdf_path: &Path) -> lopdf::Result<Document> {
+    let options = LoadOptions {
+        max_decompressed_size: Some(MAX_STREAM_BYTES),
+        ..Default::default()
+    };
+    Document::load_with_options(pdf_path, options)
+}
+
+fn candidate(
+    field: &str,
+    value: Value,
+    source: MetadataSource,
+    confidence: f32,
+) -> MetadataCandidate {
+    MetadataCandidate {
+        field: field.to_string(),
+        value,
+        source,
+        confidence,
+        applied: false,
+    }
+}
+
+/// The most confident candidate for `field`; the earlier one on a tie.
+fn best_candidate<'a>(
+    candidates: &'a [MetadataCandidate],
+    field: &str,
+) -> Option<&'a MetadataCandidate> {
+    candidates.iter().filter(|c| c.field == field).fold(
+        None,
+        |best: Option<&MetadataCandidate>, c| match best {
+            Some(b) if b.confidence >= c.confidence => Some(b),
+            _ => Some(c),
+        },
+    )
+}
+
+/// The record as a JSON object keyed by field name.
+fn record_fields(record: &BibliographicRecord) -> Result<Value, String> {
+    serde_json::to_value(record)
+        .map_err(|e| format!("Failed to serialize bibliographic record: {}", e))
+}
+
+fn mark_applied(
+    extracted: &mut ExtractedMetadata,
+    record: &BibliographicRecord,
+) -> Result<(), String> {
+    let fields = record_fields(record)?;
+    for candidate in &mut extracted.candidates {
+        candidate.applied = fields.get(&candidate.field) == Some(&candidate.value);
+    }
+    Ok(())
+}
+
+/// Title, authors, keywords and creation year from the document information
+/// dictionary. Producers often fill these with file names or leave
+/// placeholders, so implausible values are dropped.
+fn info_candidates(doc: &Document, out: &mut Vec<MetadataCandidate>) {
+    let Some(info) = doc
+        .trailer
+        .get(b"Info")
+        .ok()
+        .and_then(|o| doc.dereference(o).ok())
+        .and_then(|(_, o)| o.as_dict().ok())
+    else {
+        return;
+    };
+    let text = |key: &[u8]| {
+        info.get(key)
+            .ok()
+            .and_then(|o| doc.dereference(o).ok())
+            .and_then(|(_, o)| lopdf::decode_text_string(o).ok())
+            .map(|s| collapse_whitespace(&s))
+            .filter(|s| !s.is_empty())
+    };
+
+    if let Some(title) = text(b"Title").and_then(|t| plausible_title(&t)) {
+        out.push(candidate("title", json!(title), MetadataSource::Info, 0.6));
+    }
+    if let Some(authors) = text(b"Author").map(|a| split_authors(&a)) {
+        if !authors.is_empty() {
+            out.push(candidate(
+                "authors",
+                json!(authors),
+                MetadataSource::Info,
+                0.5,
+            ));
+        }
+    }
+    if let Some(keywords) = text(b"Keywords").map(|k| split_keywords(&k)) {
+        if !keywords.is_empty() {
+            out.push(candidate(
+                "keywords",
+                json!(keywords),
+                MetadataSource::Info,
+                0.5,
+            ));
+        }
+    }
+    // When the file was made, which is only a hint at when it was published
+    if let Some(year) = text(b"CreationDate").and_then(|d| parse_year(d.trim_start_matches("D:"))) {
+        out.push(candidate("year", json!(year), MetadataSource::Info, 0.2));
+    }
+}
+
+/// Dublin Core and PRISM properties from the catalog's XMP stream.
+fn xmp_candidates(doc: &Document, out: &mut Vec<MetadataCandidate>) {
+    let Some(stream) = doc
+        .catalog()
+        .ok()
+        .and_then(|catalog| catalog.get(b"Metadata").ok())
+        .and_then(|o| doc.dereference(o).ok())
+        .and_then(|(_, o)| o.as_stream().ok())
+    else {
+        return;
+    };
+    let xml = match stream.decompressed_content_with_limit(MAX_STREAM_BYTES) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(e) => {
+            log::warn!("[metadata] Failed to read XMP metadata: {}", e);
+            return;
+        }
+    };
+    let properties = match xmp_properties(&xml) {
+        Ok(properties) => properties,
+        Err(e) => {
+            log::warn!("[metadata] {}", e);
+            return;
+        }
+    };
+    let first = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| properties.get(*name).and_then(|values| values.first()))
+    };
+
+    if let Some(title) = first(&["dc:title"]).and_then(|t| plausible_title(t)) {
+        out.push(candidate("title", json!(title), MetadataSource::Xmp, 0.7));
+    }
+    if let Some(creators) = properties.get("dc:creator") {
+        let authors: Vec<String> = match creators.as_slice() {
+            [single] => split_authors(single),
+            many => many.to_vec(),
+        };
+        if !authors.is_empty() {
+            out.push(candidate(
+                "authors",
+                json!(authors),
+                MetadataSource::Xmp,
+                0.7,
+            ));
+        }
+    }
+    if let Some(doi) = ["prism:doi", "pdfx:doi", "dc:identifier"]
+        .iter()
+        .flat_map(|name| properties.get(*name).into_iter().flatten())
+        .find_map(|value| bibliography::normalize_doi(value))
+    {
+        out.push(candidate("doi", json!(doi), MetadataSource::Xmp, 0.9));
+    }
+    if let Some(venue) = first(&["prism:publicationName"]) {
+        out.push(candidate("venue", json!(venue), MetadataSource::Xmp, 0.7));
+    }
+    if let Some(year) =
+        first(&["prism:coverDate", "prism:publicationDate"]).and_then(|d| parse_year(d))
+    {
+        out.push(candidate("year", json!(year), MetadataSource::Xmp, 0.7));
+    } else if let Some(year) = first(&["xmp:CreateDate"]).and_then(|d| parse_year(d)) {
+        out.push(candidate("year", json!(year), MetadataSource::Xmp, 0.2));
+    }
+    // dc:description is often just a copy of the subject line; only take
+    // something long enough to be an abstract
+    if let Some(description) = first(&["dc:description"]).filter(|d| d.len() >= 200) {
+        out.push(candidate(
+            "abstract",
+            json!(description),
+            MetadataSource::Xmp,
+            0.4,
+        ));
+    }
+    if let Some(subjects) = properties.get("dc:subject") {
+        let keywords: Vec<String> = subjects.iter().flat_map(|s| split_keywords(s)).collect();
+        if !keywords.is_empty() {
+            out.push(candidate(
+                "keywords",
+                json!(keywords),
+                MetadataSource::Xmp,
+                0.6,
+            ));
+        }
+    }
+}
+
+/// Text values of XMP properties, keyed by qualified name (`dc:title`).
+/// Each item of an rdf:Alt, rdf:Seq or rdf:Bag is a separate value, as is
+/// a property written as an attribute of rdf:Description.
+fn xmp_properties(xml: &str) -> Result<HashMap<String, Vec<String>>, String> {
+    let mut reader = Reader::from_str(xml);
+    let mut properties: HashMap<String, Vec<String>> = HashMap::new();
+    // Open elements, and the text read since the last one started
+    let mut stack: Vec<String> = Vec::new();
+    let mut text = String::new();
+
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| format!("Invalid XMP metadata: {}", e))?;
+        let is_start = matches!(event, Event::Start(_));
+        match event {
+            Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"rdf:Description" => {
+                for attr in e.attributes().flatten() {
+                    let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+                    if key.starts_with("xmlns") || key.starts_with("rdf:") {
+                        continue;
+                    }
+                    if let Ok(value) = attr.unescape_value() {
+                        let value = collapse_whitespace(&value);
+                        if !value.is_empty() {
+                            properties.entry(key).or_default().push(value);
+                        }
+                    }
+                }
+                if is_start {
+                    stack.push("rdf:Description".to_string());
+                    text.clear();
+                }
+            }
+            Event::Start(e) => {
+                stack.push(String::from_utf8_lossy(e.name().as_ref()).into_owned());
+                text.clear();
+            }
+            Event::Text(t) => {
+                let content = t
+                    .xml_content()
+                    .map_err(|e| format!("Invalid XMP metadata: {}", e))?;
+                text.push_str(&content);
+            }
+            Event::GeneralRef(r) => {
+                let resolved = r
+                    .resolve_char_ref()
+                    .ok()
+                    .flatten()
+                    .map(|c| c.to_string())
+                    .or_else(|| {
+                        r.decode().ok().and_then(|name| {
+                            quick_xml::escape::resolve_predefined_entity(&name).map(str::to_string)
+                        })
+                    })
+                    .unwrap_or_default();
+                text.push_str(&resolved);
+            }
+            Event::End(_) => {
+                let element = stack.pop().unwrap_or_default();
+                let value = collapse_whitespace(&text);
+                text.clear();
+                if value.is_empty() {
+                    continue;
+                }
+                // A list item belongs to the property around its container
+                let property = if element == "rdf:li" {
+                    stack.iter().rev().find(|name| !name.starts_with("rdf:"))
+                } else {
+                    Some(&element).filter(|name| !name.starts_with("rdf:"))
+                };
+                if let Some(property) = property {
+                    properties.entry(property.clone()).or_default().push(value);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(properties)
+}
+
+/// A DOI or arXiv id printed on the first pages, such as in a header,
+/// footer or the arXiv stamp in the margin.
+fn text_candidates(doc: &Document, out: &mut Vec<MetadataCandidate>) {
+    for page in 1..=TEXT_PAGES.min(doc.get_pages().len() as u32) {
+        let text: String = doc
+            .extract_text_chunks_with_limit(&[page], MAX_STREAM_BYTES)
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ");
+        // Identifiers on the first page are more likely to be the paper's own
+        let confidence = if page == 1 { 0.8 } else { 0.6 };
+
+        if !out
+            .iter()
+            .any(|c| c.field == "doi" && c.source == MetadataSource::Text)
+        {
+            if let Some(doi) = find_doi(&text) {
+                out.push(candidate(
+                    "doi",
+                    json!(doi),
+                    MetadataSource::Text,
+                    confidence,
+                ));
+            }
+        }
+        if !out.iter().any(|c| c.field == "arxiv_id") {
+            if let Some(id) = find_arxiv_id(&text) {
+                let year = arxiv_year(&id);
+                out.push(candidate(
+                    "arxiv_id",
+                    json!(id),
+                    MetadataSource::Text,
+                    confidence + 0.1,
+                ));
+                if let Some(year) = year {
+                    out.push(candidate("year", json!(year), MetadataSource::Text, 0.5));
+                }
+            }
+        }
+    }
+}
+
+/// New-style arXiv ids start with the year and month of submission.
+fn arxiv_year(id: &str) -> Option<i32> {
+    id.get(..2)
+        .filter(|_| !id.contains('/'))
+        .and_then(|yy| yy.parse::<i32>().ok())
+        .map(|yy| 2000 + yy)
+}
+
+fn find_doi(text: &str) -> Option<String> {
+    text.split_whitespace().find_map(|word| {
+        let start = word.find("10.")?;
+        let doi = word[start..].trim_end_matches(|c: char| ".,;:)]}>\"'".contains(c));
+        bibliography::normalize_doi(doi)
+    })
+}
+
+fn find_arxiv_id(text: &str) -> Option<String> {
+    text.split_whitespace().find_map(|word| {
+        let lower = word.to_ascii_lowercase();
+        let start = lower.find("arxiv:").or_else(|| lower.find("arxiv.org/"))?;
+        let id = word[start..].trim_end_matches(|c: char| ".,;:)]}>\"'".contains(c));
+        let id = match id.split_once("arxiv.org/") {
+            Some((_, rest)) => format!("https://arxiv.org/{}", rest),
+            None => id.to_string(),
+        };
+        bibliography::normalize_arxiv_id(&id)
+    })
+}
+
+/// Title and authors from the first page: the title is set in the largest
+/// type near the top, and the author line usually follows it directly.
+fn layout_candidates(doc: &Document, out: &mut Vec<MetadataCandidate>) {
+    let Some(page_id) = doc.page_iter().next() else {
+        return;
+    };
+    let lines = match page_lines(doc, page_id) {
+        Ok(lines) => lines,
+        Err(e) => {
+            log::warn!("[metadata] Failed to read first page: {}", e);
+            return;
+        }
+    };
+    let lines: Vec<Line> = lines
+        .into_iter()
+        .map(|line| Line {
+            text: collapse_whitespace(&line.text),
+            size: line.size,
+        })
+        .filter(|line| line.text.chars().filter(|c| c.is_alphabetic()).count() >= 2)
+        .collect();
+    if lines.len() < 3 {
+        return;
+    }
+
+    let mut sizes: Vec<f32> = lines.iter().map(|line| line.size).collect();
+    sizes.sort_by(f32::total_cmp);
+    let body_size = sizes[sizes.len() / 2];
+    let head = &lines[..lines.len().min(HEAD_LINES)];
+    let largest = head.iter().map(|line| line.size).fold(0.0, f32::max);
+    if largest < body_size * 1.15 {
+        return;
+    }
+
+    let same_size = |line: &Line| (line.size - largest).abs() < 0.5;
+    let Some(start) = head.iter().position(same_size) else {
+        return;
+    };
+    let end = start
+        + lines[start..]
+            .iter()
+            .take_while(|line| same_size(line))
+            .count();
+    let title = lines[start..end]
+        .iter()
+        .map(|line| line.text.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Some(title) = plausible_title(&title) {
+        let confidence = if largest >= body_size * 1.5 {
+            0.6
+        } else {
+            0.45
+        };
+        out.push(candidate(
+            "title",
+            json!(title),
+            MetadataSource::Layout,
+            confidence,
+        ));
+    }
+    if let Some(authors) = lines.get(end).and_then(|line| author_line(&line.text)) {
+        out.push(candidate(
+            "authors",
+            json!(authors),
+            MetadataSource::Layout,
+            0.35,
+        ));
+    }
+}
+
+/// A line of text and the largest font size on it, in points.
+struct Line {
+    text: String,
+    size: f32,
+}
+
+/// The page's text as lines in content stream order. Only the vertical
+/// position of each text run is tracked, which is enough to tell lines
+/// apart in horizontal text.
+fn page_lines(doc: &Document, page_id: ObjectId) -> lopdf::Result<Vec<Line>> {
+    let encodings: BTreeMap<Vec<u8>, Encoding> = doc
+        .get_page_fonts(page_id)?
+        .into_iter()
+        .filter_map(|(name, font)| {
+            font.get_font_encoding_with_limit(doc, MAX_STREAM_BYTES)
+                .ok()
+                .map(|encoding| (name, encoding))
+        })
+        .collect();
+    let content = Content::decode(&doc.get_page_content_with_limit(page_id, MAX_STREAM_BYTES)?)?;
+
+    let mut lines: Vec<Line> = Vec::new();
+    let mut last_y: Option<f32> = None;
+    let mut ctm = IDENTITY;
+    let mut saved: Vec<Matrix> = Vec::new();
+    let mut text_matrix = IDENTITY;
+    let mut line_matrix = IDENTITY;
+    let mut font: Option<&Encoding> = None;
+    let mut font_size = 0.0;
+    let mut leading = 0.0;
+    // Whether the text position moved since the last run, which usually
+    // means a word boundary when it stays on the same line
+    let mut moved = false;
+
+    for operation in &content.operations {
+        let operands = &operation.operands;
+        let number = |i: usize| {
+            operands
+                .get(i)
+                .and_then(|o| o.as_float().ok())
+                .unwrap_or(0.0)
+        };
+        let matrix = || -> Matrix {
+            [
+                number(0),
+                number(1),
+                number(2),
+                number(3),
+                number(4),
+                number(5),
+            ]
+        };
+        // T*, ' and " start a new line before anything else
+        if matches!(operation.operator.as_str(), "T*" | "'" | "\"") {
+            line_matrix = multiply([1.0, 0.0, 0.0, 1.0, 0.0, -leading], line_matrix);
+            text_matrix = line_matrix;
+            moved = true;
+        }
+        match operation.operator.as_str() {
+            "q" => saved.push(ctm),
+            "Q" => ctm = saved.pop().unwrap_or(IDENTITY),
+            "cm" => ctm = multiply(matrix(), ctm),
+            "BT" => {
+                text_matrix = IDENTITY;
+                line_matrix = IDENTITY;
+                moved = true;
+            }
+            "Tf" => {
+                font = operands
+                    .first()
+                    .and_then(|o| o.as_name().ok())
+                    .and_then(|name| encodings.get(name));
+                font_size = number(1);
+            }
+            "TL" => leading = number(0),
+            "Td" | "TD" => {
+                if operation.operator == "TD" {
+                    leading = -number(1);
+                }
+                line_matrix = multiply([1.0, 0.0, 0.0, 1.0, number(0), number(1)], line_matrix);
+                text_matrix = line_matrix;
+                moved = true;
+            }
+            "Tm" => {
+                line_matrix = matrix();
+                text_matrix = line_matrix;
+                moved = true;
+            }
+            "Tj" | "TJ" | "'" | "\"" => {
+                let Some(encoding) = font else {
+                    continue;
+                };
+                let shown = if operation.operator == "\"" {
+                    operands
+                        .get(2)
+                        .map(std::slice::from_ref)
+                        .unwrap_or_default()
+                } else {
+                    operands.as_slice()
+                };
+                let text = shown_text(encoding, shown);
+                if text.trim().is_empty() {
+                    continue;
+                }
+
+                let m = multiply(text_matrix, ctm);
+                let size = font_size * m[2].hypot(m[3]);
+                let y = m[5];
+                match lines.last_mut() {
+                    // Superscripts such as affiliation marks sit slightly
+                    // higher but still belong to the line
+                    Some(line)
+                        if last_y
+                            .is_some_and(|last| (y - last).abs() < line.size.max(size) * 0.5) =>
+                    {
+                        if moved && !line.text.ends_with(' ') {
+                            line.text.push(' ');
+                        }
+                        line.text.push_str(&text);
+                        line.size = line.size.max(size);
+                    }
+                    _ => {
+                        lines.push(Line { text, size });
+                        last_y = Some(y);
+                    }
+                }
+                moved = false;
+            }
+            _ => {}
+        }
+    }
+    Ok(lines)
+}
+
+/// Text of Tj/TJ operands. Large negative adjustments in a TJ array are
+/// gaps between words rather than kerning.
+fn shown_text(encoding: &Encoding, operands: &[Object]) -> String {
+    let mut text = String::new();
+    for operand in operands {
+        match operand {
+            Object::String(bytes, _) => {
+                if let Ok(decoded) = Document::decode_text(encoding, bytes) {
+                    text.push_str(&decoded);
+                }
+            }
+            Object::Array(items) => text.push_str(&shown_text(encoding, items)),
+            Object::Integer(_) | Object::Real(_)
+                if operand.as_float().is_ok_and(|gap| gap < -250.0) && !text.ends_with(' ') =>
+            {
+                text.push(' ');
+            }
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Product of two PDF transformation matrices, `a` applied first.
+fn multiply(a: Matrix, b: Matrix) -> Matrix {
+    [
+        a[0] * b[0] + a[1] * b[2],
+        a[0] * b[1] + a[1] * b[3],
+        a[2] * b[0] + a[3] * b[2],
+        a[2] * b[1] + a[3] * b[3],
+        a[4] * b[0] + a[5] * b[2] + b[4],
+        a[4] * b[1] + a[5] * b[3] + b[5],
+    ]
+}
+
+/// The title, unless it looks like a file name or placeholder.
+fn plausible_title(title: &str) -> Option<String> {
+    let title = collapse_whitespace(title);
+    // Word and similar tools prefix the title with their own name
+    let title = title
+        .strip_prefix("Microsoft Word - ")
+        .unwrap_or(&title)
+        .to_string();
+    let lower = title.to_lowercase();
+    let file_like = [".pdf", ".doc", ".docx", ".dvi", ".tex", ".indd", ".ps"]
+        .iter()
+        .any(|ext| lower.ends_with(ext));
+    let placeholder = ["untitled", "title", "no title", "document"].contains(&lower.as_str());
+    let letters = title.chars().filter(|c| c.is_alphabetic()).count();
+    let valid = (4..=300).contains(&title.chars().count())
+        && letters >= 4
+        && title.contains(' ')
+        && !file_like
+        && !placeholder;
+    valid.then_some(title)
+}
+
+/// Author names from a metadata field: `A; B`, or `A, B and C`. A comma
+/// can also separate family and given names (`Smith, J.`), so the field is
+/// kept whole when splitting on commas leaves single words.
+fn split_authors(value: &str) -> Vec<String> {
+    let parts: Vec<String> = if value.contains(';') {
+        value.split(';').map(collapse_whitespace).collect()
+    } else {
+        value
+            .replace(" and ", ",")
+            .replace(" & ", ",")
+            .split(',')
+            .map(collapse_whitespace)
+            .collect()
+    };
+    let parts: Vec<String> = parts.into_iter().filter(|p| !p.is_empty()).collect();
+    if value.contains(';') || parts.iter().all(|p| p.contains(' ')) {
+        parts
+    } else {
+        vec![collapse_whitespace(value)]
+    }
+}
+
+/// Names on an author line, or None if anything on it doesn't look like a
+/// name. Footnote and affiliation marks are dropped.
+fn author_line(text: &str) -> Option<Vec<String>> {
+    const PARTICLES: [&str; 8] = ["van", "von", "de", "der", "den", "di", "da", "la"];
+    let cleaned: String = text
+        .chars()
+        .filter(|c| !c.is_ascii_digit() && !"*†‡§¶∗".contains(*c))
+        .collect();
+    let names: Vec<String> = cleaned
+        .replace(" and ", ",")
+        .replace(" & ", ",")
+        .split(',')
+        .map(collapse_whitespace)
+        .filter(|name| !name.is_empty())
+        .collect();
+    let name_like = |name: &String| {
+        let words: Vec<&str> = name.split(' ').collect();
+        (2..=5).contains(&words.len())
+            && words.iter().all(|word| {
+                PARTICLES.contains(word)
+                    || word.chars().next().is_some_and(char::is_uppercase)
+                        && word
+                            .chars()
+                            .all(|c| c.is_alphabetic() || ".-'’".contains(c))
+            })
+    };
+    (!names.is_empty() && names.iter().all(name_like)).then_some(names)
+}
+
+fn split_keywords(value: &str) -> Vec<String> {
+    value
+        .split([',', ';'])
+        .map(collapse_whitespace)
+        .filter(|k| !k.is_empty())
+        .collect()
+}
+
+/// Year from a date that starts with one, as in XMP (`2021-03-04`) and PDF
+/// (`20210304...`) dates.
+fn parse_year(date: &str) -> Option<i32> {
+    use chrono::Datelike;
+    let year: i32 = date.trim().get(..4)?.parse().ok()?;
+    (1900..=chrono::Utc::now().year() + 1)
+        .contains(&year)
+        .then_some(year)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::content::Operation;
+    use lopdf::{dictionary, Dictionary, Stream};
+
+    const XMP: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:prism="http://prismstandard.org/namespaces/basic/2.0/"
+    prism:doi="10.1000/xmp.2020.7" prism:coverDate="2020-05-01">
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Graph Networks &amp; Their
+     Applications</rdf:li></rdf:Alt></dc:title>
+   <dc:creator><rdf:Seq><rdf:li>Ada Lovelace</rdf:li><rdf:li>Alan Turing</rdf:li></rdf:Seq></dc:creator>
+   <prism:publicationName>Journal of Graphs</prism:publicationName>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+    /// A PDF whose first page shows `lines` in the given font sizes, top
+    /// to bottom, with `second_page` on a second page.
+    fn pdf(
+        lines: &[(&str, i64)],
+        second_page: &str,
+        info: Option<Dictionary>,
+        xmp: Option<&str>,
+    ) -> Document {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font", "Subtype" => "Type1", "BaseFont" => "Helvetica",
+        });
+        let resources_id =
+            doc.add_object(dictionary! { "Font" => dictionary! { "F1" => font_id } });
+        let mut page = |lines: &[(&str, i64)]| -> Object {
+            let mut operations = Vec::new();
+            let mut y = 800;
+            for (text, size) in lines {
+                y -= size * 2;
+                operations.extend([
+                    Operation::new("BT", vec![]),
+                    Operation::new("Tf", vec!["F1".into(), (*size).into()]),
+                    Operation::new("Td", vec![72.into(), y.into()]),
+                    Operation::new("Tj", vec![Object::string_literal(*text)]),
+                    Operation::new("ET", vec![]),
+                ]);
+            }
+            let content = Content { operations }.encode().unwrap();
+            let content_id = doc.add_object(Stream::new(dictionary! {}, content));
+            doc.add_object(dictionary! {
+                "Type" => "Page", "Parent" => pages_id, "Contents" => content_id,
+            })
+            .into()
+        };
+        let kids = vec![page(lines), page(&[(second_page, 10)])];
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages", "Kids" => kids, "Count" => 2,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let mut catalog = dictionary! { "Type" => "Catalog", "Pages" => pages_id };
+        if let Some(xmp) = xmp {
+            let stream = Stream::new(
+                dictionary! { "Type" => "Metadata", "Subtype" => "XML" },
+                xmp.as_bytes().to_vec(),
+            );
+            catalog.set("Metadata", doc.add_object(stream));
+        }
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", catalog_id);
+        if let Some(info) = info {
+            let info_id = doc.add_object(info);
+            doc.trailer.set("Info", info_id);
+        }
+        doc
+    }
+
+    fn first_page() -> Vec<(&'static str, i64)> {
+        vec![
+            ("arXiv:2101.00001v3 [cs.LG] 4 Jan 2021", 8),
+            ("Learning to Rank", 18),
+            ("with Large Models", 18),
+            ("Grace Hopper1, Edsger W. Dijkstra2*", 12),
+            ("Abstract", 10),
+            ("We study ranking with models that are large.", 10),
+            ("Our results show that size matters a lot.", 10),
+            ("1 Introduction", 10),
+            ("Ranking is a problem of long standing.", 10),
+        ]
+    }
+
+    fn best(candidates: &[MetadataCandidate], field: &str) -> (Value, MetadataSource, f32) {
+        let best = best_candidate(candidates, field).unwrap();
+        (best.value.clone(), best.source, best.confidence)
+    }
+
+    fn new_db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        database::init_db(&db).unwrap();
+        db
+    }
+
+    #[test]
+    fn candidates_are_ranked_by_source() {
+        let info = dictionary! {
+            "Title" => Object::string_literal("Graph Networks: A Survey"),
+            "Author" => Object::string_literal("A. Lovelace; A. Turing"),
+            "Keywords" => Object::string_literal("graphs, networks; survey"),
+            "CreationDate" => Object::string_literal("D:20190304120000Z"),
+        };
+        let doc = pdf(
+            &first_page(),
+            "See https://doi.org/10.5555/text.1 for the data.",
+            Some(info),
+            Some(XMP),
+        );
+        let candidates = extract(&doc);
+
+        assert_eq!(
+            best(&candidates, "title"),
+            (
+                json!("Graph Networks & Their Applications"),
+                MetadataSource::Xmp,
+                0.7
+            )
+        );
+        assert_eq!(
+            best(&candidates, "authors"),
+            (
+                json!(["Ada Lovelace", "Alan Turing"]),
+                MetadataSource::Xmp,
+                0.7
+            )
+        );
+        assert_eq!(
+            best(&candidates, "doi"),
+            (json!("10.1000/xmp.2020.7"), MetadataSource::Xmp, 0.9)
+        );
+        assert_eq!(
+            best(&candidates, "year"),
+            (json!(2020), MetadataSource::Xmp, 0.7)
+        );
+        assert_eq!(
+            best(&candidates, "venue"),
+            (json!("Journal of Graphs"), MetadataSource::Xmp, 0.7)
+        );
+        assert_eq!(
+            best(&candidates, "keywords"),
+            (
+                json!(["graphs", "networks", "survey"]),
+                MetadataSource::Info,
+                0.5
+            )
+        );
+        // The arXiv stamp is on the first page; the DOI on the second
+        assert_eq!(
+            best(&candidates, "arxiv_id"),
+            (json!("2101.00001v3"), MetadataSource::Text, 0.8 + 0.1)
+        );
+        let lesser = |field: &str, source| {
+            candidates
+                .iter()
+                .find(|c| c.field == field && c.source == source)
+                .map(|c| (c.value.clone(), c.confidence))
+        };
+        assert_eq!(
+            lesser("doi", MetadataSource::Text),
+            Some((json!("10.5555/text.1"), 0.6))
+        );
+        assert_eq!(
+            lesser("title", MetadataSource::Info),
+            Some((json!("Graph Networks: A Survey"), 0.6))
+        );
+        assert_eq!(
+            lesser("authors", MetadataSource::Info),
+            Some((json!(["A. Lovelace", "A. Turing"]), 0.5))
+        );
+        assert_eq!(
+            lesser("year", MetadataSource::Info),
+            Some((json!(2019), 0.2))
+        );
+        assert_eq!(
+            lesser("year", MetadataSource::Text),
+            Some((json!(2021), 0.5))
+        );
+    }
+
+    #[test]
+    fn title_and_authors_from_the_first_page() {
+        let doc = pdf(&first_page(), "", None, None);
+        let candidates = extract(&doc);
+        assert_eq!(
+            best(&candidates, "title"),
+            (
+                json!("Learning to Rank with Large Models"),
+                MetadataSource::Layout,
+                0.6
+            )
+        );
+        assert_eq!(
+            best(&candidates, "authors"),
+            (
+                json!(["Grace Hopper", "Edsger W. Dijkstra"]),
+                MetadataSource::Layout,
+                0.35
+            )
+        );
+
+        // A title barely larger than the text is less certain, and a line
+        // that isn't names isn't taken as the authors
+        let mut lines = first_page();
+        lines[1].1 = 12;
+        lines[2].1 = 12;
+        lines[3] = ("University of Somewhere, Department of Things", 10);
+        let candidates = extract(&pdf(&lines, "", None, None));
+        assert_eq!(
+            best(&candidates, "title"),
+            (
+                json!("Learning to Rank with Large Models"),
+                MetadataSource::Layout,
+                0.45
+            )
+        );
+        assert!(best_candidate(&candidates, "authors").is_none());
+
+        // Nothing stands out
+        let lines: Vec<_> = first_page()
+            .into_iter()
+            .map(|(text, _)| (text, 10))
+            .collect();
+        assert!(best_candidate(&extract(&pdf(&lines, "", None, None)), "title").is_none());
+    }
+
+    #[test]
+    fn implausible_info_values_are_dropped() {
+        let info = dictionary! {
+            "Title" => Object::string_literal("Microsoft Word - paper_final.docx"),
+            "Author" => Object::string_literal("Lovelace, A."),
+        };
+        let candidates = extract(&pdf(&[("x", 10)], "", Some(info), None));
+        assert!(best_candidate(&candidates, "title").is_none());
+        assert_eq!(best(&candidates, "authors").0, json!(["Lovelace, A."]));
+
+        assert_eq!(
+            plausible_title("Microsoft Word - Graph  Networks"),
+            Some("Graph Networks".to_string())
+        );
+        assert_eq!(plausible_title("Untitled"), None);
+        assert_eq!(plausible_title("main.tex"), None);
+        assert_eq!(plausible_title("a b"), None);
+        assert_eq!(
+            split_authors("Ada Lovelace, Alan Turing and Grace Hopper"),
+            ["Ada Lovelace", "Alan Turing", "Grace Hopper"]
+        );
+    }
+
+    #[test]
+    fn arxiv_file_names() {
+        let db = new_db();
+        let extracted = extract_into(&db, None, Some("2310.01234v2")).unwrap();
+        let record = bibliography::get_record(&db).unwrap();
+        assert_eq!(record.title.as_deref(), Some("2310.01234v2"));
+        assert_eq!(record.arxiv_id.as_deref(), Some("2310.01234v2"));
+        assert_eq!(record.year, Some(2023));
+        assert!(extracted.candidates.iter().all(|c| c.applied));
+
+        // Reopened with a readable PDF, its title replaces the file name;
+        // fields already filled are kept
+        let doc = pdf(&first_page(), "", None, None);
+        let extracted = extract_into(&db, Some(&doc), Some("2310.01234v2")).unwrap();
+        let record = bibliography::get_record(&db).unwrap();
+        assert_eq!(
+            record.title.as_deref(),
+            Some("Learning to Rank with Large Models")
+        );
+        assert_eq!(record.arxiv_id.as_deref(), Some("2310.01234v2"));
+        assert_eq!(record.year, Some(2023));
+        let applied = |field: &str, source| {
+            extracted
+                .candidates
+                .iter()
+                .find(|c| c.field == field && c.source == source)
+                .unwrap()
+                .applied
+        };
+        assert!(applied("title", MetadataSource::Layout));
+        assert!(!applied("title", MetadataSource::Filename));
+        assert!(!applied("arxiv_id", MetadataSource::Text));
+
+        // Nothing changes once confirmed
+        confirm(&db).unwrap();
+        let info = dictionary! { "Title" => Object::string_literal("Another Title Here") };
+        let extracted = extract_into(&db, Some(&pdf(&[], "", Some(info), None)), None).unwrap();
+        assert!(extracted.confirmed);
+        assert_eq!(
+            bibliography::get_record(&db).unwrap().title.as_deref(),
+            Some("Learning to Rank with Large Models")
+        );
+
+        // Other file names are only titles
+        let db = new_db();
+        extract_into(&db, None, Some("paper_final")).unwrap();
+        let record = bibliography::get_record(&db).unwrap();
+        assert_eq!(record.title.as_deref(), Some("paper_final"));
+        assert_eq!((record.arxiv_id, record.year), (None, None));
+    }
+}
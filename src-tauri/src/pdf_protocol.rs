@@ -0,0 +1,268 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// URI scheme serving the open document's PDF to the viewer at
+/// `rr://localhost/<pdf_sha256>.pdf`, so PDF.js can fetch the byte ranges it
+/// needs instead of receiving the whole file over IPC. The hash in the path
+/// keeps requests still in flight for a closed document from being answered
+/// with another document's bytes.
+pub const SCHEME: &str = "rr";
+
+/// An HTTP response, kept independent of Tauri's types
+pub struct PdfResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Which bytes of the file a Range header asks for
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    /// No usable Range header: send the whole file
+    Whole,
+    /// First and last byte, inclusive
+    Part(u64, u64),
+    /// The range lies past the end of the file
+    Unsatisfiable,
+}
+
+/// Answer a request for the PDF. `pdf` is the open document's PDF and its
+/// SHA-256, if a document is open; `range` is the request's Range header.
+/// Only single ranges are served; other Range headers get the whole file,
+/// which HTTP allows.
+pub fn respond(
+    pdf: Option<(&Path, &str)>,
+    method: &str,
+    path: &str,
+    range: Option<&str>,
+) -> PdfResponse {
+    let mut headers = vec![
+        ("Access-Control-Allow-Origin", "*".to_string()),
+        (
+            "Access-Control-Expose-Headers",
+            "Accept-Ranges, Content-Length, Content-Range".to_string(),
+        ),
+        ("Cache-Control", "no-store".to_string()),
+    ];
+    match method {
+        "GET" | "HEAD" => {}
+        // CORS preflight for the Range header
+        "OPTIONS" => {
+            headers.push(("Access-Control-Allow-Methods", "GET, HEAD".to_string()));
+            headers.push(("Access-Control-Allow-Headers", "Range".to_string()));
+            return PdfResponse {
+                status: 204,
+                headers,
+                body: Vec::new(),
+            };
+        }
+        _ => return text_response(405, headers, "Method not allowed"),
+    }
+
+    let Some((pdf_path, pdf_sha256)) = pdf else {
+        return text_response(404, headers, "No document is open");
+    };
+    if path.trim_start_matches('/') != format!("{}.pdf", pdf_sha256) {
+        return text_response(404, headers, "Not the open document");
+    }
+    let len = match fs::metadata(pdf_path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) => return text_response(500, headers, &format!("Failed to read PDF: {}", e)),
+    };
+    headers.push(("Accept-Ranges", "bytes".to_string()));
+    headers.push(("ETag", format!("\"{}\"", pdf_sha256)));
+
+    let (status, start, end) = match range.map_or(ByteRange::Whole, |r| parse_range(r, len)) {
+        ByteRange::Whole => (200, 0, len),
+        ByteRange::Part(first, last) => {
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", first, last, len)));
+            (206, first, last + 1)
+        }
+        ByteRange::Unsatisfiable => {
+            headers.push(("Content-Range", format!("bytes */{}", len)));
+            return text_response(416, headers, "Range not satisfiable");
+        }
+    };
+    headers.push(("Content-Type", "application/pdf".to_string()));
+    headers.push(("Content-Length", (end - start).to_string()));
+    if method == "HEAD" {
+        return PdfResponse {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+    }
+    match read_bytes(pdf_path, start, end) {
+        Ok(body) => PdfResponse {
+            status,
+            headers,
+            body,
+        },
+        Err(e) => {
+            headers.retain(|(name, _)| !name.starts_with("Content-"));
+            text_response(500, headers, &format!("Failed to read PDF: {}", e))
+        }
+    }
+}
+
+/// Parse a Range header such as `bytes=0-65535`, `bytes=1024-` or
+/// `bytes=-500` against a file of `len` bytes.
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some((first, last)) = header
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return ByteRange::Whole;
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // The last `suffix` bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(suffix) => ByteRange::Part(len.saturating_sub(suffix), len - 1),
+            Err(_) => ByteRange::Whole,
+        };
+    }
+    let Ok(first) = first.parse::<u64>() else {
+        return ByteRange::Whole;
+    };
+    let last = if last.is_empty() {
+        None
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= first => Some(last),
+            _ => return ByteRange::Whole,
+        }
+    };
+    if first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Part(first, last.map_or(len - 1, |last| last.min(len - 1)))
+}
+
+/// Bytes `start..end` of a file.
+fn read_bytes(path: &Path, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut body = vec![0; (end - start) as usize];
+    file.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn text_response(status: u16, mut headers: Vec<(&'static str, String)>, text: &str) -> PdfResponse {
+    headers.push(("Content-Type", "text/plain; charset=utf-8".to_string()));
+    PdfResponse {
+        status,
+        headers,
+        body: text.as_bytes().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header<'a>(response: &'a PdfResponse, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Part(0, 99));
+        assert_eq!(
+            parse_range(" bytes=10 - 19 ", 1000),
+            ByteRange::Part(10, 19)
+        );
+        assert_eq!(parse_range("bytes=900-", 1000), ByteRange::Part(900, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Part(900, 999));
+        // Ranges running past the end are cut short
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            ByteRange::Part(500, 999)
+        );
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Part(0, 999));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(
+            parse_range("bytes=2000-3000", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-10", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn other_headers_get_the_whole_file() {
+        for header in [
+            "",
+            "bytes=0-1,5-6",
+            "items=0-10",
+            "bytes=10-5",
+            "bytes=a-b",
+            "bytes=-x",
+            "bytes=5",
+            "bytes=-",
+        ] {
+            assert_eq!(parse_range(header, 1000), ByteRange::Whole, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn serves_the_requested_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("document.pdf");
+        let data: Vec<u8> = (0..100).collect();
+        fs::write(&path, &data).unwrap();
+        let pdf = Some((path.as_path(), "abc"));
+
+        let whole = respond(pdf, "GET", "/abc.pdf", None);
+        assert_eq!(
+            (whole.status, whole.body.as_slice()),
+            (200, data.as_slice())
+        );
+        assert_eq!(header(&whole, "Content-Length"), Some("100"));
+        assert_eq!(header(&whole, "Accept-Ranges"), Some("bytes"));
+
+        let part = respond(pdf, "GET", "/abc.pdf", Some("bytes=10-19"));
+        assert_eq!((part.status, part.body.as_slice()), (206, &data[10..20]));
+        assert_eq!(header(&part, "Content-Range"), Some("bytes 10-19/100"));
+
+        let head = respond(pdf, "HEAD", "/abc.pdf", Some("bytes=-5"));
+        assert_eq!((head.status, head.body.len()), (206, 0));
+        assert_eq!(header(&head, "Content-Length"), Some("5"));
+
+        let past_end = respond(pdf, "GET", "/abc.pdf", Some("bytes=100-"));
+        assert_eq!(past_end.status, 416);
+        assert_eq!(header(&past_end, "Content-Range"), Some("bytes */100"));
+    }
+
+    #[test]
+    fn refuses_other_documents_and_methods() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("document.pdf");
+        fs::write(&path, b"%PDF").unwrap();
+        let pdf = Some((path.as_path(), "abc"));
+
+        assert_eq!(respond(pdf, "GET", "/def.pdf", None).status, 404);
+        assert_eq!(respond(None, "GET", "/abc.pdf", None).status, 404);
+        assert_eq!(respond(pdf, "POST", "/abc.pdf", None).status, 405);
+        let preflight = respond(pdf, "OPTIONS", "/abc.pdf", None);
+        assert_eq!(preflight.status, 204);
+        assert_eq!(
+            header(&preflight, "Access-Control-Allow-Headers"),
+            Some("Range")
+        );
+    }
+}
@@ -0,0 +1,1059 @@
+use rusqlite::{params, Connection};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::bibliography;
+use crate::database;
+use crate::library;
+use crate::models::*;
+use crate::references::{self, MIN_FUZZY_TITLE_LEN, MIN_TITLE_SIMILARITY};
+
+/// Metadata key holding the SHA-256 of the page text the list was parsed from.
+pub const METADATA_KEY: &str = "reference_list.source";
+
+/// Headings that open a reference list.
+const HEADINGS: &[&str] = &[
+    "References",
+    "REFERENCES",
+    "Bibliography",
+    "BIBLIOGRAPHY",
+    "Literature Cited",
+    "LITERATURE CITED",
+    "Works Cited",
+    "WORKS CITED",
+];
+/// Headings that end the reference list when they follow its last entry.
+const END_HEADINGS: &[&str] = &[
+    "Appendix",
+    "APPENDIX",
+    "Supplementary Material",
+    "SUPPLEMENTARY MATERIAL",
+];
+/// Entries are never longer than this; the last one is cut here, since the
+/// text after it can't be told apart from the entry itself.
+const MAX_ENTRY_CHARS: usize = 1000;
+/// A heading followed by fewer entries is taken as an ordinary word.
+const MIN_ENTRIES: usize = 2;
+/// Names longer than this mean the author list wasn't recognised.
+const MAX_NAME_CHARS: usize = 60;
+/// Abbreviations whose trailing period doesn't end a sentence.
+const ABBREVIATIONS: &[&str] = &[
+    "ed", "eds", "vol", "no", "pp", "proc", "conf", "int", "intl", "trans", "j", "jr", "vs", "rev",
+    "lett", "phys", "sci", "comput", "res", "natl", "acad", "assoc", "mach", "syst", "eng", "math",
+    "stat", "inf", "intell", "soc", "symp", "univ", "dept",
+];
+
+/// An entry found in the page text, before it is stored
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedReference {
+    pub label: Option<String>,
+    pub page_number: u32,
+    pub text: String,
+    pub record: BibliographicRecord,
+}
+
+/// Parse the reference list from the stored page text and store it, unless
+/// the text hasn't changed since the last parse. Entries keep their to-read
+/// mark when their text is unchanged. Returns whether the list was rebuilt.
+pub fn update(db: &Connection) -> Result<bool, String> {
+    let pages =
+        database::get_page_texts(db).map_err(|e| format!("Failed to get page text: {}", e))?;
+    let mut hasher = Sha256::new();
+    for (page_number, text) in &pages {
+        hasher.update(page_number.to_le_bytes());
+        hasher.update(text.as_bytes());
+    }
+    let source = hex::encode(hasher.finalize());
+    let parsed = database::get_metadata(db, METADATA_KEY)
+        .map_err(|e| format!("Failed to read {}: {}", METADATA_KEY, e))?;
+    if parsed.as_deref() == Some(source.as_str()) {
+        return Ok(false);
+    }
+
+    let entries = parse(&pages);
+    let tx = db
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    let marked: Vec<String> = tx
+        .prepare("SELECT text FROM cited_references WHERE to_read = 1")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+        .map_err(|e| format!("Failed to read reference list: {}", e))?;
+    tx.execute("DELETE FROM cited_references", [])
+        .map_err(|e| format!("Failed to clear reference list: {}", e))?;
+    for (i, entry) in entries.iter().enumerate() {
+        let record = serde_json::to_string(&entry.record)
+            .map_err(|e| format!("Failed to serialize reference: {}", e))?;
+        tx.execute(
+            "INSERT INTO cited_references (position, label, page_number, text, record, to_read)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                i as u32 + 1,
+                entry.label,
+                entry.page_number,
+                entry.text,
+                record,
+                marked.contains(&entry.text),
+            ],
+        )
+        .map_err(|e| format!("Failed to store reference list: {}", e))?;
+    }
+    database::set_metadata(&tx, METADATA_KEY, &source)
+        .map_err(|e| format!("Failed to set {}: {}", METADATA_KEY, e))?;
+    tx.commit()
+        .map_err(|e| format!("Failed to commit reference list: {}", e))?;
+    Ok(true)
+}
+
+/// The stored reference list, each entry matched against the library in
+/// `conn` if given.
+pub fn get(conn: Option<&Connection>, db: &Connection) -> Result<Vec<CitedReference>, String> {
+    let mut stmt = db
+        .prepare(
+            "SELECT position, label, page_number, text, record, to_read
+             FROM cited_references ORDER BY position",
+        )
+        .map_err(|e| format!("Failed to read reference list: {}", e))?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, u32>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, u32>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, bool>(5)?,
+            ))
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|e| format!("Failed to read reference list: {}", e))?;
+
+    let index = match conn {
+        Some(conn) => {
+            Some(LibraryIndex::load(conn).map_err(|e| format!("Failed to read library: {}", e))?)
+        }
+        None => None,
+    };
+    let mut references = Vec::with_capacity(rows.len());
+    for (position, label, page_number, text, record, to_read) in rows {
+        let record: BibliographicRecord = serde_json::from_str(&record).unwrap_or_default();
+        let mut reference = CitedReference {
+            position,
+            label,
+            page_number,
+            text,
+            record,
+            to_read,
+            document: None,
+            matched_by: None,
+        };
+        if let (Some(conn), Some(index)) = (conn, &index) {
+            if let Some((id, matched_by)) = index.find(&reference.record) {
+                reference.document = library::get_entry(conn, id)
+                    .map_err(|e| format!("Failed to read library entry: {}", e))?;
+                reference.matched_by = reference.document.as_ref().map(|_| matched_by.to_string());
+            }
+        }
+        references.push(reference);
+    }
+    Ok(references)
+}
+
+/// Mark an entry of the reference list to read, or clear the mark. If the
+/// library has the cited document, its reading status is set to "to read"
+/// as well (and cleared again if it is still "to read").
+pub fn set_to_read(
+    conn: &Connection,
+    db: &Connection,
+    position: u32,
+    to_read: bool,
+) -> Result<CitedReference, String> {
+    let affected = db
+        .execute(
+            "UPDATE cited_references SET to_read = ?1 WHERE position = ?2",
+            params![to_read, position],
+        )
+        .map_err(|e| format!("Failed to mark reference: {}", e))?;
+    if affected == 0 {
+        return Err(format!("No reference at position {}", position));
+    }
+    let mut reference = get(Some(conn), db)?
+        .into_iter()
+        .find(|reference| reference.position == position)
+        .ok_or_else(|| "Reference disappeared after update".to_string())?;
+    let Some(document) = reference.document.as_mut() else {
+        return Ok(reference);
+    };
+    let status = if to_read {
+        Some(ReadingStatus::ToRead)
+    } else if document.status == Some(ReadingStatus::ToRead) {
+        None
+    } else {
+        return Ok(reference);
+    };
+    library::set_status(conn, document.id, status)
+        .map_err(|e| format!("Failed to set reading status: {}", e))?;
+    document.status = status;
+    Ok(reference)
+}
+
+/// Library documents by DOI, arXiv id and title, for matching many entries
+struct LibraryIndex {
+    by_doi: HashMap<String, i64>,
+    by_arxiv_id: HashMap<String, i64>,
+    by_title: HashMap<String, i64>,
+    titles: Vec<(String, i64)>,
+}
+
+impl LibraryIndex {
+    fn load(conn: &Connection) -> rusqlite::Result<Self> {
+        let mut index = LibraryIndex {
+            by_doi: HashMap::new(),
+            by_arxiv_id: HashMap::new(),
+            by_title: HashMap::new(),
+            titles: Vec::new(),
+        };
+        let mut stmt =
+            conn.prepare("SELECT id, title, doi, arxiv_id FROM documents ORDER BY id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (id, title, doi, arxiv_id) = row?;
+            if let Some(doi) = doi {
+                index.by_doi.entry(doi.to_lowercase()).or_insert(id);
+            }
+            if let Some(arxiv_id) = arxiv_id {
+                index
+                    .by_arxiv_id
+                    .entry(references::arxiv_base(&arxiv_id))
+                    .or_insert(id);
+            }
+            let title = title
+                .as_deref()
+                .map(references::title_key)
+                .unwrap_or_default();
+            if !title.is_empty() {
+                index.by_title.entry(title.clone()).or_insert(id);
+                index.titles.push((title, id));
+            }
+        }
+        Ok(index)
+    }
+
+    /// The library document an entry cites: same DOI, then same arXiv id
+    /// (any version), then the same or a very similar title.
+    fn find(&self, record: &BibliographicRecord) -> Option<(i64, &'static str)> {
+        if let Some(id) = record
+            .doi
+            .as_ref()
+            .and_then(|doi| self.by_doi.get(&doi.to_lowercase()))
+        {
+            return Some((*id, "doi"));
+        }
+        if let Some(id) = record
+            .arxiv_id
+            .as_ref()
+            .and_then(|id| self.by_arxiv_id.get(&references::arxiv_base(id)))
+        {
+            return Some((*id, "arxiv_id"));
+        }
+        let title = references::title_key(record.title.as_deref()?);
+        if let Some(id) = self.by_title.get(&title) {
+            return Some((*id, "title"));
+        }
+        if title.len() < MIN_FUZZY_TITLE_LEN {
+            return None;
+        }
+        let slack = (title.len() as f64 * (1.0 - MIN_TITLE_SIMILARITY)).ceil() as usize;
+        self.titles
+            .iter()
+            .filter(|(candidate, _)| candidate.len().abs_diff(title.len()) <= slack)
+            .map(|(candidate, id)| (strsim::normalized_levenshtein(&title, candidate), *id))
+            .filter(|(score, _)| *score >= MIN_TITLE_SIMILARITY)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, id)| (id, "title"))
+    }
+}
+
+/// Find the reference list in the page text and split it into entries.
+/// Numbered (`[1]`, `1.`), labelled (`[Vas17]`) and author-year lists are
+/// recognised. Where a heading appears more than once, the one followed by
+/// the longest list wins.
+pub fn parse(pages: &[(u32, String)]) -> Vec<ParsedReference> {
+    let mut text = String::new();
+    let mut page_starts = Vec::new();
+    for (page_number, page) in pages {
+        if !text.is_empty() {
+            text.push(' ');
+        }
+        page_starts.push((text.len(), *page_number));
+        text.push_str(page.trim());
+    }
+    let page_at = |offset: usize| {
+        page_starts
+            .iter()
+            .take_while(|(start, _)| *start <= offset)
+            .last()
+            .map_or(1, |(_, page)| *page)
+    };
+
+    let mut best: Vec<(Option<String>, usize, &str)> = Vec::new();
+    for heading in HEADINGS {
+        for (i, _) in text.match_indices(heading) {
+            // Only a heading on its own, possibly numbered ("7 References")
+            let standalone_before = match text[..i].chars().next_back() {
+                Some(c) => c.is_whitespace() || c.is_ascii_digit() || c == '.',
+                None => true,
+            };
+            let standalone_after = match text[i + heading.len()..].chars().next() {
+                Some(c) => c.is_whitespace() || c == ':',
+                None => true,
+            };
+            if !(standalone_before && standalone_after) {
+                continue;
+            }
+            let rest = &text[i + heading.len()..];
+            let list = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ':');
+            let offset = text.len() - list.len();
+            let entries = split_entries(list);
+            if entries.len() >= MIN_ENTRIES && entries.len() >= best.len() {
+                best = entries
+                    .into_iter()
+                    .map(|(label, start, entry)| (label, offset + start, entry))
+                    .collect();
+            }
+        }
+    }
+
+    best.into_iter()
+        .map(|(label, start, entry)| ParsedReference {
+            label,
+            page_number: page_at(start),
+            text: entry.to_string(),
+            record: parse_entry(entry),
+        })
+        .collect()
+}
+
+/// Split the text after a heading into (label, offset, entry) triples.
+fn split_entries(list: &str) -> Vec<(Option<String>, usize, &str)> {
+    let mut entries = if list.starts_with("[1]") {
+        split_numbered(list, |n| format!("[{}]", n), false)
+    } else if list.starts_with("1. ") {
+        split_numbered(list, |n| format!("{}.", n), true)
+    } else if list.starts_with('[') {
+        split_labelled(list)
+    } else if author_start(list) {
+        split_author_year(list)
+    } else {
+        Vec::new()
+    };
+    if let Some(last) = entries.last_mut() {
+        last.2 = cut_last_entry(last.2);
+    }
+    entries.retain(|(_, _, entry)| !entry.is_empty());
+    entries
+}
+
+/// Entries introduced by consecutive numbers. With `dotted` markers (`1.`),
+/// a marker only counts before a capital letter, so volume and page numbers
+/// don't split entries.
+fn split_numbered(
+    list: &str,
+    marker: impl Fn(u32) -> String,
+    dotted: bool,
+) -> Vec<(Option<String>, usize, &str)> {
+    let mut starts = vec![(1, 0, marker(1).len())];
+    let mut n = 2;
+    loop {
+        let (_, _, from) = starts[starts.len() - 1];
+        let label = marker(n);
+        let next = list[from..]
+            .match_indices(&label)
+            .map(|(i, _)| from + i)
+            .take_while(|i| i - from <= MAX_ENTRY_CHARS)
+            .find(|&i| {
+                let before = list[..i].chars().next_back();
+                let after = &list[i + label.len()..];
+                before.is_some_and(char::is_whitespace)
+                    && (!dotted
+                        || after
+                            .strip_prefix(' ')
+                            .and_then(|rest| rest.chars().next())
+                            .is_some_and(char::is_uppercase))
+            });
+        let Some(i) = next else {
+            break;
+        };
+        starts.push((n, i, i + label.len()));
+        n += 1;
+    }
+    collect_entries(
+        list,
+        starts
+            .into_iter()
+            .map(|(n, start, body)| (Some(n.to_string()), start, body)),
+    )
+}
+
+/// Entries introduced by alphanumeric labels such as `[Vas17]` or `[ABC+21]`.
+fn split_labelled(list: &str) -> Vec<(Option<String>, usize, &str)> {
+    let label_at = |i: usize| -> Option<(String, usize)> {
+        let rest = list[i..].strip_prefix('[')?;
+        let end = rest.find(']')?;
+        let label = &rest[..end];
+        let valid = (2..=12).contains(&label.len())
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-')
+            && label.chars().any(|c| c.is_ascii_digit());
+        valid.then(|| (label.to_string(), i + end + 2))
+    };
+    let Some((label, body)) = label_at(0) else {
+        return Vec::new();
+    };
+    let mut starts = vec![(label, 0, body)];
+    loop {
+        let from = starts[starts.len() - 1].2;
+        let next = list[from..]
+            .match_indices('[')
+            .map(|(i, _)| from + i)
+            .take_while(|i| i - from <= MAX_ENTRY_CHARS)
+            .filter(|&i| list[..i].ends_with(char::is_whitespace))
+            .find_map(|i| label_at(i).map(|(label, body)| (label, i, body)));
+        match next {
+            Some(start) => starts.push(start),
+            None => break,
+        }
+    }
+    collect_entries(
+        list,
+        starts
+            .into_iter()
+            .map(|(label, start, body)| (Some(label), start, body)),
+    )
+}
+
+/// Entries of an author-year list: a new one starts after a sentence end
+/// when the text goes on with "Surname, I." and the current entry has a year.
+fn split_author_year(list: &str) -> Vec<(Option<String>, usize, &str)> {
+    let mut starts = vec![0];
+    let mut current = 0;
+    for (i, _) in list.match_indices(". ") {
+        let next = i + 2;
+        if next - current > MAX_ENTRY_CHARS {
+            break;
+        }
+        if author_start(&list[next..]) && find_year(&list[current..i]).is_some() {
+            starts.push(next);
+            current = next;
+        }
+    }
+    collect_entries(list, starts.into_iter().map(|start| (None, start, start)))
+}
+
+/// Whether the text opens with "Surname, I." (or "Surname-Name, I.").
+fn author_start(text: &str) -> bool {
+    let Some((surname, rest)) = text.split_once(", ") else {
+        return false;
+    };
+    let mut chars = surname.chars();
+    let capitalised = chars.next().is_some_and(char::is_uppercase)
+        && surname.chars().count() >= 2
+        && chars.all(|c| c.is_alphabetic() || c == '-' || c == '\'' || c == '\u{2019}');
+    let mut initial = rest.chars();
+    capitalised && initial.next().is_some_and(char::is_uppercase) && initial.next() == Some('.')
+}
+
+/// Turn (label, entry start, body start) triples into entries ending where
+/// the next one starts.
+fn collect_entries(
+    list: &str,
+    starts: impl Iterator<Item = (Option<String>, usize, usize)>,
+) -> Vec<(Option<String>, usize, &str)> {
+    let starts: Vec<_> = starts.collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, (label, start, body))| {
+            let end = starts.get(i + 1).map_or(list.len(), |next| next.1);
+            (label.clone(), *start, list[*body..end].trim())
+        })
+        .collect()
+}
+
+/// The last entry runs to the end of the text; cut it at a following
+/// heading, or at the last sentence end within `MAX_ENTRY_CHARS`.
+fn cut_last_entry(entry: &str) -> &str {
+    let mut end = END_HEADINGS
+        .iter()
+        .filter_map(|heading| entry.find(heading))
+        .min()
+        .unwrap_or(entry.len());
+    if end > MAX_ENTRY_CHARS {
+        let mut limit = MAX_ENTRY_CHARS;
+        while !entry.is_char_boundary(limit) {
+            limit -= 1;
+        }
+        end = entry[..limit].rfind(". ").map_or(limit, |i| i + 1);
+    }
+    entry[..end].trim()
+}
+
+/// Recognise the fields of one entry. Identifiers are found anywhere; the
+/// title is the quoted part, or else the first sentence after the authors
+/// that isn't just a year, and the venue the sentence after it.
+fn parse_entry(text: &str) -> BibliographicRecord {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let doi = words.iter().find_map(|word| {
+        let start = word.find("10.")?;
+        bibliography::normalize_doi(trim_punctuation(&word[start..]))
+    });
+    let arxiv_id = words.iter().find_map(|word| {
+        let lower = word.to_ascii_lowercase();
+        let start = ["arxiv:", "arxiv.org/abs/", "arxiv.org/pdf/", "abs/"]
+            .iter()
+            .find_map(|prefix| lower.find(prefix).map(|i| i + prefix.len()))?;
+        bibliography::normalize_arxiv_id(trim_punctuation(&word[start..]))
+    });
+    let url = words
+        .iter()
+        .map(|word| trim_punctuation(word))
+        .find(|word| {
+            (word.starts_with("http://") || word.starts_with("https://"))
+                && !word.contains("doi.org/")
+                && !word.contains("arxiv.org/")
+        })
+        .map(str::to_string);
+
+    // Identifiers and links get in the way of finding sentences
+    let plain = words
+        .iter()
+        .filter(|word| {
+            let lower = word.to_ascii_lowercase();
+            !(lower.contains("://")
+                || lower.starts_with("doi:")
+                || lower.starts_with("arxiv:")
+                || lower.starts_with("www.")
+                || (lower.starts_with("10.") && lower.contains('/')))
+        })
+        .copied()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let (authors, title, venue) = match quoted(&plain) {
+        Some((before, title, after)) => (
+            parse_authors(before),
+            Some(title.to_string()),
+            parse_venue(after),
+        ),
+        None => {
+            let sentences = sentences(&plain);
+            let title_index = sentences
+                .iter()
+                .skip(1)
+                .position(|s| !is_year(s) && s.split_whitespace().count() >= 2)
+                .map(|i| i + 1);
+            let venue = title_index
+                .and_then(|i| sentences.get(i + 1))
+                .and_then(|s| parse_venue(s));
+            (
+                sentences
+                    .first()
+                    .map(|s| parse_authors(s))
+                    .unwrap_or_default(),
+                title_index.map(|i| sentences[i].clone()),
+                venue,
+            )
+        }
+    };
+
+    BibliographicRecord {
+        title: title.filter(|t| !t.is_empty()),
+        authors,
+        year: find_year(&plain),
+        venue,
+        doi,
+        arxiv_id,
+        url,
+        ..Default::default()
+    }
+}
+
+/// Text before, inside and after the first quoted part of the entry.
+fn quoted(text: &str) -> Option<(&str, &str, &str)> {
+    for (open, close) in [('\u{201c}', '\u{201d}'), ('"', '"')] {
+        let Some(start) = text.find(open) else {
+            continue;
+        };
+        let inner = &text[start + open.len_utf8()..];
+        let Some(end) = inner.find(close) else {
+            continue;
+        };
+        let title = inner[..end].trim().trim_end_matches([',', '.']);
+        if title.split_whitespace().count() >= 2 {
+            return Some((&text[..start], title, &inner[end + close.len_utf8()..]));
+        }
+    }
+    None
+}
+
+/// Split an entry into sentences at periods that don't end an initial or
+/// an abbreviation, and at colons after an author list.
+fn sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    for word in text.split_whitespace() {
+        let previous = current.last().copied();
+        current.push(word);
+        if ends_sentence(word, previous) {
+            let sentence = current.join(" ");
+            let sentence = sentence.trim_end_matches(['.', ':']).trim();
+            if !sentence.is_empty() {
+                sentences.push(sentence.to_string());
+            }
+            current.clear();
+        }
+    }
+    if !current.is_empty() {
+        sentences.push(current.join(" "));
+    }
+    sentences
+}
+
+fn ends_sentence(word: &str, previous: Option<&str>) -> bool {
+    if word.ends_with(['?', '!']) {
+        return true;
+    }
+    let stem = match word.strip_suffix(':') {
+        // "Smith, J.: Title" ends the author list
+        Some(stem) => return stem.ends_with('.'),
+        None => match word.strip_suffix('.') {
+            Some(stem) => stem,
+            None => return false,
+        },
+    };
+    let last = stem.rsplit(['.', '-', '(']).next().unwrap_or(stem);
+    if last.chars().count() == 1 && last.chars().all(char::is_alphabetic) {
+        // "Jones K." (Vancouver style) ends the author list; "K. Jones" doesn't
+        return stem.len() == 1
+            && previous.is_some_and(|previous| {
+                previous.chars().next().is_some_and(char::is_uppercase)
+                    && previous.chars().count() > 1
+                    && previous.chars().all(char::is_alphabetic)
+            });
+    }
+    !ABBREVIATIONS.contains(&last.to_lowercase().as_str())
+}
+
+/// Names from the author part of an entry. "Surname, I." pairs are turned
+/// into "I. Surname"; returns nothing if the part doesn't look like names.
+fn parse_authors(part: &str) -> Vec<String> {
+    let part = part.split('(').next().unwrap_or(part);
+    let part = part
+        .replace("et al.", "")
+        .replace("et al", "")
+        .replace(" & ", ", ")
+        .replace(", and ", ", ")
+        .replace(" and ", ", ");
+    let separator = if part.contains(';') { ';' } else { ',' };
+
+    let mut names: Vec<String> = Vec::new();
+    let mut previous_is_surname = false;
+    for piece in part.split(separator) {
+        let piece = piece.trim().trim_end_matches([',', '.', ':']).trim();
+        if piece.is_empty() || is_year(piece) {
+            continue;
+        }
+        let piece_initials = piece.split_whitespace().all(is_initials);
+        if piece_initials && previous_is_surname && separator == ',' {
+            if let Some(surname) = names.pop() {
+                names.push(format!("{} {}", with_periods(piece), surname));
+            }
+            previous_is_surname = false;
+            continue;
+        }
+        names.push(vancouver_name(piece));
+        previous_is_surname = piece.split_whitespace().count() == 1;
+    }
+
+    let plausible = names.iter().all(|name| {
+        name.chars().count() <= MAX_NAME_CHARS
+            && name.split_whitespace().count() <= 5
+            && name.chars().any(char::is_alphabetic)
+    });
+    if plausible {
+        names
+    } else {
+        Vec::new()
+    }
+}
+
+/// "Smith JK" (as written in Vancouver style) becomes "J. K. Smith".
+fn vancouver_name(name: &str) -> String {
+    match name.rsplit_once(' ') {
+        Some((surname, initials))
+            if (1..=3).contains(&initials.len())
+                && initials.chars().all(|c| c.is_ascii_uppercase())
+                && !surname.contains(' ') =>
+        {
+            format!("{} {}", with_periods(initials), surname)
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Whether a word is one or more initials, e.g. "J.", "J.K." or "J.-P.".
+fn is_initials(word: &str) -> bool {
+    word.chars().any(char::is_uppercase)
+        && word
+            .chars()
+            .all(|c| c.is_uppercase() || c == '.' || c == '-')
+        && word.chars().filter(|c| c.is_uppercase()).count() <= 3
+}
+
+/// "JK" or "J.K." as "J. K."
+fn with_periods(initials: &str) -> String {
+    if initials.contains('.') {
+        return initials.to_string();
+    }
+    initials
+        .chars()
+        .map(|c| format!("{}.", c))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The venue from the sentence after the title: without "In" and anything
+/// after the first comma or parenthesis.
+fn parse_venue(sentence: &str) -> Option<String> {
+    let venue = sentence.trim_start_matches([',', '.', ' ']);
+    let venue = ["In: ", "In ", "in "]
+        .iter()
+        .find_map(|prefix| venue.strip_prefix(prefix))
+        .unwrap_or(venue);
+    let venue = venue.split([',', '(']).next().unwrap_or(venue).trim();
+    let venue = venue.trim_end_matches('.');
+    let useless = venue.is_empty()
+        || is_year(venue)
+        || venue.to_lowercase().starts_with("arxiv")
+        || !venue.chars().any(char::is_alphabetic);
+    (!useless).then(|| venue.to_string())
+}
+
+/// "2020", "(2020)" or "2020a" on its own.
+fn is_year(text: &str) -> bool {
+    let text = text
+        .trim()
+        .trim_matches(|c| c == '(' || c == ')' || c == '.' || c == ',');
+    let digits = text.trim_end_matches(|c: char| c.is_ascii_lowercase());
+    digits.len() == 4 && year_value(digits).is_some() && text.len() <= 5
+}
+
+/// The publication year: a parenthesised year if there is one (author-year
+/// style), else the last year-like number. Semicolons separate words too,
+/// as in Vancouver style "2019;12(3):45-50".
+fn find_year(text: &str) -> Option<i32> {
+    let mut last = None;
+    for word in text.split(|c: char| c.is_whitespace() || c == ';') {
+        let parenthesised = word.starts_with('(');
+        let word = word.trim_matches(|c: char| !c.is_ascii_alphanumeric());
+        let digits = word.trim_end_matches(|c: char| c.is_ascii_lowercase());
+        if word.len() > 5 || digits.len() != 4 {
+            continue;
+        }
+        if let Some(year) = year_value(digits) {
+            if parenthesised {
+                return Some(year);
+            }
+            last = Some(year);
+        }
+    }
+    last
+}
+
+fn year_value(digits: &str) -> Option<i32> {
+    let year: i32 = digits.parse().ok()?;
+    (1800..=2100).contains(&year).then_some(year)
+}
+
+fn trim_punctuation(word: &str) -> &str {
+    word.trim_end_matches(['.', ',', ';', ')', ']', '}'])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rr_file;
+    use std::path::Path;
+
+    const NUMBERED: [&str; 2] = [
+        "6 Conclusion We are done. References [1] A. Vaswani, N. Shazeer, and N. Parmar. \
+         Attention is all you need. In Advances in Neural Information Processing Systems, \
+         pages 5998\u{2013}6008, 2017. [2] J. Devlin, M. Chang, K. Lee, and K. Toutanova. \
+         BERT: Pre-training of deep bidirectional transformers for language understanding. \
+         arXiv:1810.04805v2, 2018.",
+        "[3] K. He, X. Zhang, S. Ren, and J. Sun. Deep residual learning for image \
+         recognition. In CVPR, 2016. doi:10.1109/CVPR.2016.90. [4] A. Nobody. An unknown \
+         paper about nothing. Journal of Nothing, 12(3):1\u{2013}9, 2001. Appendix A Proofs \
+         of the main results.",
+    ];
+
+    fn pages(pages: &[&str]) -> Vec<(u32, String)> {
+        pages
+            .iter()
+            .enumerate()
+            .map(|(i, text)| (i as u32 + 1, text.to_string()))
+            .collect()
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn numbered_lists() {
+        let entries = parse(&pages(&NUMBERED));
+        let labels: Vec<_> = entries.iter().map(|e| e.label.as_deref()).collect();
+        assert_eq!(labels, [Some("1"), Some("2"), Some("3"), Some("4")]);
+        let pages: Vec<_> = entries.iter().map(|e| e.page_number).collect();
+        assert_eq!(pages, [1, 1, 2, 2]);
+
+        let first = &entries[0].record;
+        assert_eq!(
+            first.authors,
+            names(&["A. Vaswani", "N. Shazeer", "N. Parmar"])
+        );
+        assert_eq!(first.title.as_deref(), Some("Attention is all you need"));
+        assert_eq!(
+            first.venue.as_deref(),
+            Some("Advances in Neural Information Processing Systems")
+        );
+        assert_eq!(first.year, Some(2017));
+
+        let second = &entries[1].record;
+        assert_eq!(
+            second.title.as_deref(),
+            Some(
+                "BERT: Pre-training of deep bidirectional transformers for language understanding"
+            )
+        );
+        assert_eq!(second.arxiv_id.as_deref(), Some("1810.04805v2"));
+        assert_eq!((second.venue.as_deref(), second.year), (None, Some(2018)));
+
+        let third = &entries[2].record;
+        assert_eq!(third.doi.as_deref(), Some("10.1109/CVPR.2016.90"));
+        assert_eq!(third.venue.as_deref(), Some("CVPR"));
+
+        // The last entry stops at the appendix
+        assert_eq!(
+            entries[3].text,
+            "A. Nobody. An unknown paper about nothing. Journal of Nothing, 12(3):1\u{2013}9, 2001."
+        );
+        assert_eq!(entries[3].record.year, Some(2001));
+    }
+
+    #[test]
+    fn dotted_numbers_in_vancouver_style() {
+        let entries = parse(&pages(&[
+            "REFERENCES 1. Smith JK, Jones K. A study of things in practice. Nature. \
+             2019;12(3):45-50. 2. Brown A. Another study of 3. things. Science. 2020;5:1-10.",
+        ]));
+        assert_eq!(entries.len(), 2);
+        let first = &entries[0].record;
+        assert_eq!(first.authors, names(&["J. K. Smith", "K. Jones"]));
+        assert_eq!(
+            first.title.as_deref(),
+            Some("A study of things in practice")
+        );
+        assert_eq!(first.venue.as_deref(), Some("Nature"));
+        assert_eq!(first.year, Some(2019));
+        // "3. things" doesn't start an entry
+        assert_eq!(entries[1].label.as_deref(), Some("2"));
+        assert_eq!(entries[1].record.authors, names(&["A. Brown"]));
+        assert_eq!(entries[1].record.year, Some(2020));
+    }
+
+    #[test]
+    fn bracketed_labels() {
+        let entries = parse(&pages(&[
+            "Bibliography [Vas17] Ashish Vaswani and Noam Shazeer. Attention is all you \
+             need. In NeurIPS, 2017. [DCLT19] Jacob Devlin, Ming-Wei Chang. BERT: \
+             Pre-training of deep bidirectional transformers. In NAACL, 2019. \
+             https://aclanthology.org/N19-1423",
+        ]));
+        let labels: Vec<_> = entries.iter().map(|e| e.label.as_deref()).collect();
+        assert_eq!(labels, [Some("Vas17"), Some("DCLT19")]);
+        assert_eq!(
+            entries[0].record.authors,
+            names(&["Ashish Vaswani", "Noam Shazeer"])
+        );
+        assert_eq!(entries[0].record.venue.as_deref(), Some("NeurIPS"));
+        assert_eq!(
+            entries[1].record.url.as_deref(),
+            Some("https://aclanthology.org/N19-1423")
+        );
+        assert_eq!(entries[1].record.year, Some(2019));
+    }
+
+    #[test]
+    fn author_year_lists() {
+        let entries = parse(&pages(&[
+            "References Smith, J., & Doe, A. (2020). Learning things from data. Journal of \
+             Data, 3(2), 1\u{2013}10. Zhang, W. (2019a). \u{201c}Another look, at titles.\u{201d} \
+             Proceedings of the Conference, 4\u{2013}5. van der Berg, K. (2018). A third \
+             work. Publisher.",
+        ]));
+        assert_eq!(entries.len(), 2, "{:#?}", entries);
+        assert!(entries.iter().all(|e| e.label.is_none()));
+
+        let first = &entries[0].record;
+        assert_eq!(first.authors, names(&["J. Smith", "A. Doe"]));
+        assert_eq!(first.title.as_deref(), Some("Learning things from data"));
+        assert_eq!(first.venue.as_deref(), Some("Journal of Data"));
+        assert_eq!(first.year, Some(2020));
+
+        // A quoted title; "van der Berg" isn't recognised as a new entry
+        let second = &entries[1].record;
+        assert_eq!(second.authors, names(&["W. Zhang"]));
+        assert_eq!(second.title.as_deref(), Some("Another look, at titles"));
+        assert_eq!(
+            second.venue.as_deref(),
+            Some("Proceedings of the Conference")
+        );
+        assert_eq!(second.year, Some(2019));
+    }
+
+    #[test]
+    fn no_list_without_enough_entries() {
+        assert!(parse(&pages(&["See the References [1] for details."])).is_empty());
+        assert!(parse(&pages(&[
+            "Preferences [1] A. B. Title here. [2] C. D. More."
+        ]))
+        .is_empty());
+        assert!(parse(&pages(&["References are listed below. Nothing here."])).is_empty());
+    }
+
+    #[test]
+    fn last_entry_is_cut_on_a_char_boundary() {
+        // Byte MAX_ENTRY_CHARS falls inside a two-byte character
+        let entry = format!("a{}", "\u{e9}".repeat(MAX_ENTRY_CHARS));
+        assert!(!entry.is_char_boundary(MAX_ENTRY_CHARS));
+        let cut = cut_last_entry(&entry);
+        assert_eq!(cut.len(), MAX_ENTRY_CHARS - 1);
+        assert!(entry.starts_with(cut));
+
+        // Preferably at a sentence end
+        let entry = format!("A. Author. Title. {}", "\u{e9}".repeat(MAX_ENTRY_CHARS));
+        assert_eq!(cut_last_entry(&entry), "A. Author. Title.");
+        assert_eq!(cut_last_entry("Short. APPENDIX B"), "Short.");
+    }
+
+    fn add_document(conn: &Connection, dir: &Path, name: &str, record: BibliographicRecord) -> i64 {
+        let pdf = dir.join(format!("{}.pdf", name));
+        std::fs::write(&pdf, format!("%PDF-1.4 {}", name)).unwrap();
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        bibliography::set_record(&session.db, &record).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        let entry = library::record_session(conn, &session, false).unwrap();
+        rr_file::cleanup_session(&session);
+        entry.id
+    }
+
+    #[test]
+    fn entries_are_matched_against_the_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = library::open(dir.path()).unwrap();
+        let record = |title: &str| BibliographicRecord {
+            title: Some(title.to_string()),
+            ..Default::default()
+        };
+        // Slightly different title
+        let attention = add_document(
+            &conn,
+            dir.path(),
+            "attention",
+            record("Attention Is All You Needed"),
+        );
+        // Same arXiv id, without version
+        let bert = add_document(
+            &conn,
+            dir.path(),
+            "bert",
+            BibliographicRecord {
+                arxiv_id: Some("1810.04805".to_string()),
+                ..record("BERT")
+            },
+        );
+        // Same DOI, different case
+        let resnet = add_document(
+            &conn,
+            dir.path(),
+            "resnet",
+            BibliographicRecord {
+                doi: Some("10.1109/cvpr.2016.90".to_string()),
+                ..record("ResNet")
+            },
+        );
+        add_document(
+            &conn,
+            dir.path(),
+            "other",
+            record("An unrelated paper about something"),
+        );
+
+        let pdf = dir.path().join("citing.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4 citing").unwrap();
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        database::set_page_texts(&session.db, &pages(&NUMBERED)).unwrap();
+        assert!(update(&session.db).unwrap());
+        assert!(!update(&session.db).unwrap());
+
+        let references = get(Some(&conn), &session.db).unwrap();
+        let matches: Vec<_> = references
+            .iter()
+            .map(|r| (r.document.as_ref().map(|d| d.id), r.matched_by.as_deref()))
+            .collect();
+        assert_eq!(
+            matches,
+            [
+                (Some(attention), Some("title")),
+                (Some(bert), Some("arxiv_id")),
+                (Some(resnet), Some("doi")),
+                (None, None),
+            ]
+        );
+        assert!(get(None, &session.db).unwrap()[0].document.is_none());
+
+        // Marking a reference marks the cited document too, and the mark
+        // survives a reparse of unchanged entries
+        let marked = set_to_read(&conn, &session.db, 3, true).unwrap();
+        assert!(marked.to_read);
+        assert_eq!(
+            library::get_entry(&conn, resnet).unwrap().unwrap().status,
+            Some(ReadingStatus::ToRead)
+        );
+        set_to_read(&conn, &session.db, 4, true).unwrap();
+        database::set_page_texts(&session.db, &[(3, "More text.".to_string())]).unwrap();
+        assert!(update(&session.db).unwrap());
+        let marks: Vec<_> = get(None, &session.db)
+            .unwrap()
+            .iter()
+            .map(|r| r.to_read)
+            .collect();
+        assert_eq!(marks, [false, false, true, true]);
+
+        set_to_read(&conn, &session.db, 3, false).unwrap();
+        assert_eq!(
+            library::get_entry(&conn, resnet).unwrap().unwrap().status,
+            None
+        );
+        assert!(set_to_read(&conn, &session.db, 9, true).is_err());
+        rr_file::cleanup_session(&session);
+    }
+}
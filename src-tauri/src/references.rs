@@ -0,0 +1,1101 @@
+use biblatex::{Bibliography, ChunksExt, DateValue, PermissiveType};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::bibliography;
+use crate::library;
+use crate::models::*;
+use crate::rr_file::{self, RrSession};
+
+/// Titles at least this similar (normalised Levenshtein) name the same work.
+pub const MIN_TITLE_SIMILARITY: f64 = 0.9;
+/// Shorter titles, such as "Introduction", are too generic to match fuzzily.
+pub const MIN_FUZZY_TITLE_LEN: usize = 16;
+/// `matched_by` of a link the user removed.
+const REJECTED: &str = "rejected";
+/// Upper bound on entries returned by `search_entries`.
+const MAX_SEARCH_RESULTS: i64 = 50;
+
+/// Selects the columns `row_to_file` reads.
+const FILE_QUERY: &str = "SELECT id, path, format, imported_at,
+    (SELECT COUNT(*) FROM reference_entries WHERE file_id = reference_files.id)
+    FROM reference_files";
+
+/// Create the reference database tables in the library database.
+pub fn init_references(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS reference_files (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            path TEXT NOT NULL UNIQUE,
+            format TEXT NOT NULL,
+            sha256 TEXT NOT NULL,
+            imported_at TEXT NOT NULL
+        );
+
+        -- record is a BibliographicRecord as JSON; doi (lowercase), arxiv_id
+        -- (without version) and title_key are what documents are matched on
+        CREATE TABLE IF NOT EXISTS reference_entries (
+            file_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            record TEXT NOT NULL,
+            doi TEXT,
+            arxiv_id TEXT,
+            title_key TEXT,
+            PRIMARY KEY (file_id, key)
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_reference_entries_doi
+            ON reference_entries(doi);
+        CREATE INDEX IF NOT EXISTS idx_reference_entries_arxiv_id
+            ON reference_entries(arxiv_id);
+        CREATE INDEX IF NOT EXISTS idx_reference_entries_title_key
+            ON reference_entries(title_key);
+
+        -- applied_record is the entry's record when it was last written to
+        -- the document, so later changes to the file can be spotted
+        CREATE TABLE IF NOT EXISTS reference_links (
+            document_id INTEGER PRIMARY KEY,
+            file_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            matched_by TEXT NOT NULL,
+            score REAL NOT NULL,
+            applied_record TEXT
+        );
+        ",
+    )
+}
+
+/// Import a BibTeX or CSL-JSON file, replacing the entries from an earlier
+/// import of the same file, link unlinked library documents to matching
+/// entries, and update the linked documents (see `propagate`).
+pub fn import_file(
+    conn: &Connection,
+    path: &Path,
+    open: Option<&RrSession>,
+) -> Result<ReferenceImport, String> {
+    let source =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read reference file: {}", e))?;
+    let sha256 = rr_file::sha256_file(path)?;
+    let format = detect_format(path, &source);
+    let (entries, skipped) = match format {
+        "bibtex" => parse_bibtex(&source)?,
+        _ => parse_csl_json(&source)?,
+    };
+    let path = fs::canonicalize(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .to_string_lossy()
+        .to_string();
+
+    let tx = conn
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    let write = || -> rusqlite::Result<i64> {
+        tx.execute(
+            "INSERT INTO reference_files (path, format, sha256, imported_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(path) DO UPDATE SET
+                 format = excluded.format,
+                 sha256 = excluded.sha256,
+                 imported_at = excluded.imported_at",
+            params![path, format, sha256, chrono::Utc::now().to_rfc3339()],
+        )?;
+        let file_id: i64 = tx.query_row(
+            "SELECT id FROM reference_files WHERE path = ?1",
+            params![path],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "DELETE FROM reference_entries WHERE file_id = ?1",
+            params![file_id],
+        )?;
+        let mut insert = tx.prepare(
+            "INSERT OR REPLACE INTO reference_entries
+                 (file_id, key, record, doi, arxiv_id, title_key)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for (key, record) in &entries {
+            insert.execute(params![
+                file_id,
+                key,
+                serde_json::to_string(record).unwrap_or_default(),
+                record.doi.as_deref().map(str::to_lowercase),
+                record.arxiv_id.as_deref().map(arxiv_base),
+                record.title.as_deref().map(title_key),
+            ])?;
+        }
+        // Entries that left the file take their links with them
+        tx.execute(
+            "DELETE FROM reference_links WHERE file_id = ?1
+             AND key NOT IN (SELECT key FROM reference_entries WHERE file_id = ?1)",
+            params![file_id],
+        )?;
+        Ok(file_id)
+    };
+    let file_id = write().map_err(|e| format!("Failed to store reference entries: {}", e))?;
+    tx.commit()
+        .map_err(|e| format!("Failed to commit reference entries: {}", e))?;
+
+    let linked = link_unmatched(conn)?;
+    let updated = propagate(conn, open)?;
+    let file = get_file(conn, file_id)
+        .map_err(|e| format!("Failed to read reference file: {}", e))?
+        .ok_or("Reference file disappeared after import")?;
+    Ok(ReferenceImport {
+        file,
+        skipped,
+        linked,
+        updated,
+    })
+}
+
+/// Re-import the reference files that changed on disk since they were
+/// imported. Files that can no longer be read are skipped.
+pub fn refresh(
+    conn: &Connection,
+    open: Option<&RrSession>,
+) -> Result<Vec<ReferenceImport>, String> {
+    let files: Vec<(String, String)> = conn
+        .prepare("SELECT path, sha256 FROM reference_files ORDER BY id")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .map_err(|e| format!("Failed to read reference files: {}", e))?;
+
+    let mut imports = Vec::new();
+    for (path, sha256) in files {
+        let path = Path::new(&path);
+        match rr_file::sha256_file(path) {
+            Ok(current) if current == sha256 => {}
+            Ok(_) => imports.push(import_file(conn, path, open)?),
+            Err(e) => log::warn!("[references] Skipping {}: {}", path.display(), e),
+        }
+    }
+    Ok(imports)
+}
+
+/// List the imported reference files.
+pub fn list_files(conn: &Connection) -> rusqlite::Result<Vec<ReferenceFile>> {
+    let mut stmt = conn.prepare(&format!("{} ORDER BY reference_files.id", FILE_QUERY))?;
+    let files = stmt
+        .query_map([], row_to_file)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(files)
+}
+
+fn get_file(conn: &Connection, id: i64) -> rusqlite::Result<Option<ReferenceFile>> {
+    conn.query_row(
+        &format!("{} WHERE reference_files.id = ?1", FILE_QUERY),
+        params![id],
+        row_to_file,
+    )
+    .optional()
+}
+
+fn row_to_file(row: &rusqlite::Row) -> rusqlite::Result<ReferenceFile> {
+    Ok(ReferenceFile {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        format: row.get(2)?,
+        imported_at: row.get(3)?,
+        entry_count: row.get(4)?,
+    })
+}
+
+/// Forget a reference file with its entries and links. Documents keep the
+/// bibliographic records they got from it.
+pub fn remove_file(conn: &Connection, id: i64) -> rusqlite::Result<bool> {
+    conn.execute(
+        "DELETE FROM reference_links WHERE file_id = ?1",
+        params![id],
+    )?;
+    conn.execute(
+        "DELETE FROM reference_entries WHERE file_id = ?1",
+        params![id],
+    )?;
+    let affected = conn.execute("DELETE FROM reference_files WHERE id = ?1", params![id])?;
+    Ok(affected > 0)
+}
+
+/// Entries whose title contains `query`, or whose key is `query`, for
+/// linking a document by hand.
+pub fn search_entries(conn: &Connection, query: &str) -> Result<Vec<ReferenceEntry>, String> {
+    // Title keys are plain lowercase words, so the pattern needs no escaping
+    let pattern = format!("%{}%", title_key(query));
+    let mut stmt = conn
+        .prepare(
+            "SELECT file_id, key, record FROM reference_entries
+             WHERE key = ?1 OR title_key LIKE ?2
+             ORDER BY file_id, key
+             LIMIT ?3",
+        )
+        .map_err(|e| format!("Failed to prepare reference search: {}", e))?;
+    let rows = stmt
+        .query_map(params![query.trim(), pattern, MAX_SEARCH_RESULTS], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?))
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<(i64, String, String)>>>())
+        .map_err(|e| format!("Reference search failed: {}", e))?;
+    rows.into_iter()
+        .map(|(file_id, key, record)| {
+            Ok(ReferenceEntry {
+                file_id,
+                key,
+                record: serde_json::from_str(&record)
+                    .map_err(|e| format!("Invalid reference entry: {}", e))?,
+            })
+        })
+        .collect()
+}
+
+/// The reference entry a library document is linked to, if any.
+pub fn get_link(conn: &Connection, document_id: i64) -> rusqlite::Result<Option<ReferenceLink>> {
+    conn.query_row(
+        "SELECT document_id, file_id, key, matched_by, score
+         FROM reference_links WHERE document_id = ?1",
+        params![document_id],
+        |row| {
+            Ok(ReferenceLink {
+                document_id: row.get(0)?,
+                file_id: row.get(1)?,
+                key: row.get(2)?,
+                matched_by: row.get(3)?,
+                score: row.get(4)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Link a library document to an entry chosen by the user, replacing any
+/// automatic match.
+pub fn link_document(
+    conn: &Connection,
+    document_id: i64,
+    file_id: i64,
+    key: &str,
+) -> Result<ReferenceLink, String> {
+    let exists: i64 = conn
+        .query_row(
+            "SELECT COUNT(*) FROM reference_entries WHERE file_id = ?1 AND key = ?2",
+            params![file_id, key],
+            |row| row.get(0),
+        )
+        .map_err(|e| format!("Failed to read reference entry: {}", e))?;
+    if exists == 0 {
+        return Err(format!("No reference entry '{}' in file {}", key, file_id));
+    }
+    if library::get_entry(conn, document_id)
+        .map_err(|e| format!("Failed to read library entry: {}", e))?
+        .is_none()
+    {
+        return Err(format!("No library entry with id {}", document_id));
+    }
+    store_link(conn, document_id, file_id, key, "manual", 1.0)
+        .map_err(|e| format!("Failed to link document: {}", e))?;
+    get_link(conn, document_id)
+        .map_err(|e| format!("Failed to read link: {}", e))?
+        .ok_or_else(|| "Link disappeared after update".to_string())
+}
+
+/// Unlink a library document. The link is kept as rejected so the
+/// document isn't matched to the same entry again.
+pub fn unlink_document(conn: &Connection, document_id: i64) -> rusqlite::Result<bool> {
+    let affected = conn.execute(
+        "UPDATE reference_links SET matched_by = ?1, applied_record = NULL
+         WHERE document_id = ?2",
+        params![REJECTED, document_id],
+    )?;
+    Ok(affected > 0)
+}
+
+/// Drop a removed library document's link.
+pub fn remove_document(conn: &Connection, document_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM reference_links WHERE document_id = ?1",
+        params![document_id],
+    )?;
+    Ok(())
+}
+
+/// Link a library document to a matching entry if it has no link yet, then
+/// write the linked entry's record into `db` if the entry changed since it
+/// was last written. Fields the entry leaves empty keep their values.
+/// Returns whether the bibliographic record changed.
+pub fn sync_document(
+    conn: &Connection,
+    entry: &LibraryEntry,
+    db: &Connection,
+) -> Result<bool, String> {
+    let linked = get_link(conn, entry.id)
+        .map_err(|e| format!("Failed to read link: {}", e))?
+        .is_some();
+    if !linked {
+        let Some((file_id, key, matched_by, score)) =
+            find_match(conn, entry).map_err(|e| format!("Failed to match references: {}", e))?
+        else {
+            return Ok(false);
+        };
+        store_link(conn, entry.id, file_id, &key, matched_by, score)
+            .map_err(|e| format!("Failed to link document: {}", e))?;
+    }
+
+    let pending: Option<(String, Option<String>)> = conn
+        .query_row(
+            "SELECT reference_entries.record, reference_links.applied_record
+             FROM reference_links
+             JOIN reference_entries ON reference_entries.file_id = reference_links.file_id
+                 AND reference_entries.key = reference_links.key
+             WHERE reference_links.document_id = ?1 AND reference_links.matched_by != ?2",
+            params![entry.id, REJECTED],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()
+        .map_err(|e| format!("Failed to read reference entry: {}", e))?;
+    let Some((record_json, applied)) = pending else {
+        return Ok(false);
+    };
+    if applied.as_deref() == Some(record_json.as_str()) {
+        return Ok(false);
+    }
+
+    let reference: Value = serde_json::from_str(&record_json)
+        .map_err(|e| format!("Invalid reference entry: {}", e))?;
+    let mut fields = serde_json::to_value(bibliography::get_record(db)?)
+        .map_err(|e| format!("Failed to serialize bibliographic record: {}", e))?;
+    if let (Some(fields), Value::Object(reference)) = (fields.as_object_mut(), reference) {
+        for (field, value) in reference {
+            let empty = value.is_null() || value.as_array().is_some_and(|a| a.is_empty());
+            if !empty {
+                fields.insert(field, value);
+            }
+        }
+    }
+    let record: BibliographicRecord = serde_json::from_value(fields)
+        .map_err(|e| format!("Failed to build bibliographic record: {}", e))?;
+    bibliography::set_record(db, &record)?;
+    conn.execute(
+        "UPDATE reference_links SET applied_record = ?1 WHERE document_id = ?2",
+        params![record_json, entry.id],
+    )
+    .map_err(|e| format!("Failed to update link: {}", e))?;
+    Ok(true)
+}
+
+/// Write changed reference entries into the linked library documents. The
+/// open session is updated in place and saved with the document; other
+/// documents are rewritten on disk. Returns how many were updated.
+pub fn propagate(conn: &Connection, open: Option<&RrSession>) -> Result<u32, String> {
+    let pending: Vec<(i64, String)> = conn
+        .prepare(
+            "SELECT documents.id, documents.path
+             FROM reference_links
+             JOIN documents ON documents.id = reference_links.document_id
+             JOIN reference_entries ON reference_entries.file_id = reference_links.file_id
+                 AND reference_entries.key = reference_links.key
+             WHERE reference_links.matched_by != ?1
+                 AND (reference_links.applied_record IS NULL
+                      OR reference_links.applied_record != reference_entries.record)",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(params![REJECTED], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .map_err(|e| format!("Failed to read linked documents: {}", e))?;
+
+    let open_path = open.map(|session| fs::canonicalize(&session.rr_path).unwrap_or_default());
+    let mut updated = 0;
+    for (id, path) in pending {
+        let result = match open {
+            Some(session) if open_path == fs::canonicalize(&path).ok() => {
+                library::record_session(conn, session, false).map(|_| ())
+            }
+            _ => rr_file::open_rr(Path::new(&path)).and_then(|session| {
+                let result = library::record_session(conn, &session, false)
+                    .and_then(|_| rr_file::save_rr(&session));
+                rr_file::cleanup_session(&session);
+                result
+            }),
+        };
+        match result {
+            Ok(()) => updated += 1,
+            Err(e) => log::warn!("[references] Failed to update document {}: {}", id, e),
+        }
+    }
+    Ok(updated)
+}
+
+/// Link every library document without a link to a matching entry.
+fn link_unmatched(conn: &Connection) -> Result<u32, String> {
+    let ids: Vec<i64> = conn
+        .prepare(
+            "SELECT id FROM documents WHERE id NOT IN (SELECT document_id FROM reference_links)",
+        )
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+        .map_err(|e| format!("Failed to read library: {}", e))?;
+
+    let mut linked = 0;
+    for id in ids {
+        let Some(entry) =
+            library::get_entry(conn, id).map_err(|e| format!("Failed to read library: {}", e))?
+        else {
+            continue;
+        };
+        if let Some((file_id, key, matched_by, score)) =
+            find_match(conn, &entry).map_err(|e| format!("Failed to match references: {}", e))?
+        {
+            store_link(conn, id, file_id, &key, matched_by, score)
+                .map_err(|e| format!("Failed to link document: {}", e))?;
+            linked += 1;
+        }
+    }
+    Ok(linked)
+}
+
+fn store_link(
+    conn: &Connection,
+    document_id: i64,
+    file_id: i64,
+    key: &str,
+    matched_by: &str,
+    score: f64,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO reference_links
+             (document_id, file_id, key, matched_by, score, applied_record)
+         VALUES (?1, ?2, ?3, ?4, ?5, NULL)",
+        params![document_id, file_id, key, matched_by, score],
+    )?;
+    Ok(())
+}
+
+/// The best entry for a library document: same DOI, then same arXiv id
+/// (any version), then the same or a very similar title. Earlier imported
+/// files win ties.
+fn find_match(
+    conn: &Connection,
+    entry: &LibraryEntry,
+) -> rusqlite::Result<Option<(i64, String, &'static str, f64)>> {
+    let lookup = |column: &str, value: &str| {
+        conn.query_row(
+            &format!(
+                "SELECT file_id, key FROM reference_entries WHERE {} = ?1
+                 ORDER BY file_id LIMIT 1",
+                column
+            ),
+            params![value],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()
+    };
+
+    if let Some(doi) = &entry.doi {
+        if let Some((file_id, key)) = lookup("doi", &doi.to_lowercase())? {
+            return Ok(Some((file_id, key, "doi", 1.0)));
+        }
+    }
+    if let Some(arxiv_id) = &entry.arxiv_id {
+        if let Some((file_id, key)) = lookup("arxiv_id", &arxiv_base(arxiv_id))? {
+            return Ok(Some((file_id, key, "arxiv_id", 1.0)));
+        }
+    }
+    let Some(title) = entry
+        .title
+        .as_deref()
+        .map(title_key)
+        .filter(|t| !t.is_empty())
+    else {
+        return Ok(None);
+    };
+    if let Some((file_id, key)) = lookup("title_key", &title)? {
+        return Ok(Some((file_id, key, "title", 1.0)));
+    }
+    if title.len() < MIN_FUZZY_TITLE_LEN {
+        return Ok(None);
+    }
+
+    // Only titles of similar length can reach the similarity threshold
+    let slack = (title.len() as f64 * (1.0 - MIN_TITLE_SIMILARITY)).ceil() as usize;
+    let mut stmt = conn.prepare(
+        "SELECT file_id, key, title_key FROM reference_entries
+         WHERE length(title_key) BETWEEN ?1 AND ?2
+         ORDER BY file_id, key",
+    )?;
+    let candidates = stmt.query_map(
+        params![
+            title.len().saturating_sub(slack) as i64,
+            (title.len() + slack) as i64
+        ],
+        |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        },
+    )?;
+    let mut best: Option<(i64, String, f64)> = None;
+    for candidate in candidates {
+        let (file_id, key, candidate_title) = candidate?;
+        let score = strsim::normalized_levenshtein(&title, &candidate_title);
+        let better = match &best {
+            Some((_, _, best_score)) => score > *best_score,
+            None => score >= MIN_TITLE_SIMILARITY,
+        };
+        if better {
+            best = Some((file_id, key, score));
+        }
+    }
+    Ok(best.map(|(file_id, key, score)| (file_id, key, "title", score)))
+}
+
+/// Title reduced to lowercase words without accents or punctuation, so
+/// the same title from a PDF and a BibTeX file compares equal.
+pub fn title_key(title: &str) -> String {
+    let plain: String = title
+        .nfd()
+        .filter(|c| !unicode_normalization::char::is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+    plain.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// arXiv id without its version suffix, so any version of a paper matches.
+pub fn arxiv_base(id: &str) -> String {
+    match id.rfind('v') {
+        Some(i)
+            if i > 0
+                && !id[i + 1..].is_empty()
+                && id[i + 1..].chars().all(|c| c.is_ascii_digit()) =>
+        {
+            id[..i].to_string()
+        }
+        _ => id.to_string(),
+    }
+}
+
+/// "bibtex" or "csl_json", by extension or else by content.
+fn detect_format(path: &Path, source: &str) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("bib" | "bibtex" | "biblatex") => "bibtex",
+        Some("json") => "csl_json",
+        _ if source.trim_start().starts_with('[') => "csl_json",
+        _ => "bibtex",
+    }
+}
+
+/// Keep an entry only if it has a title and passes validation.
+fn accept(
+    key: String,
+    record: BibliographicRecord,
+    entries: &mut Vec<(String, BibliographicRecord)>,
+    skipped: &mut u32,
+) {
+    match bibliography::validate(&record) {
+        Ok(record) if record.title.is_some() => entries.push((key, record)),
+        Ok(_) => *skipped += 1,
+        Err(e) => {
+            log::warn!("[references] Skipping entry '{}': {}", key, e);
+            *skipped += 1;
+        }
+    }
+}
+
+/// Entries of a BibTeX or BibLaTeX file, and how many were skipped.
+fn parse_bibtex(source: &str) -> Result<(Vec<(String, BibliographicRecord)>, u32), String> {
+    let parsed = Bibliography::parse(source).map_err(|e| format!("Invalid BibTeX: {}", e))?;
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    for entry in parsed.iter() {
+        let text = |chunks: Option<biblatex::ChunksRef>| {
+            chunks
+                .map(|c| collapse_whitespace(&c.format_verbatim()))
+                .filter(|t| !t.is_empty())
+        };
+        let year = match entry.date() {
+            Ok(PermissiveType::Typed(date)) => Some(match date.value {
+                DateValue::At(d) | DateValue::After(d) | DateValue::Before(d) => d.year,
+                DateValue::Between(start, _) => start.year,
+            }),
+            Ok(PermissiveType::Chunks(chunks)) => leading_year(&chunks.format_verbatim()),
+            Err(_) => None,
+        };
+        let url = entry.url().ok();
+        let arxiv_id = entry
+            .eprint()
+            .ok()
+            .and_then(|e| bibliography::normalize_arxiv_id(&e))
+            .or_else(|| url.as_deref().and_then(bibliography::normalize_arxiv_id));
+        let venue = text(entry.journal().ok())
+            .or_else(|| text(entry.book_title().ok()))
+            .or_else(|| {
+                entry
+                    .publisher()
+                    .ok()
+                    .and_then(|p| p.first().and_then(|c| text(Some(c))))
+            });
+
+        let record = BibliographicRecord {
+            title: text(entry.title().ok()),
+            authors: entry
+                .author()
+                .map(|people| {
+                    people
+                        .iter()
+                        .map(|p| collapse_whitespace(&p.to_string()))
+                        .filter(|name| !name.is_empty() && name != "others")
+                        .collect()
+                })
+                .unwrap_or_default(),
+            year,
+            venue,
+            doi: entry
+                .doi()
+                .ok()
+                .and_then(|d| bibliography::normalize_doi(&d)),
+            arxiv_id,
+            abstract_text: text(entry.abstract_().ok()),
+            keywords: text(entry.keywords().ok())
+                .map(|k| split_list(&k))
+                .unwrap_or_default(),
+            url: url.filter(|u| u.starts_with("http://") || u.starts_with("https://")),
+        };
+        accept(entry.key.clone(), record, &mut entries, &mut skipped);
+    }
+    Ok((entries, skipped))
+}
+
+/// Entries of a CSL-JSON file, and how many were skipped.
+fn parse_csl_json(source: &str) -> Result<(Vec<(String, BibliographicRecord)>, u32), String> {
+    let items: Vec<Value> =
+        serde_json::from_str(source).map_err(|e| format!("Invalid CSL-JSON: {}", e))?;
+    let mut entries = Vec::new();
+    let mut skipped = 0;
+    for item in &items {
+        let text = |field: &str| {
+            item.get(field)
+                .and_then(Value::as_str)
+                .map(collapse_whitespace)
+                .filter(|t| !t.is_empty())
+        };
+        let key = match item.get("id") {
+            Some(Value::String(id)) => id.clone(),
+            Some(Value::Number(id)) => id.to_string(),
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let authors = item
+            .get("author")
+            .and_then(Value::as_array)
+            .map(|people| {
+                people
+                    .iter()
+                    .filter_map(|person| {
+                        let part = |name: &str| person.get(name).and_then(Value::as_str);
+                        let name = match part("literal") {
+                            Some(literal) => literal.to_string(),
+                            None => [
+                                part("given"),
+                                part("dropping-particle"),
+                                part("non-dropping-particle"),
+                                part("family"),
+                                part("suffix"),
+                            ]
+                            .into_iter()
+                            .flatten()
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                        };
+                        Some(collapse_whitespace(&name)).filter(|n| !n.is_empty())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let issued = item.get("issued");
+        let year = issued
+            .and_then(|d| d.pointer("/date-parts/0/0"))
+            .and_then(|y| match y {
+                Value::Number(n) => n.as_i64().map(|n| n as i32),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            })
+            .or_else(|| {
+                ["raw", "literal"]
+                    .iter()
+                    .find_map(|f| issued?.get(*f)?.as_str().and_then(leading_year))
+            });
+        let url = text("URL");
+        let arxiv_id = url
+            .as_deref()
+            .and_then(bibliography::normalize_arxiv_id)
+            .or_else(|| text("number").and_then(|n| bibliography::normalize_arxiv_id(&n)));
+
+        let record = BibliographicRecord {
+            title: text("title"),
+            authors,
+            year,
+            venue: text("container-title").or_else(|| text("publisher")),
+            doi: text("DOI").and_then(|d| bibliography::normalize_doi(&d)),
+            arxiv_id,
+            abstract_text: text("abstract"),
+            keywords: text("keyword").map(|k| split_list(&k)).unwrap_or_default(),
+            url: url.filter(|u| u.starts_with("http://") || u.starts_with("https://")),
+        };
+        accept(key, record, &mut entries, &mut skipped);
+    }
+    Ok((entries, skipped))
+}
+
+fn leading_year(date: &str) -> Option<i32> {
+    date.trim().get(..4)?.parse().ok()
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split([',', ';'])
+        .map(collapse_whitespace)
+        .filter(|k| !k.is_empty())
+        .collect()
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIBTEX: &str = r"
+@article{vaswani2017,
+  author = {Vaswani, Ashish and Noam Shazeer and others},
+  title = {Attention Is {All} You
+           Need},
+  journal = {Advances in Neural Information Processing Systems},
+  year = {2017},
+  doi = {https://doi.org/10.5555/3295222},
+  eprint = {1706.03762v5},
+  keywords = {attention, transformers; translation},
+}
+@inproceedings{he2016,
+  title = {Deep Residual Learning for Image Recognition},
+  booktitle = {CVPR},
+  date = {2016-06},
+  url = {https://arxiv.org/abs/1512.03385},
+}
+@misc{untitled, author = {Nobody, A.}}
+@article{future, title = {From the Future}, year = {3000}}
+";
+
+    const CSL_JSON: &str = r#"[
+  {
+    "id": "turing1936",
+    "title": "On  Computable Numbers",
+    "author": [
+      { "given": "Alan", "family": "Turing" },
+      { "given": "Ludwig", "non-dropping-particle": "van", "family": "Beethoven" },
+      { "literal": "The Team" }
+    ],
+    "issued": { "date-parts": [["1936", 11]] },
+    "container-title": "Proceedings of the London Mathematical Society",
+    "DOI": "doi:10.1112/plms/s2-42.1.230",
+    "keyword": "computability; logic"
+  },
+  {
+    "id": 42,
+    "title": "A Preprint",
+    "issued": { "raw": "2021-01-04" },
+    "number": "arXiv:2101.00001v2",
+    "URL": "ftp://example.org/preprint"
+  },
+  { "title": "No Id" },
+  { "id": "future", "title": "From the Future", "issued": { "date-parts": [[3000]] } }
+]"#;
+
+    fn add_document(
+        conn: &Connection,
+        dir: &Path,
+        name: &str,
+        record: BibliographicRecord,
+    ) -> LibraryEntry {
+        let pdf = dir.join(format!("{}.pdf", name));
+        fs::write(&pdf, format!("%PDF-1.4 {}", name)).unwrap();
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        bibliography::set_record(&session.db, &record).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        let entry = library::record_session(conn, &session, false).unwrap();
+        rr_file::cleanup_session(&session);
+        entry
+    }
+
+    fn titled(title: &str) -> BibliographicRecord {
+        BibliographicRecord {
+            title: Some(title.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn stored_record(entry: &LibraryEntry) -> BibliographicRecord {
+        let snapshot = rr_file::read_database(Path::new(&entry.path)).unwrap();
+        bibliography::get_record(&snapshot.db).unwrap()
+    }
+
+    fn bibtex_entry(key: &str, doi: &str, venue: &str, year: Option<i32>) -> String {
+        let year = year.map_or(String::new(), |y| format!("year = {{{}}},", y));
+        format!(
+            "@article{{{0}, title = {{Paper {0}}}, doi = {{{1}}}, journal = {{{2}}}, {3}}}\n",
+            key, doi, venue, year
+        )
+    }
+
+    #[test]
+    fn bibtex_files() {
+        let (entries, skipped) = parse_bibtex(BIBTEX).unwrap();
+        // One entry has no title, one an invalid year
+        assert_eq!(skipped, 2);
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["vaswani2017", "he2016"]);
+
+        assert_eq!(
+            entries[0].1,
+            BibliographicRecord {
+                title: Some("Attention Is All You Need".to_string()),
+                authors: vec!["Ashish Vaswani".to_string(), "Noam Shazeer".to_string()],
+                year: Some(2017),
+                venue: Some("Advances in Neural Information Processing Systems".to_string()),
+                doi: Some("10.5555/3295222".to_string()),
+                arxiv_id: Some("1706.03762v5".to_string()),
+                abstract_text: None,
+                keywords: vec![
+                    "attention".to_string(),
+                    "transformers".to_string(),
+                    "translation".to_string()
+                ],
+                url: None,
+            }
+        );
+        let he = &entries[1].1;
+        assert_eq!(he.year, Some(2016));
+        assert_eq!(he.venue.as_deref(), Some("CVPR"));
+        assert_eq!(he.arxiv_id.as_deref(), Some("1512.03385"));
+        assert_eq!(he.url.as_deref(), Some("https://arxiv.org/abs/1512.03385"));
+
+        assert!(parse_bibtex("@article{broken, title = {x").is_err());
+    }
+
+    #[test]
+    fn csl_json_files() {
+        let (entries, skipped) = parse_csl_json(CSL_JSON).unwrap();
+        assert_eq!(skipped, 2);
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["turing1936", "42"]);
+
+        let turing = &entries[0].1;
+        assert_eq!(turing.title.as_deref(), Some("On Computable Numbers"));
+        assert_eq!(
+            turing.authors,
+            ["Alan Turing", "Ludwig van Beethoven", "The Team"]
+        );
+        assert_eq!(turing.year, Some(1936));
+        assert_eq!(turing.doi.as_deref(), Some("10.1112/plms/s2-42.1.230"));
+        assert_eq!(turing.keywords, ["computability", "logic"]);
+
+        let preprint = &entries[1].1;
+        assert_eq!(preprint.year, Some(2021));
+        assert_eq!(preprint.arxiv_id.as_deref(), Some("2101.00001v2"));
+        assert_eq!(preprint.url, None);
+
+        assert!(parse_csl_json("{}").is_err());
+        assert_eq!(detect_format(Path::new("refs.bib"), "[]"), "bibtex");
+        assert_eq!(detect_format(Path::new("refs.JSON"), "@misc{}"), "csl_json");
+        assert_eq!(detect_format(Path::new("refs.txt"), " [{}]"), "csl_json");
+        assert_eq!(detect_format(Path::new("refs"), "@misc{}"), "bibtex");
+    }
+
+    #[test]
+    fn documents_match_by_identifier_then_title() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = library::open(dir.path()).unwrap();
+        let by_doi = add_document(
+            &conn,
+            dir.path(),
+            "doi",
+            BibliographicRecord {
+                doi: Some("10.5555/3295222".to_string()),
+                ..titled("Something Else Entirely")
+            },
+        );
+        let by_arxiv = add_document(
+            &conn,
+            dir.path(),
+            "arxiv",
+            BibliographicRecord {
+                arxiv_id: Some("1512.03385v1".to_string()),
+                ..titled("ResNet")
+            },
+        );
+        // One character off: similarity 0.98
+        let close = add_document(
+            &conn,
+            dir.path(),
+            "close",
+            titled("Deep residual learnin for image recognition"),
+        );
+        // "video" for "image": similarity 0.89, below the threshold
+        let far = add_document(
+            &conn,
+            dir.path(),
+            "far",
+            titled("Deep Residual Learning for Video Recognition"),
+        );
+        let exact = add_document(&conn, dir.path(), "exact", titled("introduction!"));
+        // Too short to match fuzzily, even at similarity 0.92
+        let short = add_document(&conn, dir.path(), "short", titled("Introductions"));
+
+        let path = dir.path().join("refs.bib");
+        fs::write(
+            &path,
+            format!(
+                "{}@misc{{intro, title = {{Introduction}}}}\n\
+                 @misc{{resnet2, title = {{Deep Residual Learning for Image Recognition}}}}",
+                BIBTEX
+            ),
+        )
+        .unwrap();
+        let import = import_file(&conn, &path, None).unwrap();
+        assert_eq!((import.file.entry_count, import.skipped), (4, 2));
+        assert_eq!(import.linked, 4);
+
+        let link = |entry: &LibraryEntry| {
+            get_link(&conn, entry.id).unwrap().map(|link| {
+                let score = (link.score * 100.0).round() as i64;
+                (link.key, link.matched_by, score)
+            })
+        };
+        assert_eq!(
+            link(&by_doi),
+            Some(("vaswani2017".into(), "doi".into(), 100))
+        );
+        assert_eq!(
+            link(&by_arxiv),
+            Some(("he2016".into(), "arxiv_id".into(), 100))
+        );
+        // Two entries have this title; the tie goes to the first key
+        assert_eq!(link(&close), Some(("he2016".into(), "title".into(), 98)));
+        assert_eq!(link(&far), None);
+        assert_eq!(link(&exact), Some(("intro".into(), "title".into(), 100)));
+        assert_eq!(link(&short), None);
+
+        // The entries' fields fill the documents, keeping their own where
+        // an entry has none
+        let record = stored_record(&by_doi);
+        assert_eq!(record.title.as_deref(), Some("Attention Is All You Need"));
+        assert_eq!(record.year, Some(2017));
+        let record = stored_record(&by_arxiv);
+        assert_eq!(record.arxiv_id.as_deref(), Some("1512.03385"));
+        assert_eq!(record.venue.as_deref(), Some("CVPR"));
+        assert_eq!(stored_record(&far).title, far.title);
+    }
+
+    #[test]
+    fn changes_to_the_file_propagate() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = library::open(dir.path()).unwrap();
+        let doi = |doi: &str| BibliographicRecord {
+            doi: Some(doi.to_string()),
+            year: Some(2001),
+            ..titled("Own Title")
+        };
+        let linked = add_document(&conn, dir.path(), "linked", doi("10.1000/one"));
+        let rejected = add_document(&conn, dir.path(), "rejected", doi("10.1000/two"));
+
+        let path = dir.path().join("refs.bib");
+        fs::write(
+            &path,
+            format!(
+                "{}{}",
+                bibtex_entry("one", "10.1000/ONE", "Old Venue", None),
+                bibtex_entry("two", "10.1000/two", "Old Venue", None)
+            ),
+        )
+        .unwrap();
+        let import = import_file(&conn, &path, None).unwrap();
+        assert_eq!((import.linked, import.updated), (2, 2));
+        let record = stored_record(&linked);
+        assert_eq!(record.title.as_deref(), Some("Paper one"));
+        assert_eq!(record.venue.as_deref(), Some("Old Venue"));
+        assert_eq!(record.year, Some(2001));
+        assert!(refresh(&conn, None).unwrap().is_empty());
+
+        // Unlinked documents are left alone from now on
+        assert!(unlink_document(&conn, rejected.id).unwrap());
+        fs::write(
+            &path,
+            format!(
+                "{}{}",
+                bibtex_entry("one", "10.1000/one", "New Venue", Some(2002)),
+                bibtex_entry("two", "10.1000/two", "New Venue", None)
+            ),
+        )
+        .unwrap();
+        let imports = refresh(&conn, None).unwrap();
+        assert_eq!(imports.len(), 1);
+        assert_eq!((imports[0].linked, imports[0].updated), (0, 1));
+
+        let record = stored_record(&linked);
+        assert_eq!(record.venue.as_deref(), Some("New Venue"));
+        assert_eq!(record.year, Some(2002));
+        let entry = library::get_entry(&conn, linked.id).unwrap().unwrap();
+        assert_eq!(entry.venue.as_deref(), Some("New Venue"));
+        assert_eq!(stored_record(&rejected).venue.as_deref(), Some("Old Venue"));
+        assert_eq!(
+            get_link(&conn, rejected.id).unwrap().unwrap().matched_by,
+            REJECTED
+        );
+
+        // The open document is updated in place
+        let session = rr_file::open_rr(Path::new(&linked.path)).unwrap();
+        fs::write(
+            &path,
+            bibtex_entry("one", "10.1000/one", "Open Venue", Some(2002)),
+        )
+        .unwrap();
+        let imports = refresh(&conn, Some(&session)).unwrap();
+        assert_eq!(imports[0].updated, 1);
+        assert_eq!(
+            bibliography::get_record(&session.db)
+                .unwrap()
+                .venue
+                .as_deref(),
+            Some("Open Venue")
+        );
+        rr_file::cleanup_session(&session);
+        // The entry left the file, taking its link along
+        assert!(get_link(&conn, rejected.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn titles_and_arxiv_ids_compare_loosely() {
+        assert_eq!(
+            title_key("  Caf\u{e9}-Style: \u{201c}Attention\u{201d} Is ALL you need!"),
+            "cafe style attention is all you need"
+        );
+        assert_eq!(title_key("--"), "");
+        assert_eq!(arxiv_base("2101.00001v12"), "2101.00001");
+        assert_eq!(arxiv_base("hep-th/9901001v1"), "hep-th/9901001");
+        assert_eq!(arxiv_base("2101.00001"), "2101.00001");
+        assert_eq!(arxiv_base("2101.00001v"), "2101.00001v");
+    }
+}
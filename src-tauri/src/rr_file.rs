@@ -1,10 +1,17 @@
+use sha2::{Digest, Sha256};
 use std::fs;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use zip::write::SimpleFileOptions;
 
+use crate::crdt;
 use crate::database;
+use crate::fingerprint;
 use crate::models::RrManifest;
+use crate::outline;
+use crate::page_labels;
+use crate::pdf_metadata;
+use crate::thumbnails;
 
 /// Session state for a currently open .rr file.
 /// The .rr file is extracted to a temp directory for editing,
@@ -61,6 +68,7 @@ pub fn open_rr(rr_path: &Path) -> Result<RrSession, String> {
     let db = rusqlite::Connection::open(&db_path)
         .map_err(|e| format!("Failed to open database: {}", e))?;
     database::init_db(&db).map_err(|e| format!("Failed to init database: {}", e))?;
+    crdt::claim_actor(&db, rr_path)?;
 
     Ok(RrSession {
         rr_path: rr_path.to_path_buf(),
@@ -69,6 +77,78 @@ pub fn open_rr(rr_path: &Path) -> Result<RrSession, String> {
     })
 }
 
+/// Read-only view of another .rr file, used when merging.
+/// Only data.sqlite is extracted; the PDF is hashed straight from the archive.
+pub struct RrSnapshot {
+    /// SHA-256 of document.pdf, if the container has one
+    pub pdf_sha256: Option<String>,
+    /// Read-only connection to the extracted data.sqlite
+    pub db: rusqlite::Connection,
+    /// Temp directory holding data.sqlite, removed on drop (after `db` closes)
+    _dir: tempfile::TempDir,
+}
+
+/// Open a .rr file read-only without extracting the PDF.
+pub fn open_rr_readonly(rr_path: &Path) -> Result<RrSnapshot, String> {
+    let dir = tempfile::tempdir().map_err(|e| format!("Failed to create temp dir: {}", e))?;
+
+    let file = fs::File::open(rr_path).map_err(|e| format!("Failed to open .rr file: {}", e))?;
+    let mut archive =
+        zip::ZipArchive::new(file).map_err(|e| format!("Failed to read .rr archive: {}", e))?;
+
+    let pdf_sha256 = match archive.by_name("document.pdf") {
+        Ok(mut entry) => {
+            let mut hasher = Sha256::new();
+            std::io::copy(&mut entry, &mut hasher)
+                .map_err(|e| format!("Failed to hash PDF: {}", e))?;
+            Some(hex::encode(hasher.finalize()))
+        }
+        Err(zip::result::ZipError::FileNotFound) => None,
+        Err(e) => return Err(format!("Failed to read PDF from archive: {}", e)),
+    };
+
+    extract_database(&mut archive, pdf_sha256, dir)
+}
+
+/// Open only data.sqlite of a .rr file, read-only. Unlike `open_rr_readonly`
+/// the PDF isn't hashed, so `pdf_sha256` is always `None`.
+pub fn read_database(rr_path: &Path) -> Result<RrSnapshot, String> {
+    let dir = tempfile::tempdir().map_err(|e| format!("Failed to create temp dir: {}", e))?;
+    let file = fs::File::open(rr_path).map_err(|e| format!("Failed to open .rr file: {}", e))?;
+    let mut archive =
+        zip::ZipArchive::new(file).map_err(|e| format!("Failed to read .rr archive: {}", e))?;
+    extract_database(&mut archive, None, dir)
+}
+
+fn extract_database(
+    archive: &mut zip::ZipArchive<fs::File>,
+    pdf_sha256: Option<String>,
+    dir: tempfile::TempDir,
+) -> Result<RrSnapshot, String> {
+    let db_path = dir.path().join("data.sqlite");
+    {
+        let mut entry = archive
+            .by_name("data.sqlite")
+            .map_err(|e| format!("Failed to read database from archive: {}", e))?;
+        let mut out_file =
+            fs::File::create(&db_path).map_err(|e| format!("Failed to create file: {}", e))?;
+        std::io::copy(&mut entry, &mut out_file)
+            .map_err(|e| format!("Failed to extract database: {}", e))?;
+    }
+
+    let db = rusqlite::Connection::open_with_flags(
+        &db_path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| format!("Failed to open database: {}", e))?;
+
+    Ok(RrSnapshot {
+        pdf_sha256,
+        db,
+        _dir: dir,
+    })
+}
+
 /// Import a raw PDF into a new .rr file.
 /// Creates the .rr container next to the PDF (or at the specified output path).
 pub fn import_pdf(pdf_path: &Path, output_path: Option<&Path>) -> Result<RrSession, String> {
@@ -98,11 +178,13 @@ pub fn import_pdf(pdf_path: &Path, output_path: Option<&Path>) -> Result<RrSessi
         .map_err(|e| format!("Failed to create database: {}", e))?;
     database::init_db(&db).map_err(|e| format!("Failed to init database: {}", e))?;
 
-    // Store the original filename as metadata
-    if let Some(stem) = pdf_path.file_stem().and_then(|s| s.to_str()) {
-        database::set_metadata(&db, "title", stem)
-            .map_err(|e| format!("Failed to set title: {}", e))?;
-    }
+    // Fill in the bibliographic record from the PDF, falling back to the file name
+    let pdf = pdf_metadata::parse(&pdf_dest);
+    let stem = pdf_path.file_stem().and_then(|s| s.to_str());
+    pdf_metadata::extract_into(&db, pdf.as_ref(), stem)?;
+    outline::extract_into(&db, pdf.as_ref())?;
+    page_labels::extract_into(&db, pdf.as_ref())?;
+    fingerprint::extract_into(&db, pdf.as_ref())?;
 
     let session = RrSession {
         rr_path,
@@ -110,16 +192,77 @@ pub fn import_pdf(pdf_path: &Path, output_path: Option<&Path>) -> Result<RrSessi
         db,
     };
 
+    // Record the PDF's hash up front so duplicates can be spotted
+    document_hash(&session)?;
+    thumbnails::extract_embedded(&session, pdf.as_ref())?;
+
     // Pack immediately so the .rr file exists on disk
     save_rr(&session)?;
+    crdt::claim_actor(&session.db, &session.rr_path)?;
 
     Ok(session)
 }
 
+/// Read what files from older versions lack from their PDF: the outline, page
+/// labels and embedded thumbnails. The PDF is parsed at most once, and only
+/// if something is missing.
+pub fn extract_missing(session: &RrSession) -> Result<(), String> {
+    let need_outline = !outline::is_extracted(&session.db)?;
+    let need_labels = !page_labels::is_extracted(&session.db)?;
+    let need_thumbnails = !thumbnails::is_extracted(&session.db)?;
+    if !(need_outline || need_labels || need_thumbnails) {
+        return Ok(());
+    }
+
+    let pdf = pdf_metadata::parse(&session.pdf_path());
+    if need_outline {
+        outline::extract_into(&session.db, pdf.as_ref())?;
+    }
+    if need_labels {
+        page_labels::extract_into(&session.db, pdf.as_ref())?;
+    }
+    if need_thumbnails {
+        thumbnails::extract_embedded(session, pdf.as_ref())?;
+    }
+    Ok(())
+}
+
 /// Re-pack the working directory into the .rr ZIP file.
 pub fn save_rr(session: &RrSession) -> Result<(), String> {
-    let file = fs::File::create(&session.rr_path)
-        .map_err(|e| format!("Failed to create .rr file: {}", e))?;
+    write_rr(session, &session.rr_path)
+}
+
+/// Pack the working directory into a .rr file at `path`, leaving the
+/// session's own file untouched.
+pub fn save_rr_copy(session: &RrSession, path: &Path) -> Result<(), String> {
+    write_rr(session, path)
+}
+
+/// SHA-256 of the session's data.sqlite as the next save would write it.
+pub fn database_hash(session: &RrSession) -> Result<String, String> {
+    prepare_database(session)?;
+    sha256_file(&session.work_dir.join("data.sqlite"))
+}
+
+/// Bring data.sqlite up to date for packing: compact the annotation history,
+/// drop redundant change_log entries (nothing else prunes them while sync is
+/// off) and flush the WAL.
+fn prepare_database(session: &RrSession) -> Result<(), String> {
+    crdt::compact(&session.db)?;
+    database::compact_change_log(&session.db)
+        .map_err(|e| format!("Failed to compact change log: {}", e))?;
+    session
+        .db
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
+        .map_err(|e| format!("Failed to checkpoint WAL: {}", e))
+}
+
+fn write_rr(session: &RrSession, path: &Path) -> Result<(), String> {
+    // Listed before the .rr file is truncated, since this can fail
+    let thumbnail_files = thumbnails::files(session)?;
+    prepare_database(session)?;
+
+    let file = fs::File::create(path).map_err(|e| format!("Failed to create .rr file: {}", e))?;
     let mut zip = zip::ZipWriter::new(file);
 
     // Add manifest.json (compressed)
@@ -155,12 +298,6 @@ pub fn save_rr(session: &RrSession) -> Result<(), String> {
     // Add data.sqlite (compressed)
     let db_path = session.work_dir.join("data.sqlite");
     if db_path.exists() {
-        // Flush WAL to main db file before packing
-        session
-            .db
-            .execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")
-            .map_err(|e| format!("Failed to checkpoint WAL: {}", e))?;
-
         let options =
             SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
         zip.start_file("data.sqlite", options)
@@ -170,12 +307,46 @@ pub fn save_rr(session: &RrSession) -> Result<(), String> {
             .map_err(|e| format!("Failed to write database: {}", e))?;
     }
 
+    // Add thumbnails of the current PDF (stored; the images are compressed already)
+    for (name, path) in thumbnail_files {
+        let options =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file(name, options)
+            .map_err(|e| format!("Failed to add thumbnail to archive: {}", e))?;
+        let data = fs::read(&path).map_err(|e| format!("Failed to read thumbnail: {}", e))?;
+        zip.write_all(&data)
+            .map_err(|e| format!("Failed to write thumbnail: {}", e))?;
+    }
+
     zip.finish()
         .map_err(|e| format!("Failed to finalize archive: {}", e))?;
 
     Ok(())
 }
 
+/// Compute the hex-encoded SHA-256 of a file, streaming it from disk.
+pub fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file =
+        fs::File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .map_err(|e| format!("Failed to hash {}: {}", path.display(), e))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// SHA-256 of the session's document.pdf, cached in metadata under `pdf_sha256`.
+pub fn document_hash(session: &RrSession) -> Result<String, String> {
+    if let Some(hash) = database::get_metadata(&session.db, "pdf_sha256")
+        .map_err(|e| format!("Failed to read pdf_sha256: {}", e))?
+    {
+        return Ok(hash);
+    }
+    let hash = sha256_file(&session.pdf_path())?;
+    database::set_metadata(&session.db, "pdf_sha256", &hash)
+        .map_err(|e| format!("Failed to set pdf_sha256: {}", e))?;
+    Ok(hash)
+}
+
 /// Clean up the working directory (call on close).
 pub fn cleanup_session(session: &RrSession) {
     let _ = fs::remove_dir_all(&session.work_dir);
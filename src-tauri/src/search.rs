@@ -0,0 +1,345 @@
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+use crate::bibliography;
+use crate::database;
+use crate::models::*;
+use crate::page_labels;
+use crate::rr_file;
+
+/// Upper bound on hits returned by a single search.
+const MAX_HITS: i64 = 500;
+
+/// Create the full-text index tables in the library database.
+pub fn init_search(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "
+        -- One row per annotation (content and selected text), page of text, and
+        -- the bibliographic record. kind is 'page', 'metadata' or the annotation type
+        CREATE VIRTUAL TABLE IF NOT EXISTS search_index USING fts5(
+            text,
+            document_id UNINDEXED,
+            page_number UNINDEXED,
+            annotation_id UNINDEXED,
+            kind UNINDEXED,
+            tokenize = 'porter unicode61 remove_diacritics 2'
+        );
+
+        -- Documents whose text is in search_index
+        CREATE TABLE IF NOT EXISTS search_state (
+            document_id INTEGER PRIMARY KEY,
+            indexed_at TEXT NOT NULL
+        );
+
+        -- Documents whose file couldn't be read for indexing, with its
+        -- modification time then; retried once the file changes
+        CREATE TABLE IF NOT EXISTS search_failures (
+            document_id INTEGER PRIMARY KEY,
+            modified_at TEXT,
+            error TEXT NOT NULL
+        );
+
+        -- Page labels of indexed documents, shown with page hits
+        CREATE TABLE IF NOT EXISTS search_page_labels (
+            document_id INTEGER NOT NULL,
+            page_number INTEGER NOT NULL,
+            label TEXT NOT NULL,
+            PRIMARY KEY (document_id, page_number)
+        );
+        ",
+    )
+}
+
+/// Replace the indexed text of a library document with the annotations,
+/// page text and bibliographic record in its data.sqlite.
+pub fn index_document(conn: &Connection, document_id: i64, db: &Connection) -> Result<(), String> {
+    let annotations = database::get_annotations(db, None)
+        .map_err(|e| format!("Failed to get annotations: {}", e))?;
+    let pages =
+        database::get_page_texts(db).map_err(|e| format!("Failed to get page text: {}", e))?;
+    let labels = page_labels::get(db)?;
+    let record = bibliography::get_record(db)?;
+    let record_text = [
+        record.title,
+        Some(record.authors.join(", ")),
+        record.venue,
+        record.abstract_text,
+        Some(record.keywords.join(", ")),
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|t| !t.trim().is_empty())
+    .collect::<Vec<_>>()
+    .join("\n");
+
+    let tx = conn
+        .unchecked_transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    let write = || -> rusqlite::Result<()> {
+        tx.execute(
+            "DELETE FROM search_index WHERE document_id = ?1",
+            params![document_id],
+        )?;
+        let mut insert = tx.prepare(
+            "INSERT INTO search_index (text, document_id, page_number, annotation_id, kind)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for annotation in &annotations {
+            let selected = annotation
+                .position_data
+                .as_ref()
+                .and_then(|p| p.selected_text.as_deref());
+            let text = [selected, annotation.content.as_deref()]
+                .into_iter()
+                .flatten()
+                .filter(|t| !t.trim().is_empty())
+                .collect::<Vec<_>>()
+                .join("\n");
+            if text.is_empty() {
+                continue;
+            }
+            insert.execute(params![
+                text,
+                document_id,
+                annotation.page_number,
+                annotation.id,
+                annotation.annotation_type.as_str(),
+            ])?;
+        }
+        if !record_text.is_empty() {
+            insert.execute(params![
+                record_text,
+                document_id,
+                None::<u32>,
+                None::<String>,
+                "metadata"
+            ])?;
+        }
+        for (page_number, text) in &pages {
+            if !text.trim().is_empty() {
+                insert.execute(params![
+                    text,
+                    document_id,
+                    page_number,
+                    None::<String>,
+                    "page"
+                ])?;
+            }
+        }
+        tx.execute(
+            "DELETE FROM search_page_labels WHERE document_id = ?1",
+            params![document_id],
+        )?;
+        for label in &labels {
+            tx.execute(
+                "INSERT INTO search_page_labels (document_id, page_number, label)
+                 VALUES (?1, ?2, ?3)",
+                params![document_id, label.page_number, label.label],
+            )?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO search_state (document_id, indexed_at) VALUES (?1, ?2)",
+            params![document_id, chrono::Utc::now().to_rfc3339()],
+        )?;
+        tx.execute(
+            "DELETE FROM search_failures WHERE document_id = ?1",
+            params![document_id],
+        )?;
+        Ok(())
+    };
+    write().map_err(|e| format!("Failed to update search index: {}", e))?;
+    tx.commit()
+        .map_err(|e| format!("Failed to commit search index: {}", e))
+}
+
+/// Drop a document from the index.
+pub fn remove_document(conn: &Connection, document_id: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM search_index WHERE document_id = ?1",
+        params![document_id],
+    )?;
+    conn.execute(
+        "DELETE FROM search_state WHERE document_id = ?1",
+        params![document_id],
+    )?;
+    conn.execute(
+        "DELETE FROM search_page_labels WHERE document_id = ?1",
+        params![document_id],
+    )?;
+    conn.execute(
+        "DELETE FROM search_failures WHERE document_id = ?1",
+        params![document_id],
+    )?;
+    Ok(())
+}
+
+/// Modification time of `path` as stored in search_failures, None if the
+/// file can't be found.
+fn modified_at(path: &Path) -> Option<String> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(chrono::DateTime::<chrono::Utc>::from(modified).to_rfc3339())
+}
+
+/// Index library documents that were added before they could be indexed.
+/// Files that can't be read are recorded as failed and only retried once
+/// they change on disk.
+pub fn index_pending(conn: &Connection) -> Result<usize, String> {
+    let pending: Vec<(i64, String, bool, Option<String>)> = conn
+        .prepare(
+            "SELECT documents.id, documents.path, search_failures.document_id IS NOT NULL,
+                    search_failures.modified_at
+             FROM documents
+             LEFT JOIN search_failures ON search_failures.document_id = documents.id
+             WHERE documents.id NOT IN (SELECT document_id FROM search_state)",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect()
+        })
+        .map_err(|e| format!("Failed to read library: {}", e))?;
+
+    let mut indexed = 0;
+    for (id, path, failed, failed_modified_at) in pending {
+        let path = Path::new(&path);
+        let modified = modified_at(path);
+        if failed && failed_modified_at == modified {
+            continue;
+        }
+        let snapshot = match rr_file::read_database(path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                log::warn!("[search] Skipping {}: {}", path.display(), e);
+                conn.execute(
+                    "INSERT OR REPLACE INTO search_failures (document_id, modified_at, error)
+                     VALUES (?1, ?2, ?3)",
+                    params![id, modified, e],
+                )
+                .map_err(|e| format!("Failed to update search index: {}", e))?;
+                continue;
+            }
+        };
+        index_document(conn, id, &snapshot.db)?;
+        indexed += 1;
+    }
+    Ok(indexed)
+}
+
+/// Search annotations and page text across the library, best matches first.
+pub fn search(conn: &Connection, query: &str) -> Result<Vec<SearchHit>, String> {
+    let Some(fts_query) = fts_query(query) else {
+        return Ok(Vec::new());
+    };
+    index_pending(conn)?;
+
+    let mut stmt = conn
+        .prepare(
+            "SELECT search_index.document_id, documents.path, documents.title,
+                    search_index.page_number, search_page_labels.label,
+                    search_index.annotation_id, search_index.kind,
+                    snippet(search_index, 0, '**', '**', '…', 16)
+             FROM search_index
+             JOIN documents ON documents.id = search_index.document_id
+             LEFT JOIN search_page_labels
+                 ON search_page_labels.document_id = search_index.document_id
+                 AND search_page_labels.page_number = search_index.page_number
+             WHERE search_index MATCH ?1
+             ORDER BY rank
+             LIMIT ?2",
+        )
+        .map_err(|e| format!("Failed to prepare search: {}", e))?;
+    let hits = stmt
+        .query_map(params![fts_query, MAX_HITS], |row| {
+            Ok(SearchHit {
+                document_id: row.get(0)?,
+                path: row.get(1)?,
+                title: row.get(2)?,
+                page_number: row.get(3)?,
+                page_label: row.get(4)?,
+                annotation_id: row.get(5)?,
+                kind: row.get(6)?,
+                snippet: row.get(7)?,
+            })
+        })
+        .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+        .map_err(|e| format!("Search failed: {}", e))?;
+    Ok(hits)
+}
+
+/// Turn free text into an FTS5 query where every word, or "quoted phrase",
+/// must match. Quoting each term keeps FTS5 syntax out of user input.
+fn fts_query(text: &str) -> Option<String> {
+    let mut terms = Vec::new();
+    for (i, part) in text.split('"').enumerate() {
+        if i % 2 == 1 {
+            let phrase = part.trim();
+            if !phrase.is_empty() {
+                terms.push(phrase);
+            }
+        } else {
+            terms.extend(part.split_whitespace());
+        }
+    }
+    if terms.is_empty() {
+        return None;
+    }
+    Some(
+        terms
+            .iter()
+            .map(|term| format!("\"{}\"", term))
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library;
+    use std::time::{Duration, SystemTime};
+
+    fn failure(conn: &Connection, id: i64) -> Option<String> {
+        conn.query_row(
+            "SELECT error FROM search_failures WHERE document_id = ?1",
+            params![id],
+            |row| row.get(0),
+        )
+        .ok()
+    }
+
+    #[test]
+    fn unreadable_files_are_retried_only_once_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let lib = library::open(&dir.path().join("data")).unwrap();
+        let pdf = dir.path().join("paper.pdf");
+        std::fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let session = rr_file::import_pdf(&pdf, None).unwrap();
+        database::set_page_texts(&session.db, &[(1, "contrastive learning".to_string())]).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        let entry = library::record_session(&lib, &session, false).unwrap();
+        let good = std::fs::read(&session.rr_path).unwrap();
+
+        std::fs::write(&session.rr_path, b"not a zip").unwrap();
+        remove_document(&lib, entry.id).unwrap();
+        assert_eq!(index_pending(&lib).unwrap(), 0);
+        assert!(failure(&lib, entry.id).is_some());
+
+        // Unchanged since it failed: not read again
+        lib.execute("UPDATE search_failures SET error = 'marker'", [])
+            .unwrap();
+        assert_eq!(index_pending(&lib).unwrap(), 0);
+        assert_eq!(failure(&lib, entry.id).as_deref(), Some("marker"));
+
+        std::fs::write(&session.rr_path, good).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&session.rr_path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(index_pending(&lib).unwrap(), 1);
+        assert!(failure(&lib, entry.id).is_none());
+        assert_eq!(search(&lib, "contrastive").unwrap().len(), 1);
+    }
+}
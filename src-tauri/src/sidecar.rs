@@ -0,0 +1,281 @@
+use std::path::Path;
+
+use crate::bibliography;
+use crate::citation;
+use crate::database;
+use crate::models::*;
+use crate::rr_file::{self, RrSession};
+
+pub const SIDECAR_FORMAT: &str = "research-reader-annotations";
+pub const SIDECAR_VERSION: &str = "1.0.0";
+
+/// Write the session's annotations and metadata, without the PDF, to `path`.
+pub fn export_sidecar(session: &RrSession, path: &Path) -> Result<(), String> {
+    let bundle = SidecarBundle {
+        format: SIDECAR_FORMAT.to_string(),
+        version: SIDECAR_VERSION.to_string(),
+        pdf_sha256: rr_file::sha256_file(&session.pdf_path())?,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        metadata: database::get_all_metadata(&session.db)
+            .map_err(|e| format!("Failed to read metadata: {}", e))?
+            .into_iter()
+            .collect(),
+        annotations: database::get_annotations(&session.db, None)
+            .map_err(|e| format!("Failed to get annotations: {}", e))?,
+    };
+
+    let json = serde_json::to_string_pretty(&bundle)
+        .map_err(|e| format!("Failed to serialize bundle: {}", e))?;
+    std::fs::write(path, json).map_err(|e| format!("Failed to write bundle: {}", e))
+}
+
+/// Read a sidecar bundle from disk and check its format marker.
+pub fn read_sidecar(path: &Path) -> Result<SidecarBundle, String> {
+    let json =
+        std::fs::read_to_string(path).map_err(|e| format!("Failed to read bundle: {}", e))?;
+    let bundle: SidecarBundle =
+        serde_json::from_str(&json).map_err(|e| format!("Invalid annotation bundle: {}", e))?;
+    if bundle.format != SIDECAR_FORMAT {
+        return Err(format!("Unsupported bundle format: {}", bundle.format));
+    }
+    Ok(bundle)
+}
+
+/// Attach a sidecar bundle to the open document.
+///
+/// The bundle is refused when its PDF hash doesn't match `document.pdf`, unless
+/// `force` is set. Annotations are imported by id, keeping the local copy when it
+/// was updated more recently; existing metadata keys are left untouched. A
+/// forced import only brings the reader's own metadata: keys describing the
+/// other PDF (page count, extraction state, bibliographic record) are skipped.
+pub fn import_sidecar(
+    session: &RrSession,
+    path: &Path,
+    force: bool,
+) -> Result<SidecarImportResult, String> {
+    let bundle = read_sidecar(path)?;
+    let pdf_hash = rr_file::sha256_file(&session.pdf_path())?;
+    let hash_matches = bundle.pdf_sha256.eq_ignore_ascii_case(&pdf_hash);
+    if !hash_matches && !force {
+        return Err(
+            "These annotations were made on a different PDF (SHA-256 mismatch)".to_string(),
+        );
+    }
+
+    let local: std::collections::HashMap<String, Annotation> =
+        database::get_annotations(&session.db, None)
+            .map_err(|e| format!("Failed to get annotations: {}", e))?
+            .into_iter()
+            .map(|a| (a.id.clone(), a))
+            .collect();
+
+    let mut annotations_imported = 0;
+    for annotation in &bundle.annotations {
+        if let Some(existing) = local.get(&annotation.id) {
+            if !is_newer(&annotation.updated_at, &existing.updated_at) {
+                continue;
+            }
+        }
+        database::upsert_annotation(&session.db, annotation)
+            .map_err(|e| format!("Failed to import annotation: {}", e))?;
+        annotations_imported += 1;
+    }
+
+    let mut metadata_imported = 0;
+    for (key, value) in &bundle.metadata {
+        // Describes the PDF the bundle was made from, not necessarily ours
+        if key == "pdf_sha256" {
+            continue;
+        }
+        if !hash_matches && is_document_metadata(key) {
+            continue;
+        }
+        let existing = database::get_metadata(&session.db, key)
+            .map_err(|e| format!("Failed to read metadata: {}", e))?;
+        if existing.is_none() {
+            database::set_metadata(&session.db, key, value)
+                .map_err(|e| format!("Failed to set metadata: {}", e))?;
+            metadata_imported += 1;
+        }
+    }
+
+    Ok(SidecarImportResult {
+        hash_matches,
+        annotations_imported,
+        metadata_imported,
+    })
+}
+
+/// Metadata that belongs to the bundle's PDF rather than to the reader.
+fn is_document_metadata(key: &str) -> bool {
+    database::is_derived_metadata(key)
+        || bibliography::is_record_key(key)
+        || key == citation::METADATA_KEY
+}
+
+/// Whether RFC 3339 timestamp `a` is strictly later than `b`.
+/// Unparseable timestamps fall back to string comparison.
+pub(crate) fn is_newer(a: &str, b: &str) -> bool {
+    match (
+        chrono::DateTime::parse_from_rfc3339(a),
+        chrono::DateTime::parse_from_rfc3339(b),
+    ) {
+        (Ok(a), Ok(b)) => a > b,
+        _ => a > b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(dir: &Path, name: &str, pdf: &[u8]) -> RrSession {
+        let pdf_path = dir.join(format!("{}.pdf", name));
+        std::fs::write(&pdf_path, pdf).unwrap();
+        rr_file::import_pdf(&pdf_path, None).unwrap()
+    }
+
+    fn note(db: &rusqlite::Connection, content: &str) -> Annotation {
+        database::create_annotation(
+            db,
+            &CreateAnnotationInput {
+                annotation_type: AnnotationType::Note,
+                page_number: 1,
+                color: None,
+                content: Some(content.to_string()),
+                position_data: None,
+            },
+        )
+        .unwrap()
+    }
+
+    /// A bundle from a document carrying both reader and PDF-derived metadata
+    fn bundle(dir: &Path) -> (Annotation, std::path::PathBuf) {
+        let source = session(dir, "source", b"%PDF-1.4 source");
+        let annotation = note(&source.db, "shared note");
+        for (key, value) in [
+            ("last_page", "7"),
+            ("page_count", "12"),
+            ("bib.doi", "10.1000/source"),
+            ("text_fingerprint", "abcdef"),
+            ("citation_key", "source2024"),
+        ] {
+            database::set_metadata(&source.db, key, value).unwrap();
+        }
+        let path = dir.join("source.rra");
+        export_sidecar(&source, &path).unwrap();
+        (annotation, path)
+    }
+
+    fn clear_metadata(db: &rusqlite::Connection, keys: &[&str]) {
+        for key in keys {
+            database::delete_metadata(db, key).unwrap();
+        }
+    }
+
+    const KEYS: [&str; 5] = [
+        "last_page",
+        "page_count",
+        "bib.doi",
+        "text_fingerprint",
+        "citation_key",
+    ];
+
+    #[test]
+    fn matching_pdf_imports_annotations_and_missing_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let (annotation, path) = bundle(dir.path());
+        let target = session(dir.path(), "target", b"%PDF-1.4 source");
+        clear_metadata(&target.db, &KEYS);
+
+        let result = import_sidecar(&target, &path, false).unwrap();
+        assert!(result.hash_matches);
+        assert_eq!(result.annotations_imported, 1);
+        assert_eq!(result.metadata_imported, KEYS.len());
+        let imported = database::get_annotation(&target.db, &annotation.id).unwrap();
+        assert_eq!(imported, Some(annotation));
+        assert_eq!(
+            database::get_metadata(&target.db, "bib.doi")
+                .unwrap()
+                .as_deref(),
+            Some("10.1000/source")
+        );
+    }
+
+    #[test]
+    fn different_pdf_is_refused_without_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, path) = bundle(dir.path());
+        let target = session(dir.path(), "target", b"%PDF-1.4 other");
+
+        assert!(import_sidecar(&target, &path, false).is_err());
+        assert!(database::get_annotations(&target.db, None)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn forced_import_skips_document_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let (annotation, path) = bundle(dir.path());
+        let target = session(dir.path(), "target", b"%PDF-1.4 other");
+        clear_metadata(&target.db, &KEYS);
+        let own_hash = database::get_metadata(&target.db, "pdf_sha256").unwrap();
+
+        let result = import_sidecar(&target, &path, true).unwrap();
+        assert!(!result.hash_matches);
+        assert_eq!(result.annotations_imported, 1);
+        assert_eq!(result.metadata_imported, 1);
+        assert!(database::get_annotation(&target.db, &annotation.id)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            database::get_metadata(&target.db, "last_page")
+                .unwrap()
+                .as_deref(),
+            Some("7")
+        );
+        for key in &KEYS[1..] {
+            assert_eq!(
+                database::get_metadata(&target.db, key).unwrap(),
+                None,
+                "{}",
+                key
+            );
+        }
+        assert_eq!(
+            database::get_metadata(&target.db, "pdf_sha256").unwrap(),
+            own_hash
+        );
+    }
+
+    #[test]
+    fn newer_local_annotations_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let (annotation, path) = bundle(dir.path());
+        let target = session(dir.path(), "target", b"%PDF-1.4 source");
+        let mut local = annotation.clone();
+        local.content = Some("edited later".to_string());
+        local.updated_at = "2999-01-01T00:00:00+00:00".to_string();
+        database::upsert_annotation(&target.db, &local).unwrap();
+
+        let result = import_sidecar(&target, &path, false).unwrap();
+        assert_eq!(result.annotations_imported, 0);
+        assert_eq!(
+            database::get_annotation(&target.db, &annotation.id).unwrap(),
+            Some(local)
+        );
+    }
+
+    #[test]
+    fn is_newer_compares_instants() {
+        assert!(is_newer(
+            "2024-01-01T12:00:00+00:00",
+            "2024-01-01T13:00:00+02:00"
+        ));
+        assert!(!is_newer(
+            "2024-01-01T11:00:00+00:00",
+            "2024-01-01T13:00:00+02:00"
+        ));
+    }
+}
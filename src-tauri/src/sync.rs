@@ -0,0 +1,591 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+
+use crate::crdt::{self, AnnotationDoc};
+use crate::database;
+use crate::models::*;
+use crate::rr_file::{self, RrSession};
+use crate::sidecar::is_newer;
+use crate::webdav::WebDavSettings;
+
+/// Name of the sync_state row tracking our own journal.
+const LOCAL_PEER: &str = "local";
+const JOURNAL_EXT: &str = "jsonl";
+const SNAPSHOT_NAME: &str = "document.rr";
+const SNAPSHOT_INFO_NAME: &str = "snapshot.json";
+
+/// Folder sync settings, stored as sync.json in the app config directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncConfig {
+    /// Folder shared through a sync client or network share, if sync is enabled
+    pub folder: Option<PathBuf>,
+    /// Identifies this installation's journal in the sync folder
+    pub device_id: String,
+    /// WebDAV server to sync with, if configured
+    #[serde(default)]
+    pub webdav: Option<WebDavSettings>,
+}
+
+impl SyncConfig {
+    /// Load the config from `config_dir`, creating a device id on first use.
+    pub fn load(config_dir: &Path) -> Result<Self, String> {
+        let path = config_dir.join("sync.json");
+        if path.exists() {
+            let json = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read sync config: {}", e))?;
+            return serde_json::from_str(&json).map_err(|e| format!("Invalid sync config: {}", e));
+        }
+        let config = SyncConfig {
+            folder: None,
+            device_id: uuid::Uuid::new_v4().to_string(),
+            webdav: None,
+        };
+        config.save(config_dir)?;
+        Ok(config)
+    }
+
+    pub fn save(&self, config_dir: &Path) -> Result<(), String> {
+        fs::create_dir_all(config_dir)
+            .map_err(|e| format!("Failed to create config dir: {}", e))?;
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| format!("Failed to serialize sync config: {}", e))?;
+        fs::write(config_dir.join("sync.json"), json)
+            .map_err(|e| format!("Failed to write sync config: {}", e))
+    }
+}
+
+/// One line of a device's change journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub device_id: String,
+    /// Local change_log seq on the writing device (informational)
+    pub seq: i64,
+    #[serde(flatten)]
+    pub op: JournalOp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalOp {
+    UpsertAnnotation {
+        annotation: Annotation,
+    },
+    DeleteAnnotation {
+        id: String,
+        deleted_at: String,
+    },
+    /// Base64-encoded Automerge changes to the annotation document. Devices that
+    /// write these also write the row-level entries above for older versions,
+    /// but readers that understand them ignore the row-level entries.
+    AnnotationChanges {
+        changes: String,
+    },
+}
+
+/// Folder holding one document's journals and bootstrap snapshot.
+/// Documents are keyed by the SHA-256 of their PDF.
+pub fn document_dir(folder: &Path, pdf_hash: &str) -> PathBuf {
+    folder.join(pdf_hash)
+}
+
+/// Sync a document with the folder: apply peers' journals, append our own
+/// changes to our journal, and publish a snapshot for devices that don't have
+/// the document yet. The snapshot is refreshed whenever the document's
+/// database differs from the one last published.
+pub fn sync_document(
+    session: &RrSession,
+    folder: &Path,
+    device_id: &str,
+) -> Result<SyncReport, String> {
+    let hash = rr_file::document_hash(session)?;
+    let dir = document_dir(folder, &hash);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create sync folder: {}", e))?;
+
+    let (applied, skipped) = pull(session, &dir, device_id)?;
+    let pushed = push(session, &dir, device_id)?;
+
+    let snapshot_updated = publish_snapshot(session, &dir)?;
+
+    Ok(SyncReport {
+        pdf_sha256: hash,
+        pushed,
+        applied,
+        skipped,
+        snapshot_updated,
+    })
+}
+
+/// Written next to the snapshot to tell whether it is up to date.
+#[derive(Serialize, Deserialize)]
+struct SnapshotInfo {
+    /// SHA-256 of the snapshot's data.sqlite
+    data_sha256: String,
+}
+
+/// Write the document to `document.rr` in `dir` unless the snapshot there
+/// already holds the same database. Returns whether it was written.
+fn publish_snapshot(session: &RrSession, dir: &Path) -> Result<bool, String> {
+    let snapshot = dir.join(SNAPSHOT_NAME);
+    let info_path = dir.join(SNAPSHOT_INFO_NAME);
+    let data_sha256 = rr_file::database_hash(session)?;
+    let published = fs::read_to_string(&info_path)
+        .ok()
+        .and_then(|json| serde_json::from_str::<SnapshotInfo>(&json).ok());
+    if snapshot.exists() && published.is_some_and(|p| p.data_sha256 == data_sha256) {
+        return Ok(false);
+    }
+
+    // Replaced in one step so peers never see a partly written snapshot
+    let partial = dir.join(format!("{}.partial", SNAPSHOT_NAME));
+    rr_file::save_rr_copy(session, &partial)
+        .and_then(|_| {
+            fs::rename(&partial, &snapshot)
+                .map_err(|e| format!("Failed to publish snapshot: {}", e))
+        })
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&partial);
+        })?;
+    let info = serde_json::to_string(&SnapshotInfo { data_sha256 })
+        .map_err(|e| format!("Failed to serialize snapshot info: {}", e))?;
+    fs::write(&info_path, info).map_err(|e| format!("Failed to write snapshot info: {}", e))?;
+    Ok(true)
+}
+
+/// Append local changes not yet journaled to `<device_id>.jsonl`.
+/// Returns the number of entries written.
+pub fn push(session: &RrSession, dir: &Path, device_id: &str) -> Result<usize, String> {
+    let db = &session.db;
+    let pushed_seq = database::get_sync_position(db, LOCAL_PEER)
+        .map_err(|e| format!("Failed to read sync state: {}", e))?;
+    let changes = database::get_changes_since(db, pushed_seq)
+        .map_err(|e| format!("Failed to read change log: {}", e))?;
+    let last_seq = changes.last().map(|c| c.seq);
+    let tombstones = database::get_tombstones(db, "annotations")
+        .map_err(|e| format!("Failed to read tombstones: {}", e))?;
+
+    let mut lines = String::new();
+    let mut written = 0;
+    for change in changes.iter().filter(|c| c.table_name == "annotations") {
+        let op = match change.op.as_str() {
+            "delete" => JournalOp::DeleteAnnotation {
+                id: change.row_id.clone(),
+                deleted_at: tombstones
+                    .get(&change.row_id)
+                    .cloned()
+                    .unwrap_or_else(|| change.changed_at.clone()),
+            },
+            _ => match database::get_annotation(db, &change.row_id)
+                .map_err(|e| format!("Failed to read annotation: {}", e))?
+            {
+                Some(annotation) => JournalOp::UpsertAnnotation { annotation },
+                // Deleted again later; the delete entry follows
+                None => continue,
+            },
+        };
+        let entry = JournalEntry {
+            device_id: device_id.to_string(),
+            seq: change.seq,
+            op,
+        };
+        let line = serde_json::to_string(&entry)
+            .map_err(|e| format!("Failed to serialize journal entry: {}", e))?;
+        lines.push_str(&line);
+        lines.push('\n');
+        written += 1;
+    }
+
+    let published = crdt::unpublished_changes(db)?;
+    if let Some((data, _)) = &published {
+        let entry = JournalEntry {
+            device_id: device_id.to_string(),
+            seq: last_seq.unwrap_or(pushed_seq),
+            op: JournalOp::AnnotationChanges {
+                changes: BASE64_STANDARD.encode(data),
+            },
+        };
+        let line = serde_json::to_string(&entry)
+            .map_err(|e| format!("Failed to serialize journal entry: {}", e))?;
+        lines.push_str(&line);
+        lines.push('\n');
+        written += 1;
+    }
+
+    if !lines.is_empty() {
+        let path = dir.join(format!("{}.{}", device_id, JOURNAL_EXT));
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| format!("Failed to open journal: {}", e))?;
+        file.write_all(lines.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|e| format!("Failed to write journal: {}", e))?;
+    }
+
+    if let Some(last_seq) = last_seq {
+        database::set_sync_position(db, LOCAL_PEER, last_seq)
+            .and_then(|_| database::prune_changes_through(db, last_seq))
+            .map_err(|e| format!("Failed to update sync state: {}", e))?;
+    }
+    if let Some((_, heads)) = published {
+        crdt::mark_published(db, &heads)?;
+    }
+    Ok(written)
+}
+
+/// Apply new entries from every other device's journal in `dir`.
+/// Annotation changes from devices with CRDT support are merged into the
+/// annotation document; row-level entries from older versions are resolved
+/// last-writer-wins on `updated_at` / `deleted_at`.
+/// Returns (applied, skipped) entry counts.
+pub fn pull(session: &RrSession, dir: &Path, device_id: &str) -> Result<(usize, usize), String> {
+    let db = &session.db;
+    let mut applied = 0;
+    let mut skipped = 0;
+
+    for (peer, path) in peer_journals(dir, device_id)? {
+        let position = database::get_sync_position(db, &peer)
+            .map_err(|e| format!("Failed to read sync state: {}", e))?
+            .max(0) as usize;
+        let text =
+            fs::read_to_string(&path).map_err(|e| format!("Failed to read journal: {}", e))?;
+
+        // Only complete lines count; a sync client may still be writing the last one
+        let complete = text.rfind('\n').map(|i| &text[..=i]).unwrap_or("");
+        let lines: Vec<&str> = complete.lines().skip(position).collect();
+        if lines.is_empty() {
+            continue;
+        }
+
+        let tx = db
+            .unchecked_transaction()
+            .map_err(|e| format!("Failed to start transaction: {}", e))?;
+        let seq_before = database::latest_change_seq(&tx)
+            .map_err(|e| format!("Failed to read change log: {}", e))?;
+        let mut entries = Vec::new();
+        for line in &lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    log::warn!("[sync] Skipping bad journal line from {}: {}", peer, e);
+                    skipped += 1;
+                }
+            }
+        }
+
+        // A device with CRDT support writes its annotation changes after the
+        // row-level entries of the same push, which they supersede
+        let last_crdt = entries
+            .iter()
+            .rposition(|e| matches!(e.op, JournalOp::AnnotationChanges { .. }));
+        let mut crdt_changes = Vec::new();
+        let mut row_ops = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            match &entry.op {
+                JournalOp::AnnotationChanges { changes } => match BASE64_STANDARD.decode(changes) {
+                    Ok(data) => crdt_changes.push(data),
+                    Err(e) => {
+                        log::warn!(
+                            "[sync] Skipping bad annotation changes from {}: {}",
+                            peer,
+                            e
+                        );
+                        skipped += 1;
+                    }
+                },
+                _ if last_crdt.is_some_and(|last| i < last) => {}
+                op => row_ops.push(op),
+            }
+        }
+
+        if !crdt_changes.is_empty() {
+            let mut doc = AnnotationDoc::load(&tx)?;
+            doc.capture(&tx)?;
+            let mut received = Vec::new();
+            for data in &crdt_changes {
+                received.extend(doc.apply_changes(data)?);
+            }
+            doc.save(&tx)?;
+            crdt::mark_received(&tx, &received)?;
+            let (added, updated, deleted) = crdt::materialise(&tx, &doc)?;
+            applied += added.len() + updated.len() + deleted.len();
+        }
+        for op in &row_ops {
+            if apply_entry(&tx, op).map_err(|e| format!("Failed to apply change: {}", e))? {
+                applied += 1;
+            } else {
+                skipped += 1;
+            }
+        }
+        if !row_ops.is_empty() {
+            crdt::capture(&tx)?;
+        }
+        // Changes from peers must not be echoed back into our own journal
+        database::discard_changes_after(&tx, seq_before)
+            .and_then(|_| database::set_sync_position(&tx, &peer, (position + lines.len()) as i64))
+            .map_err(|e| format!("Failed to update sync state: {}", e))?;
+        tx.commit()
+            .map_err(|e| format!("Failed to commit sync: {}", e))?;
+    }
+
+    Ok((applied, skipped))
+}
+
+/// Apply one row-level journal operation. Returns false when the local state
+/// was newer (or for CRDT changes, which `pull` applies separately).
+fn apply_entry(conn: &rusqlite::Connection, op: &JournalOp) -> rusqlite::Result<bool> {
+    match op {
+        JournalOp::UpsertAnnotation { annotation } => {
+            if let Some(local) = database::get_annotation(conn, &annotation.id)? {
+                if !is_newer(&annotation.updated_at, &local.updated_at) {
+                    return Ok(false);
+                }
+            } else if let Some(deleted_at) =
+                database::get_tombstones(conn, "annotations")?.get(&annotation.id)
+            {
+                if !is_newer(&annotation.updated_at, deleted_at) {
+                    return Ok(false);
+                }
+            }
+            database::upsert_annotation(conn, annotation)?;
+            database::clear_tombstone(conn, "annotations", &annotation.id)?;
+            Ok(true)
+        }
+        JournalOp::DeleteAnnotation { id, deleted_at } => {
+            match database::get_annotation(conn, id)? {
+                Some(local) if is_newer(&local.updated_at, deleted_at) => Ok(false),
+                Some(_) => {
+                    database::delete_annotation(conn, id)?;
+                    database::record_tombstone(conn, "annotations", id, deleted_at)?;
+                    Ok(true)
+                }
+                None => {
+                    database::record_tombstone(conn, "annotations", id, deleted_at)?;
+                    Ok(false)
+                }
+            }
+        }
+        JournalOp::AnnotationChanges { .. } => Ok(false),
+    }
+}
+
+/// Journals in `dir` written by devices other than `device_id`, as (peer, path).
+fn peer_journals(dir: &Path, device_id: &str) -> Result<Vec<(String, PathBuf)>, String> {
+    let mut journals = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(journals),
+        Err(e) => return Err(format!("Failed to list sync folder: {}", e)),
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(JOURNAL_EXT) {
+            continue;
+        }
+        let Some(peer) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        if peer != device_id && peer != LOCAL_PEER {
+            journals.push((peer.to_string(), path.clone()));
+        }
+    }
+    journals.sort();
+    Ok(journals)
+}
+
+/// Documents published to the sync folder, as (pdf hash, snapshot path).
+pub fn list_remote_documents(folder: &Path) -> Result<Vec<(String, PathBuf)>, String> {
+    let mut documents = Vec::new();
+    let entries = fs::read_dir(folder).map_err(|e| format!("Failed to list sync folder: {}", e))?;
+    for entry in entries.flatten() {
+        let snapshot = entry.path().join(SNAPSHOT_NAME);
+        if snapshot.exists() {
+            documents.push((entry.file_name().to_string_lossy().to_string(), snapshot));
+        }
+    }
+    documents.sort();
+    Ok(documents)
+}
+
+/// Watch the sync folder and call `on_change` with the PDF hash of any document
+/// whose journals were changed by another device. Keep the returned watcher
+/// alive for as long as events are wanted.
+pub fn watch(
+    folder: &Path,
+    device_id: &str,
+    on_change: impl Fn(String) + Send + 'static,
+) -> Result<notify::RecommendedWatcher, String> {
+    let own_journal = format!("{}.{}", device_id, JOURNAL_EXT);
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        let event = match res {
+            Ok(event) => event,
+            Err(e) => {
+                log::warn!("[sync] Watch error: {}", e);
+                return;
+            }
+        };
+        if !(event.kind.is_create() || event.kind.is_modify()) {
+            return;
+        }
+        for path in &event.paths {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if !name.ends_with(JOURNAL_EXT) || name == own_journal {
+                continue;
+            }
+            if let Some(hash) = path
+                .parent()
+                .and_then(|p| p.file_name())
+                .and_then(|n| n.to_str())
+            {
+                on_change(hash.to_string());
+            }
+        }
+    })
+    .map_err(|e| format!("Failed to create folder watcher: {}", e))?;
+
+    watcher
+        .watch(folder, RecursiveMode::Recursive)
+        .map_err(|e| format!("Failed to watch {}: {}", folder.display(), e))?;
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(session: &RrSession, content: &str) -> Annotation {
+        let annotation = database::create_annotation(
+            &session.db,
+            &CreateAnnotationInput {
+                annotation_type: AnnotationType::Note,
+                page_number: 1,
+                color: None,
+                content: Some(content.to_string()),
+                position_data: None,
+            },
+        )
+        .unwrap();
+        crdt::record(&session.db, &annotation.id).unwrap();
+        annotation
+    }
+
+    fn edit(session: &RrSession, id: &str, content: &str) {
+        database::update_annotation(
+            &session.db,
+            &UpdateAnnotationInput {
+                id: id.to_string(),
+                color: None,
+                content: Some(content.to_string()),
+                position_data: None,
+            },
+        )
+        .unwrap();
+        crdt::record(&session.db, id).unwrap();
+    }
+
+    fn content(session: &RrSession, id: &str) -> Option<String> {
+        database::get_annotation(&session.db, id)
+            .unwrap()
+            .and_then(|a| a.content)
+    }
+
+    /// A document on device A and the sync folder it shares
+    fn device_a(dir: &Path) -> (RrSession, PathBuf) {
+        let folder = dir.join("share");
+        fs::create_dir(&folder).unwrap();
+        let pdf = dir.join("paper.pdf");
+        fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let session = rr_file::import_pdf(&pdf, Some(&dir.join("a.rr"))).unwrap();
+        (session, folder)
+    }
+
+    /// Device B, set up from the snapshot A published
+    fn device_b(dir: &Path, folder: &Path, hash: &str) -> RrSession {
+        let snapshot = document_dir(folder, hash).join(SNAPSHOT_NAME);
+        fs::copy(snapshot, dir.join("b.rr")).unwrap();
+        rr_file::open_rr(&dir.join("b.rr")).unwrap()
+    }
+
+    #[test]
+    fn devices_converge_through_the_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, folder) = device_a(dir.path());
+        let x = note(&a, "x");
+        let report = sync_document(&a, &folder, "device-a").unwrap();
+        assert!(report.pushed > 0);
+        let b = device_b(dir.path(), &folder, &report.pdf_sha256);
+        sync_document(&b, &folder, "device-b").unwrap();
+
+        edit(&b, &x.id, "edited on b");
+        let y = note(&b, "y");
+        sync_document(&b, &folder, "device-b").unwrap();
+        let report = sync_document(&a, &folder, "device-a").unwrap();
+        assert!(report.applied > 0);
+        assert_eq!(content(&a, &x.id).as_deref(), Some("edited on b"));
+        assert_eq!(content(&a, &y.id).as_deref(), Some("y"));
+
+        database::delete_annotation(&a.db, &y.id).unwrap();
+        crdt::record(&a.db, &y.id).unwrap();
+        sync_document(&a, &folder, "device-a").unwrap();
+        sync_document(&b, &folder, "device-b").unwrap();
+        assert!(database::get_annotation(&b.db, &y.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn snapshot_is_refreshed_when_the_document_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, folder) = device_a(dir.path());
+        let report = sync_document(&a, &folder, "device-a").unwrap();
+        assert!(report.snapshot_updated);
+        let report = sync_document(&a, &folder, "device-a").unwrap();
+        assert!(!report.snapshot_updated);
+
+        let x = note(&a, "x");
+        let report = sync_document(&a, &folder, "device-a").unwrap();
+        assert!(report.snapshot_updated);
+        let snapshot = document_dir(&folder, &report.pdf_sha256).join(SNAPSHOT_NAME);
+        let published = rr_file::read_database(&snapshot).unwrap();
+        assert!(database::get_annotation(&published.db, &x.id)
+            .unwrap()
+            .is_some());
+
+        // Metadata only travels in the snapshot
+        database::set_metadata(&a.db, "title", "Renamed").unwrap();
+        assert!(
+            sync_document(&a, &folder, "device-a")
+                .unwrap()
+                .snapshot_updated
+        );
+    }
+
+    #[test]
+    fn change_log_is_compacted_on_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, _) = device_a(dir.path());
+        let x = note(&a, "x");
+        for i in 0..5 {
+            edit(&a, &x.id, &format!("edit {}", i));
+        }
+        let y = note(&a, "y");
+        database::delete_annotation(&a.db, &y.id).unwrap();
+        assert_eq!(database::get_changes_since(&a.db, 0).unwrap().len(), 8);
+
+        rr_file::save_rr(&a).unwrap();
+        let changes = database::get_changes_since(&a.db, 0).unwrap();
+        let ops: Vec<(&str, &str)> = changes
+            .iter()
+            .map(|c| (c.row_id.as_str(), c.op.as_str()))
+            .collect();
+        assert_eq!(ops, [(x.id.as_str(), "upsert"), (y.id.as_str(), "delete")]);
+    }
+}
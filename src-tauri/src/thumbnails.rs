@@ -0,0 +1,320 @@
+use std::fs;
+use std::path::PathBuf;
+
+use lopdf::{Document, Object};
+
+use crate::database;
+use crate::rr_file::{self, RrSession};
+
+/// Directory in the container holding page thumbnails, one subdirectory per
+/// PDF hash: `thumbnails/<pdf_sha256>/<page>.<png|jpg|webp>`. Thumbnails of a
+/// different PDF than the container's are left out on save.
+pub const DIR: &str = "thumbnails";
+/// Metadata key set once thumbnails embedded in the PDF have been copied.
+pub const METADATA_KEY: &str = "thumbnails.embedded";
+/// Thumbnails are meant to be low resolution; larger images are refused.
+pub const MAX_BYTES: usize = 256 * 1024;
+
+const EXTENSIONS: &[&str] = &["png", "jpg", "webp"];
+
+/// The thumbnail of a page, if one has been stored.
+pub fn get(session: &RrSession, page_number: u32) -> Result<Option<Vec<u8>>, String> {
+    let dir = dir(session)?;
+    for extension in EXTENSIONS {
+        let path = dir.join(format!("{}.{}", page_number, extension));
+        if path.exists() {
+            let bytes = fs::read(&path).map_err(|e| format!("Failed to read thumbnail: {}", e))?;
+            return Ok(Some(bytes));
+        }
+    }
+    Ok(None)
+}
+
+/// Store the thumbnail of a page, replacing any earlier one.
+pub fn put(session: &RrSession, page_number: u32, bytes: &[u8]) -> Result<(), String> {
+    if page_number == 0 {
+        return Err("Page numbers start at 1".to_string());
+    }
+    let page_count = database::get_metadata(&session.db, "page_count")
+        .map_err(|e| format!("Failed to read page_count: {}", e))?
+        .and_then(|s| s.parse::<u32>().ok());
+    if let Some(page_count) = page_count {
+        if page_number > page_count {
+            return Err(format!(
+                "Page {} is past the end of the document ({} pages)",
+                page_number, page_count
+            ));
+        }
+    }
+    if bytes.len() > MAX_BYTES {
+        return Err(format!(
+            "Thumbnail is {} KiB; the limit is {} KiB",
+            bytes.len() / 1024,
+            MAX_BYTES / 1024
+        ));
+    }
+    let extension = image_extension(bytes).ok_or("Thumbnail must be a PNG, JPEG or WebP image")?;
+
+    let dir = dir(session)?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create thumbnail dir: {}", e))?;
+    for other in EXTENSIONS.iter().filter(|&&ext| ext != extension) {
+        let path = dir.join(format!("{}.{}", page_number, other));
+        if path.exists() {
+            fs::remove_file(&path).map_err(|e| format!("Failed to replace thumbnail: {}", e))?;
+        }
+    }
+    fs::write(dir.join(format!("{}.{}", page_number, extension)), bytes)
+        .map_err(|e| format!("Failed to write thumbnail: {}", e))
+}
+
+/// Whether the PDF has been checked for embedded thumbnails yet.
+pub fn is_extracted(db: &rusqlite::Connection) -> Result<bool, String> {
+    database::get_metadata(db, METADATA_KEY)
+        .map(|done| done.is_some())
+        .map_err(|e| format!("Failed to read {}: {}", METADATA_KEY, e))
+}
+
+/// Copy the thumbnails a PDF carries in its pages' /Thumb entries. Only
+/// JPEG (DCTDecode) thumbnails can be stored without re-encoding, so other
+/// pages are left for the frontend to render. Pages that already have a
+/// thumbnail keep it. `pdf` is the session's PDF, None if it couldn't be
+/// parsed.
+pub fn extract_embedded(session: &RrSession, pdf: Option<&Document>) -> Result<usize, String> {
+    let mut copied = 0;
+    if let Some(doc) = pdf {
+        for (page_number, page_id) in doc.get_pages() {
+            let thumb = doc
+                .get_dictionary(page_id)
+                .and_then(|page| page.get(b"Thumb"))
+                .and_then(|thumb| doc.dereference(thumb))
+                .and_then(|(_, thumb)| thumb.as_stream());
+            let Ok(stream) = thumb else {
+                continue;
+            };
+            let is_jpeg = match stream.dict.get(b"Filter") {
+                Ok(Object::Name(name)) => name == b"DCTDecode",
+                Ok(Object::Array(filters)) => {
+                    matches!(filters.as_slice(), [Object::Name(name)] if name == b"DCTDecode")
+                }
+                _ => false,
+            };
+            if !is_jpeg
+                || image_extension(&stream.content).is_none()
+                || stream.content.len() > MAX_BYTES
+                || get(session, page_number)?.is_some()
+            {
+                continue;
+            }
+            put(session, page_number, &stream.content)?;
+            copied += 1;
+        }
+    }
+    database::set_metadata(&session.db, METADATA_KEY, "true")
+        .map_err(|e| format!("Failed to set {}: {}", METADATA_KEY, e))?;
+    Ok(copied)
+}
+
+/// Archive name and path of every stored thumbnail of the session's PDF,
+/// in page order, for packing into the container.
+pub fn files(session: &RrSession) -> Result<Vec<(String, PathBuf)>, String> {
+    let hash = rr_file::document_hash(session)?;
+    let dir = session.work_dir.join(DIR).join(&hash);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&dir).map_err(|e| format!("Failed to list thumbnails: {}", e))? {
+        let entry = entry.map_err(|e| format!("Failed to list thumbnails: {}", e))?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let page = name
+            .split_once('.')
+            .filter(|(_, extension)| EXTENSIONS.contains(extension))
+            .and_then(|(page, _)| page.parse::<u32>().ok());
+        if let Some(page) = page {
+            files.push((page, format!("{}/{}/{}", DIR, hash, name), entry.path()));
+        }
+    }
+    files.sort();
+    Ok(files
+        .into_iter()
+        .map(|(_, name, path)| (name, path))
+        .collect())
+}
+
+fn dir(session: &RrSession) -> Result<PathBuf, String> {
+    Ok(session
+        .work_dir
+        .join(DIR)
+        .join(rr_file::document_hash(session)?))
+}
+
+/// File extension for PNG, JPEG and WebP images, by their magic bytes.
+fn image_extension(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("jpg")
+    } else if bytes.len() > 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::{dictionary, Stream};
+    use std::path::Path;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\nnot really a png";
+
+    fn jpeg(len: usize) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xd8, 0xff, 0xe0];
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    /// A PDF with one page per entry, each with the given /Thumb stream
+    fn write_pdf(path: &Path, thumbs: Vec<Option<Stream>>) {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let kids: Vec<Object> = thumbs
+            .into_iter()
+            .map(|thumb| {
+                let mut page = dictionary! { "Type" => "Page", "Parent" => pages_id };
+                if let Some(thumb) = thumb {
+                    page.set("Thumb", doc.add_object(thumb));
+                }
+                doc.add_object(page).into()
+            })
+            .collect();
+        let count = kids.len() as i64;
+        doc.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages", "Kids" => kids, "Count" => count,
+                "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            }),
+        );
+        let catalog_id = doc.add_object(dictionary! { "Type" => "Catalog", "Pages" => pages_id });
+        doc.trailer.set("Root", catalog_id);
+        doc.save(path).unwrap();
+    }
+
+    fn import(dir: &Path, thumbs: Vec<Option<Stream>>) -> RrSession {
+        let pdf = dir.join("paper.pdf");
+        write_pdf(&pdf, thumbs);
+        rr_file::import_pdf(&pdf, Some(&dir.join("paper.rr"))).unwrap()
+    }
+
+    fn archive_names(path: &Path) -> Vec<String> {
+        let archive = zip::ZipArchive::new(fs::File::open(path).unwrap()).unwrap();
+        archive.file_names().map(String::from).collect()
+    }
+
+    #[test]
+    fn thumbnails_are_saved_in_the_container() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = import(dir.path(), vec![None, None, None]);
+        put(&session, 2, PNG).unwrap();
+        put(&session, 1, &jpeg(64)).unwrap();
+        // A new format replaces the old file
+        put(&session, 2, &jpeg(32)).unwrap();
+        rr_file::save_rr(&session).unwrap();
+        rr_file::cleanup_session(&session);
+
+        let session = rr_file::open_rr(&dir.path().join("paper.rr")).unwrap();
+        assert_eq!(get(&session, 1).unwrap(), Some(jpeg(64)));
+        assert_eq!(get(&session, 2).unwrap(), Some(jpeg(32)));
+        assert_eq!(get(&session, 3).unwrap(), None);
+        let hash = rr_file::document_hash(&session).unwrap();
+        let names: Vec<String> = files(&session)
+            .unwrap()
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                format!("thumbnails/{}/1.jpg", hash),
+                format!("thumbnails/{}/2.jpg", hash)
+            ]
+        );
+        rr_file::cleanup_session(&session);
+    }
+
+    #[test]
+    fn invalid_thumbnails_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = import(dir.path(), vec![None, None]);
+        database::set_metadata(&session.db, "page_count", "2").unwrap();
+        assert!(put(&session, 1, &jpeg(MAX_BYTES)).is_ok());
+        assert!(put(&session, 1, &jpeg(MAX_BYTES + 1)).is_err());
+        assert!(put(&session, 1, b"GIF89a").is_err());
+        assert!(put(&session, 0, PNG).is_err());
+        assert!(put(&session, 3, PNG).is_err());
+        assert_eq!(get(&session, 1).unwrap(), Some(jpeg(MAX_BYTES)));
+        rr_file::cleanup_session(&session);
+    }
+
+    #[test]
+    fn only_jpeg_thumbnails_are_copied_from_the_pdf() {
+        let dir = tempfile::tempdir().unwrap();
+        let thumb = |filter: Object, content: Vec<u8>| {
+            Some(Stream::new(dictionary! { "Filter" => filter }, content))
+        };
+        let session = import(
+            dir.path(),
+            vec![
+                thumb("DCTDecode".into(), jpeg(100)),
+                thumb(vec!["DCTDecode".into()].into(), jpeg(200)),
+                thumb("FlateDecode".into(), PNG.to_vec()),
+                thumb("DCTDecode".into(), b"not a jpeg".to_vec()),
+                thumb("DCTDecode".into(), jpeg(MAX_BYTES + 1)),
+                None,
+            ],
+        );
+        assert!(is_extracted(&session.db).unwrap());
+        assert_eq!(get(&session, 1).unwrap(), Some(jpeg(100)));
+        assert_eq!(get(&session, 2).unwrap(), Some(jpeg(200)));
+        for page in 3..=6 {
+            assert_eq!(get(&session, page).unwrap(), None, "page {}", page);
+        }
+
+        // Thumbnails already stored are kept
+        put(&session, 1, PNG).unwrap();
+        let pdf = Document::load(session.pdf_path()).unwrap();
+        assert_eq!(extract_embedded(&session, Some(&pdf)).unwrap(), 0);
+        assert_eq!(get(&session, 1).unwrap(), Some(PNG.to_vec()));
+        rr_file::cleanup_session(&session);
+    }
+
+    #[test]
+    fn thumbnails_are_keyed_by_the_pdf_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = import(dir.path(), vec![None]);
+        let old_hash = rr_file::document_hash(&session).unwrap();
+        put(&session, 1, PNG).unwrap();
+        assert!(session
+            .work_dir
+            .join(DIR)
+            .join(&old_hash)
+            .join("1.png")
+            .exists());
+
+        // Another PDF in the container doesn't see them, and they're left
+        // out on save
+        database::set_metadata(&session.db, "pdf_sha256", &"0".repeat(64)).unwrap();
+        assert_eq!(get(&session, 1).unwrap(), None);
+        assert!(files(&session).unwrap().is_empty());
+        rr_file::save_rr(&session).unwrap();
+        let names = archive_names(&session.rr_path);
+        assert!(
+            names.iter().all(|name| !name.starts_with(DIR)),
+            "{:?}",
+            names
+        );
+        rr_file::cleanup_session(&session);
+    }
+}
@@ -0,0 +1,672 @@
+use lopdf::{Document, Object};
+use serde::{Deserialize, Serialize};
+
+use crate::models::*;
+
+const CONTEXT: &str = "http://www.w3.org/ns/anno.jsonld";
+/// RFC 3778 (application/pdf fragment identifiers), used for page rects.
+const PDF_FRAGMENT_SPEC: &str = "http://tools.ietf.org/rfc/rfc3778";
+/// Namespace of the ids given to imported annotations that have none.
+const CONTENT_ID_NAMESPACE: uuid::Uuid = uuid::Uuid::from_u128(0x7c9c16d259a045fa8a1069a22663d12e);
+/// Page size assumed when the caller doesn't know a page's dimensions (US Letter, points).
+const FALLBACK_PAGE_SIZE: PageSize = PageSize {
+    width: 612.0,
+    height: 792.0,
+};
+
+/// A W3C Web Annotation (https://www.w3.org/TR/annotation-model/).
+/// Only the parts of the model we can map onto `Annotation` are represented;
+/// unknown properties are ignored on import.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAnnotation {
+    #[serde(rename = "@context", skip_serializing_if = "Option::is_none")]
+    pub context: Option<serde_json::Value>,
+    pub id: Option<String>,
+    #[serde(rename = "type", default)]
+    pub kind: OneOrMany<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motivation: Option<OneOrMany<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<String>,
+    #[serde(default, skip_serializing_if = "OneOrMany::is_empty")]
+    pub body: OneOrMany<Body>,
+    pub target: OneOrMany<Target>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stylesheet: Option<Stylesheet>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> Default for OneOrMany<T> {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl<T> OneOrMany<T> {
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            OneOrMany::One(item) => std::slice::from_ref(item),
+            OneOrMany::Many(items) => items,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.as_slice().is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Body {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Target {
+    Specific(SpecificTarget),
+    Iri(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpecificTarget {
+    pub source: String,
+    #[serde(default, skip_serializing_if = "OneOrMany::is_empty")]
+    pub selector: OneOrMany<Selector>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style_class: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Selector {
+    TextQuoteSelector {
+        exact: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        suffix: Option<String>,
+    },
+    TextPositionSelector {
+        start: u32,
+        end: u32,
+    },
+    FragmentSelector {
+        #[serde(rename = "conformsTo", skip_serializing_if = "Option::is_none")]
+        conforms_to: Option<String>,
+        value: String,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stylesheet {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+/// An `AnnotationCollection` holding every annotation of a document in one page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationCollection {
+    #[serde(rename = "@context")]
+    pub context: serde_json::Value,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub total: usize,
+    pub first: AnnotationPage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationPage {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub items: Vec<WebAnnotation>,
+}
+
+/// Convert an annotation to the Web Annotation model.
+///
+/// `source` identifies the PDF (any IRI). The selected text becomes a
+/// TextQuoteSelector, the character offsets a TextPositionSelector, and each rect
+/// an RFC 3778 FragmentSelector (`page=N&viewrect=x,y,w,h`).
+pub fn to_web_annotation(annotation: &Annotation, source: &str) -> WebAnnotation {
+    let motivation = match annotation.annotation_type {
+        AnnotationType::Highlight => "highlighting",
+        AnnotationType::Note => "commenting",
+        AnnotationType::Bookmark => "bookmarking",
+    };
+
+    let mut selectors = Vec::new();
+    if let Some(pd) = &annotation.position_data {
+        if let Some(text) = &pd.selected_text {
+            selectors.push(Selector::TextQuoteSelector {
+                exact: text.clone(),
+                prefix: None,
+                suffix: None,
+            });
+        }
+        if let (Some(start), Some(end)) = (pd.start_offset, pd.end_offset) {
+            selectors.push(Selector::TextPositionSelector { start, end });
+        }
+        for rect in &pd.rects {
+            selectors.push(Selector::FragmentSelector {
+                conforms_to: Some(PDF_FRAGMENT_SPEC.to_string()),
+                value: format!(
+                    "page={}&viewrect={},{},{},{}",
+                    annotation.page_number, rect.x, rect.y, rect.width, rect.height
+                ),
+            });
+        }
+    }
+    if selectors.is_empty() {
+        selectors.push(Selector::FragmentSelector {
+            conforms_to: Some(PDF_FRAGMENT_SPEC.to_string()),
+            value: format!("page={}", annotation.page_number),
+        });
+    }
+
+    let body = annotation
+        .content
+        .as_ref()
+        .map(|content| {
+            OneOrMany::Many(vec![Body {
+                kind: Some("TextualBody".to_string()),
+                value: Some(content.clone()),
+                format: Some("text/plain".to_string()),
+                purpose: Some("commenting".to_string()),
+            }])
+        })
+        .unwrap_or_default();
+
+    let (style_class, stylesheet) = match &annotation.color {
+        Some(color) => (
+            Some("rr-color".to_string()),
+            Some(Stylesheet {
+                kind: "CssStylesheet".to_string(),
+                value: format!(".rr-color {{ background-color: {}; }}", color),
+            }),
+        ),
+        None => (None, None),
+    };
+
+    WebAnnotation {
+        context: Some(serde_json::Value::String(CONTEXT.to_string())),
+        id: Some(format!("urn:uuid:{}", annotation.id)),
+        kind: OneOrMany::One("Annotation".to_string()),
+        motivation: Some(OneOrMany::One(motivation.to_string())),
+        created: Some(annotation.created_at.clone()),
+        modified: Some(annotation.updated_at.clone()),
+        body,
+        target: OneOrMany::One(Target::Specific(SpecificTarget {
+            source: source.to_string(),
+            selector: OneOrMany::Many(selectors),
+            style_class,
+        })),
+        stylesheet,
+    }
+}
+
+/// The `source` exported targets name the PDF by: its SHA-256 as a URN, which
+/// stays the same wherever the file is.
+pub fn source_urn(pdf_sha256: &str) -> String {
+    format!("urn:sha256:{}", pdf_sha256.to_lowercase())
+}
+
+/// Sources that name the PDF: `source_urn`, and the `urn:x-pdf:` URN that
+/// PDF.js and Hypothesis derive from the file identifier in its trailer.
+pub fn document_sources(pdf_sha256: &str, pdf: Option<&Document>) -> Vec<String> {
+    let mut sources = vec![source_urn(pdf_sha256)];
+    let file_id = pdf
+        .and_then(|doc| doc.trailer.get(b"ID").ok())
+        .and_then(|id| id.as_array().ok())
+        .and_then(|id| id.first());
+    if let Some(Object::String(bytes, _)) = file_id {
+        let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        sources.push(format!("urn:x-pdf:{}", hex));
+    }
+    sources
+}
+
+/// Wrap a document's annotations in an `AnnotationCollection`.
+pub fn to_collection(
+    annotations: &[Annotation],
+    source: &str,
+    label: Option<String>,
+) -> AnnotationCollection {
+    let items: Vec<WebAnnotation> = annotations
+        .iter()
+        .map(|a| {
+            let mut item = to_web_annotation(a, source);
+            // The context is declared once on the collection
+            item.context = None;
+            item
+        })
+        .collect();
+
+    AnnotationCollection {
+        context: serde_json::Value::String(CONTEXT.to_string()),
+        kind: "AnnotationCollection".to_string(),
+        label,
+        total: items.len(),
+        first: AnnotationPage {
+            kind: "AnnotationPage".to_string(),
+            items,
+        },
+    }
+}
+
+/// Convert a Web Annotation back to an `Annotation`, or None if it isn't
+/// for this document.
+///
+/// Only targets whose `source` is one of `sources` are read, unless `sources`
+/// is None. `page_sizes` holds the dimensions of each page in order (index 0
+/// is page 1). Annotations without a page FragmentSelector, or on a page past
+/// the end, are skipped. Ids of the form `urn:uuid:<uuid>` are kept; other
+/// ids become a UUIDv5 of the IRI, and annotations without one get a UUIDv5
+/// of their JSON, so importing the same file again updates the annotations
+/// instead of duplicating them.
+pub fn from_web_annotation(
+    web: &WebAnnotation,
+    sources: Option<&[String]>,
+    page_sizes: &[PageSize],
+) -> Result<Option<Annotation>, String> {
+    let for_document = |source: &str| match sources {
+        Some(sources) => sources.iter().any(|s| s.eq_ignore_ascii_case(source)),
+        None => true,
+    };
+    let selectors: Vec<&Selector> = web
+        .target
+        .as_slice()
+        .iter()
+        .filter_map(|t| match t {
+            Target::Specific(specific) if for_document(&specific.source) => {
+                Some(specific.selector.as_slice())
+            }
+            _ => None,
+        })
+        .flatten()
+        .collect();
+
+    let mut page_number = None;
+    let mut rects = Vec::new();
+    let mut selected_text = None;
+    let mut offsets = None;
+    for selector in selectors {
+        match selector {
+            Selector::TextQuoteSelector { exact, .. } => {
+                selected_text.get_or_insert_with(|| exact.clone());
+            }
+            Selector::TextPositionSelector { start, end } => {
+                offsets.get_or_insert((*start, *end));
+            }
+            Selector::FragmentSelector { value, .. } => {
+                let (page, rect) = parse_pdf_fragment(value)?;
+                if page.is_some() {
+                    page_number = page_number.or(page);
+                }
+                rects.extend(rect);
+            }
+            Selector::Unsupported => {}
+        }
+    }
+
+    let motivations: Vec<&str> = web
+        .motivation
+        .as_ref()
+        .map(|m| m.as_slice().iter().map(String::as_str).collect())
+        .unwrap_or_default();
+    let annotation_type = if motivations.contains(&"bookmarking") {
+        AnnotationType::Bookmark
+    } else if motivations.contains(&"highlighting") || selected_text.is_some() {
+        AnnotationType::Highlight
+    } else {
+        AnnotationType::Note
+    };
+
+    let content = web
+        .body
+        .as_slice()
+        .iter()
+        .filter(|b| b.purpose.as_deref() != Some("tagging"))
+        .filter_map(|b| b.value.clone())
+        .reduce(|acc, v| format!("{}\n\n{}", acc, v));
+
+    let color = web
+        .stylesheet
+        .as_ref()
+        .and_then(|s| parse_css_color(&s.value));
+
+    let Some(page_number) = page_number else {
+        return Ok(None);
+    };
+    if !page_sizes.is_empty() && page_number as usize > page_sizes.len() {
+        return Ok(None);
+    }
+    let page_size = page_sizes
+        .get(page_number.saturating_sub(1) as usize)
+        .copied()
+        .unwrap_or(FALLBACK_PAGE_SIZE);
+    let position_data = if rects.is_empty() && selected_text.is_none() && offsets.is_none() {
+        None
+    } else {
+        Some(PositionData {
+            rects,
+            page_width: page_size.width,
+            page_height: page_size.height,
+            selected_text,
+            start_offset: offsets.map(|(start, _)| start),
+            end_offset: offsets.map(|(_, end)| end),
+        })
+    };
+
+    let id = match web.id.as_deref() {
+        Some(iri) => iri
+            .strip_prefix("urn:uuid:")
+            .and_then(|id| uuid::Uuid::parse_str(id).ok())
+            .unwrap_or_else(|| uuid::Uuid::new_v5(&uuid::Uuid::NAMESPACE_URL, iri.as_bytes())),
+        None => {
+            let json = serde_json::to_vec(web)
+                .map_err(|e| format!("Failed to serialize annotation: {}", e))?;
+            uuid::Uuid::new_v5(&CONTENT_ID_NAMESPACE, &json)
+        }
+    }
+    .to_string();
+    let now = chrono::Utc::now().to_rfc3339();
+    let created_at = web.created.clone().unwrap_or_else(|| now.clone());
+    let updated_at = web.modified.clone().unwrap_or_else(|| created_at.clone());
+
+    Ok(Some(Annotation {
+        id,
+        annotation_type,
+        page_number,
+        page_label: None,
+        color,
+        content,
+        position_data,
+        created_at,
+        updated_at,
+    }))
+}
+
+/// Parse a JSON-LD document containing Web Annotations. Accepts an
+/// `AnnotationCollection`, an `AnnotationPage`, a bare array, or a single annotation.
+pub fn parse_web_annotations(json: &str) -> Result<Vec<WebAnnotation>, String> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).map_err(|e| format!("Invalid JSON-LD: {}", e))?;
+
+    let items = if let Some(first) = value.get("first") {
+        first.get("items").cloned().unwrap_or_default()
+    } else if let Some(items) = value.get("items") {
+        items.clone()
+    } else if value.is_array() {
+        value
+    } else {
+        serde_json::Value::Array(vec![value])
+    };
+
+    serde_json::from_value(items).map_err(|e| format!("Invalid Web Annotation: {}", e))
+}
+
+/// Parse an RFC 3778 fragment (`page=N&viewrect=left,top,width,height`).
+fn parse_pdf_fragment(value: &str) -> Result<(Option<u32>, Option<Rect>), String> {
+    let mut page = None;
+    let mut rect = None;
+    for part in value.trim_start_matches('#').split('&') {
+        if let Some(p) = part.strip_prefix("page=") {
+            page = Some(
+                p.parse::<u32>()
+                    .ok()
+                    .filter(|&page| page > 0)
+                    .ok_or_else(|| format!("Invalid page in fragment '{}'", value))?,
+            );
+        } else if let Some(r) = part.strip_prefix("viewrect=") {
+            let numbers = r
+                .split(',')
+                .map(|n| n.trim().parse::<f64>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Invalid viewrect in fragment '{}': {}", value, e))?;
+            if let [x, y, width, height] = numbers[..] {
+                rect = Some(Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                });
+            }
+        }
+    }
+    Ok((page, rect))
+}
+
+/// Pull the first hex colour out of a CSS stylesheet value.
+fn parse_css_color(css: &str) -> Option<String> {
+    let start = css.find('#')?;
+    let hex: String = css[start + 1..]
+        .chars()
+        .take_while(|c| c.is_ascii_hexdigit())
+        .collect();
+    matches!(hex.len(), 3 | 6 | 8).then(|| format!("#{}", hex.to_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "9F86D081884C7D659A2FEAA0C55AD015A3BF4F1B2B0B822CD15D6C15B0F00A08";
+    const SIZE: PageSize = PageSize {
+        width: 600.0,
+        height: 800.0,
+    };
+
+    fn sources() -> Vec<String> {
+        document_sources(HASH, None)
+    }
+
+    fn import(json: &str) -> Vec<Option<Annotation>> {
+        let sources = sources();
+        parse_web_annotations(json)
+            .unwrap()
+            .iter()
+            .map(|web| from_web_annotation(web, Some(&sources), &[SIZE; 5]).unwrap())
+            .collect()
+    }
+
+    fn highlight() -> Annotation {
+        Annotation {
+            id: "5d1f8a3e-3b4f-4d35-9d2c-0f3c2a1b9e77".to_string(),
+            annotation_type: AnnotationType::Highlight,
+            page_number: 3,
+            page_label: None,
+            color: Some("#ffeb3b".to_string()),
+            content: Some("Key claim".to_string()),
+            position_data: Some(PositionData {
+                rects: vec![Rect {
+                    x: 1.5,
+                    y: 2.0,
+                    width: 3.0,
+                    height: 4.0,
+                }],
+                page_width: SIZE.width,
+                page_height: SIZE.height,
+                selected_text: Some("the selected text".to_string()),
+                start_offset: Some(5),
+                end_offset: Some(22),
+            }),
+            created_at: "2024-03-01T12:34:56+00:00".to_string(),
+            updated_at: "2024-03-02T08:00:00+00:00".to_string(),
+        }
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let bookmark = Annotation {
+            id: "0b6c3c8e-8f0e-4a57-b1d4-7c1e3f2a6d90".to_string(),
+            annotation_type: AnnotationType::Bookmark,
+            color: None,
+            content: None,
+            position_data: None,
+            ..highlight()
+        };
+        let collection = to_collection(
+            &[highlight(), bookmark.clone()],
+            &source_urn(HASH),
+            Some("Paper".to_string()),
+        );
+        let json = serde_json::to_string(&collection).unwrap();
+        assert!(json.contains(&format!("urn:sha256:{}", HASH.to_lowercase())));
+
+        let imported: Vec<Annotation> = import(&json).into_iter().flatten().collect();
+        assert_eq!(imported, vec![highlight(), bookmark]);
+    }
+
+    #[test]
+    fn reimporting_is_idempotent() {
+        let json =
+            serde_json::to_string(&to_collection(&[highlight()], &source_urn(HASH), None)).unwrap();
+        assert_eq!(import(&json), import(&json));
+
+        // Foreign ids, or none at all, map to the same id every time
+        let foreign = format!(
+            r#"[{{"id": "https://hypothes.is/a/AbC123", "target": {{"source": "{0}",
+                 "selector": {{"type": "FragmentSelector", "value": "page=2"}}}}}},
+               {{"target": {{"source": "{0}",
+                 "selector": {{"type": "FragmentSelector", "value": "page=4"}}}}}}]"#,
+            source_urn(HASH)
+        );
+        let imported_ids = |imported: Vec<Option<Annotation>>| -> Vec<String> {
+            imported.into_iter().flatten().map(|a| a.id).collect()
+        };
+        let ids = imported_ids(import(&foreign));
+        assert_eq!(ids, imported_ids(import(&foreign)));
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+        assert!(ids.iter().all(|id| uuid::Uuid::parse_str(id).is_ok()));
+    }
+
+    #[test]
+    fn fragment_selectors() {
+        assert_eq!(parse_pdf_fragment("page=7").unwrap(), (Some(7), None));
+        let (page, rect) = parse_pdf_fragment("#page=2&viewrect=10,20.5,30,40").unwrap();
+        assert_eq!(page, Some(2));
+        let rect = rect.unwrap();
+        assert_eq!(
+            (rect.x, rect.y, rect.width, rect.height),
+            (10.0, 20.5, 30.0, 40.0)
+        );
+        assert_eq!(parse_pdf_fragment("viewrect=1,2,3").unwrap(), (None, None));
+        assert_eq!(parse_pdf_fragment("zoom=200").unwrap(), (None, None));
+
+        assert!(parse_pdf_fragment("page=0").is_err());
+        assert!(parse_pdf_fragment("page=-1").is_err());
+        assert!(parse_pdf_fragment("page=2&viewrect=1,x,3,4").is_err());
+    }
+
+    #[test]
+    fn hypothesis_annotations() {
+        // As exported by Hypothesis for a local PDF, named by its PDF.js
+        // fingerprint
+        let mut doc = Document::with_version("1.5");
+        doc.trailer.set(
+            "ID",
+            vec![
+                Object::string_literal(vec![0xab, 0x01, 0xff]),
+                Object::string_literal(vec![0x00]),
+            ],
+        );
+        let sources = document_sources(HASH, Some(&doc));
+        assert_eq!(sources[1], "urn:x-pdf:ab01ff");
+
+        let json = r##"{
+            "@context": "http://www.w3.org/ns/anno.jsonld",
+            "type": "AnnotationCollection",
+            "first": {"type": "AnnotationPage", "items": [{
+                "id": "https://hypothes.is/a/Xyz789",
+                "type": "Annotation",
+                "created": "2023-05-01T10:00:00.000000+00:00",
+                "motivation": "commenting",
+                "body": [
+                    {"type": "TextualBody", "value": "Interesting", "format": "text/markdown"},
+                    {"type": "TextualBody", "value": "methods", "purpose": "tagging"}
+                ],
+                "target": [{
+                    "source": "urn:x-pdf:AB01FF",
+                    "selector": [
+                        {"type": "RangeSelector", "startContainer": "/div[2]", "endContainer": "/div[2]"},
+                        {"type": "TextPositionSelector", "start": 100, "end": 111},
+                        {"type": "TextQuoteSelector", "exact": "the results", "prefix": "see ", "suffix": "."},
+                        {"type": "FragmentSelector", "value": "page=2"}
+                    ]
+                }]
+            }]}
+        }"##;
+        let web = parse_web_annotations(json).unwrap();
+        let annotation = from_web_annotation(&web[0], Some(&sources), &[SIZE; 3])
+            .unwrap()
+            .unwrap();
+        assert_eq!(annotation.annotation_type, AnnotationType::Highlight);
+        assert_eq!(annotation.page_number, 2);
+        assert_eq!(annotation.content.as_deref(), Some("Interesting"));
+        assert_eq!(annotation.created_at, "2023-05-01T10:00:00.000000+00:00");
+        assert_eq!(annotation.updated_at, annotation.created_at);
+        let position = annotation.position_data.unwrap();
+        assert_eq!(position.selected_text.as_deref(), Some("the results"));
+        assert_eq!(
+            (position.start_offset, position.end_offset),
+            (Some(100), Some(111))
+        );
+        assert_eq!(position.page_width, SIZE.width);
+    }
+
+    #[test]
+    fn other_documents_and_pageless_targets_are_skipped() {
+        let target = |source: &str, fragment: &str| {
+            format!(
+                r#"{{"target": {{"source": "{}", "selector": [
+                    {{"type": "TextQuoteSelector", "exact": "words"}},
+                    {{"type": "FragmentSelector", "value": "{}"}}]}}}}"#,
+                source, fragment
+            )
+        };
+        let ours = source_urn(HASH);
+        let other = source_urn(&"0".repeat(64));
+        let json = format!(
+            "[{}, {}, {}, {}, {}]",
+            target(&ours, "page=2"),
+            target(&other, "page=2"),
+            target("https://example.com/paper.pdf", "page=2"),
+            target(&ours, "viewrect=1,2,3,4"),
+            target(&ours, "page=9"),
+        );
+        let imported = import(&json);
+        assert!(imported[0].is_some());
+        assert!(imported[1..].iter().all(Option::is_none));
+
+        // Without sources every target is read
+        let web = parse_web_annotations(&json).unwrap();
+        assert!(from_web_annotation(&web[1], None, &[SIZE; 5])
+            .unwrap()
+            .is_some());
+
+        let zero = format!("[{}]", target(&ours, "page=0"));
+        let web = parse_web_annotations(&zero).unwrap();
+        assert!(from_web_annotation(&web[0], Some(&sources()), &[SIZE; 5]).is_err());
+    }
+}
@@ -0,0 +1,1046 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::{HeaderMap, ETAG};
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::credentials;
+use crate::models::*;
+use crate::rr_file;
+use crate::sync;
+
+/// Files larger than this are uploaded in chunks when the server supports it.
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+const SNAPSHOT_NAME: &str = "document.rr";
+const ETAGS_FILE: &str = ".etags.json";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Limit for a request to get its response, and for each read of the body
+/// after that, so a large download only fails if it stalls.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// Slowest upload rate tolerated, in bytes per second. Uploads are sent as
+/// part of the request, so their timeout grows with their size.
+const MIN_UPLOAD_RATE: u64 = 64 * 1024;
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:getetag/><d:resourcetype/></d:prop></d:propfind>"#;
+
+/// WebDAV account settings, stored in sync.json. The password lives in the OS
+/// credential store under `credential_account()`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebDavSettings {
+    /// Collection that holds the library, e.g.
+    /// https://cloud.example.org/remote.php/dav/files/alice/Vellum/
+    pub url: String,
+    pub username: String,
+    /// Nextcloud-style chunked upload collection, e.g.
+    /// https://cloud.example.org/remote.php/dav/uploads/alice/ (enables resumable uploads)
+    #[serde(default)]
+    pub chunked_upload_url: Option<String>,
+}
+
+impl WebDavSettings {
+    pub fn credential_account(&self) -> String {
+        format!("webdav:{}@{}", self.username, self.url)
+    }
+}
+
+/// Downloaded body and its ETag
+pub type Download = (Vec<u8>, Option<String>);
+
+/// A member of a WebDAV collection
+#[derive(Debug, Clone)]
+pub struct Resource {
+    pub name: String,
+    pub etag: Option<String>,
+    pub is_collection: bool,
+}
+
+/// Conditional-request guard for uploads
+#[derive(Debug, Clone)]
+pub enum Precondition {
+    /// Overwrite unconditionally
+    None,
+    /// Only replace the version with this ETag
+    IfMatch(String),
+    /// Only create; fail if the resource exists
+    IfNoneMatch,
+}
+
+/// Result of an upload
+#[derive(Debug, Clone, PartialEq)]
+pub enum PutOutcome {
+    /// Stored; carries the new ETag when the server returned one
+    Stored(Option<String>),
+    /// The precondition failed: someone else changed the resource
+    Conflict,
+}
+
+/// Minimal blocking WebDAV client (RFC 4918) with ETag preconditions.
+pub struct WebDavClient {
+    client: Client,
+    base: Url,
+    username: String,
+    password: String,
+    chunked_upload_url: Option<Url>,
+}
+
+impl WebDavClient {
+    /// Build a client from settings, reading the password from the credential store.
+    pub fn from_settings(settings: &WebDavSettings) -> Result<Self, String> {
+        let password = credentials::get_secret(&settings.credential_account())?
+            .ok_or("No WebDAV password is stored for this account")?;
+        Self::new(
+            &settings.url,
+            &settings.username,
+            &password,
+            settings.chunked_upload_url.as_deref(),
+        )
+    }
+
+    pub fn new(
+        url: &str,
+        username: &str,
+        password: &str,
+        chunked_upload_url: Option<&str>,
+    ) -> Result<Self, String> {
+        Ok(WebDavClient {
+            client: Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(READ_TIMEOUT)
+                .build()
+                .map_err(|e| format!("Failed to create HTTP client: {}", e))?,
+            base: collection_url(url)?,
+            username: username.to_string(),
+            password: password.to_string(),
+            chunked_upload_url: chunked_upload_url.map(collection_url).transpose()?,
+        })
+    }
+
+    fn url(&self, path: &str) -> Result<Url, String> {
+        self.base
+            .join(path)
+            .map_err(|e| format!("Invalid WebDAV path '{}': {}", path, e))
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.client
+            .request(method, url)
+            .basic_auth(&self.username, Some(&self.password))
+    }
+
+    /// Create a collection; succeeds if it already exists.
+    pub fn ensure_collection(&self, path: &str) -> Result<(), String> {
+        self.mkcol(self.url(path)?, None)
+    }
+
+    fn mkcol(&self, url: Url, destination: Option<&Url>) -> Result<(), String> {
+        let mut request = self.request(method("MKCOL"), url.clone());
+        if let Some(destination) = destination {
+            request = request.header("Destination", destination.as_str());
+        }
+        let response = request
+            .send()
+            .map_err(|e| format!("WebDAV MKCOL {} failed: {}", url, e))?;
+        match response.status() {
+            s if s.is_success() => Ok(()),
+            // 405: the collection already exists
+            StatusCode::METHOD_NOT_ALLOWED => Ok(()),
+            s => Err(format!("WebDAV MKCOL {} failed: {}", url, s)),
+        }
+    }
+
+    /// List the members of a collection (PROPFIND, Depth: 1).
+    pub fn list(&self, path: &str) -> Result<Vec<Resource>, String> {
+        self.list_url(self.url(path)?)
+    }
+
+    fn list_url(&self, url: Url) -> Result<Vec<Resource>, String> {
+        let response = self
+            .request(method("PROPFIND"), url.clone())
+            .header("Depth", "1")
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY)
+            .send()
+            .map_err(|e| format!("WebDAV PROPFIND {} failed: {}", url, e))?;
+        match response.status() {
+            StatusCode::NOT_FOUND => return Ok(Vec::new()),
+            s if !s.is_success() => {
+                return Err(format!("WebDAV PROPFIND {} failed: {}", url, s));
+            }
+            _ => {}
+        }
+        let body = response
+            .text()
+            .map_err(|e| format!("Failed to read PROPFIND response: {}", e))?;
+        let resources = parse_multistatus(&body)?;
+        // The first response is usually the collection itself
+        Ok(resources
+            .into_iter()
+            .filter_map(|(href, etag, is_collection)| {
+                let full = url.join(&href).ok()?;
+                if full.path().trim_end_matches('/') == url.path().trim_end_matches('/') {
+                    return None;
+                }
+                let name = full.path_segments()?.rfind(|s| !s.is_empty())?.to_string();
+                let name = percent_decode(&name);
+                Some(Resource {
+                    name,
+                    etag,
+                    is_collection,
+                })
+            })
+            .collect())
+    }
+
+    /// Download a resource unless its ETag still equals `known_etag`.
+    /// Returns `None` when the resource is unchanged or doesn't exist.
+    pub fn get_if_changed(
+        &self,
+        path: &str,
+        known_etag: Option<&str>,
+    ) -> Result<Option<Download>, String> {
+        let url = self.url(path)?;
+        let mut request = self.request(Method::GET, url.clone());
+        if let Some(etag) = known_etag {
+            request = request.header("If-None-Match", etag);
+        }
+        let response = request
+            .send()
+            .map_err(|e| format!("WebDAV GET {} failed: {}", url, e))?;
+        match response.status() {
+            StatusCode::NOT_MODIFIED | StatusCode::NOT_FOUND => Ok(None),
+            s if s.is_success() => {
+                let etag = etag_of(response.headers());
+                let bytes = response
+                    .bytes()
+                    .map_err(|e| format!("Failed to download {}: {}", url, e))?;
+                Ok(Some((bytes.to_vec(), etag)))
+            }
+            s => Err(format!("WebDAV GET {} failed: {}", url, s)),
+        }
+    }
+
+    /// Download a resource to a local file.
+    pub fn download(&self, path: &str, dest: &Path) -> Result<(), String> {
+        let url = self.url(path)?;
+        let mut response = self
+            .request(Method::GET, url.clone())
+            .send()
+            .map_err(|e| format!("WebDAV GET {} failed: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("WebDAV GET {} failed: {}", url, response.status()));
+        }
+        let mut file =
+            fs::File::create(dest).map_err(|e| format!("Failed to create file: {}", e))?;
+        response
+            .copy_to(&mut file)
+            .map_err(|e| format!("Failed to download {}: {}", url, e))?;
+        Ok(())
+    }
+
+    /// Upload bytes with an ETag precondition.
+    pub fn put(
+        &self,
+        path: &str,
+        body: Vec<u8>,
+        precondition: &Precondition,
+    ) -> Result<PutOutcome, String> {
+        let url = self.url(path)?;
+        let request = with_precondition(self.request(Method::PUT, url.clone()), precondition);
+        let response = request
+            .timeout(upload_timeout(body.len() as u64))
+            .body(body)
+            .send()
+            .map_err(|e| format!("WebDAV PUT {} failed: {}", url, e))?;
+        put_outcome(&url, response.status(), response.headers())
+    }
+
+    /// Upload a local file. Large files go through the chunked upload
+    /// collection when one is configured, resuming any earlier partial upload.
+    pub fn upload_file(
+        &self,
+        local: &Path,
+        path: &str,
+        precondition: &Precondition,
+    ) -> Result<PutOutcome, String> {
+        let size = fs::metadata(local)
+            .map_err(|e| format!("Failed to stat {}: {}", local.display(), e))?
+            .len();
+        match &self.chunked_upload_url {
+            Some(uploads) if size > CHUNK_SIZE => {
+                self.upload_chunked(uploads, local, size, path, precondition)
+            }
+            _ => {
+                let url = self.url(path)?;
+                let file = fs::File::open(local)
+                    .map_err(|e| format!("Failed to open {}: {}", local.display(), e))?;
+                let response =
+                    with_precondition(self.request(Method::PUT, url.clone()), precondition)
+                        .timeout(upload_timeout(size))
+                        .body(file)
+                        .send()
+                        .map_err(|e| format!("WebDAV PUT {} failed: {}", url, e))?;
+                put_outcome(&url, response.status(), response.headers())
+            }
+        }
+    }
+
+    /// Nextcloud chunked upload v2: chunks are PUT into a transfer collection
+    /// named after the content, so an interrupted upload finds its finished
+    /// chunks again, then assembled with a MOVE of `.file` onto the destination.
+    fn upload_chunked(
+        &self,
+        uploads: &Url,
+        local: &Path,
+        size: u64,
+        path: &str,
+        precondition: &Precondition,
+    ) -> Result<PutOutcome, String> {
+        let dest = self.url(path)?;
+        let content_hash = rr_file::sha256_file(local)?;
+        let transfer_id = format!("vellum-{}", &content_hash[..32]);
+        let transfer = collection_url(
+            uploads
+                .join(&format!("{}/", transfer_id))
+                .map_err(|e| format!("Invalid upload URL: {}", e))?
+                .as_str(),
+        )?;
+        self.mkcol(transfer.clone(), Some(&dest))?;
+
+        let done: std::collections::HashSet<String> = self
+            .list_url(transfer.clone())?
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+
+        let mut file = fs::File::open(local)
+            .map_err(|e| format!("Failed to open {}: {}", local.display(), e))?;
+        let chunk_count = size.div_ceil(CHUNK_SIZE);
+        for index in 0..chunk_count {
+            let name = format!("{:05}", index + 1);
+            if done.contains(&name) {
+                continue;
+            }
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+            file.seek(SeekFrom::Start(index * CHUNK_SIZE))
+                .and_then(|_| (&mut file).take(CHUNK_SIZE).read_to_end(&mut chunk))
+                .map_err(|e| format!("Failed to read {}: {}", local.display(), e))?;
+            let url = transfer
+                .join(&name)
+                .map_err(|e| format!("Invalid chunk URL: {}", e))?;
+            let response = self
+                .request(Method::PUT, url.clone())
+                .header("Destination", dest.as_str())
+                .header("OC-Total-Length", size.to_string())
+                .timeout(upload_timeout(chunk.len() as u64))
+                .body(chunk)
+                .send()
+                .map_err(|e| format!("WebDAV PUT {} failed: {}", url, e))?;
+            if !response.status().is_success() {
+                return Err(format!("WebDAV PUT {} failed: {}", url, response.status()));
+            }
+        }
+
+        let assembled = transfer
+            .join(".file")
+            .map_err(|e| format!("Invalid upload URL: {}", e))?;
+        let request = self
+            .request(method("MOVE"), assembled.clone())
+            .header("Destination", dest.as_str())
+            .header("OC-Total-Length", size.to_string());
+        let response = with_precondition(request, precondition)
+            .send()
+            .map_err(|e| format!("WebDAV MOVE {} failed: {}", assembled, e))?;
+        put_outcome(&dest, response.status(), response.headers())
+    }
+}
+
+/// Sync a document through a WebDAV server.
+///
+/// Remote journals are mirrored into `mirror_root` (only those whose ETag
+/// changed are downloaded), then `sync_local` runs the folder sync engine on
+/// the mirror (`sync::sync_document` with `mirror_root` as the folder) to
+/// merge them and append our changes. Our journal is uploaded guarded by its
+/// ETag and the snapshot is published if the server lacks it or it was
+/// refreshed. Only `sync_local` touches the document, so callers can hold
+/// their lock on it for that step alone.
+///
+/// When the mirror doesn't have the server's current copy of our journal, e.g.
+/// after the cache was cleared, it is rebuilt from the server's copy first, so
+/// an upload never drops lines already published.
+pub fn sync_document(
+    client: &WebDavClient,
+    mirror_root: &Path,
+    pdf_hash: &str,
+    device_id: &str,
+    sync_local: impl FnOnce() -> Result<SyncReport, String>,
+) -> Result<SyncReport, String> {
+    let remote_dir = format!("{}/", pdf_hash);
+    let mirror = sync::document_dir(mirror_root, pdf_hash);
+    fs::create_dir_all(&mirror).map_err(|e| format!("Failed to create mirror dir: {}", e))?;
+
+    let etags_path = mirror.join(ETAGS_FILE);
+    let mut etags: HashMap<String, String> = fs::read_to_string(&etags_path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default();
+    let save_etags = |etags: &HashMap<String, String>| {
+        let json = serde_json::to_string(etags)
+            .map_err(|e| format!("Failed to serialize ETags: {}", e))?;
+        fs::write(&etags_path, json).map_err(|e| format!("Failed to write ETags: {}", e))
+    };
+
+    client.ensure_collection(&remote_dir)?;
+    let remote = client.list(&remote_dir)?;
+    let own_journal = format!("{}.jsonl", device_id);
+    let journal_path = mirror.join(&own_journal);
+
+    for resource in &remote {
+        if resource.is_collection
+            || !resource.name.ends_with(".jsonl")
+            || resource.name == own_journal
+        {
+            continue;
+        }
+        if resource.etag.is_some() && resource.etag.as_ref() == etags.get(&resource.name) {
+            continue;
+        }
+        let path = format!("{}{}", remote_dir, resource.name);
+        if let Some((bytes, etag)) =
+            client.get_if_changed(&path, etags.get(&resource.name).map(String::as_str))?
+        {
+            fs::write(mirror.join(&resource.name), bytes)
+                .map_err(|e| format!("Failed to mirror journal: {}", e))?;
+            if let Some(etag) = etag.or_else(|| resource.etag.clone()) {
+                etags.insert(resource.name.clone(), etag);
+            }
+        }
+    }
+
+    let remote_journal = remote.iter().find(|r| r.name == own_journal);
+    let mut unpublished = false;
+    if let Some(resource) = remote_journal {
+        let known = etags.get(&own_journal);
+        if !journal_path.exists() || known.is_none() || known != resource.etag.as_ref() {
+            let path = format!("{}{}", remote_dir, own_journal);
+            let (bytes, etag) = client
+                .get_if_changed(&path, None)?
+                .ok_or_else(|| format!("{} disappeared from the server during sync", path))?;
+            let etag = etag.or_else(|| resource.etag.clone()).ok_or_else(|| {
+                format!(
+                    "The server gave no ETag for {}, so it can't be updated safely",
+                    path
+                )
+            })?;
+            let local = fs::read(&journal_path).unwrap_or_default();
+            let merged = merge_journal(&bytes, &local);
+            unpublished = merged != bytes;
+            fs::write(&journal_path, merged)
+                .map_err(|e| format!("Failed to mirror journal: {}", e))?;
+            etags.insert(own_journal.clone(), etag);
+            save_etags(&etags)?;
+        }
+    }
+
+    let report = sync_local()?;
+
+    // Our journal is only ever written by us, so a changed ETag means another
+    // client is using the same device id (or the server copy was edited).
+    if journal_path.exists() && (report.pushed > 0 || unpublished || remote_journal.is_none()) {
+        let precondition = match remote_journal {
+            // Its ETag was recorded above if it wasn't known already
+            Some(_) => Precondition::IfMatch(
+                etags
+                    .get(&own_journal)
+                    .cloned()
+                    .ok_or("No ETag is known for this device's journal")?,
+            ),
+            None => Precondition::IfNoneMatch,
+        };
+        match client.upload_file(
+            &journal_path,
+            &format!("{}{}", remote_dir, own_journal),
+            &precondition,
+        )? {
+            PutOutcome::Stored(Some(etag)) => {
+                etags.insert(own_journal.clone(), etag);
+            }
+            // Without an ETag the next sync fetches the server's copy first
+            PutOutcome::Stored(None) => {
+                etags.remove(&own_journal);
+            }
+            PutOutcome::Conflict => {
+                // The next sync merges our journal into the server's copy
+                etags.remove(&own_journal);
+                save_etags(&etags)?;
+                return Err(format!(
+                    "The server's copy of this device's journal ({}) changed during sync; sync again to merge it",
+                    own_journal
+                ));
+            }
+        }
+    }
+
+    let remote_has_snapshot = remote.iter().any(|r| r.name == SNAPSHOT_NAME);
+    if report.snapshot_updated || !remote_has_snapshot {
+        let snapshot = mirror.join(SNAPSHOT_NAME);
+        // A snapshot is only a starting point for new devices, so a newer one
+        // simply replaces it. Without one, a conflict means another device
+        // published first, which is fine.
+        let precondition = if remote_has_snapshot {
+            Precondition::None
+        } else {
+            Precondition::IfNoneMatch
+        };
+        client.upload_file(
+            &snapshot,
+            &format!("{}{}", remote_dir, SNAPSHOT_NAME),
+            &precondition,
+        )?;
+    }
+
+    save_etags(&etags)?;
+    Ok(report)
+}
+
+/// Our journal rebuilt on top of the server's copy: `remote` followed by the
+/// lines of `local` it doesn't have. Peers track journals by line count, so
+/// what they have read must stay in place.
+fn merge_journal(remote: &[u8], local: &[u8]) -> Vec<u8> {
+    if local.starts_with(remote) {
+        return local.to_vec();
+    }
+    let remote_text = String::from_utf8_lossy(remote);
+    let published: std::collections::HashSet<&str> = remote_text.lines().collect();
+    let mut merged = remote.to_vec();
+    if !merged.is_empty() && !merged.ends_with(b"\n") {
+        merged.push(b'\n');
+    }
+    for line in String::from_utf8_lossy(local).lines() {
+        if !line.trim().is_empty() && !published.contains(line) {
+            merged.extend_from_slice(line.as_bytes());
+            merged.push(b'\n');
+        }
+    }
+    merged
+}
+
+/// PDF hashes of the documents published on the server.
+pub fn list_remote_documents(client: &WebDavClient) -> Result<Vec<String>, String> {
+    Ok(client
+        .list("")?
+        .into_iter()
+        .filter(|r| r.is_collection && r.name.len() == 64)
+        .filter(|r| r.name.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|r| r.name)
+        .collect())
+}
+
+/// Download a published document's snapshot to `dest`.
+pub fn download_document(client: &WebDavClient, pdf_hash: &str, dest: &Path) -> Result<(), String> {
+    client.download(&format!("{}/{}", pdf_hash, SNAPSHOT_NAME), dest)
+}
+
+fn method(name: &str) -> Method {
+    Method::from_bytes(name.as_bytes()).expect("valid HTTP method")
+}
+
+/// Parse a URL, making sure it ends with '/' so relative joins stay inside it.
+fn collection_url(url: &str) -> Result<Url, String> {
+    let mut url = Url::parse(url).map_err(|e| format!("Invalid WebDAV URL '{}': {}", url, e))?;
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    Ok(url)
+}
+
+fn upload_timeout(size: u64) -> Duration {
+    READ_TIMEOUT + Duration::from_secs(size / MIN_UPLOAD_RATE)
+}
+
+fn with_precondition(request: RequestBuilder, precondition: &Precondition) -> RequestBuilder {
+    match precondition {
+        Precondition::None => request,
+        Precondition::IfMatch(etag) => request.header("If-Match", etag),
+        Precondition::IfNoneMatch => request.header("If-None-Match", "*"),
+    }
+}
+
+fn put_outcome(url: &Url, status: StatusCode, headers: &HeaderMap) -> Result<PutOutcome, String> {
+    match status {
+        StatusCode::PRECONDITION_FAILED => Ok(PutOutcome::Conflict),
+        s if s.is_success() => Ok(PutOutcome::Stored(etag_of(headers))),
+        s => Err(format!("WebDAV upload to {} failed: {}", url, s)),
+    }
+}
+
+fn etag_of(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)
+        .or_else(|| headers.get("OC-ETag"))
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn percent_decode(s: &str) -> String {
+    percent_encoding::percent_decode_str(s)
+        .decode_utf8_lossy()
+        .to_string()
+}
+
+/// Parse a PROPFIND multistatus body into (href, etag, is_collection).
+fn parse_multistatus(xml: &str) -> Result<Vec<(String, Option<String>, bool)>, String> {
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut results = Vec::new();
+    let mut href: Option<String> = None;
+    let mut etag: Option<String> = None;
+    let mut is_collection = false;
+    let mut current_text: Option<&'static str> = None;
+
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| format!("Invalid PROPFIND response: {}", e))?;
+        match event {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"response" => {
+                    href = None;
+                    etag = None;
+                    is_collection = false;
+                }
+                b"href" => current_text = Some("href"),
+                b"getetag" => current_text = Some("getetag"),
+                b"collection" => is_collection = true,
+                _ => {}
+            },
+            Event::Empty(e) if e.local_name().as_ref() == b"collection" => {
+                is_collection = true;
+            }
+            Event::Text(t) => {
+                let text = t
+                    .xml_content()
+                    .map_err(|e| format!("Invalid PROPFIND response: {}", e))?
+                    .to_string();
+                match current_text {
+                    Some("href") => href.get_or_insert_with(String::new).push_str(&text),
+                    Some("getetag") => etag.get_or_insert_with(String::new).push_str(&text),
+                    _ => {}
+                }
+            }
+            Event::GeneralRef(r) => {
+                let resolved = r
+                    .resolve_char_ref()
+                    .ok()
+                    .flatten()
+                    .map(|c| c.to_string())
+                    .or_else(|| {
+                        r.decode().ok().and_then(|name| {
+                            quick_xml::escape::resolve_predefined_entity(&name).map(str::to_string)
+                        })
+                    })
+                    .unwrap_or_default();
+                match current_text {
+                    Some("href") => href.get_or_insert_with(String::new).push_str(&resolved),
+                    Some("getetag") => etag.get_or_insert_with(String::new).push_str(&resolved),
+                    _ => {}
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"href" | b"getetag" => current_text = None,
+                b"response" => {
+                    if let Some(href) = href.take() {
+                        results.push((href, etag.take(), is_collection));
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+    use crate::rr_file::RrSession;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+
+    type Files = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// In-memory WebDAV server on 127.0.0.1 with just enough of PROPFIND,
+    /// MKCOL, GET and conditional PUT for sync.
+    struct TestServer {
+        server: Arc<tiny_http::Server>,
+        thread: Option<JoinHandle<()>>,
+        url: String,
+        files: Files,
+        /// Paths of every PUT received
+        puts: Arc<Mutex<Vec<String>>>,
+        /// Appended to the target of the next If-Match PUT before its
+        /// precondition is checked, like a write racing ours
+        interfere: Arc<Mutex<Option<Vec<u8>>>>,
+    }
+
+    impl TestServer {
+        fn start() -> Self {
+            let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+            let port = server.server_addr().to_ip().unwrap().port();
+            let files: Files = Arc::default();
+            let puts: Arc<Mutex<Vec<String>>> = Arc::default();
+            let interfere: Arc<Mutex<Option<Vec<u8>>>> = Arc::default();
+            let collections = Arc::new(Mutex::new(HashSet::from(["/dav/".to_string()])));
+
+            let thread = {
+                let (server, files, puts, interfere) = (
+                    server.clone(),
+                    files.clone(),
+                    puts.clone(),
+                    interfere.clone(),
+                );
+                std::thread::spawn(move || {
+                    for mut request in server.incoming_requests() {
+                        let path = request.url().to_string();
+                        let header = |name: &'static str| {
+                            request
+                                .headers()
+                                .iter()
+                                .find(|h| h.field.equiv(name))
+                                .map(|h| h.value.as_str().to_string())
+                        };
+                        let (if_match, if_none_match) =
+                            (header("If-Match"), header("If-None-Match"));
+                        let mut body = Vec::new();
+                        request.as_reader().read_to_end(&mut body).unwrap();
+
+                        let mut files = files.lock().unwrap();
+                        let mut collections = collections.lock().unwrap();
+                        let (status, etag, data) = match request.method().as_str() {
+                            "MKCOL" if collections.contains(&path) => (405, None, Vec::new()),
+                            "MKCOL" => {
+                                collections.insert(path);
+                                (201, None, Vec::new())
+                            }
+                            "PROPFIND" if !collections.contains(&path) => (404, None, Vec::new()),
+                            "PROPFIND" => (
+                                207,
+                                None,
+                                multistatus(&path, &files, &collections).into_bytes(),
+                            ),
+                            "GET" => match files.get(&path) {
+                                None => (404, None, Vec::new()),
+                                Some(data) if if_none_match == Some(etag(data)) => {
+                                    (304, None, Vec::new())
+                                }
+                                Some(data) => (200, Some(etag(data)), data.clone()),
+                            },
+                            "PUT" => {
+                                puts.lock().unwrap().push(path.clone());
+                                if if_match.is_some() {
+                                    if let Some(extra) = interfere.lock().unwrap().take() {
+                                        files.entry(path.clone()).or_default().extend(extra);
+                                    }
+                                }
+                                let current = files.get(&path).map(|d| etag(d));
+                                let rejected = match (&if_match, &if_none_match) {
+                                    (Some(expected), _) => current.as_ref() != Some(expected),
+                                    (_, Some(_)) => current.is_some(),
+                                    _ => false,
+                                };
+                                if rejected {
+                                    (412, None, Vec::new())
+                                } else {
+                                    let tag = etag(&body);
+                                    files.insert(path, body);
+                                    (201, Some(tag), Vec::new())
+                                }
+                            }
+                            _ => (405, None, Vec::new()),
+                        };
+                        let mut response =
+                            tiny_http::Response::from_data(data).with_status_code(status);
+                        if let Some(etag) = etag {
+                            response = response.with_header(
+                                tiny_http::Header::from_bytes("ETag", etag.as_bytes()).unwrap(),
+                            );
+                        }
+                        let _ = request.respond(response);
+                    }
+                })
+            };
+
+            TestServer {
+                server,
+                thread: Some(thread),
+                url: format!("http://127.0.0.1:{}/dav/", port),
+                files,
+                puts,
+                interfere,
+            }
+        }
+
+        fn client(&self) -> WebDavClient {
+            WebDavClient::new(&self.url, "reader", "secret", None).unwrap()
+        }
+
+        fn file(&self, hash: &str, name: &str) -> Option<Vec<u8>> {
+            let path = format!("/dav/{}/{}", hash, name);
+            self.files.lock().unwrap().get(&path).cloned()
+        }
+
+        fn journal(&self, hash: &str, device: &str) -> String {
+            String::from_utf8(self.file(hash, &format!("{}.jsonl", device)).unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.server.unblock();
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn etag(data: &[u8]) -> String {
+        format!("\"{}\"", hex::encode(&Sha256::digest(data)[..8]))
+    }
+
+    fn multistatus(
+        dir: &str,
+        files: &HashMap<String, Vec<u8>>,
+        collections: &HashSet<String>,
+    ) -> String {
+        let response = |href: &str, etag: Option<String>, collection: bool| {
+            format!(
+                "<d:response><d:href>{}</d:href><d:propstat><d:prop>{}<d:resourcetype>{}</d:resourcetype></d:prop></d:propstat></d:response>",
+                href,
+                etag.map(|e| format!("<d:getetag>{}</d:getetag>", e)).unwrap_or_default(),
+                if collection { "<d:collection/>" } else { "" },
+            )
+        };
+        let is_child = |path: &str| {
+            path.strip_prefix(dir)
+                .is_some_and(|rest| !rest.is_empty() && !rest.trim_end_matches('/').contains('/'))
+        };
+        let mut body = String::from(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">"#);
+        body.push_str(&response(dir, None, true));
+        for path in collections.iter().filter(|p| is_child(p)) {
+            body.push_str(&response(path, None, true));
+        }
+        for (path, data) in files.iter().filter(|(p, _)| is_child(p)) {
+            body.push_str(&response(path, Some(etag(data)), false));
+        }
+        body.push_str("</d:multistatus>");
+        body
+    }
+
+    /// A document on one device, with its own WebDAV mirror
+    struct Device {
+        id: &'static str,
+        session: RrSession,
+        mirror: PathBuf,
+    }
+
+    impl Device {
+        fn new(dir: &Path, id: &'static str, session: RrSession) -> Self {
+            Device {
+                id,
+                session,
+                mirror: dir.join(format!("{}-cache", id)),
+            }
+        }
+
+        fn sync(&self, client: &WebDavClient) -> Result<SyncReport, String> {
+            let hash = rr_file::document_hash(&self.session)?;
+            sync_document(client, &self.mirror, &hash, self.id, || {
+                sync::sync_document(&self.session, &self.mirror, self.id)
+            })
+        }
+
+        fn note(&self, content: &str) -> Annotation {
+            let annotation = database::create_annotation(
+                &self.session.db,
+                &CreateAnnotationInput {
+                    annotation_type: AnnotationType::Note,
+                    page_number: 1,
+                    color: None,
+                    content: Some(content.to_string()),
+                    position_data: None,
+                },
+            )
+            .unwrap();
+            crate::crdt::record(&self.session.db, &annotation.id).unwrap();
+            annotation
+        }
+
+        fn content(&self, id: &str) -> Option<String> {
+            database::get_annotation(&self.session.db, id)
+                .unwrap()
+                .and_then(|a| a.content)
+        }
+    }
+
+    fn first_device(dir: &Path) -> Device {
+        let pdf = dir.join("paper.pdf");
+        fs::write(&pdf, b"%PDF-1.4 fake").unwrap();
+        let session = rr_file::import_pdf(&pdf, Some(&dir.join("a.rr"))).unwrap();
+        Device::new(dir, "device-a", session)
+    }
+
+    /// A second device that starts from the published snapshot
+    fn second_device(dir: &Path, client: &WebDavClient, hash: &str) -> Device {
+        let path = dir.join("b.rr");
+        download_document(client, hash, &path).unwrap();
+        Device::new(dir, "device-b", rr_file::open_rr(&path).unwrap())
+    }
+
+    #[test]
+    fn first_push_publishes_journal_and_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = TestServer::start();
+        let client = server.client();
+        let a = first_device(dir.path());
+        let note = a.note("first");
+
+        let report = a.sync(&client).unwrap();
+        assert!(report.pushed > 0);
+        let hash = report.pdf_sha256;
+        assert!(server.journal(&hash, "device-a").contains(&note.id));
+        assert!(server.file(&hash, SNAPSHOT_NAME).is_some());
+        assert_eq!(list_remote_documents(&client).unwrap(), vec![hash.clone()]);
+
+        // Nothing new: nothing is uploaded
+        let puts = server.puts.lock().unwrap().len();
+        let report = a.sync(&client).unwrap();
+        assert_eq!(report.pushed, 0);
+        assert_eq!(server.puts.lock().unwrap().len(), puts);
+    }
+
+    #[test]
+    fn second_device_pulls_and_pushes() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = TestServer::start();
+        let client = server.client();
+        let a = first_device(dir.path());
+        let first = a.note("first");
+        let hash = a.sync(&client).unwrap().pdf_sha256;
+
+        let b = second_device(dir.path(), &client, &hash);
+        assert_eq!(b.content(&first.id).as_deref(), Some("first"));
+        b.sync(&client).unwrap();
+
+        let second = a.note("second");
+        a.sync(&client).unwrap();
+        let report = b.sync(&client).unwrap();
+        assert!(report.applied > 0);
+        assert_eq!(b.content(&second.id).as_deref(), Some("second"));
+
+        let third = b.note("third");
+        b.sync(&client).unwrap();
+        a.sync(&client).unwrap();
+        assert_eq!(a.content(&third.id).as_deref(), Some("third"));
+    }
+
+    #[test]
+    fn journal_changed_during_sync_is_a_conflict_then_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = TestServer::start();
+        let client = server.client();
+        let a = first_device(dir.path());
+        a.note("first");
+        let hash = a.sync(&client).unwrap().pdf_sha256;
+        let published = server.journal(&hash, "device-a");
+
+        let foreign = "{\"written\":\"elsewhere\"}\n";
+        *server.interfere.lock().unwrap() = Some(foreign.as_bytes().to_vec());
+        let second = a.note("second");
+        let error = a.sync(&client).unwrap_err();
+        assert!(error.contains("changed during sync"), "{}", error);
+        let journal = server.journal(&hash, "device-a");
+        assert_eq!(journal, format!("{}{}", published, foreign));
+
+        // The next sync keeps the foreign line and adds ours after it
+        a.sync(&client).unwrap();
+        let journal = server.journal(&hash, "device-a");
+        assert!(journal.starts_with(&format!("{}{}", published, foreign)));
+        assert!(journal.contains(&second.id));
+    }
+
+    #[test]
+    fn cleared_cache_keeps_published_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = TestServer::start();
+        let client = server.client();
+        let a = first_device(dir.path());
+        let first = a.note("first");
+        let hash = a.sync(&client).unwrap().pdf_sha256;
+        let published = server.journal(&hash, "device-a");
+
+        fs::remove_dir_all(&a.mirror).unwrap();
+        let second = a.note("second");
+        a.sync(&client).unwrap();
+        let journal = server.journal(&hash, "device-a");
+        assert!(journal.starts_with(&published));
+        assert!(journal.contains(&second.id));
+
+        // A device starting now still gets everything
+        let b = second_device(dir.path(), &client, &hash);
+        b.sync(&client).unwrap();
+        assert_eq!(b.content(&first.id).as_deref(), Some("first"));
+        assert_eq!(b.content(&second.id).as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn merge_journal_keeps_the_servers_lines_first() {
+        assert_eq!(merge_journal(b"a\nb\n", b"a\nb\nc\n"), b"a\nb\nc\n");
+        assert_eq!(merge_journal(b"a\nx\n", b"a\nb\nc\n"), b"a\nx\nb\nc\n");
+        assert_eq!(merge_journal(b"a", b"b\n"), b"a\nb\n");
+        assert_eq!(merge_journal(b"", b"a\n"), b"a\n");
+    }
+
+    #[test]
+    fn uploads_get_more_time_as_they_grow() {
+        assert_eq!(upload_timeout(0), READ_TIMEOUT);
+        assert!(upload_timeout(100 * 1024 * 1024) > upload_timeout(CHUNK_SIZE));
+    }
+
+    #[test]
+    fn parses_multistatus() {
+        let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:">
+  <d:response><d:href>/dav/abc/</d:href><d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat></d:response>
+  <d:response><d:href>/dav/abc/device%20one.jsonl</d:href><d:propstat><d:prop><d:getetag>&quot;12&amp;34&quot;</d:getetag><d:resourcetype/></d:prop></d:propstat></d:response>
+</d:multistatus>"#;
+        assert_eq!(
+            parse_multistatus(xml).unwrap(),
+            vec![
+                ("/dav/abc/".to_string(), None, true),
+                (
+                    "/dav/abc/device%20one.jsonl".to_string(),
+                    Some("\"12&34\"".to_string()),
+                    false
+                ),
+            ]
+        );
+    }
+}
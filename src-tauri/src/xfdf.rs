@@ -0,0 +1,613 @@
+use quick_xml::escape::{escape, resolve_predefined_entity};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+use crate::models::*;
+
+/// Page size assumed when the caller doesn't know a page's dimensions (US Letter, points).
+const FALLBACK_PAGE_SIZE: PageSize = PageSize {
+    width: 612.0,
+    height: 792.0,
+};
+
+/// Size of the icon rect written for sticky notes, which we store as a single point.
+const NOTE_ICON_SIZE: f64 = 20.0;
+
+/// Serialize annotations to an XFDF document.
+///
+/// XFDF uses PDF user space (origin at the bottom-left of the page, 0-based page
+/// indices), so rects are flipped using the page height stored in `position_data`.
+/// Bookmarks have no XFDF equivalent and are written as `text` annotations with a
+/// "Bookmark" subject so they survive a round-trip.
+pub fn to_xfdf(annotations: &[Annotation], pdf_name: Option<&str>) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<xfdf xmlns=\"http://ns.adobe.com/xfdf/\" xml:space=\"preserve\">\n");
+    out.push_str("  <annots>\n");
+
+    for annotation in annotations {
+        write_annotation(&mut out, annotation);
+    }
+
+    out.push_str("  </annots>\n");
+    if let Some(name) = pdf_name {
+        out.push_str(&format!("  <f href=\"{}\"/>\n", escape(name)));
+    }
+    out.push_str("</xfdf>\n");
+    out
+}
+
+fn write_annotation(out: &mut String, annotation: &Annotation) {
+    let page_height = annotation
+        .position_data
+        .as_ref()
+        .map(|pd| pd.page_height)
+        .unwrap_or(FALLBACK_PAGE_SIZE.height);
+    let rects: &[Rect] = annotation
+        .position_data
+        .as_ref()
+        .map(|pd| pd.rects.as_slice())
+        .unwrap_or(&[]);
+
+    let (element, subject) = match annotation.annotation_type {
+        AnnotationType::Highlight => ("highlight", "Highlight"),
+        AnnotationType::Note => ("text", "Comment"),
+        AnnotationType::Bookmark => ("text", "Bookmark"),
+    };
+
+    let rect = match annotation.annotation_type {
+        AnnotationType::Highlight => bounding_rect(rects, page_height),
+        _ => note_rect(rects.first(), page_height),
+    };
+
+    out.push_str(&format!(
+        "    <{} page=\"{}\" rect=\"{}\" name=\"{}\" subject=\"{}\" creationdate=\"{}\" date=\"{}\"",
+        element,
+        annotation.page_number.saturating_sub(1),
+        format_numbers(&rect),
+        escape(&annotation.id),
+        subject,
+        to_pdf_date(&annotation.created_at),
+        to_pdf_date(&annotation.updated_at),
+    ));
+    if let Some(color) = &annotation.color {
+        out.push_str(&format!(" color=\"{}\"", escape(color.to_uppercase())));
+    }
+    if matches!(annotation.annotation_type, AnnotationType::Highlight) {
+        let coords: Vec<f64> = rects
+            .iter()
+            .flat_map(|r| quad_points(r, page_height))
+            .collect();
+        out.push_str(&format!(" coords=\"{}\"", format_numbers(&coords)));
+    } else {
+        out.push_str(" icon=\"Comment\"");
+    }
+    out.push('>');
+
+    if let Some(content) = &annotation.content {
+        out.push_str(&format!("<contents>{}</contents>", escape(content)));
+    }
+    out.push_str(&format!("</{}>\n", element));
+}
+
+/// Parse annotations from an XFDF document.
+///
+/// `page_sizes` holds the dimensions of each page in order (index 0 is page 1) and
+/// is used to convert PDF user-space coordinates back to our top-left origin.
+/// Elements other than `highlight` and `text` are skipped.
+pub fn from_xfdf(xml: &str, page_sizes: &[PageSize]) -> Result<Vec<Annotation>, String> {
+    let mut reader = Reader::from_str(xml);
+    let mut annotations = Vec::new();
+
+    // The annotation element currently being read, and its <contents> text
+    let mut current: Option<(BytesStart<'static>, Option<String>)> = None;
+    let mut in_contents = false;
+
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| format!("Invalid XFDF at byte {}: {}", reader.error_position(), e))?;
+        match event {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"highlight" | b"text" => current = Some((e.into_owned(), None)),
+                b"contents" if current.is_some() => in_contents = true,
+                _ => {}
+            },
+            Event::Empty(e) => {
+                if matches!(e.local_name().as_ref(), b"highlight" | b"text") {
+                    annotations.extend(parse_annotation(&e, None, page_sizes)?);
+                }
+            }
+            Event::Text(t) if in_contents => {
+                let text = t
+                    .xml_content()
+                    .map_err(|e| format!("Invalid XFDF contents: {}", e))?;
+                push_contents(&mut current, &text);
+            }
+            Event::GeneralRef(r) if in_contents => {
+                let text = match r
+                    .resolve_char_ref()
+                    .map_err(|e| format!("Invalid XFDF character reference: {}", e))?
+                {
+                    Some(ch) => ch.to_string(),
+                    None => {
+                        let name = r
+                            .decode()
+                            .map_err(|e| format!("Invalid XFDF entity: {}", e))?;
+                        resolve_predefined_entity(&name)
+                            .ok_or_else(|| format!("Unknown XFDF entity: &{};", name))?
+                            .to_string()
+                    }
+                };
+                push_contents(&mut current, &text);
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"contents" => in_contents = false,
+                b"highlight" | b"text" => {
+                    if let Some((start, contents)) = current.take() {
+                        annotations.extend(parse_annotation(&start, contents, page_sizes)?);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(annotations)
+}
+
+fn push_contents(current: &mut Option<(BytesStart<'static>, Option<String>)>, text: &str) {
+    if let Some((_, contents)) = current {
+        contents.get_or_insert_with(String::new).push_str(text);
+    }
+}
+
+fn parse_annotation(
+    element: &BytesStart,
+    content: Option<String>,
+    page_sizes: &[PageSize],
+) -> Result<Option<Annotation>, String> {
+    let attr = |name: &str| -> Result<Option<String>, String> {
+        element
+            .try_get_attribute(name)
+            .map_err(|e| format!("Invalid XFDF attribute '{}': {}", name, e))?
+            .map(|a| {
+                a.unescape_value()
+                    .map(|v| v.into_owned())
+                    .map_err(|e| format!("Invalid XFDF attribute '{}': {}", name, e))
+            })
+            .transpose()
+    };
+
+    let page_index: u32 = attr("page")?
+        .ok_or("XFDF annotation is missing its page attribute")?
+        .trim()
+        .parse()
+        .map_err(|e| format!("Invalid XFDF page index: {}", e))?;
+    let Some(page_number) = page_index.checked_add(1) else {
+        log::warn!("[xfdf] Skipping annotation on page index {}", page_index);
+        return Ok(None);
+    };
+    let page_size = page_sizes
+        .get(page_index as usize)
+        .copied()
+        .unwrap_or(FALLBACK_PAGE_SIZE);
+
+    let is_highlight = element.local_name().as_ref() == b"highlight";
+    let annotation_type = if is_highlight {
+        AnnotationType::Highlight
+    } else if attr("subject")?.as_deref() == Some("Bookmark") {
+        AnnotationType::Bookmark
+    } else {
+        AnnotationType::Note
+    };
+
+    let rect = parse_numbers(&attr("rect")?.unwrap_or_default())?;
+    let rects = if is_highlight {
+        let coords = parse_numbers(&attr("coords")?.unwrap_or_default())?;
+        let quads: Vec<Rect> = coords
+            .chunks_exact(8)
+            .map(|quad| rect_from_quad(quad, page_size.height))
+            .collect();
+        if quads.is_empty() && rect.len() == 4 {
+            vec![rect_from_bounds(&rect, page_size.height)]
+        } else {
+            quads
+        }
+    } else if rect.len() == 4 {
+        // Notes are anchored at the top-left corner of their icon
+        vec![Rect {
+            x: rect[0],
+            y: page_size.height - rect[3],
+            width: 0.0,
+            height: 0.0,
+        }]
+    } else {
+        Vec::new()
+    };
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let updated_at = attr("date")?
+        .and_then(|d| from_pdf_date(&d))
+        .unwrap_or_else(|| now.clone());
+    let created_at = attr("creationdate")?
+        .and_then(|d| from_pdf_date(&d))
+        .unwrap_or_else(|| updated_at.clone());
+
+    let position_data = if rects.is_empty() {
+        None
+    } else {
+        Some(PositionData {
+            rects,
+            page_width: page_size.width,
+            page_height: page_size.height,
+            selected_text: None,
+            start_offset: None,
+            end_offset: None,
+        })
+    };
+
+    Ok(Some(Annotation {
+        id: attr("name")?
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        annotation_type,
+        page_number,
+        page_label: None,
+        color: attr("color")?.map(|c| c.to_lowercase()),
+        content,
+        position_data,
+        created_at,
+        updated_at,
+    }))
+}
+
+/// Bounding box of all rects as `x1,y1,x2,y2` in PDF user space.
+fn bounding_rect(rects: &[Rect], page_height: f64) -> Vec<f64> {
+    if rects.is_empty() {
+        return vec![0.0, 0.0, 0.0, 0.0];
+    }
+    let x1 = rects.iter().map(|r| r.x).fold(f64::INFINITY, f64::min);
+    let x2 = rects
+        .iter()
+        .map(|r| r.x + r.width)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let top = rects.iter().map(|r| r.y).fold(f64::INFINITY, f64::min);
+    let bottom = rects
+        .iter()
+        .map(|r| r.y + r.height)
+        .fold(f64::NEG_INFINITY, f64::max);
+    vec![x1, page_height - bottom, x2, page_height - top]
+}
+
+/// Icon rect for a note, hanging down and to the right from its anchor point.
+fn note_rect(anchor: Option<&Rect>, page_height: f64) -> Vec<f64> {
+    let (x, y) = anchor.map(|r| (r.x, r.y)).unwrap_or((0.0, 0.0));
+    let top = page_height - y;
+    vec![x, top - NOTE_ICON_SIZE, x + NOTE_ICON_SIZE, top]
+}
+
+/// Quad points in Acrobat order: top-left, top-right, bottom-left, bottom-right.
+fn quad_points(rect: &Rect, page_height: f64) -> [f64; 8] {
+    let left = rect.x;
+    let right = rect.x + rect.width;
+    let top = page_height - rect.y;
+    let bottom = page_height - (rect.y + rect.height);
+    [left, top, right, top, left, bottom, right, bottom]
+}
+
+fn rect_from_quad(quad: &[f64], page_height: f64) -> Rect {
+    let xs = [quad[0], quad[2], quad[4], quad[6]];
+    let ys = [quad[1], quad[3], quad[5], quad[7]];
+    let left = xs.iter().copied().fold(f64::INFINITY, f64::min);
+    let right = xs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let bottom = ys.iter().copied().fold(f64::INFINITY, f64::min);
+    let top = ys.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    Rect {
+        x: left,
+        y: page_height - top,
+        width: right - left,
+        height: top - bottom,
+    }
+}
+
+fn rect_from_bounds(bounds: &[f64], page_height: f64) -> Rect {
+    rect_from_quad(
+        &[
+            bounds[0], bounds[3], bounds[2], bounds[3], bounds[0], bounds[1], bounds[2], bounds[1],
+        ],
+        page_height,
+    )
+}
+
+fn format_numbers(values: &[f64]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_numbers(s: &str) -> Result<Vec<f64>, String> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            part.parse::<f64>()
+                .map_err(|e| format!("Invalid XFDF number '{}': {}", part, e))
+        })
+        .collect()
+}
+
+/// Convert an RFC 3339 timestamp to a PDF date string (`D:YYYYMMDDHHmmSS+HH'mm'`).
+fn to_pdf_date(rfc3339: &str) -> String {
+    match chrono::DateTime::parse_from_rfc3339(rfc3339) {
+        Ok(dt) => {
+            let offset = dt.offset().local_minus_utc();
+            let sign = if offset < 0 { '-' } else { '+' };
+            let offset = offset.abs();
+            format!(
+                "D:{}{}{:02}'{:02}'",
+                dt.format("%Y%m%d%H%M%S"),
+                sign,
+                offset / 3600,
+                (offset % 3600) / 60
+            )
+        }
+        Err(_) => String::new(),
+    }
+}
+
+/// Convert a PDF date string back to RFC 3339. Missing time fields default to zero.
+pub(crate) fn from_pdf_date(pdf_date: &str) -> Option<String> {
+    let s = pdf_date.trim().trim_start_matches("D:");
+    let digits: String = s.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() < 4 {
+        return None;
+    }
+    let field = |start: usize, len: usize, default: u32| -> u32 {
+        digits
+            .get(start..start + len)
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default)
+    };
+    let date =
+        chrono::NaiveDate::from_ymd_opt(field(0, 4, 0) as i32, field(4, 2, 1), field(6, 2, 1))?;
+    let time = chrono::NaiveTime::from_hms_opt(field(8, 2, 0), field(10, 2, 0), field(12, 2, 0))?;
+
+    let rest = &s[digits.len()..];
+    let offset_secs = match rest.chars().next() {
+        Some(sign @ ('+' | '-')) => {
+            let tz: String = rest[1..].chars().filter(|c| c.is_ascii_digit()).collect();
+            let hours: i32 = tz.get(0..2).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let minutes: i32 = tz.get(2..4).and_then(|v| v.parse().ok()).unwrap_or(0);
+            let secs = hours * 3600 + minutes * 60;
+            if sign == '-' {
+                -secs
+            } else {
+                secs
+            }
+        }
+        _ => 0,
+    };
+    let offset = chrono::FixedOffset::east_opt(offset_secs)?;
+    let dt = date.and_time(time).and_local_timezone(offset).single()?;
+    Some(dt.to_rfc3339())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LETTER: PageSize = PageSize {
+        width: 612.0,
+        height: 792.0,
+    };
+    const A4: PageSize = PageSize {
+        width: 595.0,
+        height: 842.0,
+    };
+
+    fn annotation(
+        id: &str,
+        annotation_type: AnnotationType,
+        page_number: u32,
+        page: PageSize,
+        rects: Vec<Rect>,
+    ) -> Annotation {
+        Annotation {
+            id: id.to_string(),
+            annotation_type,
+            page_number,
+            page_label: None,
+            color: Some("#ffeb3b".to_string()),
+            content: None,
+            position_data: Some(PositionData {
+                rects,
+                page_width: page.width,
+                page_height: page.height,
+                selected_text: None,
+                start_offset: None,
+                end_offset: None,
+            }),
+            created_at: "2024-03-01T12:34:56+00:00".to_string(),
+            updated_at: "2024-03-02T08:00:00+01:00".to_string(),
+        }
+    }
+
+    fn rect(x: f64, y: f64, width: f64, height: f64) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn round_trip(annotations: &[Annotation], page_sizes: &[PageSize]) -> Vec<Annotation> {
+        from_xfdf(&to_xfdf(annotations, Some("paper.pdf")), page_sizes).unwrap()
+    }
+
+    #[test]
+    fn highlight_round_trip() {
+        let mut highlight = annotation(
+            "h1",
+            AnnotationType::Highlight,
+            2,
+            A4,
+            vec![
+                rect(72.0, 100.5, 300.0, 12.0),
+                rect(72.0, 114.0, 120.25, 12.0),
+            ],
+        );
+        highlight.content = Some("contrastive loss".to_string());
+
+        let imported = round_trip(&[highlight.clone()], &[LETTER, A4]);
+        assert_eq!(imported, vec![highlight]);
+    }
+
+    #[test]
+    fn note_and_bookmark_round_trip() {
+        let mut note = annotation(
+            "n1",
+            AnnotationType::Note,
+            1,
+            LETTER,
+            vec![rect(40.0, 60.0, 0.0, 0.0)],
+        );
+        note.content = Some("Check eq. 3".to_string());
+        let mut bookmark = annotation(
+            "b1",
+            AnnotationType::Bookmark,
+            1,
+            LETTER,
+            vec![rect(0.0, 0.0, 0.0, 0.0)],
+        );
+        bookmark.color = None;
+
+        let imported = round_trip(&[note.clone(), bookmark.clone()], &[LETTER]);
+        assert_eq!(imported, vec![note, bookmark]);
+    }
+
+    #[test]
+    fn colours_are_written_upper_case_and_read_lower_case() {
+        let mut highlight = annotation(
+            "h1",
+            AnnotationType::Highlight,
+            1,
+            LETTER,
+            vec![rect(10.0, 10.0, 10.0, 10.0)],
+        );
+        highlight.color = Some("#A1b2C3".to_string());
+
+        let xml = to_xfdf(&[highlight], None);
+        assert!(xml.contains("color=\"#A1B2C3\""), "{}", xml);
+        let imported = from_xfdf(&xml, &[LETTER]).unwrap();
+        assert_eq!(imported[0].color.as_deref(), Some("#a1b2c3"));
+    }
+
+    #[test]
+    fn exported_coordinates_use_pdf_user_space() {
+        let highlight = annotation(
+            "h1",
+            AnnotationType::Highlight,
+            3,
+            LETTER,
+            vec![rect(72.0, 100.0, 300.0, 12.0)],
+        );
+        let note = annotation(
+            "n1",
+            AnnotationType::Note,
+            3,
+            LETTER,
+            vec![rect(40.0, 60.0, 0.0, 0.0)],
+        );
+
+        let xml = to_xfdf(&[highlight, note], None);
+        // 0-based page index, origin at the bottom-left
+        assert!(xml.contains("page=\"2\""), "{}", xml);
+        assert!(xml.contains("rect=\"72,680,372,692\""), "{}", xml);
+        assert!(
+            xml.contains("coords=\"72,692,372,692,72,680,372,680\""),
+            "{}",
+            xml
+        );
+        assert!(xml.contains("rect=\"40,712,60,732\""), "{}", xml);
+    }
+
+    #[test]
+    fn imported_coordinates_use_the_page_size() {
+        let xml = r##"<?xml version="1.0" encoding="UTF-8"?>
+<xfdf xmlns="http://ns.adobe.com/xfdf/" xml:space="preserve"><annots>
+  <highlight page="1" rect="50,700,250,712" coords="50,712,250,712,50,700,250,700" name="a" color="#FF0000"/>
+  <highlight page="0" rect="100,500,200,520" name="b"/>
+  <text page="1" rect="30,800,50,820" name="c"/>
+</annots></xfdf>"##;
+        let imported = from_xfdf(xml, &[LETTER, A4]).unwrap();
+        assert_eq!(imported.len(), 3);
+
+        let quads = imported[0].position_data.as_ref().unwrap();
+        assert_eq!(imported[0].page_number, 2);
+        assert_eq!((quads.page_width, quads.page_height), (A4.width, A4.height));
+        assert_eq!(quads.rects, vec![rect(50.0, 130.0, 200.0, 12.0)]);
+
+        // Without quad points the rect is used
+        let bounds = imported[1].position_data.as_ref().unwrap();
+        assert_eq!(bounds.rects, vec![rect(100.0, 272.0, 100.0, 20.0)]);
+
+        let note = imported[2].position_data.as_ref().unwrap();
+        assert_eq!(imported[2].annotation_type, AnnotationType::Note);
+        assert_eq!(note.rects, vec![rect(30.0, 22.0, 0.0, 0.0)]);
+    }
+
+    #[test]
+    fn out_of_range_page_indexes_are_skipped() {
+        let xml = r##"<xfdf xmlns="http://ns.adobe.com/xfdf/"><annots>
+  <text page="4294967295" rect="30,800,50,820" name="far"/>
+  <text page="0" rect="30,800,50,820" name="near"/>
+</annots></xfdf>"##;
+        let imported = from_xfdf(xml, &[LETTER]).unwrap();
+        assert_eq!(imported.len(), 1);
+        assert_eq!(imported[0].id, "near");
+        assert!(from_xfdf(
+            r#"<xfdf><annots><text page="4294967296"/></annots></xfdf>"#,
+            &[]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn contents_with_xml_special_characters_survive() {
+        let mut note = annotation(
+            "n&1",
+            AnnotationType::Note,
+            1,
+            LETTER,
+            vec![rect(1.0, 2.0, 0.0, 0.0)],
+        );
+        note.content = Some("a < b && c > \"d\" 'e'\nsecond line </contents>".to_string());
+
+        let xml = to_xfdf(&[note.clone()], Some("R&D <draft>.pdf"));
+        assert!(!xml.contains("a < b"), "{}", xml);
+        let imported = from_xfdf(&xml, &[LETTER]).unwrap();
+        assert_eq!(imported, vec![note]);
+    }
+
+    #[test]
+    fn pdf_dates_convert_both_ways() {
+        assert_eq!(
+            to_pdf_date("2024-03-02T08:00:00-05:30"),
+            "D:20240302080000-05'30'"
+        );
+        assert_eq!(
+            from_pdf_date("D:20240302080000-05'30'").as_deref(),
+            Some("2024-03-02T08:00:00-05:30")
+        );
+        assert_eq!(
+            from_pdf_date("D:2024").as_deref(),
+            Some("2024-01-01T00:00:00+00:00")
+        );
+        assert_eq!(from_pdf_date("garbage"), None);
+    }
+}
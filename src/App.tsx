@@ -10,6 +10,7 @@ import { AiPanel } from "@/components/ai/AiPanel";
 import { WelcomeScreen } from "@/components/WelcomeScreen";
 import * as commands from "@/lib/tauri-commands";
 import { confirmPdfImport } from "@/lib/pdf-import";
+import { listenForDeepLinks } from "@/lib/deep-link";
 import { MessageSquare, PanelRightClose, PanelRightOpen, Sparkles } from "lucide-react";
 import { cn } from "@/lib/utils";
 
@@ -41,6 +42,9 @@ export default function App() {
     loadConversationForDocument,
   ]);
 
+  // Links and files opened from outside, at startup and while running
+  useEffect(() => listenForDeepLinks(), []);
+
   // Auto-save every 30 seconds
   useEffect(() => {
     if (!doc) return;
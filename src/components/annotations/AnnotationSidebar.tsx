@@ -2,11 +2,13 @@ import { useState, useMemo } from "react";
 import { useAnnotationStore } from "@/stores/annotation-store";
 import { usePdfStore } from "@/stores/pdf-store";
 import type { Annotation, AnnotationType } from "@/types";
+import * as commands from "@/lib/tauri-commands";
 import { cn } from "@/lib/utils";
 import {
   Highlighter,
   MessageSquare,
   Bookmark,
+  Link,
   Trash2,
   Filter,
 } from "lucide-react";
@@ -200,6 +202,26 @@ export function AnnotationSidebar() {
                   ) : null}
                 </div>
 
+                {/* Copy a vellum:// link to the annotation */}
+                <button
+                  className="flex-shrink-0 rounded p-1 text-muted-foreground opacity-0 transition-opacity hover:bg-accent hover:text-foreground group-hover:opacity-100"
+                  onClick={(e) => {
+                    e.stopPropagation();
+                    commands
+                      .getDeepLink(annotation.page_number, annotation.id)
+                      .then((link) => navigator.clipboard.writeText(link))
+                      .catch((err) => {
+                        console.error(
+                          "[annotations] Failed to copy link:",
+                          err,
+                        );
+                      });
+                  }}
+                  title="Copy link to annotation"
+                >
+                  <Link size={14} />
+                </button>
+
                 {/* Delete button */}
                 <button
                   className="flex-shrink-0 rounded p-1 text-muted-foreground opacity-0 transition-opacity hover:bg-destructive/10 hover:text-destructive group-hover:opacity-100"
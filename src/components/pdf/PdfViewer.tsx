@@ -19,6 +19,7 @@ import { cn } from "@/lib/utils";
 import { ErrorBoundary } from "@/components/ErrorBoundary";
 import { StickyNote } from "lucide-react";
 import * as commands from "@/lib/tauri-commands";
+import { openPdfStream, type PdfRangeTransport } from "@/lib/pdf-stream";
 import type { Annotation } from "@/types";
 
 pdfjs.GlobalWorkerOptions.workerSrc = new URL(
@@ -28,6 +29,8 @@ pdfjs.GlobalWorkerOptions.workerSrc = new URL(
 
 /** Number of pages to render above/below the visible range */
 const PAGE_BUFFER = 4;
+/** Fetch only the byte ranges needed for the pages being shown */
+const DOCUMENT_OPTIONS = { disableAutoFetch: true, disableStream: true };
 /** Default PDF page dimensions in points (US Letter) */
 const DEFAULT_PAGE_WIDTH = 612;
 const DEFAULT_PAGE_HEIGHT = 792;
@@ -87,6 +90,9 @@ export function PdfViewer() {
   const setCurrentPage = usePdfStore((s) => s.setCurrentPage);
   const setVisiblePages = usePdfStore((s) => s.setVisiblePages);
   const setZoom = usePdfStore((s) => s.setZoom);
+  const pendingPage = usePdfStore((s) => s.pendingPage);
+  const goToPage = usePdfStore((s) => s.goToPage);
+  const clearPendingPage = usePdfStore((s) => s.clearPendingPage);
   const renderDevicePixelRatio = useMemo(
     () => Math.min(window.devicePixelRatio || 1, 1.5),
     [],
@@ -112,9 +118,13 @@ export function PdfViewer() {
     handleMouseUp,
   } = useTextSelection();
 
-  // Read PDF bytes from Rust backend
-  const [pdfData, setPdfData] = useState<{ data: Uint8Array } | null>(null);
+  // PDF bytes from the Rust backend, streamed in ranges where possible
+  const [pdfData, setPdfData] = useState<
+    { range: PdfRangeTransport } | { data: Uint8Array } | null
+  >(null);
   const [pdfError, setPdfError] = useState<string | null>(null);
+  // Whether PDF.js has loaded pdfData, so page elements exist
+  const [pdfLoaded, setPdfLoaded] = useState(false);
 
   // Right-click context menu state
   const [contextMenu, setContextMenu] = useState<{
@@ -132,6 +142,7 @@ export function PdfViewer() {
       queueMicrotask(() => {
         setPdfData(null);
         setPdfError(null);
+        setPdfLoaded(false);
         setPageDimensions({});
         clearDocumentContext();
       });
@@ -143,12 +154,20 @@ export function PdfViewer() {
       if (cancelled) return;
       setPdfData(null);
       setPdfError(null);
+      setPdfLoaded(false);
       setPageDimensions({});
     });
 
-    commands
-      .readPdfBytes()
-      .then((buffer) => {
+    openPdfStream(doc.pdf_sha256)
+      .then((range) => {
+        if (!cancelled) setPdfData({ range });
+      })
+      .catch(async (streamErr) => {
+        console.warn(
+          "[PdfViewer] Streaming failed, reading the whole PDF:",
+          streamErr,
+        );
+        const buffer = await commands.readPdfBytes();
         if (!cancelled) {
           const arr = new Uint8Array(buffer);
           setPdfData({ data: arr });
@@ -170,11 +189,13 @@ export function PdfViewer() {
     (loadedPdf: { numPages: number; getPage: (page: number) => Promise<unknown> }) => {
       const pages = loadedPdf.numPages;
       setNumPages(pages);
+      setPdfLoaded(true);
 
       const runId = textExtractionRunRef.current + 1;
       textExtractionRunRef.current = runId;
 
       void (async () => {
+        const extracted: Record<number, string> = {};
         for (let pageNum = 1; pageNum <= pages; pageNum++) {
           if (textExtractionRunRef.current !== runId) return;
           try {
@@ -190,6 +211,7 @@ export function PdfViewer() {
               .replace(/\s+/g, " ")
               .trim();
             setPageText(pageNum, pageText);
+            extracted[pageNum] = pageText;
           } catch (err) {
             console.warn(`[PdfViewer] Failed text extraction for page ${pageNum}:`, err);
           }
@@ -199,6 +221,13 @@ export function PdfViewer() {
             await new Promise((resolve) => window.setTimeout(resolve, 0));
           }
         }
+
+        // Persist for library-wide search
+        try {
+          await commands.setPageTexts(extracted);
+        } catch (err) {
+          console.warn("[PdfViewer] Failed to store page text:", err);
+        }
       })();
     },
     [setNumPages, setPageText],
@@ -380,6 +409,19 @@ export function PdfViewer() {
     };
   }, [scrollToPage]);
 
+  // Scroll to a page requested before the document had loaded (deep links,
+  // --page)
+  useEffect(() => {
+    if (!pdfLoaded || pendingPage === null) return;
+    const raf = window.requestAnimationFrame(() => {
+      goToPage(pendingPage);
+      clearPendingPage();
+    });
+    return () => {
+      window.cancelAnimationFrame(raf);
+    };
+  }, [clearPendingPage, goToPage, pdfLoaded, pendingPage]);
+
   useEffect(() => {
     handleScroll();
   }, [handleScroll, numPages, pdfData]);
@@ -905,6 +947,7 @@ export function PdfViewer() {
       <ErrorBoundary>
         <Document
           file={pdfData}
+          options={DOCUMENT_OPTIONS}
           onLoadSuccess={onDocumentLoadSuccess}
           loading={
             <div className="flex h-full items-center justify-center">
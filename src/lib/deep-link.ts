@@ -0,0 +1,54 @@
+import { listen } from "@tauri-apps/api/event";
+import type { DeepLinkTarget } from "@/types";
+import * as commands from "@/lib/tauri-commands";
+import { confirmPdfImport } from "@/lib/pdf-import";
+import { usePdfStore } from "@/stores/pdf-store";
+import { useAnnotationStore } from "@/stores/annotation-store";
+
+/** Open the linked document, unless it is open already, and go to the target */
+export async function openDeepLink(target: DeepLinkTarget): Promise<void> {
+  const pdf = usePdfStore.getState();
+  if (pdf.document?.rr_path !== target.path) {
+    if (!confirmPdfImport(target.path)) return;
+    await pdf.openFile(target.path);
+    if (!usePdfStore.getState().document) return;
+  }
+
+  let page = target.page_number;
+  if (target.annotation_id) {
+    const annotation = (await commands.getAnnotations()).find(
+      (a) => a.id === target.annotation_id,
+    );
+    if (annotation) {
+      page = annotation.page_number;
+      useAnnotationStore.getState().selectAnnotation(annotation.id);
+    } else {
+      console.warn("[deep-link] Annotation not found:", target.annotation_id);
+    }
+  }
+  if (page) usePdfStore.getState().requestPage(page);
+}
+
+async function openPendingDeepLink(): Promise<void> {
+  try {
+    const target = await commands.takeDeepLink();
+    if (target) await openDeepLink(target);
+  } catch (err) {
+    console.error("[deep-link] Failed to open link:", err);
+  }
+}
+
+/**
+ * Open the document the app was started with, by a vellum:// link, the
+ * command line or the file manager, then any delivered later.
+ * Returns a function that stops listening.
+ */
+export function listenForDeepLinks(): () => void {
+  void openPendingDeepLink();
+  const unlisten = listen("deep-link", () => {
+    void openPendingDeepLink();
+  });
+  return () => {
+    void unlisten.then((stop) => stop());
+  };
+}
@@ -0,0 +1,55 @@
+import { convertFileSrc } from "@tauri-apps/api/core";
+import { pdfjs } from "react-pdf";
+
+/** Bytes fetched before PDF.js starts; it requests the rest as it needs it */
+const INITIAL_CHUNK_SIZE = 64 * 1024;
+
+export type PdfRangeTransport = InstanceType<
+  typeof pdfjs.PDFDataRangeTransport
+>;
+
+/** URL of the open document's PDF on the backend's `rr://` scheme */
+export function pdfUrl(pdfSha256: string): string {
+  return convertFileSrc(`${pdfSha256}.pdf`, "rr");
+}
+
+async function fetchRange(
+  url: string,
+  begin: number,
+  end: number,
+): Promise<{ bytes: Uint8Array; total: number }> {
+  const response = await fetch(url, {
+    headers: { Range: `bytes=${begin}-${end - 1}` },
+  });
+  if (response.status !== 206) {
+    throw new Error(`Range request failed with status ${response.status}`);
+  }
+  const total = Number(
+    response.headers.get("Content-Range")?.split("/")[1] ?? Number.NaN,
+  );
+  return { bytes: new Uint8Array(await response.arrayBuffer()), total };
+}
+
+/**
+ * Load the open document's PDF in ranges over the `rr://` scheme, so large
+ * files don't have to be read into memory and sent over IPC in one piece.
+ */
+export async function openPdfStream(
+  pdfSha256: string,
+): Promise<PdfRangeTransport> {
+  const url = pdfUrl(pdfSha256);
+  const first = await fetchRange(url, 0, INITIAL_CHUNK_SIZE);
+  if (!Number.isFinite(first.total)) {
+    throw new Error("Range response is missing the file size");
+  }
+
+  const transport = new pdfjs.PDFDataRangeTransport(first.total, first.bytes);
+  transport.requestDataRange = (begin: number, end: number) => {
+    fetchRange(url, begin, end)
+      .then(({ bytes }) => transport.onDataRange(begin, bytes))
+      .catch((err) => {
+        console.error("[pdf-stream] Failed to load bytes", begin, end, err);
+      });
+  };
+  return transport;
+}
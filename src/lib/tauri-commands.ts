@@ -3,9 +3,39 @@
 import { invoke } from "@tauri-apps/api/core";
 import type {
   Annotation,
+  BibliographicRecord,
+  BibliographyFormat,
+  CitationStyle,
+  CitedReference,
+  CollabInfo,
+  Collection,
   CreateAnnotationInput,
+  DeepLinkTarget,
   DocumentInfo,
+  DuplicateMatch,
+  ExtractedMetadata,
+  LibraryConfig,
+  LibraryEntry,
+  LibraryImport,
+  LibraryQuery,
+  MergeStrategy,
+  MergeSummary,
+  Outline,
+  PageLabel,
+  PageSize,
+  ReadingStatus,
+  ReferenceEntry,
+  ReferenceFile,
+  ReferenceImport,
+  ReferenceLink,
+  SearchHit,
+  SidecarImportResult,
+  SyncConfig,
+  SyncedDocument,
+  SyncReport,
+  ThreeWayMergeSummary,
   UpdateAnnotationInput,
+  WatchedFolder,
 } from "@/types";
 
 export async function openFile(path: string): Promise<DocumentInfo> {
@@ -20,10 +50,61 @@ export async function closeFile(): Promise<void> {
   return invoke("close_file");
 }
 
+export async function setPageTexts(
+  pages: Record<number, string>,
+): Promise<DuplicateMatch[]> {
+  return invoke<DuplicateMatch[]>("set_page_texts", { pages });
+}
+
+export async function getBibliography(): Promise<BibliographicRecord> {
+  return invoke<BibliographicRecord>("get_bibliography");
+}
+
+export async function setBibliography(
+  record: BibliographicRecord,
+): Promise<BibliographicRecord> {
+  return invoke<BibliographicRecord>("set_bibliography", { record });
+}
+
+export async function getExtractedMetadata(): Promise<ExtractedMetadata | null> {
+  return invoke<ExtractedMetadata | null>("get_extracted_metadata");
+}
+
+export async function extractMetadata(): Promise<ExtractedMetadata> {
+  return invoke<ExtractedMetadata>("extract_metadata");
+}
+
+export async function takeDeepLink(): Promise<DeepLinkTarget | null> {
+  return invoke<DeepLinkTarget | null>("take_deep_link");
+}
+
+export async function getDeepLink(
+  pageNumber?: number,
+  annotationId?: string,
+): Promise<string> {
+  return invoke<string>("get_deep_link", {
+    pageNumber: pageNumber ?? null,
+    annotationId: annotationId ?? null,
+  });
+}
+
 export async function readPdfBytes(): Promise<ArrayBuffer> {
   return invoke<ArrayBuffer>("read_pdf_bytes");
 }
 
+export async function getThumbnail(pageNumber: number): Promise<ArrayBuffer> {
+  return invoke<ArrayBuffer>("get_thumbnail", { pageNumber });
+}
+
+export async function setThumbnail(
+  pageNumber: number,
+  image: Uint8Array,
+): Promise<void> {
+  return invoke("set_thumbnail", image, {
+    headers: { "page-number": String(pageNumber) },
+  });
+}
+
 export async function getAnnotations(
   pageNumber?: number,
 ): Promise<Annotation[]> {
@@ -54,3 +135,359 @@ export async function setDocumentMetadata(
 ): Promise<void> {
   return invoke("set_document_metadata", { key, value });
 }
+
+export async function exportXfdf(path: string): Promise<void> {
+  return invoke("export_xfdf", { path });
+}
+
+export async function importXfdf(
+  path: string,
+  pageSizes: PageSize[],
+): Promise<Annotation[]> {
+  return invoke<Annotation[]>("import_xfdf", { path, pageSizes });
+}
+
+export async function exportWebAnnotations(path: string): Promise<void> {
+  return invoke("export_web_annotations", { path });
+}
+
+export async function importWebAnnotations(
+  path: string,
+  pageSizes: PageSize[],
+  force = false,
+): Promise<Annotation[]> {
+  return invoke<Annotation[]>("import_web_annotations", {
+    path,
+    pageSizes,
+    force,
+  });
+}
+
+export async function exportSidecar(path: string): Promise<void> {
+  return invoke("export_sidecar", { path });
+}
+
+export async function importSidecar(
+  path: string,
+  force = false,
+): Promise<SidecarImportResult> {
+  return invoke<SidecarImportResult>("import_sidecar", { path, force });
+}
+
+export async function mergeFrom(
+  path: string,
+  strategy: MergeStrategy = "newer_wins",
+): Promise<MergeSummary> {
+  return invoke<MergeSummary>("merge_from", { path, strategy });
+}
+
+export async function mergeThreeWay(
+  base: string,
+  ours: string,
+  theirs: string,
+  output: string,
+  strategy: MergeStrategy = "newer_wins",
+): Promise<ThreeWayMergeSummary> {
+  return invoke<ThreeWayMergeSummary>("merge_three_way", {
+    base,
+    ours,
+    theirs,
+    output,
+    strategy,
+  });
+}
+
+export async function getSyncConfig(): Promise<SyncConfig> {
+  return invoke<SyncConfig>("get_sync_config");
+}
+
+export async function setSyncFolder(folder: string | null): Promise<SyncConfig> {
+  return invoke<SyncConfig>("set_sync_folder", { folder });
+}
+
+export async function syncNow(): Promise<SyncReport> {
+  return invoke<SyncReport>("sync_now");
+}
+
+export async function syncLibrary(): Promise<SyncReport[]> {
+  return invoke<SyncReport[]>("sync_library");
+}
+
+export async function listSyncedDocuments(): Promise<SyncedDocument[]> {
+  return invoke<SyncedDocument[]>("list_synced_documents");
+}
+
+export async function setWebdavAccount(
+  url: string,
+  username: string,
+  password: string | null,
+  chunkedUploadUrl: string | null,
+): Promise<SyncConfig> {
+  return invoke<SyncConfig>("set_webdav_account", {
+    url,
+    username,
+    password,
+    chunkedUploadUrl,
+  });
+}
+
+export async function clearWebdavAccount(): Promise<SyncConfig> {
+  return invoke<SyncConfig>("clear_webdav_account");
+}
+
+export async function webdavSyncNow(): Promise<SyncReport> {
+  return invoke<SyncReport>("webdav_sync_now");
+}
+
+export async function webdavListDocuments(): Promise<string[]> {
+  return invoke<string[]>("webdav_list_documents");
+}
+
+export async function webdavDownloadDocument(
+  pdfSha256: string,
+  dest: string,
+): Promise<void> {
+  return invoke("webdav_download_document", { pdfSha256, dest });
+}
+
+export async function startCollabSession(name: string): Promise<CollabInfo> {
+  return invoke<CollabInfo>("start_collab_session", { name });
+}
+
+export async function joinCollabSession(
+  code: string,
+  name: string,
+): Promise<CollabInfo> {
+  return invoke<CollabInfo>("join_collab_session", { code, name });
+}
+
+export async function leaveCollabSession(): Promise<void> {
+  return invoke("leave_collab_session");
+}
+
+export async function setCollabPage(page: number | null): Promise<void> {
+  return invoke("set_collab_page", { page });
+}
+
+export async function getCollabSession(): Promise<CollabInfo | null> {
+  return invoke<CollabInfo | null>("get_collab_session");
+}
+
+export async function listLibrary(
+  query?: LibraryQuery,
+): Promise<LibraryEntry[]> {
+  return invoke<LibraryEntry[]>("list_library", { query: query ?? null });
+}
+
+export async function relocateLibraryEntry(
+  id: number,
+  path: string,
+): Promise<LibraryEntry> {
+  return invoke<LibraryEntry>("relocate_library_entry", { id, path });
+}
+
+export async function removeLibraryEntry(id: number): Promise<boolean> {
+  return invoke<boolean>("remove_library_entry", { id });
+}
+
+export async function getLibraryConfig(): Promise<LibraryConfig> {
+  return invoke<LibraryConfig>("get_library_config");
+}
+
+export async function setLibraryFolder(
+  folder: string | null,
+): Promise<LibraryConfig> {
+  return invoke<LibraryConfig>("set_library_folder", { folder });
+}
+
+export async function setWatchedFolders(
+  folders: WatchedFolder[],
+): Promise<LibraryConfig> {
+  return invoke<LibraryConfig>("set_watched_folders", { folders });
+}
+
+export async function importToLibrary(
+  path: string,
+  removeOriginal: boolean,
+): Promise<LibraryImport> {
+  return invoke<LibraryImport>("import_to_library", { path, removeOriginal });
+}
+
+export async function searchLibrary(query: string): Promise<SearchHit[]> {
+  return invoke<SearchHit[]>("search_library", { query });
+}
+
+export async function importReferenceFile(
+  path: string,
+): Promise<ReferenceImport> {
+  return invoke<ReferenceImport>("import_reference_file", { path });
+}
+
+export async function refreshReferenceFiles(): Promise<ReferenceImport[]> {
+  return invoke<ReferenceImport[]>("refresh_reference_files");
+}
+
+export async function listReferenceFiles(): Promise<ReferenceFile[]> {
+  return invoke<ReferenceFile[]>("list_reference_files");
+}
+
+export async function removeReferenceFile(id: number): Promise<boolean> {
+  return invoke<boolean>("remove_reference_file", { id });
+}
+
+export async function searchReferences(
+  query: string,
+): Promise<ReferenceEntry[]> {
+  return invoke<ReferenceEntry[]>("search_references", { query });
+}
+
+export async function getReferenceLink(
+  documentId: number,
+): Promise<ReferenceLink | null> {
+  return invoke<ReferenceLink | null>("get_reference_link", { documentId });
+}
+
+export async function linkReference(
+  documentId: number,
+  fileId: number,
+  key: string,
+): Promise<ReferenceLink> {
+  return invoke<ReferenceLink>("link_reference", { documentId, fileId, key });
+}
+
+export async function unlinkReference(documentId: number): Promise<boolean> {
+  return invoke<boolean>("unlink_reference", { documentId });
+}
+
+export async function getOutline(): Promise<Outline> {
+  return invoke<Outline>("get_outline");
+}
+
+export async function getPageLabels(): Promise<PageLabel[]> {
+  return invoke<PageLabel[]>("get_page_labels");
+}
+
+export async function pageLabelToNumber(
+  label: string,
+): Promise<number | null> {
+  return invoke<number | null>("page_label_to_number", { label });
+}
+
+export async function pageNumberToLabel(
+  pageNumber: number,
+): Promise<string | null> {
+  return invoke<string | null>("page_number_to_label", { pageNumber });
+}
+
+export async function getCitedReferences(): Promise<CitedReference[]> {
+  return invoke<CitedReference[]>("get_cited_references");
+}
+
+export async function setCitedReferenceToRead(
+  position: number,
+  toRead: boolean,
+): Promise<CitedReference> {
+  return invoke<CitedReference>("set_cited_reference_to_read", {
+    position,
+    toRead,
+  });
+}
+
+export async function exportBibliography(
+  path: string,
+  documentIds: number[],
+  collectionIds: number[],
+  format: BibliographyFormat,
+): Promise<number> {
+  return invoke<number>("export_bibliography", {
+    path,
+    documentIds,
+    collectionIds,
+    format,
+  });
+}
+
+export async function setCitationKey(
+  id: number,
+  key: string,
+): Promise<LibraryEntry> {
+  return invoke<LibraryEntry>("set_citation_key", { id, key });
+}
+
+export async function formatCitation(style: CitationStyle): Promise<string> {
+  return invoke<string>("format_citation", { style });
+}
+
+export async function setReadingStatus(
+  id: number,
+  status: ReadingStatus | null,
+): Promise<boolean> {
+  return invoke<boolean>("set_reading_status", { id, status });
+}
+
+export async function setReadingPriority(
+  id: number,
+  priority: number | null,
+): Promise<boolean> {
+  return invoke<boolean>("set_reading_priority", { id, priority });
+}
+
+export async function setDueDate(
+  id: number,
+  dueDate: string | null,
+): Promise<boolean> {
+  return invoke<boolean>("set_due_date", { id, dueDate });
+}
+
+export async function listCollections(): Promise<Collection[]> {
+  return invoke<Collection[]>("list_collections");
+}
+
+export async function createCollection(
+  name: string,
+  parentId: number | null,
+): Promise<Collection> {
+  return invoke<Collection>("create_collection", { name, parentId });
+}
+
+export async function renameCollection(
+  id: number,
+  name: string,
+): Promise<Collection> {
+  return invoke<Collection>("rename_collection", { id, name });
+}
+
+export async function moveCollection(
+  id: number,
+  parentId: number | null,
+): Promise<Collection> {
+  return invoke<Collection>("move_collection", { id, parentId });
+}
+
+export async function deleteCollection(id: number): Promise<boolean> {
+  return invoke<boolean>("delete_collection", { id });
+}
+
+export async function addToCollection(
+  collectionId: number,
+  documentIds: number[],
+): Promise<void> {
+  return invoke("add_to_collection", { collectionId, documentIds });
+}
+
+export async function removeFromCollection(
+  collectionId: number,
+  documentIds: number[],
+): Promise<void> {
+  return invoke("remove_from_collection", { collectionId, documentIds });
+}
+
+export async function mergeIntoLibraryDocument(
+  id: number,
+  strategy?: MergeStrategy,
+): Promise<MergeSummary> {
+  return invoke<MergeSummary>("merge_into_library_document", {
+    id,
+    strategy: strategy ?? null,
+  });
+}
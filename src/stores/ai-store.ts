@@ -2,6 +2,7 @@ import { create } from "zustand";
 import { generateText } from "ai";
 import { createGoogleGenerativeAI } from "@ai-sdk/google";
 import { buildToolModePrompt } from "@/lib/ai-prompts";
+import { pageLabelToNumber } from "@/lib/tauri-commands";
 import { useAnnotationStore } from "@/stores/annotation-store";
 import { usePdfStore } from "@/stores/pdf-store";
 import type { Annotation, DocumentInfo } from "@/types";
@@ -12,7 +13,7 @@ type VoiceMode = "off" | "push-to-talk";
 type ToolAction =
   | {
       tool: "goToPage";
-      args: { pageNumber: number };
+      args: { pageNumber?: number; pageLabel?: string };
     }
   | {
       tool: "addNote";
@@ -312,7 +313,8 @@ function buildContextBlock(
     .map((a) => {
       const text = a.position_data?.selected_text ?? "";
       const note = a.content ?? "";
-      return `- (${a.type}) p.${a.page_number} color=${a.color ?? "none"} text="${text}" note="${note}"`;
+      const label = a.page_label ? ` (labelled ${a.page_label})` : "";
+      return `- (${a.type}) p.${a.page_number}${label} color=${a.color ?? "none"} text="${text}" note="${note}"`;
     })
     .join("\n");
 
@@ -400,7 +402,17 @@ async function callGemini(opts: {
 
 async function executeToolAction(action: ToolAction): Promise<string> {
   if (action.tool === "goToPage") {
-    const pageNumber = clampPage(action.args.pageNumber);
+    const { pageLabel } = action.args;
+    let target = action.args.pageNumber;
+    if (pageLabel) {
+      const labelled = await pageLabelToNumber(pageLabel);
+      if (labelled === null) {
+        return `Skipped goToPage: no page is labelled "${pageLabel}".`;
+      }
+      target = labelled;
+    }
+    if (target === undefined) return "Skipped goToPage: no page given.";
+    const pageNumber = clampPage(target);
     usePdfStore.getState().goToPage(pageNumber);
     return `Navigated to page ${pageNumber}.`;
   }
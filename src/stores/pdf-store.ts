@@ -15,6 +15,8 @@ interface PdfState {
   numPages: number;
   zoom: number;
   visiblePages: number[];
+  // Page to scroll to once the viewer has loaded the document
+  pendingPage: number | null;
 
   // Interaction mode
   mode: InteractionMode;
@@ -29,6 +31,8 @@ interface PdfState {
   zoomOut: () => void;
   setVisiblePages: (pages: number[]) => void;
   goToPage: (page: number) => void;
+  requestPage: (page: number) => void;
+  clearPendingPage: () => void;
   setMode: (mode: InteractionMode) => void;
 }
 
@@ -44,6 +48,7 @@ export const usePdfStore = create<PdfState>((set, get) => ({
   numPages: 0,
   zoom: 1.0,
   visiblePages: [],
+  pendingPage: null,
   mode: "view",
 
   openFile: async (path: string) => {
@@ -55,6 +60,7 @@ export const usePdfStore = create<PdfState>((set, get) => ({
         isLoading: false,
         currentPage: doc.last_page ?? 1,
         numPages: doc.page_count ?? 0,
+        pendingPage: null,
       });
     } catch (e) {
       set({ isLoading: false, error: String(e) });
@@ -75,6 +81,7 @@ export const usePdfStore = create<PdfState>((set, get) => ({
       numPages: 0,
       zoom: 1.0,
       visiblePages: [],
+      pendingPage: null,
       mode: "view",
     });
   },
@@ -116,5 +123,7 @@ export const usePdfStore = create<PdfState>((set, get) => ({
       .__scrollToPage as ((page: number) => void) | undefined;
     scrollToPage?.(clamped);
   },
+  requestPage: (page: number) => set({ pendingPage: page }),
+  clearPendingPage: () => set({ pendingPage: null }),
   setMode: (mode: InteractionMode) => set({ mode }),
 }));
@@ -22,6 +22,7 @@ export interface Annotation {
   id: string;
   type: AnnotationType;
   page_number: number;
+  page_label: string | null;
   color: string | null;
   content: string | null;
   position_data: PositionData | null;
@@ -44,14 +45,290 @@ export interface UpdateAnnotationInput {
   position_data?: PositionData;
 }
 
+export interface BibliographicRecord {
+  title: string | null;
+  authors: string[];
+  year: number | null;
+  venue: string | null;
+  doi: string | null;
+  arxiv_id: string | null;
+  abstract: string | null;
+  keywords: string[];
+  url: string | null;
+}
+
+export type MetadataSource = "info" | "xmp" | "text" | "layout" | "filename";
+
+export interface MetadataCandidate {
+  field: keyof BibliographicRecord;
+  value: string | string[] | number;
+  source: MetadataSource;
+  confidence: number;
+  applied: boolean;
+}
+
+export interface ExtractedMetadata {
+  candidates: MetadataCandidate[];
+  extracted_at: string;
+  confirmed: boolean;
+}
+
+export interface PageSize {
+  width: number;
+  height: number;
+}
+
+export interface SidecarImportResult {
+  hash_matches: boolean;
+  annotations_imported: number;
+  metadata_imported: number;
+}
+
+export type MergeStrategy = "newer_wins" | "keep_both";
+
+export interface MergeConflict {
+  id: string;
+  resolution: "kept_ours" | "kept_both" | "kept_edit" | "deleted";
+  copy_id: string | null;
+}
+
+export interface MergeSummary {
+  added: string[];
+  updated: string[];
+  deleted: string[];
+  conflicts: MergeConflict[];
+  unchanged: number;
+}
+
+export interface TableMergeSummary {
+  added: number;
+  updated: number;
+  deleted: number;
+  conflicts: string[];
+}
+
+export interface ThreeWayMergeSummary {
+  annotations: TableMergeSummary;
+  metadata: TableMergeSummary;
+  conversations: TableMergeSummary;
+}
+
+export interface WebDavSettings {
+  url: string;
+  username: string;
+  chunked_upload_url: string | null;
+}
+
+export interface SyncConfig {
+  folder: string | null;
+  device_id: string;
+  webdav: WebDavSettings | null;
+}
+
+export interface SyncReport {
+  pdf_sha256: string;
+  pushed: number;
+  applied: number;
+  skipped: number;
+  snapshot_updated: boolean;
+}
+
+export interface SyncedDocument {
+  pdf_sha256: string;
+  path: string;
+}
+
+export interface Presence {
+  peer_id: string;
+  name: string;
+  page: number | null;
+  is_host: boolean;
+}
+
+export interface CollabInfo {
+  is_host: boolean;
+  join_code: string | null;
+  peers: Presence[];
+}
+
+export interface AnnotationChangeSet {
+  added: string[];
+  updated: string[];
+  deleted: string[];
+}
+
+export interface CollabConnection {
+  connected: boolean;
+  message: string | null;
+}
+
+export interface LibraryEntry {
+  id: number;
+  path: string;
+  title: string | null;
+  pdf_sha256: string | null;
+  page_count: number | null;
+  last_page: number | null;
+  highlight_count: number;
+  note_count: number;
+  bookmark_count: number;
+  added_at: string;
+  last_opened_at: string | null;
+  status: ReadingStatus | null;
+  priority: number | null;
+  due_date: string | null;
+  collection_ids: number[];
+  text_fingerprint: string | null;
+  authors: string[];
+  year: number | null;
+  venue: string | null;
+  doi: string | null;
+  arxiv_id: string | null;
+  citation_key: string | null;
+  missing: boolean;
+}
+
+export interface DuplicateMatch {
+  kind: "identical" | "similar";
+  entry: LibraryEntry;
+}
+
+export type ReadingStatus = "to_read" | "reading" | "done";
+
+export interface Collection {
+  id: number;
+  name: string;
+  parent_id: number | null;
+  created_at: string;
+  document_count: number;
+}
+
+export type LibrarySort =
+  | "title"
+  | "last_opened"
+  | "added"
+  | "annotations"
+  | "priority"
+  | "due_date"
+  | "year";
+
+export interface LibraryQuery {
+  sort?: LibrarySort | null;
+  descending?: boolean;
+  text?: string | null;
+  missing_only?: boolean;
+  status?: ReadingStatus | null;
+  collection_id?: number | null;
+}
+
+export interface WatchedFolder {
+  path: string;
+  patterns: string[];
+  remove_original: boolean;
+}
+
+export interface LibraryConfig {
+  folder: string | null;
+  watched_folders: WatchedFolder[];
+}
+
+export interface LibraryImport {
+  source: string;
+  status: "imported" | "duplicate" | "failed";
+  entry: LibraryEntry | null;
+  error: string | null;
+}
+
+export interface SearchHit {
+  document_id: number;
+  path: string;
+  title: string | null;
+  page_number: number | null;
+  page_label: string | null;
+  annotation_id: string | null;
+  kind: "page" | "metadata" | AnnotationType;
+  snippet: string;
+}
+
 export interface DocumentInfo {
   pdf_path: string;
+  pdf_sha256: string;
   rr_path: string;
   title: string | null;
   page_count: number | null;
   last_page: number | null;
+  duplicates: DuplicateMatch[];
+}
+
+export interface ReferenceFile {
+  id: number;
+  path: string;
+  format: "bibtex" | "csl_json";
+  entry_count: number;
+  imported_at: string;
 }
 
+export interface ReferenceEntry {
+  file_id: number;
+  key: string;
+  record: BibliographicRecord;
+}
+
+export interface ReferenceLink {
+  document_id: number;
+  file_id: number;
+  key: string;
+  matched_by: "doi" | "arxiv_id" | "title" | "manual" | "rejected";
+  score: number;
+}
+
+export interface ReferenceImport {
+  file: ReferenceFile;
+  skipped: number;
+  linked: number;
+  updated: number;
+}
+
+export type OutlineSource = "pdf" | "headings";
+
+export interface OutlineItem {
+  level: number;
+  title: string;
+  page_number: number | null;
+  top: number | null;
+}
+
+export interface Outline {
+  source: OutlineSource | null;
+  items: OutlineItem[];
+}
+
+export interface DeepLinkTarget {
+  path: string;
+  page_number: number | null;
+  annotation_id: string | null;
+}
+
+export interface PageLabel {
+  page_number: number;
+  label: string;
+}
+
+export interface CitedReference {
+  position: number;
+  label: string | null;
+  page_number: number;
+  text: string;
+  record: BibliographicRecord;
+  to_read: boolean;
+  document: LibraryEntry | null;
+  matched_by: "doi" | "arxiv_id" | "title" | null;
+}
+
+export type BibliographyFormat = "bibtex" | "csl_json";
+
+export type CitationStyle = "apa" | "mla" | "chicago" | "bibtex" | "csl_json";
+
 export const HIGHLIGHT_COLORS = [
   { name: "Yellow", value: "#fef08a", dark: "#854d0e80" },
   { name: "Green", value: "#bbf7d0", dark: "#16653480" },